@@ -55,6 +55,15 @@ mod passfile {
     pub(crate) fn get_password_from_passfile(_: &mut Config) -> Result<()> {
         Ok(())
     }
+    pub(crate) fn get_passfile() -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Return the path of the passfile used for
+/// looking up passwords if any
+pub fn passfile_path() -> Option<PathBuf> {
+    passfile::get_passfile()
 }
 
 /// Error while parsing service file or
@@ -11,7 +11,7 @@ use tokio_postgres::config::Host;
 /// Look for passfile
 /// First check the environment variable PGPASSFILE
 /// then check in $HOME/.pgpass
-fn get_passfile() -> Option<PathBuf> {
+pub(crate) fn get_passfile() -> Option<PathBuf> {
     std::env::var("PGPASSFILE")
         .ok()
         .map(|path| Path::new(&path).into())
@@ -1,11 +1,12 @@
 //!
 //! Postgres event dispatcher
 //!
-use futures::{stream, StreamExt};
-use tokio::sync::mpsc;
+use futures::{stream, Future, StreamExt};
+use tokio::sync::{mpsc, watch};
 use tokio_postgres::{
     error::DbError, AsyncMessage, Client, Socket,
     tls::{MakeTlsConnect, TlsConnect},    
+    types::Type,
 };
 
 use crate::{Config, Error, Notification, Result};
@@ -24,6 +25,7 @@ pub struct PgEventDispatcher {
     session_pid: i32,
     tx: mpsc::Sender<Notification>,
     events: HashSet<String>,
+    closed: watch::Receiver<bool>,
 }
 
 impl PgEventDispatcher {
@@ -42,6 +44,7 @@ impl PgEventDispatcher {
         let (client, mut conn) = config.connect(tls).await?;
 
         let sender = tx.clone();
+        let (closed_tx, closed) = watch::channel(false);
 
         // Send the connection in its own task
         // connection will close when the client will be dropped
@@ -73,8 +76,13 @@ impl PgEventDispatcher {
                     }
                 } else {
                     log::debug!("Stream poll returned 'None'");
+                    break;
                 }
             }
+            // Drop the connection before signaling the closure
+            // so that the client is seen as closed
+            drop(stream);
+            let _ = closed_tx.send(true);
             log::debug!("PG: Stopped polling postgres messages");
         });
 
@@ -86,6 +94,7 @@ impl PgEventDispatcher {
             session_pid,
             tx,
             events: HashSet::new(),
+            closed,
         })
     }
 
@@ -127,6 +136,76 @@ impl PgEventDispatcher {
         }
     }
 
+    /// Listen and unlisten multiple events in a single batch
+    ///
+    /// If the connection is closed, the events are only recorded
+    /// and will be listened on [`respawn`](Self::respawn).
+    pub async fn batch_update<L, U>(&mut self, listen: L, unlisten: U) -> Result<()>
+    where
+        L: IntoIterator<Item = String>,
+        U: IntoIterator<Item = String>,
+    {
+        let listen = listen.into_iter().collect::<Vec<_>>();
+        let unlisten = unlisten.into_iter().collect::<Vec<_>>();
+        let query = unlisten
+            .iter()
+            .map(|s| format!("UNLISTEN {s};"))
+            .chain(listen.iter().map(|s| format!("LISTEN {s};")))
+            .collect::<String>();
+        if !query.is_empty() && !self.is_closed() {
+            self.client.batch_execute(&query).await?;
+        }
+        unlisten.iter().for_each(|s| {
+            self.events.remove(s);
+        });
+        self.events.extend(listen);
+        Ok(())
+    }
+
+    /// Return the listened events
+    pub fn events(&self) -> &HashSet<String> {
+        &self.events
+    }
+
+    /// Send a notification for `channel` with `payload`
+    pub async fn notify(&self, channel: &str, payload: &str) -> Result<()> {
+        self.client
+            .execute("SELECT pg_notify($1, $2);", &[&channel, &payload])
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Fetch a text payload with `query`, `$1` being bound to `key`
+    ///
+    /// Integer parameters are parsed from `key`, `None` is returned
+    /// if `key` is not a valid integer or if no row is found.
+    pub async fn fetch_payload(&self, query: &str, key: &str) -> Result<Option<String>> {
+        let statement = self.client.prepare(query).await?;
+        let row = match statement.params() {
+            [Type::INT8] => match key.parse::<i64>() {
+                Ok(key) => self.client.query_opt(&statement, &[&key]).await?,
+                Err(_) => None,
+            },
+            [Type::INT4] => match key.parse::<i32>() {
+                Ok(key) => self.client.query_opt(&statement, &[&key]).await?,
+                Err(_) => None,
+            },
+            _ => self.client.query_opt(&statement, &[&key]).await?,
+        };
+        row.map(|row| row.try_get(0)).transpose()
+    }
+
+    /// Return the fraction of the notification queue
+    /// currently occupied by pending notifications
+    pub async fn notification_queue_usage(&self) -> Result<f64> {
+        let row = self
+            .client
+            .query_one("SELECT pg_notification_queue_usage();", &[])
+            .await?;
+        Ok(row.get(0))
+    }
+
     /// Reconnect listener with its config
     pub async fn respawn<T>(&mut self, tls: T) -> Result<()> 
     where
@@ -161,6 +240,18 @@ impl PgEventDispatcher {
     pub fn is_closed(&self) -> bool {
         self.client.is_closed()
     }
+
+    /// Return a future that resolves when the
+    /// current connection is closed
+    ///
+    /// The future is not affected by a later [`respawn`](Self::respawn).
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut closed = self.closed.clone();
+        async move {
+            // An error means that the connection task is gone
+            let _ = closed.wait_for(|closed| *closed).await;
+        }
+    }
 }
 
 //
@@ -0,0 +1,12 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        if std::env::var_os("PROTOC").is_none() {
+            std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        }
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/pg_event_server.proto"], &["proto"])
+            .unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+//!
+//! Access log
+//!
+//! Structured records of the subscriptions lifecycle
+//! and of the publish calls.
+//!
+//! Records are logged as json with the `access` target
+//! and may be written to a dedicated file.
+//!
+use actix_web::dev::ServiceRequest;
+use serde::Serialize;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::utils::timestamp_millis;
+
+/// Log target of the access records
+pub const TARGET: &str = "access";
+
+/// Reason of the end of a subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The client closed the connection
+    Client,
+    /// The subscriber was disconnected by the
+    /// slow consumer policy
+    SlowConsumer,
+    /// A subscribed channel was removed
+    ChannelRemoved,
+    /// The event stream was closed by the server
+    Server,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    SubscribeStart {
+        ts: u64,
+        id: Uuid,
+        request_id: &'a str,
+        channels: &'a str,
+        client_id: Option<&'a str>,
+        client_ip: Option<&'a str>,
+        peer_addr: Option<&'a str>,
+    },
+    SubscribeEnd {
+        ts: u64,
+        id: Uuid,
+        request_id: &'a str,
+        channels: &'a str,
+        duration_ms: u128,
+        events: u64,
+        bytes: u64,
+        reason: CloseReason,
+    },
+    Publish {
+        ts: u64,
+        request_id: &'a str,
+        channel: &'a str,
+        event: &'a str,
+        size: usize,
+        client_ip: Option<&'a str>,
+        status: u16,
+    },
+}
+
+fn log(record: &Record) {
+    match serde_json::to_string(record) {
+        Ok(record) => log::info!(target: TARGET, "{record}"),
+        Err(err) => log::error!("Failed to serialize access record: {err:?}"),
+    }
+}
+
+/// Query parameters holding credentials
+const REDACTED_PARAMS: [&str; 3] = ["api_key", "sig", "exp"];
+
+/// Return the request line for the http log, with
+/// the credentials of the query string redacted
+pub fn request_line(req: &ServiceRequest) -> String {
+    let query = req
+        .query_string()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if REDACTED_PARAMS.contains(&key) => format!("{key}=<redacted>"),
+            _ => pair.into(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!(
+        "{} {}{}{query} {:?}",
+        req.method(),
+        req.path(),
+        if query.is_empty() { "" } else { "?" },
+        req.version()
+    )
+}
+
+/// Log a publish call
+pub fn publish(
+    request_id: &str,
+    channel: &str,
+    event: &str,
+    size: usize,
+    client_ip: Option<&str>,
+    status: u16,
+) {
+    log(&Record::Publish {
+        ts: timestamp_millis(),
+        request_id,
+        channel,
+        event,
+        size,
+        client_ip,
+        status,
+    })
+}
+
+/// Subscriber session
+///
+/// The session is shared between the broadcaster
+/// and the event stream.
+pub struct Session {
+    id: Uuid,
+    request_id: String,
+    channels: String,
+    started: Instant,
+    events: Cell<u64>,
+    bytes: Cell<u64>,
+    reason: Cell<Option<CloseReason>>,
+}
+
+impl Session {
+    /// Start a new session and log the start record
+    pub fn start(
+        id: Uuid,
+        request_id: String,
+        channels: String,
+        client_id: Option<&str>,
+        client_ip: Option<&str>,
+        peer_addr: Option<&str>,
+    ) -> Self {
+        log(&Record::SubscribeStart {
+            ts: timestamp_millis(),
+            id,
+            request_id: &request_id,
+            channels: &channels,
+            client_id,
+            client_ip,
+            peer_addr,
+        });
+        Self {
+            id,
+            request_id,
+            channels,
+            started: Instant::now(),
+            events: Cell::new(0),
+            bytes: Cell::new(0),
+            reason: Cell::new(None),
+        }
+    }
+
+    /// Count events delivered to the subscriber
+    pub fn sent(&self, events: usize, bytes: usize) {
+        self.events.set(self.events.get() + events as u64);
+        self.bytes.set(self.bytes.get() + bytes as u64);
+    }
+
+    /// Set the close reason
+    ///
+    /// Only the first reason is retained.
+    pub fn close(&self, reason: CloseReason) {
+        if self.reason.get().is_none() {
+            self.reason.set(Some(reason));
+        }
+    }
+}
+
+/// Log the end record of the session when dropped
+pub struct SessionGuard(pub Rc<Session>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let session = &self.0;
+        log(&Record::SubscribeEnd {
+            ts: timestamp_millis(),
+            id: session.id,
+            request_id: &session.request_id,
+            channels: &session.channels,
+            duration_ms: session.started.elapsed().as_millis(),
+            events: session.events.get(),
+            bytes: session.bytes.get(),
+            reason: session.reason.get().unwrap_or(CloseReason::Client),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn session_accounting() {
+        let session = Session::start(
+            Uuid::new_v4(),
+            "req".into(),
+            "test".into(),
+            None,
+            None,
+            None,
+        );
+        session.sent(1, 10);
+        session.sent(3, 32);
+        session.close(CloseReason::SlowConsumer);
+        session.close(CloseReason::Server);
+        assert_eq!(session.events.get(), 4);
+        assert_eq!(session.bytes.get(), 42);
+        assert_eq!(session.reason.get(), Some(CloseReason::SlowConsumer));
+
+        let record = serde_json::to_value(Record::SubscribeEnd {
+            ts: 0,
+            id: session.id,
+            request_id: &session.request_id,
+            channels: &session.channels,
+            duration_ms: 0,
+            events: 4,
+            bytes: 42,
+            reason: CloseReason::SlowConsumer,
+        })
+        .unwrap();
+        assert_eq!(record["type"], "subscribe_end");
+        assert_eq!(record["reason"], "slow_consumer");
+    }
+
+    #[test]
+    fn redacted_request_line() {
+        let req = TestRequest::get()
+            .uri("/events/subscribe/foo?api_key=k1&event=a&exp=1700000000&sig=abc")
+            .to_srv_request();
+        assert_eq!(
+            request_line(&req),
+            "GET /events/subscribe/foo?api_key=<redacted>&event=a&exp=<redacted>&sig=<redacted> HTTP/1.1"
+        );
+
+        let req = TestRequest::get().uri("/stats").to_srv_request();
+        assert_eq!(request_line(&req), "GET /stats HTTP/1.1");
+    }
+}
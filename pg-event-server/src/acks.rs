@@ -0,0 +1,311 @@
+//!
+//! Acknowledged delivery
+//!
+//! On channels with an `ack` section, events sent to a
+//! consumer are kept until the consumer acknowledges them. Unacknowledged
+//! events are redelivered when the consumer reconnects, up to
+//! `max_retries` times.
+//!
+//! Pending events are shared across all workers. They are persisted
+//! in the history store, so that they are redelivered after a restart
+//! or by another instance: acknowledged delivery requires the channel
+//! history and a history store.
+//!
+//! Consumers that do not connect for `consumer_ttl` are evicted
+//! from memory, their events remain in the history store until they
+//! leave the history.
+//!
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::AckConfig;
+use crate::events::{ChanId, Event};
+use crate::historystore::HistoryStore;
+
+/// Event waiting for acknowledgement
+struct Pending {
+    event: Event,
+    /// Number of redeliveries
+    retries: u32,
+}
+
+/// Unacknowledged events of a consumer
+struct Consumer {
+    pending: VecDeque<Pending>,
+    /// Last delivery or redelivery
+    updated: Instant,
+    ttl: Duration,
+}
+
+impl Consumer {
+    fn new(ttl: Duration, now: Instant) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            updated: now,
+            ttl,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.updated) >= self.ttl
+    }
+}
+
+type Consumers = HashMap<(ChanId, String), Consumer>;
+
+/// Shared registry of unacknowledged events
+#[derive(Default, Clone)]
+pub struct Acks {
+    consumers: Arc<Mutex<Consumers>>,
+    store: Option<HistoryStore>,
+}
+
+impl Acks {
+    /// Create a registry persisting the
+    /// unacknowledged events in `store`
+    pub fn new(store: Option<HistoryStore>) -> Self {
+        Self {
+            consumers: Arc::default(),
+            store,
+        }
+    }
+
+    /// Return true if the unacknowledged
+    /// events are persisted
+    pub fn is_persistent(&self) -> bool {
+        self.store.is_some()
+    }
+
+    /// Record the delivery of `event` to `consumer` on channel
+    /// `id` named `channel`
+    ///
+    /// The oldest events are discarded if the consumer
+    /// has more than `max_pending` unacknowledged events.
+    pub fn delivered(
+        &self,
+        id: ChanId,
+        channel: &str,
+        consumer: &str,
+        event: &Event,
+        conf: &AckConfig,
+    ) {
+        let discarded = self.delivered_at(id, consumer, event, conf, Instant::now());
+        if let Some(store) = &self.store {
+            let channel = channel.into();
+            store.delivered(&channel, consumer, event.id());
+            if let Some(discarded) = discarded {
+                store.acked(&channel, consumer, vec![discarded]);
+            }
+        }
+    }
+
+    /// Record the delivery, return the id of
+    /// the discarded event if any
+    fn delivered_at(
+        &self,
+        id: ChanId,
+        consumer: &str,
+        event: &Event,
+        conf: &AckConfig,
+        now: Instant,
+    ) -> Option<String> {
+        let mut consumers = self.consumers.lock().unwrap();
+        let key = (id, consumer.to_string());
+        if !consumers.contains_key(&key) {
+            // Evict idle consumers when a new one shows up
+            consumers.retain(|_, c| !c.is_expired(now));
+        }
+        let entry = consumers
+            .entry(key)
+            .or_insert_with(|| Consumer::new(conf.consumer_ttl, now));
+        entry.updated = now;
+        entry.pending.push_back(Pending {
+            event: event.clone(),
+            retries: 0,
+        });
+        if entry.pending.len() <= conf.max_pending {
+            return None;
+        }
+        entry.pending.pop_front().map(|discarded| {
+            log::warn!(
+                "Discarding unacknowledged event {} for consumer '{consumer}'",
+                discarded.event.id(),
+            );
+            discarded.event.id().to_string()
+        })
+    }
+
+    /// Acknowledge the events `ids` of `consumer` on channel
+    /// `id` named `channel`
+    ///
+    /// Return the number of acknowledged events
+    /// held in memory.
+    pub fn ack(&self, id: ChanId, channel: &str, consumer: &str, ids: &[String]) -> usize {
+        if let Some(store) = &self.store {
+            store.acked(&channel.into(), consumer, ids.to_vec());
+        }
+        let mut consumers = self.consumers.lock().unwrap();
+        let key = (id, consumer.to_string());
+        let Some(entry) = consumers.get_mut(&key) else {
+            return 0;
+        };
+        let count = entry.pending.len();
+        entry
+            .pending
+            .retain(|p| !ids.iter().any(|id| id == p.event.id()));
+        let count = count - entry.pending.len();
+        if entry.pending.is_empty() {
+            consumers.remove(&key);
+        }
+        count
+    }
+
+    /// Return the unacknowledged events of `consumer`
+    /// on channel `id` named `channel` to be redelivered
+    ///
+    /// Events already redelivered `max_retries` times
+    /// are discarded. The events are loaded from the history
+    /// store if the consumer is not known in memory.
+    pub async fn redeliver(
+        &self,
+        id: ChanId,
+        channel: &str,
+        consumer: &str,
+        conf: &AckConfig,
+    ) -> Vec<Event> {
+        let Some(store) = &self.store else {
+            return self
+                .redeliver_local(id, consumer, conf, Instant::now())
+                .map(|(events, _)| events)
+                .unwrap_or_default();
+        };
+        let channel: Arc<str> = channel.into();
+        if let Some((events, exhausted)) = self.redeliver_local(id, consumer, conf, Instant::now())
+        {
+            if !exhausted.is_empty() {
+                store.acked(&channel, consumer, exhausted);
+            }
+            store.redelivered(
+                &channel,
+                consumer,
+                events.iter().map(|e| e.id().to_string()).collect(),
+            );
+            return events;
+        }
+        let pending = store
+            .pending(id, &channel, consumer, conf.max_retries)
+            .await;
+        if pending.is_empty() {
+            return vec![];
+        }
+        let now = Instant::now();
+        let events = pending.iter().map(|(event, _)| event.clone()).collect();
+        let mut consumers = self.consumers.lock().unwrap();
+        let entry = consumers
+            .entry((id, consumer.to_string()))
+            .or_insert_with(|| Consumer::new(conf.consumer_ttl, now));
+        entry.updated = now;
+        entry.pending = pending
+            .into_iter()
+            .map(|(event, retries)| Pending { event, retries })
+            .collect();
+        events
+    }
+
+    /// Return the unacknowledged events of `consumer` held
+    /// in memory and the ids of the discarded events
+    ///
+    /// Return `None` if the consumer is not known.
+    fn redeliver_local(
+        &self,
+        id: ChanId,
+        consumer: &str,
+        conf: &AckConfig,
+        now: Instant,
+    ) -> Option<(Vec<Event>, Vec<String>)> {
+        let mut consumers = self.consumers.lock().unwrap();
+        let key = (id, consumer.to_string());
+        let entry = consumers.get_mut(&key)?;
+        let mut exhausted = vec![];
+        entry.pending.retain(|p| {
+            let keep = p.retries < conf.max_retries;
+            if !keep {
+                log::warn!(
+                    "Giving up redelivery of event {} to consumer '{consumer}'",
+                    p.event.id()
+                );
+                exhausted.push(p.event.id().to_string());
+            }
+            keep
+        });
+        entry.updated = now;
+        let events = entry
+            .pending
+            .iter_mut()
+            .map(|p| {
+                p.retries += 1;
+                p.event.clone()
+            })
+            .collect();
+        if entry.pending.is_empty() {
+            consumers.remove(&key);
+        }
+        Some((events, exhausted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(events: Vec<Event>) -> Vec<String> {
+        events.iter().map(|e| e.id().to_string()).collect()
+    }
+
+    #[actix_web::test]
+    async fn acknowledged_delivery() {
+        let conf = AckConfig {
+            max_retries: 1,
+            max_pending: 2,
+            consumer_ttl: Duration::from_secs(60),
+        };
+        let acks = Acks::default();
+        acks.delivered(1, "foo", "c1", &Event::with_id("1"), &conf);
+        acks.delivered(1, "foo", "c1", &Event::with_id("2"), &conf);
+        acks.delivered(1, "foo", "c1", &Event::with_id("3"), &conf);
+        acks.delivered(1, "foo", "c2", &Event::with_id("3"), &conf);
+
+        // Oldest event discarded
+        assert_eq!(acks.ack(1, "foo", "c1", &["1".into(), "2".into()]), 1);
+
+        assert_eq!(ids(acks.redeliver(1, "foo", "c1", &conf).await), ["3"]);
+        // Retries exhausted
+        assert!(acks.redeliver(1, "foo", "c1", &conf).await.is_empty());
+        assert!(acks.redeliver(2, "bar", "c2", &conf).await.is_empty());
+        assert_eq!(ids(acks.redeliver(1, "foo", "c2", &conf).await), ["3"]);
+    }
+
+    #[test]
+    fn idle_consumers_eviction() {
+        let conf = AckConfig {
+            max_retries: 3,
+            max_pending: 10,
+            consumer_ttl: Duration::from_secs(60),
+        };
+        let acks = Acks::default();
+        let now = Instant::now();
+        acks.delivered_at(1, "idle", &Event::with_id("1"), &conf, now);
+        acks.delivered_at(1, "active", &Event::with_id("1"), &conf, now);
+        let later = now + Duration::from_secs(30);
+        acks.delivered_at(1, "active", &Event::with_id("2"), &conf, later);
+
+        // Idle consumers are evicted when a new consumer shows up
+        let later = now + Duration::from_secs(61);
+        acks.delivered_at(1, "new", &Event::with_id("2"), &conf, later);
+        assert!(acks.redeliver_local(1, "idle", &conf, later).is_none());
+        let (events, _) = acks.redeliver_local(1, "active", &conf, later).unwrap();
+        assert_eq!(ids(events), ["1", "2"]);
+        assert_eq!(acks.consumers.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,210 @@
+//!
+//! Admin api
+//!
+//! Admin endpoints require a key declared in the
+//! `[server.admin]` section, passed in the `Authorization`
+//! header with the `Key` scheme.
+//!
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use actix_web_lab::middleware::Next;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    acks::Acks,
+    config::{AdminConfig, ChannelConfig, ChannelDefaults, Visibility},
+    connections::{Connections, SubscriberInfo},
+    eventpattern::EventPattern,
+    events::DispatchHandle,
+    metrics::Metrics,
+    pool::PoolStatus,
+    registry::{ChannelRegistry, Entry},
+    utils::authorization_key,
+    Error, Result,
+};
+
+/// Admin api settings
+pub struct Admin {
+    keys: HashSet<String>,
+    /// Directory used for resolving relative
+    /// paths in channel configurations
+    root: PathBuf,
+    /// Default settings of the added channels
+    defaults: ChannelDefaults,
+}
+
+impl Admin {
+    pub fn new(conf: &AdminConfig, root: &Path, defaults: &ChannelDefaults) -> Self {
+        Self {
+            keys: conf.api_keys.iter().cloned().collect(),
+            root: root.into(),
+            defaults: defaults.clone(),
+        }
+    }
+
+    /// Check that the request present a valid admin key
+    fn check(&self, req: &HttpRequest) -> Result<()> {
+        match authorization_key(req) {
+            Some(key) if self.keys.contains(key) => Ok(()),
+            Some(_) => Err(Error::Forbidden),
+            None => Err(Error::Unauthorized),
+        }
+    }
+}
+
+/// Middleware checking admin credentials
+pub async fn authenticate(
+    admin: web::Data<Admin>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    if let Err(err) = admin.check(req.request()) {
+        return Ok(req.error_response(err).map_into_right_body());
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[derive(Serialize)]
+struct ChannelInfo<'a> {
+    id: &'a str,
+    description: Option<&'a str>,
+    tags: &'a [String],
+    visibility: Visibility,
+    allowed_events: &'a [EventPattern],
+    session_pid: Option<i32>,
+    connected: bool,
+    subscribers: Vec<&'a SubscriberInfo>,
+}
+
+/// List channels and their subscribers
+pub async fn list_channels(
+    registry: web::Data<ChannelRegistry>,
+    connections: web::Data<Connections>,
+    status: web::Data<PoolStatus>,
+) -> impl Responder {
+    let channels = registry.channels();
+    let subscribers = connections.subscribers();
+    let backends = status.backends();
+
+    HttpResponse::Ok().json(
+        channels
+            .iter()
+            .map(|Entry { conf, .. }| {
+                let backend = backends.iter().find(|b| b.channels.contains(&conf.id));
+                ChannelInfo {
+                    id: &conf.id,
+                    description: conf.description.as_deref(),
+                    tags: &conf.tags,
+                    visibility: conf.visibility,
+                    allowed_events: &conf.allowed_events,
+                    session_pid: backend.map(|b| b.session_pid),
+                    connected: backend.is_some_and(|b| b.connected),
+                    subscribers: subscribers
+                        .iter()
+                        .filter(|s| s.channels.contains(&conf.id))
+                        .collect(),
+                }
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Return the postgres connections of the pool
+pub async fn pool_stats(
+    status: web::Data<PoolStatus>,
+    metrics: web::Data<Metrics>,
+) -> impl Responder {
+    HttpResponse::Ok().json(status.stats(&metrics))
+}
+
+/// Add a new channel
+///
+/// The body is the json representation of the
+/// channel configuration.
+pub async fn add_channel(
+    admin: web::Data<Admin>,
+    dispatch: web::Data<DispatchHandle>,
+    acks: web::Data<Acks>,
+    conf: web::Json<ChannelConfig>,
+) -> Result<impl Responder> {
+    let mut conf = conf.into_inner();
+    if !conf.enabled {
+        return Err(Error::InvalidRequest("Disabled channel".into()));
+    }
+    admin.defaults.apply(&mut conf);
+    conf.sanitize(&admin.root)?;
+    if conf.id.is_empty() {
+        return Err(Error::InvalidRequest("Empty channel id".into()));
+    }
+    if conf.ack.is_some() && !acks.is_persistent() {
+        return Err(Error::InvalidRequest(
+            "Acknowledged delivery requires a history store".into(),
+        ));
+    }
+    dispatch.add_channel(conf).await?;
+    Ok(HttpResponse::Created().finish())
+}
+
+/// Remove a channel
+///
+/// Subscribers of the channel are disconnected.
+pub async fn remove_channel(
+    dispatch: web::Data<DispatchHandle>,
+    id: web::Path<String>,
+) -> Result<impl Responder> {
+    dispatch.remove_channel(id.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+    use actix_web_lab::middleware::from_fn;
+
+    #[actix_web::test]
+    async fn admin_authentication() {
+        let admin = Admin::new(
+            &AdminConfig {
+                api_keys: vec!["secret".into()],
+            },
+            Path::new("."),
+            &ChannelDefaults::default(),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(admin))
+                .app_data(web::Data::new(ChannelRegistry::default()))
+                .app_data(web::Data::new(Connections::default()))
+                .app_data(web::Data::new(PoolStatus::default()))
+                .service(
+                    web::scope("/admin")
+                        .wrap(from_fn(authenticate))
+                        .route("/channels", web::get().to(list_channels)),
+                ),
+        )
+        .await;
+
+        let status = |key: Option<&'static str>| {
+            let mut req = test::TestRequest::get().uri("/admin/channels");
+            if let Some(key) = key {
+                req = req.insert_header(("Authorization", key));
+            }
+            req.to_request()
+        };
+
+        let resp = test::call_service(&app, status(None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, status(Some("Key wrong"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, status(Some("Key secret"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,137 @@
+//!
+//! Alerts
+//!
+//! Alerts report conditions that require an
+//! intervention. They are logged at error level and
+//! may be posted as json to a webhook.
+//!
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::utils::timestamp_millis;
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+///
+/// Alert configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertConfig {
+    /// Url receiving the alerts
+    pub webhook: String,
+    /// Request timeout
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+/// Alert record
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Alert<'a> {
+    /// The maximum number of reconnection attempts
+    /// to a database was reached
+    ReconnectFailed {
+        ts: u64,
+        channels: &'a [String],
+        database: Option<&'a str>,
+        hosts: Vec<String>,
+        attempts: u32,
+    },
+}
+
+impl<'a> Alert<'a> {
+    pub fn reconnect_failed(
+        channels: &'a [String],
+        config: &'a pg_event_listener::Config,
+        attempts: u32,
+    ) -> Self {
+        use tokio_postgres::config::Host;
+        Self::ReconnectFailed {
+            ts: timestamp_millis(),
+            channels,
+            database: config.get_dbname(),
+            hosts: config
+                .get_hosts()
+                .iter()
+                .map(|host| match host {
+                    Host::Tcp(host) => host.clone(),
+                    #[cfg(unix)]
+                    Host::Unix(path) => path.display().to_string(),
+                })
+                .collect(),
+            attempts,
+        }
+    }
+}
+
+/// Alert hook
+#[derive(Clone, Default)]
+pub struct Alerts(Option<Rc<Webhook>>);
+
+struct Webhook {
+    client: awc::Client,
+    url: String,
+}
+
+impl Alerts {
+    pub fn new(conf: Option<&AlertConfig>) -> Self {
+        Self(conf.map(|conf| {
+            Rc::new(Webhook {
+                client: awc::Client::builder().timeout(conf.timeout).finish(),
+                url: conf.webhook.clone(),
+            })
+        }))
+    }
+
+    /// Log the alert and post it to the webhook
+    pub fn fire(&self, alert: &Alert) {
+        let body = match serde_json::to_string(alert) {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("Failed to serialize alert: {err:?}");
+                return;
+            }
+        };
+        log::error!("ALERT: {body}");
+        if let Some(webhook) = self.0.clone() {
+            actix_web::rt::spawn(async move {
+                match webhook
+                    .client
+                    .post(&webhook.url)
+                    .content_type("application/json")
+                    .send_body(body)
+                    .await
+                {
+                    Ok(resp) if resp.status().is_success() => (),
+                    Ok(resp) => log::error!(
+                        "Alert webhook {}: rejected with status {}",
+                        webhook.url,
+                        resp.status()
+                    ),
+                    Err(err) => log::error!("Alert webhook {}: {err}", webhook.url),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alert_record() {
+        let config: pg_event_listener::Config =
+            "host=db.example.org dbname=events".parse().unwrap();
+        let channels = ["test".to_string()];
+        let record = serde_json::to_value(Alert::reconnect_failed(&channels, &config, 5)).unwrap();
+        assert_eq!(record["type"], "reconnect_failed");
+        assert_eq!(record["channels"][0], "test");
+        assert_eq!(record["database"], "events");
+        assert_eq!(record["hosts"][0], "db.example.org");
+        assert_eq!(record["attempts"], 5);
+    }
+}
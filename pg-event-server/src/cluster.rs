@@ -0,0 +1,382 @@
+//!
+//! Cluster membership
+//!
+//! Instances serving the same channels register themselves in a
+//! table of a coordination database: each instance periodically
+//! writes its name, its advertised url and the number of subscribers
+//! of its channels. The members are listed at `/cluster/members` and
+//! the instances serving each channel at `/cluster/channels`.
+//!
+//! Members that did not write for `expire_after` are not listed,
+//! an instance stopping gracefully removes its entry.
+//!
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+use tokio_postgres::Client;
+
+use crate::metrics::Metrics;
+use crate::pool::PoolStatus;
+use crate::postgres::tls::PgTlsConnect;
+use crate::registry::ChannelRegistry;
+use crate::stats::Stats;
+use crate::utils::is_table_name;
+use crate::{Error, Result};
+
+fn default_table() -> String {
+    "pg_event_cluster".into()
+}
+
+const fn default_create_table() -> bool {
+    true
+}
+
+const fn default_heartbeat_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+const fn default_expire_after() -> Duration {
+    Duration::from_secs(30)
+}
+
+///
+/// Cluster configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterConfig {
+    /// Connection string of the coordination database
+    pub connection_string: Option<String>,
+    /// Membership table, optionally schema qualified
+    #[serde(default = "default_table")]
+    pub table: String,
+    /// Create the table if it does not exist
+    #[serde(default = "default_create_table")]
+    pub create_table: bool,
+    /// Url of the instance advertised to the
+    /// other members
+    pub url: Option<String>,
+    /// Delay between updates of the member entry
+    #[serde(default = "default_heartbeat_interval", with = "humantime_serde")]
+    pub heartbeat_interval: Duration,
+    /// Delay after which a member that did
+    /// not update its entry is not listed
+    #[serde(default = "default_expire_after", with = "humantime_serde")]
+    pub expire_after: Duration,
+}
+
+impl ClusterConfig {
+    pub fn check(&self) -> Result<()> {
+        if !is_table_name(&self.table) {
+            return Err(Error::Config(format!(
+                "Cluster: invalid table name '{}'",
+                self.table
+            )));
+        }
+        if self.heartbeat_interval.is_zero() || self.expire_after <= self.heartbeat_interval {
+            return Err(Error::Config(
+                "Cluster: expire_after must be greater than a positive heartbeat_interval".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Channel served by a member
+#[derive(Debug, Serialize, Deserialize)]
+struct MemberChannel {
+    id: String,
+    subscribers: i64,
+    /// Postgres connection of the channel is up
+    connected: bool,
+}
+
+/// Member of the cluster
+#[derive(Debug, Serialize)]
+struct Member {
+    instance: String,
+    url: Option<String>,
+    version: String,
+    /// Start time in milliseconds since epoch
+    started_at: i64,
+    /// Last update in milliseconds since epoch
+    last_seen: i64,
+    /// Total number of subscribers
+    subscribers: i64,
+    channels: Vec<MemberChannel>,
+    /// The member is the responding instance
+    #[serde(rename = "self")]
+    local: bool,
+}
+
+struct Inner {
+    conf: ClusterConfig,
+    pgconfig: pg_event_listener::Config,
+    tls: PgTlsConnect,
+    instance: String,
+    client: Mutex<Option<Client>>,
+}
+
+/// Membership of the instance
+#[derive(Clone)]
+pub struct Cluster(Arc<Inner>);
+
+impl Cluster {
+    pub fn new(conf: &ClusterConfig, instance: String, tls: PgTlsConnect) -> Result<Self> {
+        Ok(Self(Arc::new(Inner {
+            conf: conf.clone(),
+            pgconfig: pg_client_config::load_config(conf.connection_string.as_deref())?,
+            tls,
+            instance,
+            client: Mutex::new(None),
+        })))
+    }
+
+    /// Return the client, reconnecting if
+    /// the connection is closed
+    async fn client(&self) -> Result<MappedMutexGuard<'_, Client>> {
+        let mut client = self.0.client.lock().await;
+        if client.as_ref().is_none_or(Client::is_closed) {
+            *client = Some(self.connect().await?);
+        }
+        Ok(MutexGuard::map(client, |c| c.as_mut().unwrap()))
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let (client, connection) = self.0.pgconfig.connect(self.0.tls.clone()).await?;
+        actix_web::rt::spawn(async move {
+            if let Err(err) = connection.await {
+                log::error!("Cluster: connection closed: {err}");
+            }
+        });
+        if self.0.conf.create_table {
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} (\
+                        instance text PRIMARY KEY, \
+                        url text, \
+                        version text NOT NULL, \
+                        started_at bigint NOT NULL, \
+                        last_seen timestamptz NOT NULL, \
+                        channels jsonb NOT NULL)",
+                    self.0.conf.table
+                ))
+                .await?;
+        }
+        Ok(client)
+    }
+
+    /// Start updating the member entry of the instance
+    pub fn start(
+        &self,
+        registry: ChannelRegistry,
+        metrics: Metrics,
+        status: PoolStatus,
+        stats: Stats,
+    ) {
+        let cluster = self.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticks = tokio::time::interval(cluster.0.conf.heartbeat_interval);
+            let mut joined = false;
+            loop {
+                ticks.tick().await;
+                let channels = registry
+                    .channels()
+                    .into_iter()
+                    .map(|entry| MemberChannel {
+                        subscribers: metrics.subscribers(&entry.conf.id).get(),
+                        connected: status.is_channel_connected(&entry.conf.id),
+                        id: entry.conf.id,
+                    })
+                    .collect::<Vec<_>>();
+                match cluster.heartbeat(&channels, stats.started_at()).await {
+                    Ok(()) if !joined => {
+                        log::info!("Cluster: joined as '{}'", cluster.0.instance);
+                        joined = true;
+                    }
+                    Ok(()) => (),
+                    Err(err) => log::error!("Cluster: failed to update member entry: {err:?}"),
+                }
+            }
+        });
+    }
+
+    async fn heartbeat(&self, channels: &[MemberChannel], started_at: u64) -> Result<()> {
+        let channels = serde_json::to_string(channels).unwrap_or_else(|_| "[]".into());
+        self.client()
+            .await?
+            .execute(
+                &format!(
+                    "INSERT INTO {} (instance, url, version, started_at, last_seen, channels) \
+                    VALUES ($1, $2, $3, $4, now(), $5::text::jsonb) \
+                    ON CONFLICT (instance) DO UPDATE SET url = EXCLUDED.url, \
+                    version = EXCLUDED.version, started_at = EXCLUDED.started_at, \
+                    last_seen = EXCLUDED.last_seen, channels = EXCLUDED.channels",
+                    self.0.conf.table
+                ),
+                &[
+                    &self.0.instance,
+                    &self.0.conf.url,
+                    &env!("CARGO_PKG_VERSION"),
+                    &(started_at as i64),
+                    &channels,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the member entry of the instance
+    pub async fn leave(&self) {
+        let result = match self.client().await {
+            Ok(client) => client
+                .execute(
+                    &format!("DELETE FROM {} WHERE instance = $1", self.0.conf.table),
+                    &[&self.0.instance],
+                )
+                .await
+                .map_err(Error::from),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(_) => log::info!("Cluster: left as '{}'", self.0.instance),
+            Err(err) => log::error!("Cluster: failed to remove member entry: {err:?}"),
+        }
+    }
+
+    /// Return the live members
+    async fn members(&self) -> Result<Vec<Member>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                &format!(
+                    "SELECT instance, url, version, started_at, \
+                    (extract(epoch FROM last_seen) * 1000)::bigint, channels::text \
+                    FROM {} WHERE last_seen > now() - make_interval(secs => $1) \
+                    ORDER BY instance",
+                    self.0.conf.table
+                ),
+                &[&self.0.conf.expire_after.as_secs_f64()],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let instance: String = row.get(0);
+                let channels: Vec<MemberChannel> =
+                    serde_json::from_str(row.get(5)).unwrap_or_default();
+                Member {
+                    local: instance == self.0.instance,
+                    instance,
+                    url: row.get(1),
+                    version: row.get(2),
+                    started_at: row.get(3),
+                    last_seen: row.get(4),
+                    subscribers: channels.iter().map(|c| c.subscribers).sum(),
+                    channels,
+                }
+            })
+            .collect())
+    }
+
+    /// Return the live members, failures are
+    /// reported as unavailability
+    async fn live_members(&self) -> Result<Vec<Member>> {
+        self.members().await.map_err(|err| {
+            log::error!("Cluster: failed to read members: {err:?}");
+            Error::ClusterUnavailable
+        })
+    }
+}
+
+/// Instance serving a channel
+#[derive(Serialize)]
+struct ChannelMember<'a> {
+    instance: &'a str,
+    url: Option<&'a str>,
+    subscribers: i64,
+    connected: bool,
+}
+
+/// Return the instances serving each channel
+fn channel_members(members: &[Member]) -> BTreeMap<&str, Vec<ChannelMember<'_>>> {
+    let mut channels = BTreeMap::<_, Vec<_>>::new();
+    for member in members {
+        for chan in &member.channels {
+            channels
+                .entry(chan.id.as_str())
+                .or_default()
+                .push(ChannelMember {
+                    instance: &member.instance,
+                    url: member.url.as_deref(),
+                    subscribers: chan.subscribers,
+                    connected: chan.connected,
+                });
+        }
+    }
+    channels
+}
+
+/// Members handler
+pub async fn members(cluster: web::Data<Cluster>) -> Result<HttpResponse> {
+    let members = cluster.live_members().await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "instance": cluster.0.instance,
+        "members": members,
+    })))
+}
+
+/// Channels handler
+pub async fn channels(cluster: web::Data<Cluster>) -> Result<HttpResponse> {
+    let members = cluster.live_members().await?;
+    Ok(HttpResponse::Ok().json(channel_members(&members)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cluster_config() {
+        let conf: ClusterConfig = toml::from_str("").unwrap();
+        assert_eq!(conf.table, "pg_event_cluster");
+        assert!(conf.check().is_ok());
+
+        let conf: ClusterConfig =
+            toml::from_str("heartbeat_interval = \"30s\"\nexpire_after = \"30s\"").unwrap();
+        assert!(conf.check().is_err());
+    }
+
+    #[test]
+    fn cluster_channels() {
+        let member = |instance: &str, channels: &[(&str, i64)]| Member {
+            instance: instance.into(),
+            url: None,
+            version: "0".into(),
+            started_at: 0,
+            last_seen: 0,
+            subscribers: 0,
+            channels: channels
+                .iter()
+                .map(|(id, subscribers)| MemberChannel {
+                    id: (*id).into(),
+                    subscribers: *subscribers,
+                    connected: true,
+                })
+                .collect(),
+            local: false,
+        };
+        let members = [
+            member("hub1", &[("foo", 1), ("bar", 2)]),
+            member("hub2", &[("foo", 3)]),
+        ];
+        let channels = channel_members(&members);
+        assert_eq!(channels["foo"].len(), 2);
+        assert_eq!(channels["foo"][1].instance, "hub2");
+        assert_eq!(channels["foo"][1].subscribers, 3);
+        assert_eq!(channels["bar"].len(), 1);
+    }
+}
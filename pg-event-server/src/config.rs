@@ -9,12 +9,44 @@
 //! * `confdir` - Directory where to find resources
 //! * `listen` - The socket addresses to listen to (as `"ip:port"` strings)
 //!
-use serde::Deserialize;
+//! ## The `[server.cors]` section
+//!
+//! * `allowed_origins` - List of allowed origins (`"*"` for any)
+//! * `allow_credentials` - Allow credentials in cross-origin requests
+//! * `max_age` - Max age of preflight requests in seconds
+//!
+//! ## The `[server.admin]` section
+//!
+//! * `api_keys` - List of keys allowed to access the admin api
+//!
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::alert::AlertConfig;
+use crate::cluster::ClusterConfig;
 use crate::errors::{Error, Result};
+use crate::eventpattern::EventPattern;
+use crate::forwardauth::ForwardAuthConfig;
+use crate::grpc::GrpcConfig;
+use crate::ha::HaConfig;
+use crate::historystore::HistoryStoreConfig;
+use crate::introspection::IntrospectionConfig;
+use crate::metrics::MetricsConfig;
+use crate::payloadtable::PayloadTableConfig;
+use crate::peer::PeerConfig;
 use crate::postgres::tls::PgTlsConfig;
+use crate::ratelimit::RateLimitsConfig;
+use crate::replication::ReplicationConfig;
+use crate::routes::Route;
+use crate::sinks::SinkConfig;
+use crate::telemetry::TracingConfig;
+use crate::transform::Template;
+use crate::validation::ValidationConfig;
 
 fn default_title() -> String {
     const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -29,6 +61,10 @@ const fn default_events_buffer_size() -> usize {
     1024
 }
 
+const fn default_broadcast_concurrency() -> usize {
+    256
+}
+
 const fn default_reconnection_delay() -> u16 {
     60
 }
@@ -37,41 +73,246 @@ const fn default_ssl_enabled() -> bool {
     false
 }
 
+const fn default_slow_consumer_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn default_client_request_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn default_keep_alive() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn default_max_connection_rate() -> usize {
+    256
+}
+
+const fn default_shutdown_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_http2() -> bool {
+    true
+}
+
+const fn default_backoff_factor() -> f64 {
+    2.
+}
+
+const fn default_required() -> bool {
+    false
+}
+
+const fn default_enabled() -> bool {
+    true
+}
+
+/// Postgres notification payloads must be
+/// shorter than 8000 bytes
+const fn default_max_payload_size() -> usize {
+    7999
+}
+
+const fn default_cors_max_age() -> Option<usize> {
+    Some(3600)
+}
+
+///
+/// Admin api configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminConfig {
+    /// Keys allowed to access the admin api
+    pub api_keys: Vec<String>,
+}
+
+///
+/// CORS configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct CorsConfig {
+    /// Allowed origins, use `"*"` for allowing any origin
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Allow credentials (cookies, authorization headers)
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Max age of preflight requests in seconds
+    #[serde(default = "default_cors_max_age")]
+    pub max_age: Option<usize>,
+}
+
 ///
 /// Server global configuration
 ///
 #[derive(Debug, Clone, Deserialize)]
 pub struct Server {
-    /// The sockets addresses to listen to
+    /// The sockets addresses to listen to,
+    /// use `unix:<path>` for a unix socket
     pub listen: String,
 
+    /// Permissions of the unix socket
+    pub socket_mode: Option<u32>,
+    /// Owner of the unix socket as `user[:group]`
+    pub socket_owner: Option<String>,
+
+    /// Run as this user once the listening
+    /// socket is bound
+    pub user: Option<String>,
+    /// Run as this group once the listening
+    /// socket is bound
+    pub group: Option<String>,
+
     /// Description of the server
     #[serde(default = "default_title")]
     pub title: String,
 
+    /// Name of the instance among the instances
+    /// serving the same channels, default to
+    /// the host name
+    pub instance_name: Option<String>,
+
+    /// Prefix of all routes
+    #[serde(default)]
+    pub base_path: String,
+
     /// Number of workers
     /// Optional: the default number of workers is half the number of Cpu
     /// (1 minimum)
     pub num_workers: Option<usize>,
 
+    /// Timeout for receiving the request head,
+    /// `0s` disables the timeout
+    #[serde(default = "default_client_request_timeout", with = "humantime_serde")]
+    pub client_request_timeout: Duration,
+    /// Keep-alive timeout of idle connections,
+    /// `0s` disables keep-alive
+    #[serde(default = "default_keep_alive", with = "humantime_serde")]
+    pub keep_alive: Duration,
+    /// Maximum number of concurrent TLS
+    /// handshakes per worker
+    #[serde(default = "default_max_connection_rate")]
+    pub max_connection_rate: usize,
+    /// Delay for closing the connections
+    /// on graceful shutdown
+    #[serde(default = "default_shutdown_timeout", with = "humantime_serde")]
+    pub shutdown_timeout: Duration,
+    /// Negotiate HTTP/2 on TLS listeners
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+
     /// Enable ssl
     #[serde(default = "default_ssl_enabled")]
     pub ssl_enabled: bool,
+    /// TLS implementation
+    #[serde(default)]
+    pub ssl_backend: SslBackend,
     /// Server ssl key
     pub ssl_key_file: Option<PathBuf>,
     /// Server ssl cert
     pub ssl_cert_file: Option<PathBuf>,
+    /// CA certificates used for verifying
+    /// client certificates, enable client
+    /// authentication
+    pub ssl_client_ca_file: Option<PathBuf>,
+    /// Reject clients without a valid certificate
+    #[serde(default)]
+    pub ssl_client_auth_required: bool,
+
+    /// CORS configuration
+    pub cors: Option<CorsConfig>,
+
+    /// Admin api configuration, the admin api
+    /// is disabled if not set
+    pub admin: Option<AdminConfig>,
+
+    /// gRPC service configuration, the service
+    /// is disabled if not set
+    pub grpc: Option<GrpcConfig>,
+
+    /// Forward auth configuration, subscription
+    /// requests are not forwarded if not set
+    pub forward_auth: Option<ForwardAuthConfig>,
+
+    /// OAuth2 token introspection configuration,
+    /// bearer tokens are not accepted if not set
+    pub introspection: Option<IntrospectionConfig>,
+
+    /// Serve the test client page
+    /// at `/debug/client`
+    #[serde(default)]
+    pub debug_client: bool,
+
+    /// Enable compression of responses
+    /// negotiated with `Accept-Encoding`
+    #[serde(default)]
+    pub compression: bool,
+
+    /// Maximum number of simultaneous SSE connections
+    pub max_connections: Option<usize>,
+
+    /// Maximum number of simultaneous SSE connections
+    /// per client ip
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Networks of proxies allowed to set
+    /// forwarding headers
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Rate limits of the subscribe and
+    /// publish endpoints
+    #[serde(default)]
+    pub rate_limit: RateLimitsConfig,
+
+    /// Secret used for verifying signed
+    /// subscription urls
+    pub url_signing_secret: Option<String>,
+
+    /// Write access records to this file
+    /// instead of the server log
+    pub access_log: Option<PathBuf>,
+
+    /// ACME certificate management, the certificate
+    /// files are used if not set
+    pub acme: Option<AcmeConfig>,
+
+    /// Additional certificates selected by
+    /// server name
+    #[serde(default)]
+    pub certificates: Vec<CertificateConfig>,
 }
 
 // Handle SSL configuration
-use crate::server::tls::{make_tls_config, TlsServerConfig};
+use crate::server::{
+    acme::AcmeConfig,
+    tls::{CertResolver, CertificateConfig, ServerCerts},
+};
 
 impl Server {
-    pub fn make_tls_config(&self) -> Result<Option<TlsServerConfig>> {
-        if self.ssl_enabled {
-            Some(make_tls_config(self)).transpose()
-        } else {
-            Ok(None)
+    /// Return the name of the instance
+    pub fn instance_name(&self) -> String {
+        self.instance_name
+            .clone()
+            .unwrap_or_else(crate::utils::hostname)
+    }
+
+    pub fn make_server_certs(&self) -> Result<Option<ServerCerts>> {
+        if !self.ssl_enabled {
+            return Ok(None);
+        }
+        match self.ssl_backend {
+            SslBackend::Rustls => CertResolver::new(self)
+                .map(|resolver| Some(ServerCerts::Rustls(Arc::new(resolver)))),
+            #[cfg(feature = "server-openssl")]
+            SslBackend::Openssl => crate::server::openssl::SslContexts::new(self)
+                .map(|contexts| Some(ServerCerts::Openssl(Arc::new(contexts)))),
+            #[cfg(not(feature = "server-openssl"))]
+            SslBackend::Openssl => Err(Error::Config(
+                "OpenSSL backend requires the 'server-openssl' feature".into(),
+            )),
         }
     }
 
@@ -81,6 +322,30 @@ impl Server {
                 self.num_workers = None;
             }
         }
+        let base_path = self.base_path.trim_matches('/');
+        self.base_path = if base_path.is_empty() {
+            String::new()
+        } else {
+            format!("/{base_path}")
+        };
+        if self.max_connections == Some(0) {
+            self.max_connections = None;
+        }
+        if self.max_connections_per_ip == Some(0) {
+            self.max_connections_per_ip = None;
+        }
+        if self.ssl_enabled && self.listen.starts_with("unix:") {
+            return Err(Error::Config("SSL is not supported on unix sockets".into()));
+        }
+        if self.ssl_enabled && !self.http2 && self.ssl_backend == SslBackend::Rustls {
+            return Err(Error::Config(
+                "Disabling HTTP/2 requires the 'openssl' ssl backend".into(),
+            ));
+        }
+        if self.max_connection_rate == 0 {
+            return Err(Error::Config("max_connection_rate must be positive".into()));
+        }
+        self.rate_limit.check()?;
         if let Some(ref ssl_key) = self.ssl_key_file {
             if !ssl_key.has_root() {
                 self.ssl_key_file = Some(root.join(ssl_key));
@@ -91,6 +356,33 @@ impl Server {
                 self.ssl_cert_file = Some(root.join(ssl_cert));
             }
         }
+        if let Some(ref ssl_ca) = self.ssl_client_ca_file {
+            if !ssl_ca.has_root() {
+                self.ssl_client_ca_file = Some(root.join(ssl_ca));
+            }
+        }
+        if let Some(ref access_log) = self.access_log {
+            if !access_log.has_root() {
+                self.access_log = Some(root.join(access_log));
+            }
+        }
+        for cert in self.certificates.iter_mut() {
+            if !cert.ssl_cert_file.has_root() {
+                cert.ssl_cert_file = root.join(&cert.ssl_cert_file);
+            }
+            if !cert.ssl_key_file.has_root() {
+                cert.ssl_key_file = root.join(&cert.ssl_key_file);
+            }
+        }
+        if let Some(mut acme) = self.acme.take() {
+            if self.ssl_backend != SslBackend::Rustls {
+                return Err(Error::Config(
+                    "ACME requires the 'rustls' ssl backend".into(),
+                ));
+            }
+            acme.sanitize(root, self)?;
+            self.acme = Some(acme);
+        }
         Ok(())
     }
 }
@@ -105,6 +397,10 @@ pub struct Settings {
     #[serde(default, rename(deserialize = "channel"))]
     pub channels: Vec<ChannelConfig>,
 
+    /// Default channel settings
+    #[serde(default)]
+    pub defaults: ChannelDefaults,
+
     /// worker buffer size
     #[serde(default = "default_worker_buffer_size")]
     pub worker_buffer_size: usize,
@@ -113,63 +409,794 @@ pub struct Settings {
     #[serde(default = "default_events_buffer_size")]
     pub events_buffer_size: usize,
 
-    /// Reconnection delay in seconds
+    /// Maximum number of concurrent sends
+    /// when broadcasting an event in a worker
+    #[serde(default = "default_broadcast_concurrency")]
+    pub broadcast_concurrency: usize,
+
+    /// Maximum delay in seconds between
+    /// failed reconnection attempts
     #[serde(default = "default_reconnection_delay")]
     pub reconnect_delay: u16,
 
+    /// Sharing of postgres connections
+    #[serde(default)]
+    pub pooling: PoolingMode,
+
+    /// Maximum number of postgres connections
+    pub max_pg_connections: Option<usize>,
+
+    /// Event id format
+    #[serde(default)]
+    pub event_id: EventIdFormat,
+
+    /// Log output
+    #[serde(default)]
+    pub log_output: LogOutput,
+
+    /// OpenTelemetry tracing
+    pub tracing: Option<TracingConfig>,
+
+    /// Metrics exporters
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+
     /// Postgres tls configuration
     pub postgres_tls: PgTlsConfig,
+
+    /// Event sinks
+    #[serde(default)]
+    pub sink: SinkConfig,
+
+    /// Alert hook
+    pub alert: Option<AlertConfig>,
+
+    /// Active/standby high availability
+    pub ha: Option<HaConfig>,
+
+    /// Upstream peers whose events are
+    /// re-broadcast on local channels
+    #[serde(default, rename(deserialize = "peer"))]
+    pub peers: Vec<PeerConfig>,
+
+    /// Directory where channel histories are persisted
+    pub history_dir: Option<PathBuf>,
+
+    /// Channel histories shared with other instances
+    pub history_store: Option<HistoryStoreConfig>,
+
+    /// Cluster membership
+    pub cluster: Option<ClusterConfig>,
 }
 
 impl Settings {
     fn sanitize(&mut self, root: &Path) -> Result<()> {
-        self.channels.iter_mut().for_each(|c| c.sanitize());
+        if let Some(ref dir) = self.history_dir {
+            if !dir.has_root() {
+                self.history_dir = Some(root.join(dir));
+            }
+        }
+        self.attach_peers()?;
+        // Disabled channels are parked
+        self.channels.retain(|c| {
+            if !c.enabled {
+                log::info!("Channel '{}' is disabled", c.id);
+            }
+            c.enabled
+        });
+        self.channels.iter_mut().try_for_each(|c| {
+            self.defaults.apply(c);
+            c.sanitize(root)
+        })?;
+        self.sink.sanitize(root);
+        if self.max_pg_connections == Some(0) {
+            self.max_pg_connections = None;
+        }
+        if let Some(tracing) = &self.tracing {
+            tracing.sanitize()?;
+        }
+        if let Some(ha) = &mut self.ha {
+            if ha.connection_string.is_none() {
+                ha.connection_string
+                    .clone_from(&self.defaults.connection_string);
+            }
+            ha.check()?;
+        }
+        if let (None, Some(chan)) = (
+            &self.history_store,
+            self.channels.iter().find(|c| c.ack.is_some()),
+        ) {
+            return Err(Error::Config(format!(
+                "Channel '{}': acknowledged delivery requires a [history_store]",
+                chan.id
+            )));
+        }
+        if let Some(store) = &mut self.history_store {
+            if store.connection_string.is_none() {
+                store
+                    .connection_string
+                    .clone_from(&self.defaults.connection_string);
+            }
+            store.check()?;
+        }
+        if let Some(cluster) = &mut self.cluster {
+            if cluster.connection_string.is_none() {
+                cluster
+                    .connection_string
+                    .clone_from(&self.defaults.connection_string);
+            }
+            cluster.check()?;
+        }
         self.server.sanitize(root)
     }
 
+    /// Attach the peers to their local channel
+    ///
+    /// Channels that are not declared are created
+    /// with the default settings.
+    fn attach_peers(&mut self) -> Result<()> {
+        for peer in &self.peers {
+            peer.check()?;
+            let id = peer.channel_id()?;
+            let conf = match self
+                .channels
+                .iter_mut()
+                .find(|c| c.id.trim_start_matches('/') == id)
+            {
+                Some(conf) => conf,
+                None => {
+                    let mut table = toml::Table::new();
+                    table.insert("id".into(), id.into());
+                    self.channels.push(table.try_into()?);
+                    self.channels.last_mut().unwrap()
+                }
+            };
+            if conf.peer.is_some() {
+                return Err(Error::Config(format!(
+                    "Channel '{id}' is fed by more than one peer"
+                )));
+            }
+            conf.peer = Some(peer.clone());
+        }
+        Ok(())
+    }
+
     pub fn check(&self) -> Result<()> {
         self.postgres_tls.check()
     }
 }
 
+///
+/// Visibility of a channel in the channel catalogue
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Listed in the catalogue
+    #[default]
+    Public,
+    /// Not listed but still subscribable
+    Hidden,
+}
+
+///
+/// Policy applied when a subscriber's buffer is full
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumerPolicy {
+    /// Skip the event for that subscriber
+    Drop,
+    /// Close the subscriber connection
+    Disconnect,
+    /// Wait for the subscriber until timeout
+    /// then skip the event
+    #[default]
+    BlockWithTimeout,
+}
+
+///
+/// Channel publish endpoint configuration
+///
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PublishConfig {
+    /// Enable the publish endpoint
+    #[serde(default)]
+    pub enabled: bool,
+    /// List of api keys allowed to publish
+    /// to the channel
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// Maximum size in bytes of published payloads
+    #[serde(default = "default_max_payload_size")]
+    pub max_payload_size: usize,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            api_keys: vec![],
+            max_payload_size: default_max_payload_size(),
+        }
+    }
+}
+
+const fn default_coalesce_max() -> usize {
+    100
+}
+
+///
+/// Event coalescing configuration
+///
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct CoalesceConfig {
+    /// Duration for collecting events
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    /// Maximum number of events in a batch
+    #[serde(default = "default_coalesce_max")]
+    pub max: usize,
+}
+
+const fn default_ack_max_retries() -> u32 {
+    3
+}
+
+const fn default_ack_max_pending() -> usize {
+    1000
+}
+
+const fn default_ack_consumer_ttl() -> Duration {
+    Duration::from_secs(3600)
+}
+
+///
+/// Acknowledged delivery configuration
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct AckConfig {
+    /// Maximum number of redeliveries of an event
+    #[serde(default = "default_ack_max_retries")]
+    pub max_retries: u32,
+    /// Maximum number of unacknowledged
+    /// events kept per consumer
+    #[serde(default = "default_ack_max_pending")]
+    pub max_pending: usize,
+    /// Delay after which the events of a consumer
+    /// that did not connect are no longer kept in memory
+    #[serde(default = "default_ack_consumer_ttl", with = "humantime_serde")]
+    pub consumer_ttl: Duration,
+}
+
+const fn default_history_max_events() -> usize {
+    1000
+}
+
+///
+/// Event history retention configuration
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct HistoryConfig {
+    /// Maximum number of retained events
+    #[serde(default = "default_history_max_events")]
+    pub max_events: usize,
+    /// Maximum age of retained events
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+}
+
+///
+/// Policy applied to payloads exceeding
+/// the channel maximum size
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PayloadSizePolicy {
+    /// Drop the event
+    #[default]
+    Drop,
+    /// Truncate the payload to the maximum size
+    Truncate,
+    /// Reject oversized payloads at the publish
+    /// endpoint and drop oversized events
+    RejectAtPublish,
+}
+
+///
+/// Encoding of the notification payloads
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    /// UTF-8 text
+    #[default]
+    Text,
+    /// Base64 encoded binary data
+    Base64,
+    /// Hex encoded binary data, with
+    /// an optional `\x` prefix
+    Hex,
+}
+
+impl PayloadEncoding {
+    /// Decode a binary payload and return it
+    /// encoded as standard base64
+    ///
+    /// Whitespaces are ignored. Return `None` if the
+    /// payload is text or is not valid.
+    pub fn normalize(&self, payload: &str) -> Option<String> {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let payload = payload
+            .chars()
+            .filter(|c| !c.is_ascii_whitespace())
+            .collect::<String>();
+        let data = match self {
+            Self::Text => return None,
+            Self::Base64 => STANDARD.decode(payload).ok()?,
+            Self::Hex => {
+                let hex = payload.strip_prefix("\\x").unwrap_or(&payload);
+                if hex.len() % 2 != 0 {
+                    return None;
+                }
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                    .collect::<Option<Vec<_>>>()?
+            }
+        };
+        Some(STANDARD.encode(data))
+    }
+}
+
+///
+/// Format of the event ids
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventIdFormat {
+    /// Random UUID
+    UuidV4,
+    /// Time ordered UUID
+    #[default]
+    UuidV7,
+    /// Time ordered ULID
+    Ulid,
+}
+
+///
+/// Sharing of postgres connections between channels
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PoolingMode {
+    /// Share connections with identical
+    /// connection parameters
+    Strict,
+    /// Share connections with the same
+    /// host, database and user
+    #[default]
+    HostDbUser,
+    /// One connection per channel
+    None,
+}
+
+///
+/// TLS implementation of the server
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslBackend {
+    #[default]
+    Rustls,
+    Openssl,
+}
+
+///
+/// Log output backend
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogOutput {
+    /// Log to stderr
+    #[default]
+    Stderr,
+    /// Log to the local syslog daemon
+    Syslog,
+    /// Log to the systemd journal
+    Journald,
+}
+
+///
+/// Format of the SSE data
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// Send the raw payload
+    #[default]
+    Raw,
+    /// Wrap the payload in a json envelope with
+    /// the event metadata
+    Envelope,
+    /// Convert row changes to Debezium
+    /// change events
+    Debezium,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "envelope" => Ok(Self::Envelope),
+            "debezium" => Ok(Self::Debezium),
+            _ => Err(Error::InvalidRequest(format!("Invalid format '{s}'"))),
+        }
+    }
+}
+
 ///
 /// Subscription channel configuration
 ///
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct ChannelConfig {
     /// Id to channel
     /// Used in subscription request
     pub id: String,
+    /// Alternative ids of the channel, i.e.
+    /// legacy subscription paths
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Description of the channel
+    pub description: Option<String>,
+    /// Tags of the channel
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Visibility in the channel catalogue
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// If false, the channel is not loaded
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
     /// List of events allowed to subscribe to
     /// If no events are defined then *all* events
     /// are allowed.
     #[serde(default)]
-    pub allowed_events: Vec<String>,
+    pub allowed_events: Vec<EventPattern>,
+    /// List of events excluded from the
+    /// allowed events
+    #[serde(default)]
+    pub deny_events: Vec<EventPattern>,
     /// Connection string
     pub connection_string: Option<String>,
+    /// Postgres tls configuration, overriding
+    /// the global configuration
+    pub postgres_tls: Option<PgTlsConfig>,
+    /// Mapping of postgres event names to
+    /// SSE event names
+    #[serde(default)]
+    pub event_map: HashMap<String, String>,
+    /// List of api keys allowed to subscribe to
+    /// this channel.
+    /// If no keys are defined then subscription
+    /// is not restricted.
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// Path to a file containing api keys, one
+    /// key per line.
+    pub api_key_file: Option<PathBuf>,
+    /// List of client certificate identities allowed
+    /// to subscribe to this channel.
+    /// If empty, subscription is not restricted.
+    #[serde(default)]
+    pub allowed_identities: Vec<String>,
+    /// Networks allowed to subscribe to this channel,
+    /// no restriction if empty
+    #[serde(default)]
+    pub allow_ips: Vec<IpNet>,
+    /// Networks denied to subscribe to this channel
+    #[serde(default)]
+    pub deny_ips: Vec<IpNet>,
+    /// List of OAuth2 scopes allowed to subscribe
+    /// to this channel with a bearer token.
+    #[serde(default)]
+    pub allowed_scopes: Vec<String>,
+    /// Format of the SSE data
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Encoding of the notification payloads
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
+    /// Compress payloads larger than this
+    /// size in bytes (envelope format only)
+    pub compress_payload_above: Option<usize>,
+    /// Policy for subscribers that do not consume
+    /// events fast enough
+    #[serde(default)]
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Timeout for the `block-with-timeout` policy
+    #[serde(default = "default_slow_consumer_timeout", with = "humantime_serde")]
+    pub slow_consumer_timeout: Duration,
+    /// Maximum size in bytes of broadcast payloads
+    pub max_payload_size: Option<usize>,
+    /// Policy for payloads exceeding `max_payload_size`
+    #[serde(default)]
+    pub payload_size_policy: PayloadSizePolicy,
+    /// Batch events received within a window
+    /// into a single message
+    pub coalesce: Option<CoalesceConfig>,
+    /// Keep events until consumers acknowledge them
+    pub ack: Option<AckConfig>,
+    /// Retain events for replay
+    pub history: Option<HistoryConfig>,
+    /// Receive row changes from a logical
+    /// replication slot instead of notifications
+    pub replication: Option<ReplicationConfig>,
+    /// Fetch the payloads from a table, notifications
+    /// hold the key of the payload
+    pub payload_table: Option<PayloadTableConfig>,
+    /// Validate the payloads against a JSON Schema
+    pub validation: Option<ValidationConfig>,
+    /// Template of the outgoing payloads
+    pub transform: Option<Template>,
+    /// Rules forwarding events to other channels
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// If true, the channel has no database
+    /// connection and only receives routed events
+    #[serde(default)]
+    pub routed_only: bool,
+    /// Upstream peer feeding the channel, set
+    /// from the `[[peer]]` sections
+    #[serde(skip)]
+    pub peer: Option<PeerConfig>,
+    /// Drop events identical to an event broadcast
+    /// within this duration
+    #[serde(default, with = "humantime_serde")]
+    pub dedup_window: Option<Duration>,
+    /// If true, the server is not ready when the
+    /// channel backend is down and fails to start
+    /// if the backend is unreachable
+    #[serde(default = "default_required")]
+    pub required: bool,
+    /// Reject subscriptions with `503` while
+    /// the channel backend is down
+    #[serde(default)]
+    pub reject_when_down: bool,
+    /// Maximum delay in seconds between failed
+    /// reconnection attempts, default to the
+    /// global `reconnect_delay`
+    pub reconnect_delay: Option<u16>,
+    /// Stop reconnecting and fire an alert after
+    /// this number of failed attempts
+    pub max_attempts: Option<u32>,
+    /// Growth factor of the delay between
+    /// failed reconnection attempts
+    #[serde(default = "default_backoff_factor")]
+    pub backoff_factor: f64,
+    /// Publish endpoint configuration
+    #[serde(default)]
+    pub publish: PublishConfig,
 }
 
 impl ChannelConfig {
-    pub fn sanitize(&mut self) {
+    pub fn sanitize(&mut self, root: &Path) -> Result<()> {
         self.id = self.id.trim_start_matches('/').into();
+        for alias in &mut self.aliases {
+            *alias = alias.trim_start_matches('/').into();
+            if alias.is_empty() || *alias == self.id {
+                return Err(Error::Config(format!(
+                    "Channel '{}': invalid alias '{alias}'",
+                    self.id
+                )));
+            }
+        }
+        if !(1.0..f64::INFINITY).contains(&self.backoff_factor) {
+            return Err(Error::Config(format!(
+                "Channel '{}': backoff_factor must be a number greater or equal to 1",
+                self.id
+            )));
+        }
+        if self.max_attempts == Some(0) {
+            return Err(Error::Config(format!(
+                "Channel '{}': max_attempts must be positive",
+                self.id
+            )));
+        }
+        if self.routed_only
+            && (self.replication.is_some() || self.payload_table.is_some() || self.publish.enabled)
+        {
+            return Err(Error::Config(format!(
+                "Channel '{}': routed only channels have no database connection",
+                self.id
+            )));
+        }
+        if self.peer.is_some()
+            && (self.routed_only
+                || self.replication.is_some()
+                || self.payload_table.is_some()
+                || self.publish.enabled)
+        {
+            return Err(Error::Config(format!(
+                "Channel '{}': channels fed by a peer have no database connection",
+                self.id
+            )));
+        }
+        if self.ack.is_some() && self.history.is_none() {
+            return Err(Error::Config(format!(
+                "Channel '{}': acknowledged delivery requires a 'history' section",
+                self.id
+            )));
+        }
+        if self.ack.is_some_and(|ack| ack.consumer_ttl.is_zero()) {
+            return Err(Error::Config(format!(
+                "Channel '{}': ack consumer_ttl must be positive",
+                self.id
+            )));
+        }
+        if let Some(replication) = &self.replication {
+            replication.check(&self.id)?;
+        }
+        if let Some(payload_table) = &self.payload_table {
+            if self.replication.is_some() {
+                return Err(Error::Config(format!(
+                    "Channel '{}': payload tables are not supported with replication",
+                    self.id
+                )));
+            }
+            payload_table.check(&self.id)?;
+        }
+        if self.publish.enabled && self.publish.api_keys.is_empty() {
+            return Err(Error::Config(format!(
+                "Channel '{}': publishing requires api keys",
+                self.id
+            )));
+        }
+        if self.publish.max_payload_size > default_max_payload_size() {
+            return Err(Error::Config(format!(
+                "Channel '{}': max_payload_size must not exceed {} bytes",
+                self.id,
+                default_max_payload_size()
+            )));
+        }
+        if let Some(ref path) = self.api_key_file {
+            let path = if path.has_root() {
+                path.clone()
+            } else {
+                root.join(path)
+            };
+            log::debug!("Loading api keys for channel {} from {path:?}", self.id);
+            self.api_keys.extend(
+                fs::read_to_string(&path)
+                    .map_err(|err| {
+                        Error::Config(format!("Failed to read api key file {path:?}: {err:?}"))
+                    })?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty() && !s.starts_with('#'))
+                    .map(String::from),
+            );
+            self.api_key_file = Some(path);
+        }
+        if let Some(validation) = &mut self.validation {
+            validation.load(root, &self.id)?;
+        }
+        if let Some(tls) = &mut self.postgres_tls {
+            tls.sanitize(root);
+            tls.check()?;
+        }
+        Ok(())
     }
 }
 
 ///
-/// Channel set config
+/// Default channel settings
 ///
-#[derive(Debug, Clone, Deserialize)]
-pub struct ChannelSetConfig {
-    /// List of channel configuration in this
-    /// channel set
-    #[serde(default, rename(deserialize = "channel"))]
-    pub channels: Vec<ChannelConfig>,
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ChannelDefaults {
+    /// Connection string of the channels
+    /// without connection string
+    pub connection_string: Option<String>,
+}
+
+impl ChannelDefaults {
+    /// Apply the defaults to the channel `conf`
+    pub fn apply(&self, conf: &mut ChannelConfig) {
+        if conf.connection_string.is_none() {
+            conf.connection_string.clone_from(&self.connection_string);
+        }
+    }
+}
+
+///
+/// Settings overridden from the command line
+///
+#[derive(Debug, Default, Clone)]
+pub struct Overrides {
+    /// The socket address to listen to
+    pub listen: Option<String>,
+    /// Number of workers
+    pub num_workers: Option<usize>,
+    /// Disable TLS on the listening socket
+    pub no_tls: bool,
+    /// Additional channels
+    pub channels: Vec<toml::Table>,
+}
+
+impl Overrides {
+    /// Merge the overrides into the configuration `value`
+    fn apply(&self, value: &mut toml::Value) {
+        let mut server = toml::Table::new();
+        if let Some(listen) = &self.listen {
+            server.insert("listen".into(), listen.as_str().into());
+        }
+        if let Some(num_workers) = self.num_workers {
+            server.insert("num_workers".into(), (num_workers as i64).into());
+        }
+        if self.no_tls {
+            server.insert("ssl_enabled".into(), false.into());
+            // ACME requires TLS
+            if let Some(server) = value.get_mut("server").and_then(|v| v.as_table_mut()) {
+                server.remove("acme");
+            }
+        }
+        let mut layer = toml::Table::new();
+        layer.insert("server".into(), toml::Value::Table(server));
+        if !self.channels.is_empty() {
+            layer.insert(
+                "channel".into(),
+                toml::Value::Array(
+                    self.channels
+                        .iter()
+                        .cloned()
+                        .map(toml::Value::Table)
+                        .collect(),
+                ),
+            );
+        }
+        merge(value, toml::Value::Table(layer));
+    }
+}
+
+/// Parse a channel definition from comma separated `key=value` pairs
+///
+/// Keys are `id`, `conn` for the connection string and `events`
+/// which may be repeated.
+pub fn parse_channel(s: &str) -> std::result::Result<toml::Table, String> {
+    let mut channel = toml::Table::new();
+    let mut events = vec![];
+    for pair in s.split(',').filter(|p| !p.is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("expected 'key=value', found '{pair}'"))?;
+        match key.trim() {
+            "id" => channel.insert("id".into(), value.into()),
+            "conn" => channel.insert("connection_string".into(), value.into()),
+            "events" => {
+                events.push(toml::Value::from(value));
+                None
+            }
+            key => return Err(format!("unknown channel key '{key}'")),
+        };
+    }
+    if !channel.contains_key("id") {
+        return Err("missing channel 'id'".into());
+    }
+    if !events.is_empty() {
+        channel.insert("allowed_events".into(), toml::Value::Array(events));
+    }
+    Ok(channel)
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     /// Configuration settings
     pub settings: Settings,
+    /// Path of the configuration file
+    pub path: PathBuf,
 }
 
 impl Config {
@@ -177,8 +1204,20 @@ impl Config {
     ///
     /// Will read channel configurations in a directory
     /// located in the same directory as the configuration file.
-    pub fn read(path: &Path) -> Result<Self> {
-        let mut settings: Settings = toml::from_str(&fs::read_to_string(path)?)?;
+    /// `overrides` are applied on top of the configuration file.
+    pub fn read(path: &Path, overrides: &Overrides) -> Result<Self> {
+        let mut settings: Settings = Self::read_value(path, overrides)?.try_into()?;
+        settings.sanitize(path.parent().unwrap_or(Path::new("./")))?;
+        Ok(Config {
+            settings,
+            path: path.into(),
+        })
+    }
+
+    /// Return the merged configuration of `path`, with
+    /// the channel sets and the `overrides`, before validation
+    pub fn read_value(path: &Path, overrides: &Overrides) -> Result<toml::Value> {
+        let mut value = read_value(path)?;
 
         let root = path.parent().unwrap_or(Path::new("./"));
 
@@ -195,9 +1234,14 @@ impl Config {
                     match entry {
                         Ok(path) => {
                             log::info!("Loading channels configuration: {}", path.display());
-                            let mut chanset: ChannelSetConfig =
-                                toml::from_str(&fs::read_to_string(path)?)?;
-                            settings.channels.append(&mut chanset.channels);
+                            let mut chanset = read_value(&path)?;
+                            if let Some(channels) =
+                                chanset.as_table_mut().and_then(|t| t.remove("channel"))
+                            {
+                                let mut layer = toml::Table::new();
+                                layer.insert("channel".into(), channels);
+                                merge(&mut value, toml::Value::Table(layer));
+                            }
                         }
                         Err(err) => {
                             log::error!("Failed to read config file path: {err:?}");
@@ -206,8 +1250,13 @@ impl Config {
                 }
             }
         }
-        settings.sanitize(root)?;
-        Ok(Config { settings })
+        overrides.apply(&mut value);
+        Ok(value)
+    }
+
+    /// Return the directory of the configuration file
+    pub fn root(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("./"))
     }
 
     pub fn check(&self) -> Result<()> {
@@ -215,9 +1264,180 @@ impl Config {
     }
 }
 
+/// Read the toml file `path`
+///
+/// Environment variables are expanded in string values.
+fn read_value(path: &Path) -> Result<toml::Value> {
+    let mut value = read_layers(path, &mut vec![])?;
+    expand_env(&mut value).map_err(|err| Error::Config(format!("{}: {err}", path.display())))?;
+    Ok(value)
+}
+
+/// Read the toml file `path` merged with its included files
+///
+/// The files of the `include` list, relative to `path`, are merged
+/// in order and the content of `path` is merged last. `stack` holds
+/// the including files.
+fn read_layers(path: &Path, stack: &mut Vec<PathBuf>) -> Result<toml::Value> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|err| Error::Config(format!("Failed to read {}: {err}", path.display())))?;
+    if stack.contains(&canonical) {
+        return Err(Error::Config(format!(
+            "Circular include of {}",
+            path.display()
+        )));
+    }
+    let mut value: toml::Value = toml::from_str(&fs::read_to_string(path)?)?;
+    let Some(include) = value.as_table_mut().and_then(|t| t.remove("include")) else {
+        return Ok(value);
+    };
+    let root = path.parent().unwrap_or(Path::new("./"));
+    stack.push(canonical);
+    let invalid = || {
+        Error::Config(format!(
+            "{}: 'include' must be a list of paths",
+            path.display()
+        ))
+    };
+    let mut merged = toml::Value::Table(Default::default());
+    for file in include.as_array().ok_or_else(invalid)? {
+        let file = file.as_str().ok_or_else(invalid)?;
+        log::debug!("Including configuration: {file}");
+        merge(&mut merged, read_layers(&root.join(file), stack)?);
+    }
+    stack.pop();
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// Merge `layer` into `value`
+///
+/// Tables are merged recursively, arrays of tables (i.e `[[channel]]`)
+/// are appended and other values are replaced.
+fn merge(value: &mut toml::Value, layer: toml::Value) {
+    match (value, layer) {
+        (toml::Value::Table(table), toml::Value::Table(layer)) => {
+            for (key, item) in layer {
+                match table.get_mut(&key) {
+                    Some(current) => merge(current, item),
+                    None => {
+                        table.insert(key, item);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(values), toml::Value::Array(layer))
+            if values.iter().chain(&layer).all(toml::Value::is_table) =>
+        {
+            values.extend(layer)
+        }
+        (value, layer) => *value = layer,
+    }
+}
+
+/// Expand environment variables in the string values of `value`
+fn expand_env(value: &mut toml::Value) -> std::result::Result<(), String> {
+    match value {
+        toml::Value::String(s) if s.contains('$') => {
+            *s = interpolate(s, |name| std::env::var(name).ok())?;
+        }
+        toml::Value::Array(values) => values.iter_mut().try_for_each(expand_env)?,
+        toml::Value::Table(table) => table.iter_mut().try_for_each(|(_, v)| expand_env(v))?,
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` in `s`
+///
+/// `$${` is an escaped `${`.
+fn interpolate(
+    s: &str,
+    var: impl Fn(&str) -> Option<String>,
+) -> std::result::Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+        } else if let Some(expr) = rest.strip_prefix("${") {
+            let end = expr
+                .find('}')
+                .ok_or_else(|| format!("Unclosed '${{' in '{s}'"))?;
+            let (name, default) = match expr[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&expr[..end], None),
+            };
+            // Empty variables use the default value
+            match var(name)
+                .filter(|v| default.is_none() || !v.is_empty())
+                .or(default.map(String::from))
+            {
+                Some(value) => out.push_str(&value),
+                None => return Err(format!("Environment variable '{name}' is not set")),
+            }
+            rest = &expr[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
 // Shortcut
-pub fn read_config(path: &Path) -> Result<Config> {
-    Config::read(path)
+/// Commented default configuration
+pub const DEFAULT_CONFIG: &str = include_str!("../default-config.toml");
+
+const REDACTED: &str = "<redacted>";
+
+/// Redact the secrets of the configuration `value`
+///
+/// Values of `api_keys` and of keys ending with `secret`, `password`
+/// or `token` are replaced, as well as the passwords of connection strings.
+pub fn redact(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => table.iter_mut().for_each(|(key, v)| {
+            if matches!(key.as_str(), "api_key" | "api_keys")
+                || ["secret", "password", "token"]
+                    .iter()
+                    .any(|s| key.ends_with(s))
+            {
+                mask(v)
+            } else if let (true, Some(conn)) = (key == "connection_string", v.as_str()) {
+                *v = redact_connection_string(conn).into()
+            } else {
+                redact(v)
+            }
+        }),
+        toml::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+fn mask(value: &mut toml::Value) {
+    match value {
+        toml::Value::String(s) => *s = REDACTED.into(),
+        toml::Value::Array(values) => values.iter_mut().for_each(mask),
+        _ => (),
+    }
+}
+
+fn redact_connection_string(conn: &str) -> String {
+    let keyword = regex::Regex::new(r"(password\s*=\s*)('(?:[^'\\]|\\.)*'|\S+)").unwrap();
+    let url = regex::Regex::new(r"(://[^:/@]*:)[^@/]*@").unwrap();
+    let conn = keyword.replace_all(conn, format!("${{1}}{REDACTED}"));
+    url.replace_all(&conn, format!("${{1}}{REDACTED}@"))
+        .into_owned()
+}
+
+pub fn read_config(path: &Path, overrides: &Overrides) -> Result<Config> {
+    Config::read(path, overrides)
 }
 
 #[cfg(test)]
@@ -229,12 +1449,265 @@ mod tests {
     #[test]
     fn load_configuration() {
         setup();
-        let conf = Config::read(confdir!("config.toml")).unwrap();
+        let conf = Config::read(confdir!("config.toml"), &Default::default()).unwrap();
 
         assert_eq!(conf.settings.server.title, "Pg event test server");
+        assert_eq!(conf.settings.server.base_path, "/notify");
+        assert_eq!(conf.settings.server.keep_alive, Duration::from_secs(75));
+        assert_eq!(
+            conf.settings.server.client_request_timeout,
+            Duration::from_secs(5)
+        );
+        assert!(conf.settings.server.http2);
+        // Disabled channels are not loaded
         assert_eq!(conf.settings.channels.len(), 2);
 
         let chan0 = &conf.settings.channels[0];
         assert_eq!(chan0.allowed_events, ["foo", "bar", "baz"]);
+        assert!(chan0.api_keys.is_empty());
+        assert_eq!(chan0.slow_consumer, SlowConsumerPolicy::Drop);
+        // Channels start without their database by default
+        assert!(!chan0.required);
+        assert_eq!(
+            chan0.event_map.get("foo").map(String::as_str),
+            Some("update")
+        );
+
+        let chan1 = &conf.settings.channels[1];
+        assert_eq!(chan1.api_keys, ["secret", "key1", "key2"]);
+        assert_eq!(chan1.slow_consumer, SlowConsumerPolicy::BlockWithTimeout);
+        assert_eq!(chan1.slow_consumer_timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn config_layers() {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            reconnect_delay = 10
+            [server]
+            listen = "0.0.0.0:4001"
+            title = "Common"
+            [[channel]]
+            id = "common"
+            "#,
+        )
+        .unwrap();
+        let layer: toml::Value = toml::from_str(
+            r#"
+            [server]
+            title = "Site"
+            [[channel]]
+            id = "site"
+            "#,
+        )
+        .unwrap();
+        merge(&mut value, layer);
+        assert_eq!(value["reconnect_delay"].as_integer(), Some(10));
+        assert_eq!(value["server"]["listen"].as_str(), Some("0.0.0.0:4001"));
+        assert_eq!(value["server"]["title"].as_str(), Some("Site"));
+        assert_eq!(value["channel"].as_array().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn config_overrides() {
+        setup();
+        let overrides = Overrides {
+            listen: Some("0.0.0.0:4001".into()),
+            num_workers: Some(2),
+            no_tls: true,
+            channels: vec![
+                parse_channel("id=cli,events=foo,events=bar_*,conn=service=pg").unwrap(),
+            ],
+        };
+        let conf = Config::read(confdir!("config.toml"), &overrides).unwrap();
+        assert_eq!(conf.settings.server.listen, "0.0.0.0:4001");
+        assert_eq!(conf.settings.server.num_workers, Some(2));
+        assert!(!conf.settings.server.ssl_enabled);
+
+        let chan = conf
+            .settings
+            .channels
+            .iter()
+            .find(|c| c.id == "cli")
+            .unwrap();
+        assert_eq!(chan.allowed_events, ["foo", "bar_*"]);
+        assert_eq!(chan.connection_string.as_deref(), Some("service=pg"));
+
+        assert!(parse_channel("events=foo").is_err());
+        assert!(parse_channel("id=foo,bar").is_err());
+        assert!(parse_channel("id=foo,user=bar").is_err());
+    }
+
+    #[test]
+    fn ack_requires_persistence() {
+        setup();
+        let path = confdir!("config.toml").to_path_buf();
+        let settings = |extra: &str| -> Result<Settings> {
+            let mut value = Config::read_value(&path, &Default::default())?;
+            let extra: toml::Table = toml::from_str(extra)?;
+            value.as_table_mut().unwrap().extend(extra);
+            let mut settings: Settings = value.try_into()?;
+            settings.sanitize(path.parent().unwrap())?;
+            Ok(settings)
+        };
+
+        let channel = r#"
+            [[channel]]
+            id = "jobs"
+            allowed_events = ["job"]
+            [channel.ack]
+            "#;
+        let history = "[channel.history]\n";
+        let store = "[history_store]\n";
+        assert!(settings(&format!("{store}{channel}")).is_err());
+        assert!(settings(&format!("{channel}{history}")).is_err());
+        let conf = settings(&format!("{store}{channel}{history}")).unwrap();
+        assert_eq!(
+            conf.channels[0].ack.map(|ack| ack.consumer_ttl),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn peer_channels() {
+        setup();
+        let path = confdir!("config.toml").to_path_buf();
+        let settings = |peers: &str| -> Result<Settings> {
+            let mut value = Config::read_value(&path, &Default::default())?;
+            let peers: toml::Table = toml::from_str(peers)?;
+            value.as_table_mut().unwrap().extend(peers);
+            let mut settings: Settings = value.try_into()?;
+            settings.sanitize(path.parent().unwrap())?;
+            Ok(settings)
+        };
+
+        let conf = settings(
+            r#"
+            [[peer]]
+            url = "https://hub/events/subscribe/test"
+            [[peer]]
+            url = "https://hub/events/subscribe/foo"
+            channel = "regional"
+            "#,
+        )
+        .unwrap();
+        let peer_of = |id: &str| {
+            conf.channels
+                .iter()
+                .find(|c| c.id == id)
+                .and_then(|c| c.peer.as_ref())
+                .map(|p| p.url.as_str())
+        };
+        assert_eq!(peer_of("test"), Some("https://hub/events/subscribe/test"));
+        assert_eq!(
+            peer_of("regional"),
+            Some("https://hub/events/subscribe/foo")
+        );
+
+        assert!(settings(
+            r#"
+            [[peer]]
+            url = "https://hub/events/subscribe/foo"
+            [[peer]]
+            url = "https://hub2/events/subscribe/foo"
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn redact_secrets() {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            [server]
+            url_signing_secret = "s3cret"
+            admin = { api_keys = ["k1", "k2"] }
+            [[peer]]
+            url = "https://hub/events/subscribe/foo"
+            api_key = "k3"
+            [[channel]]
+            id = "foo"
+            connection_string = "host=db password='a b' user=me"
+            [[channel]]
+            id = "bar"
+            connection_string = "postgres://me:pass@db/events"
+            "#,
+        )
+        .unwrap();
+        redact(&mut value);
+        assert_eq!(
+            value["server"]["url_signing_secret"].as_str(),
+            Some(REDACTED)
+        );
+        assert_eq!(
+            value["server"]["admin"]["api_keys"][1].as_str(),
+            Some(REDACTED)
+        );
+        assert_eq!(
+            value["channel"][0]["connection_string"].as_str(),
+            Some("host=db password=<redacted> user=me")
+        );
+        assert_eq!(
+            value["channel"][1]["connection_string"].as_str(),
+            Some("postgres://me:<redacted>@db/events")
+        );
+        assert_eq!(value["peer"][0]["api_key"].as_str(), Some(REDACTED));
+        assert_eq!(value["channel"][1]["id"].as_str(), Some("bar"));
+    }
+
+    #[test]
+    fn default_config() {
+        let settings: Settings = toml::from_str(DEFAULT_CONFIG).unwrap();
+        assert_eq!(settings.events_buffer_size, default_events_buffer_size());
+        assert!(settings.channels.is_empty());
+    }
+
+    #[test]
+    fn env_interpolation() {
+        let var = |name: &str| (name == "PGPASSWORD").then(|| "s3cret".to_string());
+        assert_eq!(
+            interpolate("host=db password=${PGPASSWORD}", var).unwrap(),
+            "host=db password=s3cret"
+        );
+        assert_eq!(
+            interpolate("service=${PGSERVICE:-events}", var).unwrap(),
+            "service=events"
+        );
+        assert_eq!(interpolate("a$b $${c}", var).unwrap(), "a$b ${c}");
+        assert!(interpolate("service=${PGSERVICE}", var).is_err());
+        assert!(interpolate("service=${PGSERVICE", var).is_err());
+    }
+
+    #[test]
+    fn channel_defaults() {
+        let defaults: ChannelDefaults =
+            toml::from_str(r#"connection_string = "service=events""#).unwrap();
+        let mut conf: ChannelConfig = toml::from_str(r#"id = "foo""#).unwrap();
+        defaults.apply(&mut conf);
+        assert_eq!(conf.connection_string.as_deref(), Some("service=events"));
+
+        let mut conf: ChannelConfig =
+            toml::from_str("id = 'bar'\nconnection_string = 'service=local'").unwrap();
+        defaults.apply(&mut conf);
+        assert_eq!(conf.connection_string.as_deref(), Some("service=local"));
+    }
+
+    #[test]
+    fn payload_encodings() {
+        assert_eq!(
+            PayloadEncoding::Base64.normalize("AAH/\nAA==").as_deref(),
+            Some("AAH/AA==")
+        );
+        assert_eq!(
+            PayloadEncoding::Hex.normalize("\\x0001ff00").as_deref(),
+            Some("AAH/AA==")
+        );
+        assert_eq!(
+            PayloadEncoding::Hex.normalize("0001FF00").as_deref(),
+            Some("AAH/AA==")
+        );
+        assert!(PayloadEncoding::Hex.normalize("0001f").is_none());
+        assert!(PayloadEncoding::Base64.normalize("not base64").is_none());
+        assert!(PayloadEncoding::Text.normalize("foo").is_none());
     }
 }
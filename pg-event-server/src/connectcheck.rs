@@ -0,0 +1,141 @@
+//!
+//! Connection check
+//!
+//! Open the postgres connection of each channel and verify that
+//! its events can be listened. The `LISTEN` statements are issued
+//! in a transaction that is rolled back.
+//!
+use std::time::Duration;
+
+use crate::config::{ChannelConfig, Settings};
+use crate::eventpattern::EventPattern;
+use crate::postgres::tls::PgTlsConnect;
+use crate::{Error, Result};
+
+/// Timeout of the check of a channel
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Check the connection of all channels
+///
+/// The result of each channel is printed, an error is
+/// returned if any channel failed.
+pub async fn run(settings: &Settings) -> Result<()> {
+    let tls = settings.postgres_tls.make_tls_connect()?;
+    let mut failed = 0;
+    for conf in &settings.channels {
+        match tokio::time::timeout(CHECK_TIMEOUT, check_channel(conf, &tls)).await {
+            Ok(Ok(report)) => println!("Channel '{}': ok ({report})", conf.id),
+            Ok(Err(err)) => {
+                failed += 1;
+                println!("Channel '{}': FAILED: {}", conf.id, describe(&err));
+            }
+            Err(_) => {
+                failed += 1;
+                println!(
+                    "Channel '{}': FAILED: no response after {}s",
+                    conf.id,
+                    CHECK_TIMEOUT.as_secs()
+                );
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(Error::Config(format!(
+            "{failed} channel(s) failed the connection check"
+        )));
+    }
+    Ok(())
+}
+
+async fn check_channel(conf: &ChannelConfig, tls: &PgTlsConnect) -> Result<String> {
+    if conf.routed_only {
+        return Ok("routed only, no connection".into());
+    }
+    if let Some(peer) = &conf.peer {
+        return Ok(format!("fed by peer {}, no connection", peer.url));
+    }
+    let pgconfig = pg_client_config::load_config(conf.connection_string.as_deref())?;
+    let password = match (pgconfig.get_password(), pg_client_config::passfile_path()) {
+        (Some(_), _) => "password set".to_string(),
+        (None, Some(path)) => format!("no password, no entry matched in {}", path.display()),
+        (None, None) => "no password".to_string(),
+    };
+    let tls = match &conf.postgres_tls {
+        Some(tls) => tls.make_tls_connect()?,
+        None => tls.clone(),
+    };
+    let (client, connection) = pgconfig
+        .connect(tls)
+        .await
+        .map_err(|err| Error::Config(format!("{} ({password})", pg_error(&err))))?;
+    actix_web::rt::spawn(async move {
+        if let Err(err) = connection.await {
+            log::debug!("Check connection closed: {err}");
+        }
+    });
+
+    let row = client
+        .query_one(
+            "SELECT pg_backend_pid(), \
+            coalesce((SELECT ssl FROM pg_stat_ssl WHERE pid = pg_backend_pid()), false)",
+            &[],
+        )
+        .await?;
+    let (session_pid, ssl): (i32, bool) = (row.get(0), row.get(1));
+    let mut report = format!(
+        "session {session_pid}, tls {}, {password}",
+        if ssl { "on" } else { "off" }
+    );
+
+    if let Some(replication) = conf.replication.as_ref().filter(|r| !r.create_slot) {
+        client
+            .query_opt(
+                "SELECT 1 FROM pg_replication_slots WHERE slot_name = $1",
+                &[&replication.slot],
+            )
+            .await?
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "replication slot '{}' does not exist",
+                    replication.slot
+                ))
+            })?;
+        report.push_str(&format!(", slot '{}'", replication.slot));
+    } else if conf.replication.is_none() {
+        let events: Vec<_> = conf
+            .allowed_events
+            .iter()
+            .filter_map(EventPattern::name)
+            .collect();
+        if !events.is_empty() {
+            let listen = events
+                .iter()
+                .map(|event| format!("LISTEN {event};"))
+                .collect::<String>();
+            client
+                .batch_execute(&format!("BEGIN;{listen}ROLLBACK;"))
+                .await?;
+            report.push_str(&format!(", listen {}", events.join(", ")));
+        }
+    }
+    Ok(report)
+}
+
+fn describe(err: &Error) -> String {
+    match err {
+        Error::PostgresError(err) => pg_error(err),
+        Error::PostgresConnection(err) => err.to_string(),
+        Error::Config(msg) | Error::PostgresTlsError(msg) => msg.clone(),
+        err => err.to_string(),
+    }
+}
+
+/// Postgres errors only display their kind
+fn pg_error(err: &pg_event_listener::Error) -> String {
+    use std::error::Error as _;
+    match (err.as_db_error(), err.source()) {
+        (Some(db), _) => format!("{}: {}", db.severity(), db.message()),
+        (None, Some(source)) => format!("{err}: {source}"),
+        (None, None) => err.to_string(),
+    }
+}
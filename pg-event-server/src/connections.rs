@@ -0,0 +1,224 @@
+//!
+//! Track opened SSE connections
+//!
+//! Connections are counted across all workers so that
+//! limits apply to the server as a whole.
+//!
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::{Error, Result};
+
+/// Informations about a subscriber
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriberInfo {
+    pub id: Uuid,
+    /// Subscribed channels
+    pub channels: Vec<String>,
+    pub client_id: Option<String>,
+    pub remote_ip: Option<String>,
+    /// Connection time in milliseconds since epoch
+    pub connected_at: u64,
+    /// Number of events dropped for this subscriber
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    total: usize,
+    per_ip: HashMap<String, usize>,
+    subscribers: HashMap<Uuid, SubscriberInfo>,
+}
+
+/// Shared connection registry
+#[derive(Default, Clone)]
+pub struct Connections {
+    max_connections: Option<usize>,
+    max_per_ip: Option<usize>,
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl Connections {
+    /// Create new registry
+    ///
+    /// `max_connections` is the maximum number of simultaneous
+    /// connections for the server and `max_per_ip` the maximum number
+    /// of simultaneous connections for a client ip.
+    pub fn new(max_connections: Option<usize>, max_per_ip: Option<usize>) -> Self {
+        Self {
+            max_connections,
+            max_per_ip,
+            ..Self::default()
+        }
+    }
+
+    /// Register a new connection for `ip`
+    ///
+    /// The connection is released when the returned guard
+    /// is dropped.
+    pub fn acquire(&self, ip: Option<&str>) -> Result<ConnectionGuard> {
+        let mut counters = self.counters.lock().unwrap();
+        if self
+            .max_connections
+            .is_some_and(|max| counters.total >= max)
+        {
+            log::warn!("Maximum number of connections reached");
+            return Err(Error::MaxConnectionsReached);
+        }
+        if let Some(ip) = ip {
+            let count = counters.per_ip.entry(ip.into()).or_default();
+            if self.max_per_ip.is_some_and(|max| *count >= max) {
+                log::warn!("Too many connections for {ip}");
+                return Err(Error::TooManyConnections);
+            }
+            *count += 1;
+        }
+        counters.total += 1;
+        Ok(ConnectionGuard {
+            connections: self.clone(),
+            ip: ip.map(String::from),
+            ident: None,
+        })
+    }
+
+    /// Return the list of registered subscribers
+    pub fn subscribers(&self) -> Vec<SubscriberInfo> {
+        self.counters
+            .lock()
+            .unwrap()
+            .subscribers
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Count an event dropped for subscriber `ident`
+    ///
+    /// Return the number of events dropped
+    /// for this subscriber.
+    pub fn drop_event(&self, ident: &Uuid) -> u64 {
+        self.counters
+            .lock()
+            .unwrap()
+            .subscribers
+            .get_mut(ident)
+            .map_or(0, |info| {
+                info.dropped += 1;
+                info.dropped
+            })
+    }
+
+    /// Return the number of events dropped
+    /// for subscriber `ident`
+    pub fn dropped(&self, ident: &Uuid) -> u64 {
+        self.counters
+            .lock()
+            .unwrap()
+            .subscribers
+            .get(ident)
+            .map_or(0, |info| info.dropped)
+    }
+
+    fn release(&self, ip: Option<&str>, ident: Option<&Uuid>) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.total -= 1;
+        if let Some(ident) = ident {
+            counters.subscribers.remove(ident);
+        }
+        if let Some(ip) = ip {
+            if let Some(count) = counters.per_ip.get_mut(ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counters.per_ip.remove(ip);
+                }
+            }
+        }
+    }
+}
+
+/// Release the connection on drop
+pub struct ConnectionGuard {
+    connections: Connections,
+    ip: Option<String>,
+    ident: Option<Uuid>,
+}
+
+impl ConnectionGuard {
+    /// Register subscriber informations for this connection
+    pub fn register(&mut self, info: SubscriberInfo) {
+        self.ident = Some(info.id);
+        self.connections
+            .counters
+            .lock()
+            .unwrap()
+            .subscribers
+            .insert(info.id, info);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections
+            .release(self.ip.as_deref(), self.ident.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_per_ip() {
+        let connections = Connections::new(None, Some(2));
+
+        let g1 = connections.acquire(Some("127.0.0.1")).unwrap();
+        let _g2 = connections.acquire(Some("127.0.0.1")).unwrap();
+        assert!(connections.acquire(Some("127.0.0.1")).is_err());
+        let _g3 = connections.acquire(Some("127.0.0.2")).unwrap();
+
+        drop(g1);
+        assert!(connections.acquire(Some("127.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn max_connections() {
+        let connections = Connections::new(Some(2), None);
+
+        let g1 = connections.acquire(Some("127.0.0.1")).unwrap();
+        let _g2 = connections.acquire(None).unwrap();
+        assert!(matches!(
+            connections.acquire(Some("127.0.0.2")),
+            Err(Error::MaxConnectionsReached)
+        ));
+
+        drop(g1);
+        assert!(connections.acquire(Some("127.0.0.2")).is_ok());
+    }
+
+    #[test]
+    fn subscribers_info() {
+        let connections = Connections::default();
+
+        let mut guard = connections.acquire(None).unwrap();
+        let id = Uuid::new_v4();
+        guard.register(SubscriberInfo {
+            id,
+            channels: vec!["test".into()],
+            client_id: None,
+            remote_ip: None,
+            connected_at: 0,
+            dropped: 0,
+        });
+        assert_eq!(connections.subscribers().len(), 1);
+
+        assert_eq!(connections.drop_event(&id), 1);
+        assert_eq!(connections.drop_event(&id), 2);
+        assert_eq!(connections.subscribers()[0].dropped, 2);
+        assert_eq!(connections.drop_event(&Uuid::new_v4()), 0);
+
+        drop(guard);
+        assert!(connections.subscribers().is_empty());
+    }
+}
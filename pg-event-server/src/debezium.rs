@@ -0,0 +1,132 @@
+//!
+//! Debezium change event envelope
+//!
+//! Row change payloads, as sent by replication sources or by the
+//! triggers of the `gen-trigger` command, are converted to the
+//! Debezium json format (without schema) so that Debezium consumers
+//! can process them unchanged.
+//!
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::events::Event;
+use crate::utils::timestamp_millis;
+
+#[derive(Serialize)]
+struct Source<'a> {
+    version: &'static str,
+    connector: &'static str,
+    /// Channel id
+    name: &'a str,
+    /// Reception time of the change
+    ts_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    db: Option<&'a str>,
+    schema: &'a str,
+    table: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lsn: Option<u64>,
+}
+
+/// Debezium change event
+#[derive(Serialize)]
+pub(crate) struct ChangeEvent<'a> {
+    before: Option<&'a Value>,
+    after: Option<&'a Value>,
+    source: Source<'a>,
+    op: &'static str,
+    ts_ms: u64,
+}
+
+/// Parse a textual lsn (`XXX/XXX`)
+fn parse_lsn(lsn: &str) -> Option<u64> {
+    let (hi, lo) = lsn.split_once('/')?;
+    let hi = u64::from_str_radix(hi, 16).ok()?;
+    let lo = u64::from_str_radix(lo, 16).ok()?;
+    Some((hi << 32) | lo)
+}
+
+impl<'a> ChangeEvent<'a> {
+    /// Convert the change of `event` on `channel`
+    ///
+    /// Return `None` if the payload is not a row change.
+    pub(crate) fn new(channel: &'a str, event: &'a Event) -> Option<Self> {
+        let change = event.json()?;
+        let (schema, table) = change.get("table")?.as_str()?.split_once('.')?;
+        let data = change.get("data").filter(|v| !v.is_null());
+        let (op, before, after) = match change.get("op")?.as_str()? {
+            "INSERT" => ("c", None, data),
+            "UPDATE" => ("u", change.get("old"), data),
+            "DELETE" => ("d", data, None),
+            "TRUNCATE" => ("t", None, None),
+            _ => return None,
+        };
+        Some(Self {
+            before,
+            after,
+            source: Source {
+                version: env!("CARGO_PKG_VERSION"),
+                connector: "postgresql",
+                name: channel,
+                ts_ms: event.timestamp(),
+                db: change.get("db").and_then(Value::as_str),
+                schema,
+                table,
+                lsn: change
+                    .get("lsn")
+                    .and_then(Value::as_str)
+                    .and_then(parse_lsn),
+            },
+            op,
+            ts_ms: timestamp_millis(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn convert(payload: Value) -> Option<Value> {
+        let event = Event::from_parts(1, "1", "items", 0, &payload.to_string(), false, 42);
+        ChangeEvent::new("changes", &event).map(|change| serde_json::to_value(change).unwrap())
+    }
+
+    #[test]
+    fn change_events() {
+        let change = convert(json!({
+            "table": "public.items",
+            "op": "UPDATE",
+            "data": {"id": 1, "name": "new"},
+            "old": {"id": 1, "name": "old"},
+            "lsn": "1/16B3748",
+        }))
+        .unwrap();
+        assert_eq!(change["op"], "u");
+        assert_eq!(change["before"], json!({"id": 1, "name": "old"}));
+        assert_eq!(change["after"], json!({"id": 1, "name": "new"}));
+        assert_eq!(change["source"]["name"], "changes");
+        assert_eq!(change["source"]["schema"], "public");
+        assert_eq!(change["source"]["table"], "items");
+        assert_eq!(change["source"]["ts_ms"], 42);
+        assert_eq!(change["source"]["lsn"], 0x1_016B_3748u64);
+        assert!(change["source"].get("db").is_none());
+
+        let change = convert(json!({
+            "table": "public.items",
+            "op": "DELETE",
+            "data": {"id": 1},
+            "db": "test",
+        }))
+        .unwrap();
+        assert_eq!(change["op"], "d");
+        assert_eq!(change["before"], json!({"id": 1}));
+        assert!(change["after"].is_null());
+        assert_eq!(change["source"]["db"], "test");
+
+        // Not a row change
+        assert!(convert(json!({"op": "INSERT"})).is_none());
+        assert!(convert(json!({"table": "public.items", "op": "MERGE"})).is_none());
+    }
+}
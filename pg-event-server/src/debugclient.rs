@@ -0,0 +1,15 @@
+//
+// Test client page
+//
+// Embedded page subscribing to a channel
+// and rendering incoming events.
+//
+use actix_web::HttpResponse;
+
+static PAGE: &str = include_str!("../static/debug_client.html");
+
+pub async fn handler() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(PAGE)
+}
@@ -0,0 +1,72 @@
+//!
+//! Duplicate event suppression
+//!
+//! Events with the same postgres event name and payload
+//! are dropped if an identical event was broadcast
+//! within the window.
+//!
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+pub struct DedupWindow {
+    window: Duration,
+    /// Broadcast time of the events indexed
+    /// by the hash of the event and the payload
+    seen: HashMap<u64, Instant>,
+    /// Time of the last purge of expired entries
+    purged: Instant,
+}
+
+impl DedupWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+            purged: Instant::now(),
+        }
+    }
+
+    /// Return true if an identical event has been
+    /// broadcast within the window, otherwise record
+    /// the event.
+    pub fn is_duplicate(&mut self, event: &str, payload: &str, now: Instant) -> bool {
+        if now.duration_since(self.purged) > self.window {
+            let window = self.window;
+            self.seen.retain(|_, t| now.duration_since(*t) < window);
+            self.purged = now;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        (event, payload).hash(&mut hasher);
+        let key = hasher.finish();
+
+        match self.seen.get(&key) {
+            Some(t) if now.duration_since(*t) < self.window => true,
+            _ => {
+                self.seen.insert(key, now);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup_window() {
+        let mut dedup = DedupWindow::new(Duration::from_secs(2));
+        let now = Instant::now();
+
+        assert!(!dedup.is_duplicate("foo", "1", now));
+        assert!(dedup.is_duplicate("foo", "1", now + Duration::from_secs(1)));
+        assert!(!dedup.is_duplicate("foo", "2", now + Duration::from_secs(1)));
+        assert!(!dedup.is_duplicate("bar", "1", now + Duration::from_secs(1)));
+        // Window elapsed
+        assert!(!dedup.is_duplicate("foo", "1", now + Duration::from_secs(2)));
+        assert!(dedup.is_duplicate("foo", "1", now + Duration::from_secs(3)));
+    }
+}
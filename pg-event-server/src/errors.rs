@@ -19,24 +19,89 @@ pub enum Error {
     PostgresError(#[from] pg_event_listener::Error),
     #[error("Subscription do not exists")]
     SubscriptionNotFound,
+    #[error("Channel '{0}' already exists")]
+    ChannelExists(String),
+    #[error("Event dispatcher is not available")]
+    DispatcherUnavailable,
+    #[error("Postgres connection of channel '{0}' is down")]
+    BackendUnavailable(String),
+    #[cfg(feature = "replication")]
+    #[error("Replication error: {0}")]
+    Replication(String),
+    #[error("Peer error: {0}")]
+    Peer(String),
+    #[error("Maximum number of postgres connections reached ({0})")]
+    TooManyPgConnections(usize),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Missing credentials")]
+    Unauthorized,
+    #[error("Access denied")]
+    Forbidden,
+    #[error("Too many connections")]
+    TooManyConnections,
+    #[error("Maximum number of connections reached")]
+    MaxConnectionsReached,
+    #[error("Authorization service unavailable: {0}")]
+    AuthUnavailable(String),
+    #[error("Rate limit exceeded")]
+    RateLimited(u64),
+    #[error("Payload too large")]
+    PayloadTooLarge,
     #[error("Postgres TLS error: {0}")]
     PostgresTlsError(String),
+    #[error("HA advisory lock lost")]
+    LockLost,
+    #[error("Cluster registry is not available")]
+    ClusterUnavailable,
+    #[cfg(feature = "acme")]
+    #[error("ACME error: {0}")]
+    Acme(String),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-use actix_web::http::{header::ContentType, StatusCode};
+use actix_web::http::{
+    header::{ContentType, RETRY_AFTER},
+    StatusCode,
+};
 use actix_web::HttpResponse;
 
+/// Delay in seconds clients should wait before
+/// retrying when the service is unavailable
+const RETRY_AFTER_SECS: u32 = 10;
+
 impl actix_web::ResponseError for Error {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::json())
-            .finish()
+        let mut builder = HttpResponse::build(self.status_code());
+        builder.insert_header(ContentType::json());
+        match self {
+            Error::MaxConnectionsReached | Error::BackendUnavailable(_) => {
+                builder.insert_header((RETRY_AFTER, RETRY_AFTER_SECS));
+            }
+            Error::RateLimited(secs) => {
+                builder.insert_header((RETRY_AFTER, (*secs).max(1)));
+            }
+            _ => (),
+        }
+        builder.finish()
     }
     fn status_code(&self) -> StatusCode {
         match *self {
             Error::SubscriptionNotFound => StatusCode::NOT_FOUND,
+            Error::ChannelExists(_) => StatusCode::CONFLICT,
+            Error::DispatcherUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Error::BackendUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::TooManyPgConnections(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::TooManyConnections => StatusCode::TOO_MANY_REQUESTS,
+            Error::MaxConnectionsReached => StatusCode::SERVICE_UNAVAILABLE,
+            Error::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::AuthUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::ClusterUnavailable => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -0,0 +1,161 @@
+//!
+//! Event name patterns
+//!
+//! Allowed events of a channel may be exact names, glob
+//! patterns (i.e `qgis_*`) or regular expressions prefixed
+//! with `~` (i.e `~^audit_[0-9]+$`).
+//!
+//! Postgres cannot listen to patterns: only exact names are
+//! listened, patterns match the events received on the channel
+//! connection at dispatch time.
+//!
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::Error;
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Name,
+    Glob(glob::Pattern),
+    Regex(regex::Regex),
+}
+
+/// Allowed event name or pattern
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct EventPattern {
+    source: String,
+    matcher: Matcher,
+}
+
+impl TryFrom<String> for EventPattern {
+    type Error = Error;
+
+    fn try_from(source: String) -> Result<Self, Error> {
+        let matcher = if let Some(re) = source.strip_prefix('~') {
+            Matcher::Regex(
+                regex::Regex::new(re)
+                    .map_err(|err| Error::Config(format!("Invalid event regex '{re}': {err}")))?,
+            )
+        } else if source.contains(['*', '?', '[']) {
+            Matcher::Glob(
+                glob::Pattern::new(&source).map_err(|err| {
+                    Error::Config(format!("Invalid event pattern '{source}': {err}"))
+                })?,
+            )
+        } else {
+            Matcher::Name
+        };
+        Ok(Self { source, matcher })
+    }
+}
+
+impl Serialize for EventPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl PartialEq for EventPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl PartialEq<&str> for EventPattern {
+    fn eq(&self, other: &&str) -> bool {
+        self.source == *other
+    }
+}
+
+impl fmt::Display for EventPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl EventPattern {
+    /// Return the event name if the pattern
+    /// is an exact name
+    pub fn name(&self) -> Option<&str> {
+        match self.matcher {
+            Matcher::Name => Some(&self.source),
+            _ => None,
+        }
+    }
+
+    /// Return true if `event` matches the pattern
+    pub fn matches(&self, event: &str) -> bool {
+        match &self.matcher {
+            Matcher::Name => self.source == event,
+            Matcher::Glob(pattern) => pattern.matches(event),
+            Matcher::Regex(re) => re.is_match(event),
+        }
+    }
+}
+
+/// Return true if the SSE event `name` is allowed by `allowed`
+/// and not excluded by `denied`
+///
+/// Postgres events are renamed according to `event_map`, all
+/// events are allowed if `allowed` is empty.
+pub fn is_allowed(
+    allowed: &[EventPattern],
+    denied: &[EventPattern],
+    event_map: &HashMap<String, String>,
+    name: &str,
+) -> bool {
+    if allowed.is_empty() && denied.is_empty() {
+        return true;
+    }
+    // Postgres events sent as `name`
+    let renamed = event_map
+        .iter()
+        .filter(|(_, v)| *v == name)
+        .map(|(k, _)| k.as_str());
+    let unmapped = event_map.get(name).is_none_or(|v| v == name);
+    renamed.chain(unmapped.then_some(name)).any(|event| {
+        (allowed.is_empty() || allowed.iter().any(|p| p.matches(event)))
+            && !denied.iter().any(|p| p.matches(event))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(s: &[&str]) -> Vec<EventPattern> {
+        s.iter()
+            .map(|s| EventPattern::try_from(s.to_string()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn event_patterns() {
+        let p = patterns(&["foo", "qgis_*", "~^audit_[0-9]+$"]);
+        assert_eq!(p[0].name(), Some("foo"));
+        assert!(p[1].name().is_none());
+        assert!(p[0].matches("foo") && !p[0].matches("foobar"));
+        assert!(p[1].matches("qgis_project") && !p[1].matches("postgis"));
+        assert!(p[2].matches("audit_12") && !p[2].matches("audit_x"));
+
+        let event_map = HashMap::from([("qgis_layer".to_string(), "layer".to_string())]);
+        assert!(is_allowed(&p, &[], &event_map, "layer"));
+        assert!(is_allowed(&p, &[], &event_map, "audit_1"));
+        assert!(!is_allowed(&p, &[], &event_map, "qgis_layer"));
+        assert!(!is_allowed(&p, &[], &event_map, "bar"));
+        assert!(is_allowed(&[], &[], &event_map, "bar"));
+
+        let denied = patterns(&["qgis_layer", "~^audit_0"]);
+        assert!(!is_allowed(&p, &denied, &event_map, "layer"));
+        assert!(!is_allowed(&p, &denied, &event_map, "audit_01"));
+        assert!(is_allowed(&p, &denied, &event_map, "audit_10"));
+        assert!(!is_allowed(&[], &denied, &event_map, "audit_0"));
+        assert!(is_allowed(&[], &denied, &event_map, "bar"));
+
+        assert!(EventPattern::try_from("~(".to_string()).is_err());
+        assert!(EventPattern::try_from("[a".to_string()).is_err());
+    }
+}
@@ -10,11 +10,36 @@
 //!    of candidate channels.
 //!
 //!
-use crate::{config::ChannelConfig, pool::PgNotificationDispatch, pool::Pool, Result};
-use pg_event_listener::Notification;
-use std::time::Duration;
-use tokio::sync::mpsc;
+use crate::{
+    config::{ChannelConfig, EventIdFormat, HistoryConfig, PayloadEncoding, PayloadSizePolicy},
+    dedup::DedupWindow,
+    eventpattern::EventPattern,
+    history::History,
+    metrics::{Metrics, QueueUsageConfig},
+    payloadtable::PayloadTable,
+    pool::{PgNotification, PgNotificationDispatch, Pool, PoolOptions, PoolStatus, StateChange},
+    postgres::tls::PgTlsConnect,
+    registry::ChannelRegistry,
+    routes::Route,
+    stats::Stats,
+    telemetry::TraceContext,
+    transform::Template,
+    validation::ValidationConfig,
+    Error, Result,
+};
+use bytestring::ByteString;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::iter;
+use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::field;
+use uuid::Uuid;
 
+use crate::alert::Alerts;
 use crate::config::Settings;
 
 pub type ChanId = usize;
@@ -22,30 +47,127 @@ pub type ChanId = usize;
 // A simple readonly type for not allocating memory
 // when we have only one element, which should be
 // the vast majority of cases.
-use crate::utils::Values;
+use crate::utils::{timestamp_millis, Interner, Values};
 
 type ChanIds = Values<ChanId>;
 
+/// Name of the backend status events
+pub const STATUS_EVENT: &str = "_status";
+
 /// Event broadcasted to
 /// All workers
+///
+/// Events are shared between workers, the id and the
+/// payload are reference counted so that they are passed
+/// down to subscribers without copy.
 #[derive(Default, Debug, Clone)]
 pub struct Event {
-    id: String,
-    event: String,
+    id: ByteString,
+    event: Arc<str>,
     session: i32,
-    payload: String,
+    payload: ByteString,
     channels: ChanIds,
+    timestamp: u64,
+    // Payload parsed as json, computed on demand
+    json: OnceLock<Option<Value>>,
+    // Trace context of the notification
+    trace: TraceContext,
+    // Backend status event
+    status: bool,
+    // Binary payload encoded as base64
+    binary: bool,
 }
 
 impl Event {
     /// Create new event from notification
-    fn new(id: String, notification: Notification, channels: ChanIds) -> Self {
+    ///
+    /// `event` is the interned name of the notification channel.
+    fn new(
+        id: String,
+        event: Arc<str>,
+        notification: PgNotification,
+        channels: ChanIds,
+        trace: TraceContext,
+    ) -> Self {
         Self {
-            id,
+            id: id.into(),
             session: notification.process_id(),
-            event: notification.channel().into(),
-            payload: notification.payload().into(),
+            event,
+            payload: notification.into_payload().into(),
+            channels,
+            timestamp: timestamp_millis(),
+            json: OnceLock::new(),
+            trace,
+            status: false,
+            binary: false,
+        }
+    }
+    /// Create a status event reporting the state of
+    /// the postgres connection of `channels`
+    fn status(connected: bool, channels: ChanIds) -> Self {
+        let timestamp = timestamp_millis();
+        Self {
+            event: STATUS_EVENT.into(),
+            payload: serde_json::json!({
+                "state": if connected { "up" } else { "down" },
+                "ts": timestamp,
+            })
+            .to_string()
+            .into(),
+            channels,
+            timestamp,
+            status: true,
+            ..Self::default()
+        }
+    }
+    /// Restore a persisted event of channel `chan_id`
+    pub fn from_parts(
+        chan_id: ChanId,
+        id: &str,
+        event: &str,
+        session: i32,
+        payload: &str,
+        binary: bool,
+        timestamp: u64,
+    ) -> Self {
+        Self {
+            id: id.to_string().into(),
+            event: event.into(),
+            session,
+            payload: payload.to_string().into(),
+            binary,
+            channels: ChanIds::One([chan_id]),
+            timestamp,
+            ..Self::default()
+        }
+    }
+    /// Create an empty event with `id`
+    #[cfg(test)]
+    pub fn with_id(id: &str) -> Self {
+        Self {
+            id: id.to_string().into(),
+            ..Self::default()
+        }
+    }
+    /// Return a copy of the event for `channels` with
+    /// the payload truncated to at most `size` bytes
+    fn truncated(&self, size: usize, channels: ChanIds) -> Self {
+        self.with_payload(truncate(&self.payload, size), self.binary, channels)
+    }
+    /// Return a copy of the event for `channels`
+    /// with `payload`
+    fn with_payload(&self, payload: ByteString, binary: bool, channels: ChanIds) -> Self {
+        Self {
+            id: self.id.clone(),
+            event: self.event.clone(),
+            session: self.session,
+            payload,
             channels,
+            timestamp: self.timestamp,
+            json: OnceLock::new(),
+            trace: self.trace.clone(),
+            status: self.status,
+            binary,
         }
     }
     /// Unique id for this event
@@ -60,6 +182,11 @@ impl Event {
     pub fn event(&self) -> &str {
         &self.event
     }
+    /// Return the reception time of the event
+    /// in milliseconds since epoch
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
     /// return the postgres session id
     pub fn session_pid(&self) -> i32 {
         self.session
@@ -68,29 +195,237 @@ impl Event {
     pub fn payload(&self) -> &str {
         &self.payload
     }
+    /// Return true if the payload is binary
+    /// data encoded as base64
+    pub fn is_binary(&self) -> bool {
+        self.binary
+    }
+    /// Return the payload data, decoded from
+    /// base64 for binary payloads
+    #[cfg_attr(not(any(feature = "mqtt", feature = "nats")), allow(dead_code))]
+    pub fn payload_bytes(&self) -> Cow<'_, [u8]> {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        match self.binary {
+            true => STANDARD
+                .decode(self.payload.as_bytes())
+                .map_or(Cow::Borrowed(self.payload.as_bytes()), Cow::Owned),
+            false => Cow::Borrowed(self.payload.as_bytes()),
+        }
+    }
+    /// Return true if this is a backend status event
+    pub fn is_status(&self) -> bool {
+        self.status
+    }
+    /// Return the trace context of the notification
+    pub fn trace_context(&self) -> &TraceContext {
+        &self.trace
+    }
+    /// Return the payload as json
+    ///
+    /// Return `None` if the payload is not valid json
+    /// or is binary.
+    pub fn json(&self) -> Option<&Value> {
+        self.json
+            .get_or_init(|| {
+                (!self.binary)
+                    .then(|| serde_json::from_str(&self.payload).ok())
+                    .flatten()
+            })
+            .as_ref()
+    }
+}
+
+/// Truncate `payload` to at most `size` bytes
+fn truncate(payload: &ByteString, size: usize) -> ByteString {
+    let mut end = size.min(payload.len());
+    while !payload.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.slice_ref(&payload[..end])
 }
 
 /// Channel
 pub struct Channel {
+    /// Internal channel id
+    chan_id: ChanId,
+    /// Channel id
+    id: String,
     /// Allowed events for this channel
-    events: Vec<String>,
+    events: Vec<EventPattern>,
+    /// Denied events for this channel
+    denied: Vec<EventPattern>,
     /// The event dispatch_id
     dispatch_id: i32,
+    /// Duplicate events suppression
+    dedup: Option<DedupWindow>,
+    /// Maximum payload size and policy
+    max_payload_size: Option<(usize, PayloadSizePolicy)>,
+    /// Retention of events for replay
+    history: Option<HistoryConfig>,
+    /// Table of the notified payloads
+    payload_table: Option<PayloadTable>,
+    /// Encoding of the payloads
+    encoding: PayloadEncoding,
+    /// Schema validation of the payloads
+    validation: Option<ValidationConfig>,
+    /// Transformation of the payloads
+    transform: Option<Template>,
+    /// Routing rules to other channels
+    routes: Vec<Route>,
 }
 
 impl Channel {
     /// Create new [`Channel`]
-    pub fn new(dispatch_id: i32, conf: ChannelConfig) -> Self {
+    pub fn new(chan_id: ChanId, dispatch_id: i32, conf: ChannelConfig) -> Self {
         Self {
+            chan_id,
+            id: conf.id,
             events: conf.allowed_events,
+            denied: conf.deny_events,
             dispatch_id,
+            dedup: conf.dedup_window.map(DedupWindow::new),
+            max_payload_size: conf
+                .max_payload_size
+                .map(|size| (size, conf.payload_size_policy)),
+            history: conf.history,
+            payload_table: conf.payload_table.map(PayloadTable::new),
+            encoding: conf.encoding,
+            validation: conf.validation,
+            transform: conf.transform,
+            routes: conf.routes,
         }
     }
     /// Return true if that Channel is listening
     /// for `event`
     pub fn is_listening_for(&self, dispatch_id: i32, event: &str) -> bool {
         self.dispatch_id == dispatch_id
-            && (self.events.is_empty() || self.events.iter().any(|e| *e == event))
+            && (self.events.is_empty() || self.events.iter().any(|e| e.matches(event)))
+            && !self.denied.iter().any(|e| e.matches(event))
+    }
+    /// Return true if an identical event has been
+    /// broadcast within the dedup window
+    fn is_duplicate(&mut self, event: &str, payload: &str, now: Instant) -> bool {
+        self.dedup
+            .as_mut()
+            .is_some_and(|dedup| dedup.is_duplicate(event, payload, now))
+    }
+    /// Apply the maximum payload size of the channel
+    ///
+    /// Return the size to which the payload must be
+    /// truncated, or `Err` if the event is dropped.
+    fn check_size(
+        &self,
+        event: &str,
+        payload: &str,
+        binary: bool,
+        metrics: &Metrics,
+    ) -> Result<Option<usize>, ()> {
+        match self.max_payload_size {
+            Some((size, policy)) if payload.len() > size => {
+                metrics.event_oversized(&self.id);
+                // Binary payloads are never truncated
+                if policy != PayloadSizePolicy::Truncate || binary {
+                    log::warn!(
+                        "Dropping oversized event '{event}' ({} bytes) for channel '{}'",
+                        payload.len(),
+                        self.id
+                    );
+                    return Err(());
+                }
+                Ok(Some(size))
+            }
+            _ => Ok(None),
+        }
+    }
+    /// Return the payload of an event routed to the channel
+    ///
+    /// Return `None` if the event is dropped as a duplicate
+    /// or as oversized.
+    fn route_payload(
+        &mut self,
+        event: &str,
+        payload: ByteString,
+        binary: bool,
+        now: Instant,
+        metrics: &Metrics,
+    ) -> Option<ByteString> {
+        if self.is_duplicate(event, &payload, now) {
+            metrics.event_deduplicated(&self.id);
+            return None;
+        }
+        match self.check_size(event, &payload, binary, metrics).ok()? {
+            Some(size) => Some(truncate(&payload, size)),
+            None => Some(payload),
+        }
+    }
+}
+
+/// Commands sent to the dispatcher
+enum Command {
+    /// Add a new channel
+    AddChannel(Box<ChannelConfig>, oneshot::Sender<Result<()>>),
+    /// Remove the channel with the given id
+    RemoveChannel(String, oneshot::Sender<Result<()>>),
+    /// Notify `event` with `payload` on the connection
+    /// of the channel `id`
+    Publish {
+        id: String,
+        event: String,
+        payload: String,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Replace the set of channels
+    Reload(
+        Vec<ChannelConfig>,
+        PgTlsConnect,
+        oneshot::Sender<Result<()>>,
+    ),
+}
+
+/// Handle for sending commands to the dispatcher
+#[derive(Clone)]
+pub struct DispatchHandle(mpsc::Sender<Command>);
+
+impl DispatchHandle {
+    async fn send<F>(&self, command: F) -> Result<()>
+    where
+        F: FnOnce(oneshot::Sender<Result<()>>) -> Command,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(command(tx))
+            .await
+            .map_err(|_| Error::DispatcherUnavailable)?;
+        rx.await.map_err(|_| Error::DispatcherUnavailable)?
+    }
+
+    /// Add a new channel
+    pub async fn add_channel(&self, conf: ChannelConfig) -> Result<()> {
+        self.send(|tx| Command::AddChannel(Box::new(conf), tx))
+            .await
+    }
+
+    /// Remove the channel `id`
+    pub async fn remove_channel(&self, id: String) -> Result<()> {
+        self.send(|tx| Command::RemoveChannel(id, tx)).await
+    }
+
+    /// Notify `event` with `payload` on the connection
+    /// of the channel `id`
+    pub async fn publish(&self, id: String, event: String, payload: String) -> Result<()> {
+        self.send(|reply| Command::Publish {
+            id,
+            event,
+            payload,
+            reply,
+        })
+        .await
+    }
+
+    /// Replace the set of channels and the tls connector
+    /// used for new connections
+    pub async fn reload(&self, channels: Vec<ChannelConfig>, tls: PgTlsConnect) -> Result<()> {
+        self.send(|tx| Command::Reload(channels, tls, tx)).await
     }
 }
 
@@ -98,12 +433,277 @@ impl Channel {
 // Dispatcher
 //
 
+/// Channels handled by the dispatcher
+struct Channels {
+    list: Vec<Channel>,
+    registry: ChannelRegistry,
+}
+
+impl Channels {
+    /// Synchronize `history` with the channels
+    /// retaining events
+    fn sync_history(&self, history: &History) {
+        history.sync(self.list.iter().filter_map(|chan| {
+            chan.history
+                .as_ref()
+                .map(|conf| (chan.chan_id, chan.id.as_str(), conf))
+        }));
+    }
+    /// Add a new channel
+    async fn add(&mut self, pool: &Mutex<Pool>, conf: ChannelConfig) -> Result<()> {
+        if let Some(id) = iter::once(&conf.id)
+            .chain(&conf.aliases)
+            .find(|id| self.registry.contains(id))
+        {
+            return Err(Error::ChannelExists(id.clone()));
+        }
+        // Create postgres configuration
+        let mut pool = pool.lock().await;
+        let dispatch = pool.add_connection(&conf).await?;
+        let events = self.required_events(dispatch, None, &conf.allowed_events);
+        if let Err(err) = pool.sync_events(dispatch, events).await {
+            pool.remove_channel(dispatch, &conf.id);
+            return Err(err);
+        }
+        let chan_id = self.registry.insert(conf.clone());
+        self.list.push(Channel::new(chan_id, dispatch, conf));
+        Ok(())
+    }
+
+    /// Remove a channel
+    ///
+    /// Events that are not used by other channels of
+    /// the same connection are unlistened.
+    async fn remove(&mut self, pool: &Mutex<Pool>, id: &str) -> Result<()> {
+        let chan_id = self
+            .registry
+            .remove(id)
+            .ok_or(Error::SubscriptionNotFound)?
+            .chan_id;
+        let index = self
+            .list
+            .iter()
+            .position(|chan| chan.chan_id == chan_id)
+            .ok_or(Error::SubscriptionNotFound)?;
+        let chan = self.list.remove(index);
+        let mut pool = pool.lock().await;
+        pool.remove_channel(chan.dispatch_id, id);
+        self.unlisten_unused(&mut pool, chan.dispatch_id).await;
+        Ok(())
+    }
+
+    /// Update the configuration of an existing channel
+    ///
+    /// The channel keeps its internal id so that
+    /// subscribers are not disconnected.
+    async fn update(&mut self, pool: &Mutex<Pool>, conf: ChannelConfig) -> Result<()> {
+        let index = self
+            .list
+            .iter()
+            .position(|chan| chan.id == conf.id)
+            .ok_or(Error::SubscriptionNotFound)?;
+        if let Some(alias) = conf.aliases.iter().find(|alias| {
+            self.registry
+                .get(alias)
+                .is_some_and(|entry| entry.conf.id != conf.id)
+        }) {
+            return Err(Error::ChannelExists(alias.clone()));
+        }
+        let mut pool = pool.lock().await;
+        let dispatch_id = pool.add_connection(&conf).await?;
+        let events = self.required_events(dispatch_id, Some(&conf.id), &conf.allowed_events);
+        if let Err(err) = pool.sync_events(dispatch_id, events).await {
+            if self.list[index].dispatch_id != dispatch_id {
+                pool.remove_channel(dispatch_id, &conf.id);
+            }
+            return Err(err);
+        }
+
+        let chan_id = self.list[index].chan_id;
+        let old = std::mem::replace(
+            &mut self.list[index],
+            Channel::new(chan_id, dispatch_id, conf.clone()),
+        );
+        if old.dispatch_id != dispatch_id {
+            pool.remove_channel(old.dispatch_id, &conf.id);
+            self.unlisten_unused(&mut pool, old.dispatch_id).await;
+        }
+        self.registry.update(conf);
+        Ok(())
+    }
+
+    /// Return the events required on the connection `dispatch_id`
+    /// by its channels, except the channel `except`, and
+    /// the `extra` events
+    ///
+    /// Only exact event names are listened.
+    fn required_events(
+        &self,
+        dispatch_id: i32,
+        except: Option<&str>,
+        extra: &[EventPattern],
+    ) -> HashSet<String> {
+        self.list
+            .iter()
+            .filter(|chan| chan.dispatch_id == dispatch_id && Some(chan.id.as_str()) != except)
+            .flat_map(|chan| chan.events.iter())
+            .chain(extra)
+            .filter_map(EventPattern::name)
+            .map(String::from)
+            .collect()
+    }
+
+    /// Unlisten the events of the connection `dispatch_id`
+    /// that are not used by its channels anymore
+    async fn unlisten_unused(&self, pool: &mut Pool, dispatch_id: i32) {
+        let events = self.required_events(dispatch_id, None, &[]);
+        if let Err(err) = pool.sync_events(dispatch_id, events).await {
+            log::warn!("Failed to unlisten events on session {dispatch_id}: {err:?}");
+        }
+    }
+
+    /// Replace the set of channels
+    ///
+    /// Channels with unchanged configuration are left untouched.
+    async fn reload(&mut self, pool: &Mutex<Pool>, channels: Vec<ChannelConfig>) -> Result<()> {
+        let mut errors = 0;
+        let removed = self
+            .list
+            .iter()
+            .filter(|chan| !channels.iter().any(|conf| conf.id == chan.id))
+            .map(|chan| chan.id.clone())
+            .collect::<Vec<_>>();
+        for id in removed {
+            self.remove(pool, &id).await?;
+            log::info!("Removed channel '{id}'");
+        }
+        for conf in channels {
+            let id = conf.id.clone();
+            let result = match self.registry.get(&id) {
+                None => self.add(pool, conf).await.map(|_| "Added"),
+                Some(entry) if entry.conf != conf => {
+                    self.update(pool, conf).await.map(|_| "Updated")
+                }
+                Some(_) => continue,
+            };
+            match result {
+                Ok(action) => log::info!("{action} channel '{id}'"),
+                Err(err) => {
+                    log::error!("Failed to reload channel '{id}': {err}");
+                    errors += 1;
+                }
+            }
+        }
+        // Give another chance to the connections
+        // that reached their maximum attempts
+        pool.lock().await.retry_exhausted();
+        if errors > 0 {
+            Err(Error::Config(format!(
+                "Failed to reload {errors} channel(s)"
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Handle dispatcher command
+    ///
+    /// Return true if the channels may have changed.
+    async fn handle(&mut self, pool: &Mutex<Pool>, command: Command) -> bool {
+        match command {
+            Command::AddChannel(conf, reply) => {
+                let id = conf.id.clone();
+                let result = self.add(pool, *conf).await;
+                match &result {
+                    Ok(_) => log::info!("Added channel '{id}'"),
+                    Err(err) => log::error!("Failed to add channel '{id}': {err}"),
+                }
+                let _ = reply.send(result);
+                true
+            }
+            Command::RemoveChannel(id, reply) => {
+                let result = self.remove(pool, &id).await;
+                if result.is_ok() {
+                    log::info!("Removed channel '{id}'");
+                }
+                let _ = reply.send(result);
+                true
+            }
+            Command::Publish {
+                id,
+                event,
+                payload,
+                reply,
+            } => {
+                let result = match self.list.iter().find(|chan| chan.id == id) {
+                    Some(chan) => {
+                        pool.lock()
+                            .await
+                            .notify(chan.dispatch_id, &event, &payload)
+                            .await
+                    }
+                    None => Err(Error::SubscriptionNotFound),
+                };
+                if let Err(err) = &result {
+                    log::error!("Failed to publish '{event}' on channel '{id}': {err}");
+                }
+                let _ = reply.send(result);
+                false
+            }
+            Command::Reload(channels, tls, reply) => {
+                pool.lock().await.set_tls(tls);
+                let _ = reply.send(self.reload(pool, channels).await);
+                true
+            }
+        }
+    }
+}
+
+/// Event id generator
+///
+/// Time ordered ids are monotonic within the process.
+struct IdGenerator {
+    format: EventIdFormat,
+    ulid: ulid::Generator,
+}
+
+impl IdGenerator {
+    fn new(format: EventIdFormat) -> Self {
+        Self {
+            format,
+            ulid: ulid::Generator::new(),
+        }
+    }
+
+    fn generate(&mut self) -> String {
+        match self.format {
+            EventIdFormat::UuidV4 => Uuid::new_v4().to_string(),
+            EventIdFormat::UuidV7 => Uuid::now_v7().to_string(),
+            // Generation fails only if the random part
+            // overflows within the same millisecond
+            EventIdFormat::Ulid => self
+                .ulid
+                .generate()
+                .unwrap_or_else(|_| ulid::Ulid::new())
+                .to_string(),
+        }
+    }
+}
+
 /// Channel pool
 pub struct EventDispatch {
-    pool: Pool,
-    channels: Vec<Channel>,
+    pool: Rc<Mutex<Pool>>,
+    channels: Channels,
     rx: mpsc::Receiver<PgNotificationDispatch>,
-    reconnect_delay: u16,
+    commands_tx: mpsc::Sender<Command>,
+    commands_rx: mpsc::Receiver<Command>,
+    closed_rx: mpsc::UnboundedReceiver<i32>,
+    states_rx: mpsc::UnboundedReceiver<StateChange>,
+    id_generator: IdGenerator,
+    queue_usage: QueueUsageConfig,
+    metrics: Metrics,
+    stats: Stats,
+    history: History,
 }
 
 impl EventDispatch {
@@ -111,71 +711,559 @@ impl EventDispatch {
     ///
     /// `buffer` is the channel buffer size:
     /// see [`tokio::sync::mpsc::channel`]
-    pub async fn connect(settings: &Settings) -> Result<Self> {
+    ///
+    /// The state of the postgres connections is reported
+    /// to `status`.
+    ///
+    /// Events of channels with a `history` section are
+    /// recorded in `history`.
+    pub async fn connect(
+        settings: &Settings,
+        status: PoolStatus,
+        metrics: Metrics,
+        stats: Stats,
+        history: History,
+    ) -> Result<Self> {
         let (tx, rx) = mpsc::channel(settings.events_buffer_size);
-        let reconnect_delay = settings.reconnect_delay;
-        let mut pool = Pool::new(tx, settings.postgres_tls.make_tls_connect()?);
+        let (commands_tx, commands_rx) = mpsc::channel(16);
+        let (closed_tx, closed_rx) = mpsc::unbounded_channel();
+        let (states_tx, states_rx) = mpsc::unbounded_channel();
+        let pool = Rc::new(Mutex::new(Pool::new(
+            tx,
+            closed_tx,
+            states_tx,
+            settings.postgres_tls.make_tls_connect()?,
+            PoolOptions {
+                reconnect_delay: Duration::from_secs(settings.reconnect_delay.max(1).into()),
+                pooling: settings.pooling,
+                max_connections: settings.max_pg_connections,
+            },
+            status,
+            metrics.clone(),
+            Alerts::new(settings.alert.as_ref()),
+        )));
 
-        let mut channels = Vec::<Channel>::with_capacity(settings.channels.len());
+        let mut channels = Channels {
+            list: Vec::with_capacity(settings.channels.len()),
+            registry: ChannelRegistry::default(),
+        };
         for conf in settings.channels.iter() {
-            // Create postgres configuration
-            // TODO Make sure that a channel with the same id does not already
-            // exists.
-            let dispatch = pool.add_connection(conf).await?;
-            channels.push(Channel::new(dispatch, conf.clone()));
+            channels.add(&pool, conf.clone()).await?;
         }
+        channels.sync_history(&history);
 
         Ok(Self {
             pool,
             channels,
             rx,
-            reconnect_delay,
+            commands_tx,
+            commands_rx,
+            closed_rx,
+            states_rx,
+            id_generator: IdGenerator::new(settings.event_id),
+            queue_usage: settings.metrics.queue_usage,
+            metrics,
+            stats,
+            history,
         })
     }
 
+    /// Return the channel registry
+    pub fn registry(&self) -> ChannelRegistry {
+        self.channels.registry.clone()
+    }
+
+    /// Return a handle for sending commands
+    /// to the dispatcher
+    pub fn handle(&self) -> DispatchHandle {
+        DispatchHandle(self.commands_tx.clone())
+    }
+
     /// Pool handler in charge of reconnection
-    fn start_pool_handler(mut pool: Pool, reconnect_delay: u16) {
+    ///
+    /// Reconnection is attempted as soon as a connection
+    /// is closed, successive failed attempts are delayed
+    /// according to the channels reconnection settings.
+    fn start_pool_handler(pool: Rc<Mutex<Pool>>, mut closed_rx: mpsc::UnboundedReceiver<i32>) {
         actix_web::rt::spawn(async move {
+            while let Some(dispatch_id) = closed_rx.recv().await {
+                log::warn!("Reconnecting dispatcher {dispatch_id}");
+                loop {
+                    // Release the pool while waiting for the next
+                    // attempt, other connections must not be blocked
+                    let next_attempt = pool.lock().await.reconnect().await;
+                    match next_attempt {
+                        Some(next_attempt) => {
+                            actix_web::rt::time::sleep_until(next_attempt.into()).await
+                        }
+                        None => break,
+                    }
+                }
+            }
+        });
+    }
+
+    /// Monitor the notification queue usage
+    /// of the postgres connections
+    fn start_queue_monitor(pool: Rc<Mutex<Pool>>, conf: QueueUsageConfig) {
+        if conf.interval.is_zero() {
+            return;
+        }
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(conf.interval);
             loop {
-                actix_web::rt::time::sleep(Duration::from_secs(reconnect_delay.into())).await;
-                pool.reconnect().await;
+                interval.tick().await;
+                pool.lock()
+                    .await
+                    .check_queue_usage(conf.warn_threshold)
+                    .await;
             }
         });
     }
 
     /// Listen for event
+    ///
+    /// Workers are notified of channel changes
+    /// with an empty event.
+    ///
+    /// Subscribers are notified of the postgres connections
+    /// going down or up with status events.
     pub async fn dispatch<F>(self, mut f: F)
     where
         F: FnMut(Event),
     {
-        let channels = self.channels;
-        let mut rx = self.rx;
+        let Self {
+            pool,
+            mut channels,
+            mut rx,
+            mut commands_rx,
+            closed_rx,
+            mut states_rx,
+            mut id_generator,
+            queue_usage,
+            metrics,
+            stats,
+            history,
+            ..
+        } = self;
+
+        // Postgres channel names shared by events
+        let mut names = Interner::default();
 
-        Self::start_pool_handler(self.pool, self.reconnect_delay);
+        Self::start_pool_handler(pool.clone(), closed_rx);
+        Self::start_queue_monitor(pool.clone(), queue_usage);
 
-        use uuid::Uuid;
+        loop {
+            let dispatch = tokio::select! {
+                dispatch = rx.recv() => match dispatch {
+                    Some(dispatch) => dispatch,
+                    None => break,
+                },
+                Some(command) = commands_rx.recv() => {
+                    if channels.handle(&pool, command).await {
+                        channels.sync_history(&history);
+                        f(Event::default());
+                    }
+                    continue;
+                }
+                Some(change) = states_rx.recv() => {
+                    let ids = channels
+                        .list
+                        .iter()
+                        .filter(|chan| chan.dispatch_id == change.dispatch_id)
+                        .map(|chan| chan.chan_id)
+                        .collect::<ChanIds>();
+                    if !ids.is_empty() {
+                        f(Event::status(change.connected, ids));
+                    }
+                    continue;
+                }
+            };
 
-        while let Some(dispatch) = rx.recv().await {
             let event = dispatch.notification().channel();
             let remote_session = dispatch.notification().process_id();
 
             let dispatch_id = dispatch.dispatch_id();
+            metrics.event_received(dispatch_id);
+
+            let span = tracing::info_span!(
+                "notification",
+                event,
+                session = remote_session,
+                id = field::Empty
+            );
+
+            let payload = dispatch.notification().payload();
+
+            // Fetch the payloads of channels with a payload table,
+            // the notification payload is the key of the payload
+            let mut fetched = Vec::<(ChanId, String, ByteString)>::new();
+            for chan in channels
+                .list
+                .iter_mut()
+                .filter(|chan| chan.is_listening_for(dispatch_id, event))
+            {
+                let Some(table) = chan.payload_table.as_mut() else {
+                    continue;
+                };
+                // Channels with the same query share the payload
+                let shared = fetched
+                    .iter()
+                    .find(|(_, query, _)| query == table.query())
+                    .map(|(.., payload)| payload.clone());
+                let result = match shared {
+                    Some(payload) => Some(payload),
+                    None => table.fetch(&pool, dispatch_id, payload, &chan.id).await,
+                };
+                if let Some(result) = result {
+                    fetched.push((chan.chan_id, table.query().into(), result));
+                }
+            }
 
             // Find all candidates channels for this event
-            let ids = channels
-                .iter()
-                .enumerate()
-                .filter_map(|(i, chan)| chan.is_listening_for(dispatch_id, event).then_some(i))
-                .collect::<ChanIds>();
+            let now = Instant::now();
+            let mut candidates = 0;
+            // Channels receiving a truncated payload
+            let mut truncated = Vec::<(usize, ChanId)>::new();
+            // Channels receiving a fetched, binary or transformed payload
+            let mut replaced = Vec::<(ChanId, ByteString, bool)>::new();
+            // Invalid payloads sent to dead-letter channels
+            let mut dead_letters = Vec::<(String, ByteString, bool)>::new();
+            // Payloads forwarded by routing rules
+            let mut routed = Vec::<(String, ByteString, bool)>::new();
+            let matching = tracing::info_span!(parent: &span, "channel_match");
+            let ids = matching.in_scope(|| {
+                channels
+                    .list
+                    .iter_mut()
+                    .filter(|chan| chan.is_listening_for(dispatch_id, event))
+                    .inspect(|_| candidates += 1)
+                    .filter_map(|chan| {
+                        let fetched = match chan.payload_table {
+                            Some(_) => Some(
+                                fetched
+                                    .iter()
+                                    .find(|(id, ..)| *id == chan.chan_id)?
+                                    .2
+                                    .clone(),
+                            ),
+                            None => None,
+                        };
+                        // Binary payloads are normalized to base64
+                        let decoded = match chan.encoding {
+                            PayloadEncoding::Text => None,
+                            encoding => {
+                                let decoded =
+                                    encoding.normalize(fetched.as_deref().unwrap_or(payload));
+                                if decoded.is_none() {
+                                    log::warn!(
+                                        "Dropping event '{event}' with invalid {encoding:?} payload for channel '{}'",
+                                        chan.id
+                                    );
+                                    return None;
+                                }
+                                decoded.map(ByteString::from)
+                            }
+                        };
+                        let binary = decoded.is_some();
+                        let fetched = decoded.or(fetched);
+                        let payload = fetched.as_deref().unwrap_or(payload);
+                        if let Some(validation) = &chan.validation {
+                            if let Err(err) = validation.validate(payload) {
+                                metrics.event_invalid(&chan.id);
+                                log::warn!(
+                                    "Invalid event '{event}' for channel '{}': {err}",
+                                    chan.id
+                                );
+                                if let Some(dead_letter) = &validation.dead_letter {
+                                    let payload = fetched.clone().unwrap_or_else(|| payload.into());
+                                    dead_letters.push((dead_letter.clone(), payload, binary));
+                                }
+                                return None;
+                            }
+                        }
+                        let (fetched, binary) = match &chan.transform {
+                            Some(template) => {
+                                let json = (!binary)
+                                    .then(|| serde_json::from_str::<Value>(payload).ok())
+                                    .flatten();
+                                match template.render(&chan.id, event, payload, json.as_ref()) {
+                                    Ok(rendered) => (Some(ByteString::from(rendered)), false),
+                                    Err(err) => {
+                                        log::warn!(
+                                            "Failed to transform event '{event}' for channel '{}': {err}",
+                                            chan.id
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => (fetched.clone(), binary),
+                        };
+                        let payload = fetched.as_deref().unwrap_or(payload);
+                        if chan.is_duplicate(event, payload, now) {
+                            metrics.event_deduplicated(&chan.id);
+                            return None;
+                        }
+                        let size = chan.check_size(event, payload, binary, &metrics).ok()?;
+                        // Routes forward the payload accepted by the channel
+                        if !chan.routes.is_empty() {
+                            let json = (!binary)
+                                .then(|| serde_json::from_str::<Value>(payload).ok())
+                                .flatten();
+                            for target in chan
+                                .routes
+                                .iter()
+                                .filter_map(|route| route.target(event, json.as_ref()))
+                            {
+                                if !routed.iter().any(|(id, ..)| *id == target) {
+                                    let payload = fetched.clone().unwrap_or_else(|| payload.into());
+                                    let payload = match size {
+                                        Some(size) => truncate(&payload, size),
+                                        None => payload,
+                                    };
+                                    routed.push((target, payload, binary));
+                                }
+                            }
+                        }
+                        match (fetched, size) {
+                            (Some(fetched), size) => {
+                                let fetched = match size {
+                                    Some(size) => truncate(&fetched, size),
+                                    None => fetched,
+                                };
+                                replaced.push((chan.chan_id, fetched, binary));
+                                None
+                            }
+                            (None, Some(size)) => {
+                                truncated.push((size, chan.chan_id));
+                                None
+                            }
+                            (None, None) => Some(chan.chan_id),
+                        }
+                    })
+                    .collect::<ChanIds>()
+            });
+            for (dead_letter, payload, binary) in dead_letters {
+                match channels.list.iter().find(|chan| chan.id == dead_letter) {
+                    Some(chan) => replaced.push((chan.chan_id, payload, binary)),
+                    None => log::error!("Dead-letter channel '{dead_letter}' not found"),
+                }
+            }
+            // Routed events are subject to the limits of their target
+            for (target, payload, binary) in routed {
+                match channels.list.iter_mut().find(|chan| chan.id == target) {
+                    Some(chan) => {
+                        if let Some(payload) =
+                            chan.route_payload(event, payload, binary, now, &metrics)
+                        {
+                            replaced.push((chan.chan_id, payload, binary))
+                        }
+                    }
+                    None => log::debug!("No channel '{target}' for routed event '{event}'"),
+                }
+            }
 
-            if !ids.is_empty() {
+            if !ids.is_empty() || !truncated.is_empty() || !replaced.is_empty() {
                 // Each event will have a unique identifier
-                let id = Uuid::new_v4().to_string();
+                let id = id_generator.generate();
                 log::info!("EVENT({remote_session}) {event}: {id}");
-                f(Event::new(id, dispatch.take_notification(), ids));
+                span.record("id", &id);
+                let event = Event::new(
+                    id,
+                    names.intern(event),
+                    dispatch.take_notification(),
+                    ids,
+                    TraceContext::of(&span),
+                );
+
+                // Channels with the same size share the truncated event
+                truncated.sort_unstable();
+                let truncated = truncated
+                    .chunk_by(|a, b| a.0 == b.0)
+                    .map(|group| {
+                        event.truncated(group[0].0, group.iter().map(|(_, id)| *id).collect())
+                    })
+                    .collect::<Vec<_>>();
+                let replaced = replaced
+                    .into_iter()
+                    .map(|(chan_id, payload, binary)| {
+                        event.with_payload(payload, binary, ChanIds::One([chan_id]))
+                    })
+                    .collect::<Vec<_>>();
+
+                for event in iter::once(event)
+                    .chain(truncated)
+                    .chain(replaced)
+                    .filter(|event| !event.channels().is_empty())
+                {
+                    channels
+                        .list
+                        .iter()
+                        .filter(|chan| event.channels().contains(&chan.chan_id))
+                        .for_each(|chan| {
+                            metrics.event_broadcast(&chan.id);
+                            stats.last_event(&chan.id, event.id(), event.timestamp());
+                            if let Some(conf) = &chan.history {
+                                history.record(chan.chan_id, &event, conf);
+                            }
+                        });
+                    f(event);
+                }
+            } else if candidates > 0 {
+                log::debug!("Filtered event '{event}' for session '{remote_session}'");
             } else {
                 log::error!("Unprocessed event '{event}' for session '{remote_session}'");
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_ids() {
+        for format in [EventIdFormat::UuidV7, EventIdFormat::Ulid] {
+            let mut ids = IdGenerator::new(format);
+            let first = ids.generate();
+            let second = ids.generate();
+            assert!(first < second, "{format:?}: {first} >= {second}");
+        }
+        assert_eq!(IdGenerator::new(EventIdFormat::Ulid).generate().len(), 26);
+    }
+
+    #[test]
+    fn routed_payload_limits() {
+        let channel = |conf: &str| {
+            let conf: ChannelConfig = toml::from_str(conf).unwrap();
+            Channel::new(1, 0, conf)
+        };
+        let metrics = Metrics::new();
+        let now = Instant::now();
+        let payload = ByteString::from("0123456789");
+
+        // Oversized routed payloads are dropped
+        let mut target = channel("id = \"tenant\"\nmax_payload_size = 4");
+        assert_eq!(
+            target.route_payload("foo", payload.clone(), false, now, &metrics),
+            None
+        );
+        // or truncated
+        let mut target =
+            channel("id = \"tenant\"\nmax_payload_size = 4\npayload_size_policy = \"truncate\"");
+        assert_eq!(
+            target
+                .route_payload("foo", payload.clone(), false, now, &metrics)
+                .as_deref(),
+            Some("0123")
+        );
+        // Binary payloads are never truncated
+        assert_eq!(
+            target.route_payload("foo", payload.clone(), true, now, &metrics),
+            None
+        );
+
+        // Duplicates are dropped
+        let mut target = channel("id = \"tenant\"\ndedup_window = \"1s\"");
+        assert!(target
+            .route_payload("foo", payload.clone(), false, now, &metrics)
+            .is_some());
+        assert!(target
+            .route_payload("foo", payload, false, now, &metrics)
+            .is_none());
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let event = Event {
+            id: "1".into(),
+            payload: "héllo".into(),
+            ..Event::default()
+        };
+        // Truncated on char boundaries
+        let truncated = event.truncated(2, [1].into_iter().collect());
+        assert_eq!(truncated.payload(), "h");
+        assert_eq!(truncated.id(), "1");
+        assert_eq!(truncated.channels(), [1]);
+        assert_eq!(event.truncated(3, ChanIds::default()).payload(), "hé");
+        assert_eq!(event.truncated(10, ChanIds::default()).payload(), "héllo");
+    }
+
+    #[test]
+    fn binary_payload() {
+        let event = Event::from_parts(1, "1", "foo", 0, "MTIz", true, 0);
+        assert!(event.json().is_none());
+        assert_eq!(&*event.payload_bytes(), b"123");
+        let text = event.with_payload("123".into(), false, ChanIds::default());
+        assert_eq!(text.json(), Some(&Value::from(123)));
+        assert_eq!(&*text.payload_bytes(), b"123");
+    }
+
+    #[test]
+    fn status_event() {
+        let event = Event::status(false, [1, 2].into_iter().collect());
+        assert!(event.is_status());
+        assert_eq!(event.event(), STATUS_EVENT);
+        assert_eq!(event.channels(), [1, 2]);
+        assert!(event.id().is_empty());
+        let json = event.json().unwrap();
+        assert_eq!(json["state"], "down");
+        assert_eq!(json["ts"], event.timestamp());
+    }
+
+    #[actix_web::test]
+    async fn pool_released_while_reconnecting() {
+        use crate::alert::Alerts;
+        use crate::config::PoolingMode;
+        use crate::postgres::tls::PgTlsConfig;
+
+        let (tx, _rx) = mpsc::channel(1);
+        let (closed_tx, closed_rx) = mpsc::unbounded_channel();
+        let (state_tx, _state_rx) = mpsc::unbounded_channel();
+        let mut pool = Pool::new(
+            tx,
+            closed_tx,
+            state_tx,
+            PgTlsConfig::default().make_tls_connect().unwrap(),
+            PoolOptions {
+                reconnect_delay: Duration::from_secs(60),
+                pooling: PoolingMode::default(),
+                max_connections: None,
+            },
+            PoolStatus::default(),
+            Metrics::new(),
+            Alerts::default(),
+        );
+
+        // Nothing listens on port 1
+        let conf = |id: &str, user: &str| -> ChannelConfig {
+            toml::from_str(&format!(
+                r#"
+                id = "{id}"
+                allowed_events = ["foo"]
+                connection_string = "host=127.0.0.1 port=1 user={user} connect_timeout=1"
+                required = false
+                "#
+            ))
+            .unwrap()
+        };
+        let down = pool.add_connection(&conf("down", "a")).await.unwrap();
+        let other = pool.add_connection(&conf("other", "b")).await.unwrap();
+        assert_ne!(down, other);
+
+        let pool = Rc::new(Mutex::new(pool));
+        EventDispatch::start_pool_handler(pool.clone(), closed_rx);
+        actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+
+        // The handler waits for the next attempt without
+        // holding the pool: publishing is not blocked
+        let result = tokio::time::timeout(Duration::from_millis(300), async {
+            pool.lock().await.notify(other, "foo", "").await
+        })
+        .await
+        .expect("Pool is locked by the reconnection handler");
+        assert!(matches!(result, Err(Error::DispatcherUnavailable)));
+    }
+}
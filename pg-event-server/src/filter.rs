@@ -0,0 +1,379 @@
+//!
+//! JSON payload filters
+//!
+//! Filters are small expressions evaluated against
+//! JSON payloads, using a JSONPath-like syntax for
+//! selecting values:
+//!
+//! ```text
+//! $.status == "error"
+//! $.level >= 3 && $.tags[0] != "debug"
+//! ($.kind == "task" || $.kind == "job") && $.owner
+//! ```
+//!
+//! A path alone (i.e `$.owner`) matches if the value exists
+//! and is not `null` or `false`.
+//!
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use crate::{Error, Result};
+
+/// Maximum length of a filter expression
+const MAX_FILTER_LENGTH: usize = 1024;
+
+/// Maximum nesting of parenthesized
+/// or negated expressions
+const MAX_NESTING_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Test(Vec<Segment>),
+    Compare(Vec<Segment>, Op, Value),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+/// Compiled filter expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    /// Parse a filter expression
+    pub fn parse(s: &str) -> Result<Self> {
+        if s.len() > MAX_FILTER_LENGTH {
+            return Err(Error::InvalidRequest(format!(
+                "Invalid filter: longer than {MAX_FILTER_LENGTH} bytes"
+            )));
+        }
+        let mut parser = Parser::new(s);
+        let expr = parser.parse_or()?;
+        parser.skip_ws();
+        if parser.pos < s.len() {
+            return Err(parser.error("unexpected input"));
+        }
+        Ok(Self { expr })
+    }
+
+    /// Return true if the value match the filter
+    pub fn matches(&self, value: &Value) -> bool {
+        Self::eval(&self.expr, value)
+    }
+
+    fn eval(expr: &Expr, value: &Value) -> bool {
+        match expr {
+            Expr::Test(path) => !matches!(
+                select(value, path),
+                None | Some(Value::Null) | Some(Value::Bool(false))
+            ),
+            Expr::Compare(path, op, rhs) => select(value, path)
+                .map(|lhs| compare(lhs, *op, rhs))
+                .unwrap_or(false),
+            Expr::And(a, b) => Self::eval(a, value) && Self::eval(b, value),
+            Expr::Or(a, b) => Self::eval(a, value) || Self::eval(b, value),
+            Expr::Not(e) => !Self::eval(e, value),
+        }
+    }
+}
+
+/// Compiled path selecting a value (i.e `$.a.b`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path(Vec<Segment>);
+
+impl Path {
+    /// Parse a path
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut parser = Parser::new(s);
+        let path = parser.parse_path()?;
+        parser.skip_ws();
+        if parser.pos < s.len() {
+            return Err(parser.error("unexpected input"));
+        }
+        Ok(Self(path))
+    }
+
+    /// Return the selected value
+    pub fn select<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        select(value, &self.0)
+    }
+}
+
+fn select<'a>(value: &'a Value, path: &[Segment]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |v, segment| match segment {
+        Segment::Key(k) => v.get(k),
+        Segment::Index(i) => v.get(i),
+    })
+}
+
+fn compare(lhs: &Value, op: Op, rhs: &Value) -> bool {
+    let ordering = match (lhs, rhs) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .zip(b.as_f64())
+            .and_then(|(a, b)| a.partial_cmp(&b)),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (a, b) if a == b => Some(Ordering::Equal),
+        _ => None,
+    };
+    match op {
+        Op::Eq => ordering == Some(Ordering::Equal),
+        Op::Ne => ordering != Some(Ordering::Equal),
+        Op::Lt => ordering == Some(Ordering::Less),
+        Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        Op::Gt => ordering == Some(Ordering::Greater),
+        Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+    }
+}
+
+//
+// Parser
+//
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(s: &'a str) -> Self {
+        Self { s, pos: 0, depth: 0 }
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        Error::InvalidRequest(format!("Invalid filter at position {}: {msg}", self.pos))
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn skip_ws(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while self.eat("||") {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+        while self.eat("&&") {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        if self.eat("(") {
+            self.enter()?;
+            let expr = self.parse_or()?;
+            if !self.eat(")") {
+                return Err(self.error("expecting ')'"));
+            }
+            self.depth -= 1;
+            Ok(expr)
+        } else if self.eat("!") {
+            self.enter()?;
+            let expr = Expr::Not(Box::new(self.parse_unary()?));
+            self.depth -= 1;
+            Ok(expr)
+        } else {
+            self.parse_compare()
+        }
+    }
+
+    /// Enter a nested expression
+    fn enter(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING_DEPTH {
+            return Err(self.error("too many nested expressions"));
+        }
+        Ok(())
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr> {
+        let path = self.parse_path()?;
+        // Order matters: check two-chars operators first
+        let op = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ]
+        .into_iter()
+        .find_map(|(token, op)| self.eat(token).then_some(op));
+
+        match op {
+            Some(op) => Ok(Expr::Compare(path, op, self.parse_literal()?)),
+            None => Ok(Expr::Test(path)),
+        }
+    }
+
+    fn parse_path(&mut self) -> Result<Vec<Segment>> {
+        if !self.eat("$") {
+            return Err(self.error("expecting '$'"));
+        }
+        let mut path = vec![];
+        loop {
+            if self.rest().starts_with('.') {
+                self.pos += 1;
+                let ident = self
+                    .rest()
+                    .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                    .next()
+                    .unwrap_or_default();
+                if ident.is_empty() {
+                    return Err(self.error("expecting identifier"));
+                }
+                self.pos += ident.len();
+                path.push(Segment::Key(ident.into()));
+            } else if self.rest().starts_with('[') {
+                self.pos += 1;
+                match self.parse_literal()? {
+                    Value::String(k) => path.push(Segment::Key(k)),
+                    Value::Number(n) if n.is_u64() => {
+                        path.push(Segment::Index(n.as_u64().unwrap() as usize))
+                    }
+                    _ => return Err(self.error("expecting index or key")),
+                }
+                if !self.eat("]") {
+                    return Err(self.error("expecting ']'"));
+                }
+            } else {
+                break;
+            }
+        }
+        Ok(path)
+    }
+
+    fn parse_literal(&mut self) -> Result<Value> {
+        self.skip_ws();
+        let rest = self.rest();
+        let len = if rest.starts_with('"') {
+            // Find the closing quote, skipping escaped chars
+            let mut escaped = false;
+            rest.char_indices()
+                .skip(1)
+                .find_map(|(i, c)| match c {
+                    '"' if !escaped => Some(i + 1),
+                    '\\' => {
+                        escaped = !escaped;
+                        None
+                    }
+                    _ => {
+                        escaped = false;
+                        None
+                    }
+                })
+                .ok_or_else(|| self.error("unterminated string"))?
+        } else {
+            rest.find(|c: char| !(c.is_alphanumeric() || "+-._".contains(c)))
+                .unwrap_or(rest.len())
+        };
+        match serde_json::from_str::<Value>(&rest[..len]) {
+            Ok(value) if !value.is_array() && !value.is_object() => {
+                self.pos += len;
+                Ok(value)
+            }
+            _ => Err(self.error("expecting literal")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn filter_compare() {
+        let value = json!({"status": "error", "level": 3, "tags": ["a", "b"]});
+
+        assert!(Filter::parse(r#"$.status=="error""#)
+            .unwrap()
+            .matches(&value));
+        assert!(!Filter::parse(r#"$.status != "error""#)
+            .unwrap()
+            .matches(&value));
+        assert!(Filter::parse("$.level >= 3").unwrap().matches(&value));
+        assert!(!Filter::parse("$.level > 3").unwrap().matches(&value));
+        assert!(Filter::parse(r#"$.tags[1] == "b""#)
+            .unwrap()
+            .matches(&value));
+        assert!(Filter::parse(r#"$["status"] == "error""#)
+            .unwrap()
+            .matches(&value));
+        assert!(!Filter::parse(r#"$.missing == "error""#)
+            .unwrap()
+            .matches(&value));
+    }
+
+    #[test]
+    fn filter_logical() {
+        let value = json!({"kind": "task", "owner": "me", "done": false});
+
+        assert!(
+            Filter::parse(r#"($.kind == "task" || $.kind == "job") && $.owner"#)
+                .unwrap()
+                .matches(&value)
+        );
+        assert!(!Filter::parse("$.done").unwrap().matches(&value));
+        assert!(Filter::parse(r#"($.done==false)&&$.owner=="me""#)
+            .unwrap()
+            .matches(&value));
+        assert!(Filter::parse("!$.done").unwrap().matches(&value));
+    }
+
+    #[test]
+    fn filter_parse_errors() {
+        assert!(Filter::parse("status == 1").is_err());
+        assert!(Filter::parse("$.status ==").is_err());
+        assert!(Filter::parse("($.status == 1").is_err());
+        assert!(Filter::parse("$.status == 1 garbage").is_err());
+
+        // Nesting and length are bounded
+        let nested = format!("{}$.a{}", "(".repeat(33), ")".repeat(33));
+        assert!(Filter::parse(&nested).is_err());
+        assert!(Filter::parse(&"!".repeat(100_000)).is_err());
+        let nested = format!("{}$.a{}", "(".repeat(32), ")".repeat(32));
+        assert!(Filter::parse(&nested).is_ok());
+        let long = vec!["$.a"; 300].join(" || ");
+        assert!(Filter::parse(&long).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//!
+//! Forward authentication
+//!
+//! Subscription requests are validated by an external
+//! auth service: the request method, uri and selected headers
+//! are forwarded and any `2xx` response allows the request.
+//!
+use actix_web::{http::StatusCode, web, HttpRequest};
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::{server::proxy::TrustedProxies, Error, Result};
+
+fn default_headers() -> Vec<String> {
+    vec!["Authorization".into(), "Cookie".into()]
+}
+
+fn default_identity_header() -> String {
+    "X-Auth-User".into()
+}
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+///
+/// Forward auth configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForwardAuthConfig {
+    /// Auth service url
+    pub url: String,
+    /// Request headers forwarded to the auth service
+    #[serde(default = "default_headers")]
+    pub headers: Vec<String>,
+    /// Response header holding the subscriber identity
+    #[serde(default = "default_identity_header")]
+    pub identity_header: String,
+    /// Request timeout
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+/// Forward auth client
+pub struct ForwardAuth {
+    conf: ForwardAuthConfig,
+}
+
+impl ForwardAuth {
+    pub fn new(conf: ForwardAuthConfig) -> Self {
+        Self { conf }
+    }
+
+    /// Check the request against the auth service
+    ///
+    /// Return the subscriber identity if the auth service
+    /// returned one.
+    pub async fn check(&self, req: &HttpRequest) -> Result<Option<String>> {
+        let client = awc::Client::builder().timeout(self.conf.timeout).finish();
+        let mut request = {
+            let info = req.connection_info();
+            client
+                .get(&self.conf.url)
+                .insert_header(("X-Forwarded-Method", req.method().as_str()))
+                .insert_header(("X-Forwarded-Proto", info.scheme()))
+                .insert_header(("X-Forwarded-Host", info.host()))
+                .insert_header(("X-Forwarded-Uri", req.uri().to_string()))
+        };
+        if let Some(ip) = req
+            .app_data::<web::Data<TrustedProxies>>()
+            .and_then(|proxies| proxies.client_ip(req))
+        {
+            request = request.insert_header(("X-Forwarded-For", ip.to_string()));
+        }
+        for name in &self.conf.headers {
+            for value in req.headers().get_all(name.as_str()) {
+                request = request.append_header((name.as_str(), value.clone()));
+            }
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|err| Error::AuthUnavailable(err.to_string()))?;
+        match response.status() {
+            status if status.is_success() => Ok(response
+                .headers()
+                .get(self.conf.identity_header.as_str())
+                .and_then(|v| v.to_str().ok())
+                .filter(|v| !v.is_empty())
+                .map(String::from)),
+            StatusCode::UNAUTHORIZED => Err(Error::Unauthorized),
+            status => {
+                log::debug!("Forward auth denied request with status {status}");
+                Err(Error::Forbidden)
+            }
+        }
+    }
+}
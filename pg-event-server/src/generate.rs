@@ -0,0 +1,54 @@
+//!
+//! Generate shell completions and man pages
+//!
+//! Completions and man pages are generated from the
+//! command line definition and cover all subcommands.
+//!
+use clap::{Args, Subcommand};
+use clap_complete::Shell;
+use std::io;
+use std::path::PathBuf;
+
+use crate::Result;
+
+#[derive(Debug, Args)]
+pub struct Generate {
+    #[command(subcommand)]
+    target: Target,
+}
+
+#[derive(Debug, Subcommand)]
+enum Target {
+    /// Print the completion script of a shell
+    Completions {
+        /// Target shell
+        shell: Shell,
+    },
+    /// Print the man page
+    Man {
+        /// Write the pages of the command and of the
+        /// subcommands to this directory
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+    },
+}
+
+impl Generate {
+    /// Generate the target of the command `cmd`
+    pub fn run(&self, mut cmd: clap::Command) -> Result<()> {
+        match &self.target {
+            Target::Completions { shell } => {
+                let name = cmd.get_name().to_string();
+                clap_complete::generate(*shell, &mut cmd, name, &mut io::stdout());
+            }
+            Target::Man { out_dir: None } => {
+                clap_mangen::Man::new(cmd).render(&mut io::stdout())?;
+            }
+            Target::Man { out_dir: Some(dir) } => {
+                std::fs::create_dir_all(dir)?;
+                clap_mangen::generate_to(cmd, dir)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+//!
+//! Generate the SQL of a notification trigger
+//!
+//! The trigger sends a json envelope with the table, the operation
+//! and the payload expression evaluated on the row:
+//!
+//! ```json
+//! {"table": "public.mytable", "db": "mydb", "op": "INSERT", "data": {...}}
+//! ```
+//!
+//! Postgres rejects notifications larger than 8000 bytes: envelopes
+//! exceeding `max_payload_size` are sent without `data` and with
+//! `"truncated": true`.
+//!
+//! With `payload_table`, envelopes are stored in the table and
+//! only the key of the row is notified (see the `payload_table`
+//! channel configuration).
+//!
+use clap::{Args, ValueEnum};
+
+/// Operations firing the trigger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl Operation {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Self::Insert => "INSERT",
+            Self::Update => "UPDATE",
+            Self::Delete => "DELETE",
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct GenTrigger {
+    /// Table, optionally schema qualified
+    #[arg(long)]
+    table: String,
+    /// Postgres notification channel
+    #[arg(long)]
+    channel: String,
+    /// SQL expression of the event data
+    #[arg(long, default_value = "row_to_json(COALESCE(NEW, OLD))")]
+    payload: String,
+    /// Operations firing the trigger
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "insert,update,delete"
+    )]
+    on: Vec<Operation>,
+    /// Maximum size of the notification in bytes
+    #[arg(long, default_value_t = 7999, value_parser = clap::value_parser!(u16).range(1..8000))]
+    max_payload_size: u16,
+    /// Store the envelopes in this table and
+    /// notify the key of the row
+    #[arg(long)]
+    payload_table: Option<String>,
+}
+
+/// Quote a SQL identifier
+fn ident(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+/// Quote a name, optionally schema qualified
+fn qualified(name: &str) -> String {
+    match name.split_once('.') {
+        Some((schema, name)) => format!("{}.{}", ident(schema), ident(name)),
+        None => ident(name),
+    }
+}
+
+/// Quote a SQL string literal
+fn literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+impl GenTrigger {
+    /// Return the SQL of the trigger function and
+    /// of the trigger
+    pub fn sql(&self) -> String {
+        let (schema, table) = match self.table.split_once('.') {
+            Some((schema, table)) => (Some(schema), table),
+            None => (None, self.table.as_str()),
+        };
+        let qualify = |name: &str| match schema {
+            Some(schema) => format!("{}.{}", ident(schema), ident(name)),
+            None => ident(name),
+        };
+        let function = qualify(&format!("{table}_notify"));
+        let trigger = ident(&format!("{table}_notify"));
+        let table = qualify(table);
+        let events = self
+            .on
+            .iter()
+            .map(Operation::as_sql)
+            .collect::<Vec<_>>()
+            .join(" OR ");
+
+        let (create_table, declare, notify) = match &self.payload_table {
+            Some(payload_table) => (
+                format!(
+                    "\
+CREATE TABLE IF NOT EXISTS {payload_table} (
+    id bigserial PRIMARY KEY,
+    payload text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+",
+                    payload_table = qualified(payload_table),
+                ),
+                "\n    key bigint;",
+                format!(
+                    "\
+INSERT INTO {payload_table} (payload) VALUES (envelope) RETURNING id INTO key;
+    PERFORM pg_notify({channel}, key::text);",
+                    payload_table = qualified(payload_table),
+                    channel = literal(&self.channel),
+                ),
+            ),
+            None => (
+                String::new(),
+                "",
+                format!(
+                    "\
+IF octet_length(envelope) > {max_size} THEN
+        envelope := json_build_object(
+            'table', TG_TABLE_SCHEMA || '.' || TG_TABLE_NAME,
+            'db', current_database(),
+            'op', TG_OP,
+            'truncated', true
+        )::text;
+    END IF;
+    PERFORM pg_notify({channel}, envelope);",
+                    max_size = self.max_payload_size,
+                    channel = literal(&self.channel),
+                ),
+            ),
+        };
+
+        format!(
+            "\
+{create_table}CREATE OR REPLACE FUNCTION {function}() RETURNS trigger AS $$
+DECLARE
+    envelope text;{declare}
+BEGIN
+    envelope := json_build_object(
+        'table', TG_TABLE_SCHEMA || '.' || TG_TABLE_NAME,
+        'db', current_database(),
+        'op', TG_OP,
+        'data', {payload}
+    )::text;
+    {notify}
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS {trigger} ON {table};
+CREATE TRIGGER {trigger}
+    AFTER {events} ON {table}
+    FOR EACH ROW EXECUTE FUNCTION {function}();
+",
+            payload = self.payload,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: GenTrigger,
+    }
+
+    #[test]
+    fn trigger_sql() {
+        let cli = Cli::parse_from([
+            "test",
+            "--table",
+            "app.my\"table",
+            "--channel",
+            "chan'nel",
+            "--on",
+            "insert,update",
+        ]);
+        let sql = cli.args.sql();
+        assert!(sql.contains(r#"FUNCTION "app"."my""table_notify"() RETURNS trigger"#));
+        assert!(sql.contains(r#"AFTER INSERT OR UPDATE ON "app"."my""table""#));
+        assert!(sql.contains("pg_notify('chan''nel', envelope)"));
+        assert!(sql.contains("'data', row_to_json(COALESCE(NEW, OLD))"));
+        assert!(sql.contains("'db', current_database()"));
+        assert!(sql.contains("FOR EACH ROW"));
+        assert!(sql.contains("octet_length(envelope) > 7999"));
+        assert!(!sql.contains("CREATE TABLE"));
+
+        let cli = Cli::parse_from([
+            "test",
+            "--table",
+            "items",
+            "--channel",
+            "items",
+            "--payload-table",
+            "app.events",
+        ]);
+        let sql = cli.args.sql();
+        assert!(sql.contains(r#"CREATE TABLE IF NOT EXISTS "app"."events""#));
+        assert!(sql.contains(r#"INSERT INTO "app"."events" (payload) VALUES (envelope)"#));
+        assert!(sql.contains("pg_notify('items', key::text)"));
+        assert!(!sql.contains("truncated"));
+    }
+}
@@ -0,0 +1,250 @@
+//!
+//! Consumer groups
+//!
+//! Subscribers sharing a group name on a channel receive
+//! a disjoint subset of the events of the channel.
+//!
+//! Members are shared across all workers, each worker selects
+//! the same member for an event from the hash of the event key.
+//!
+//! The event key and the event restrictions are settings of the
+//! group, set by the first member: an event rejected by the selected
+//! member would be rejected by any other member.
+//!
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::events::{ChanId, Event};
+use crate::filter::Filter;
+use crate::{Error, Result};
+
+/// Settings shared by the members of a group
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupSettings {
+    /// Payload field used as event key
+    pub key: Option<String>,
+    /// Events requested by the members
+    pub events: HashSet<String>,
+    /// Filter on json payloads
+    pub filter: Option<Filter>,
+}
+
+struct Group {
+    settings: GroupSettings,
+    members: Vec<Uuid>,
+}
+
+type Members = HashMap<ChanId, HashMap<String, Group>>;
+
+/// Shared registry of consumer groups
+#[derive(Default, Clone)]
+pub struct ConsumerGroups(Arc<Mutex<Members>>);
+
+impl ConsumerGroups {
+    /// Add subscriber `ident` to `group` on `channels`
+    ///
+    /// Fail if the group exists with other settings on
+    /// one of the channels.
+    ///
+    /// The subscriber leaves the group when the returned
+    /// guard is dropped.
+    pub fn join(
+        &self,
+        channels: Vec<ChanId>,
+        group: &str,
+        settings: &GroupSettings,
+        ident: Uuid,
+    ) -> Result<GroupGuard> {
+        let mut members = self.0.lock().unwrap();
+        if channels.iter().any(|id| {
+            members
+                .get(id)
+                .and_then(|groups| groups.get(group))
+                .is_some_and(|g| g.settings != *settings)
+        }) {
+            return Err(Error::InvalidRequest(format!(
+                "Consumer group '{group}' has other 'group_key', 'event' or 'filter' settings"
+            )));
+        }
+        channels.iter().for_each(|id| {
+            members
+                .entry(*id)
+                .or_default()
+                .entry(group.into())
+                .or_insert_with(|| Group {
+                    settings: settings.clone(),
+                    members: vec![],
+                })
+                .members
+                .push(ident)
+        });
+        Ok(GroupGuard {
+            groups: self.clone(),
+            channels,
+            group: group.into(),
+            ident,
+        })
+    }
+
+    /// Return true if `ident` is the member of `group`
+    /// selected for `key` on channel `id`
+    fn is_selected(&self, id: ChanId, group: &str, ident: Uuid, key: &str) -> bool {
+        let members = self.0.lock().unwrap();
+        match members
+            .get(&id)
+            .and_then(|groups| groups.get(group))
+            .map(|g| &g.members)
+        {
+            Some(list) if !list.is_empty() => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                list[(hasher.finish() % list.len() as u64) as usize] == ident
+            }
+            _ => false,
+        }
+    }
+
+    fn leave(&self, channels: &[ChanId], group: &str, ident: Uuid) {
+        let mut members = self.0.lock().unwrap();
+        channels.iter().for_each(|id| {
+            if let Some(groups) = members.get_mut(id) {
+                if let Some(g) = groups.get_mut(group) {
+                    g.members.retain(|member| *member != ident);
+                    if g.members.is_empty() {
+                        groups.remove(group);
+                    }
+                }
+                if groups.is_empty() {
+                    members.remove(id);
+                }
+            }
+        });
+    }
+}
+
+/// Membership of a subscriber in a consumer group
+pub struct GroupGuard {
+    groups: ConsumerGroups,
+    channels: Vec<ChanId>,
+    group: String,
+    ident: Uuid,
+}
+
+impl Drop for GroupGuard {
+    fn drop(&mut self) {
+        self.groups.leave(&self.channels, &self.group, self.ident);
+    }
+}
+
+/// Consumer group of a subscriber
+pub struct GroupMember {
+    groups: ConsumerGroups,
+    name: String,
+    /// Payload field used as event key,
+    /// the event id is used if not set
+    key: Option<String>,
+    ident: Uuid,
+}
+
+impl GroupMember {
+    pub fn new(groups: ConsumerGroups, name: String, key: Option<String>, ident: Uuid) -> Self {
+        Self {
+            groups,
+            name,
+            key,
+            ident,
+        }
+    }
+
+    /// Return true if the event on channel `id`
+    /// must be delivered to this member
+    ///
+    /// Events without the key field are distributed
+    /// by event id.
+    pub fn is_selected(&self, id: ChanId, event: &Event) -> bool {
+        let key = self
+            .key
+            .as_deref()
+            .and_then(|key| event.json()?.get(key))
+            .map(|value| match value.as_str() {
+                Some(s) => Cow::Borrowed(s),
+                None => Cow::Owned(value.to_string()),
+            })
+            .unwrap_or(Cow::Borrowed(event.id()));
+        self.groups.is_selected(id, &self.name, self.ident, &key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumer_groups() {
+        let groups = ConsumerGroups::default();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let settings = GroupSettings::default();
+        let guard_a = groups.join(vec![1], "workers", &settings, a).unwrap();
+        let guard_b = groups.join(vec![1, 2], "workers", &settings, b).unwrap();
+
+        // Each key is delivered to exactly one member
+        for key in ["1", "2", "3", "4", "5", "6"] {
+            assert!(
+                groups.is_selected(1, "workers", a, key) ^ groups.is_selected(1, "workers", b, key)
+            );
+            assert!(groups.is_selected(2, "workers", b, key));
+            assert!(!groups.is_selected(2, "workers", a, key));
+            assert!(!groups.is_selected(1, "other", a, key));
+        }
+
+        drop(guard_b);
+        assert!(groups.is_selected(1, "workers", a, "1"));
+        assert!(!groups.is_selected(2, "workers", b, "1"));
+
+        drop(guard_a);
+        assert!(groups.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn consumer_group_settings() {
+        let groups = ConsumerGroups::default();
+        let settings = GroupSettings {
+            key: Some("user".into()),
+            ..Default::default()
+        };
+        let _guard = groups
+            .join(vec![1], "workers", &settings, Uuid::new_v4())
+            .unwrap();
+
+        // Members must share the group settings
+        let other = GroupSettings {
+            filter: Some(Filter::parse("$.urgent").unwrap()),
+            ..settings.clone()
+        };
+        assert!(groups
+            .join(vec![1], "workers", &other, Uuid::new_v4())
+            .is_err());
+        assert!(groups
+            .join(
+                vec![2, 1],
+                "workers",
+                &GroupSettings::default(),
+                Uuid::new_v4()
+            )
+            .is_err());
+        assert!(groups
+            .join(vec![1], "workers", &settings, Uuid::new_v4())
+            .is_ok());
+        // Settings are per group and channel
+        assert!(groups
+            .join(vec![2], "workers", &other, Uuid::new_v4())
+            .is_ok());
+        assert!(groups
+            .join(vec![1], "others", &other, Uuid::new_v4())
+            .is_ok());
+    }
+}
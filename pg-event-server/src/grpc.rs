@@ -0,0 +1,303 @@
+//!
+//! gRPC subscription service
+//!
+//! The `Subscribe` rpc streams the events of a channel
+//! as the SSE subscription endpoint does.
+//!
+//! Events are received from the dispatcher through
+//! the sinks subsystem.
+//!
+use serde::Deserialize;
+
+///
+/// gRPC service configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+pub struct GrpcConfig {
+    /// The socket address to listen to
+    pub listen: String,
+}
+
+#[cfg(feature = "grpc")]
+pub use service::start;
+
+#[cfg(feature = "grpc")]
+mod service {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{broadcast, mpsc};
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::{Request, Response, Status};
+
+    use super::GrpcConfig;
+    use crate::{
+        eventpattern, events::Event, filter::Filter, metrics::Metrics, registry::ChannelRegistry,
+        sinks::Sink, utils::is_ip_allowed, Error, Result,
+    };
+
+    mod proto {
+        tonic::include_proto!("pg_event_server");
+    }
+
+    use proto::events_server::{Events, EventsServer};
+
+    /// Interval for checking that the channel still exists
+    const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Forward events to the gRPC streams
+    struct GrpcSink(broadcast::Sender<(Arc<str>, Event)>);
+
+    impl Sink for GrpcSink {
+        fn send(&self, channel: &str, event: &Event) {
+            // Fail only if there is no active stream
+            let _ = self.0.send((channel.into(), event.clone()));
+        }
+    }
+
+    struct EventsService {
+        registry: ChannelRegistry,
+        metrics: Metrics,
+        events: broadcast::Sender<(Arc<str>, Event)>,
+        buffer_size: usize,
+    }
+
+    /// Return the key from the `authorization`
+    /// metadata using the `Key` scheme
+    fn authorization_key<T>(request: &Request<T>) -> Option<&str> {
+        request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Key "))
+            .map(str::trim)
+    }
+
+    #[tonic::async_trait]
+    impl Events for EventsService {
+        type SubscribeStream = ReceiverStream<Result<proto::Event, Status>>;
+
+        async fn subscribe(
+            &self,
+            request: Request<proto::SubscribeRequest>,
+        ) -> Result<Response<Self::SubscribeStream>, Status> {
+            let key = authorization_key(&request).map(String::from);
+            let remote_ip = request.remote_addr().map(|addr| addr.ip());
+            let req = request.into_inner();
+            let conf = self
+                .registry
+                .get(&req.channel)
+                .ok_or_else(|| Status::not_found("Channel not found"))?
+                .conf;
+
+            if !is_ip_allowed(&conf.allow_ips, &conf.deny_ips, remote_ip) {
+                return Err(Status::permission_denied("Access denied"));
+            }
+
+            // Bearer tokens are not supported
+            if !conf.api_keys.is_empty() || !conf.allowed_scopes.is_empty() {
+                match key {
+                    Some(key) if conf.api_keys.contains(&key) => (),
+                    Some(_) => return Err(Status::permission_denied("Access denied")),
+                    None => return Err(Status::unauthenticated("Missing credentials")),
+                }
+            }
+            // Client certificates are only available for SSE
+            if !conf.allowed_identities.is_empty() {
+                return Err(Status::permission_denied("Access denied"));
+            }
+
+            let filter = Some(req.filter.as_str())
+                .filter(|s| !s.is_empty())
+                .map(Filter::parse)
+                .transpose()
+                .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+            let event_name = {
+                let event_map = conf.event_map.clone();
+                move |event: &str| {
+                    event_map
+                        .get(event)
+                        .cloned()
+                        .unwrap_or_else(|| event.into())
+                }
+            };
+            if let Some(event) = req.events.iter().find(|e| {
+                !eventpattern::is_allowed(
+                    &conf.allowed_events,
+                    &conf.deny_events,
+                    &conf.event_map,
+                    e,
+                )
+            }) {
+                return Err(Status::invalid_argument(format!(
+                    "Event '{event}' is not allowed"
+                )));
+            }
+            let requested = req.events.into_iter().collect::<HashSet<_>>();
+
+            let channel: Arc<str> = conf.id.as_str().into();
+            let subscribers = self.metrics.subscribers(&channel);
+            let dropped_events = self.metrics.dropped_events(&channel);
+            let bytes_sent = self.metrics.bytes_sent(&channel);
+            let registry = self.registry.clone();
+            let mut events = self.events.subscribe();
+            let (tx, rx) = mpsc::channel(self.buffer_size);
+
+            log::info!("GRPC SUBSCRIBE({channel})");
+            subscribers.inc();
+            tokio::spawn(async move {
+                let mut check = tokio::time::interval(CHECK_INTERVAL);
+                loop {
+                    tokio::select! {
+                        received = events.recv() => match received {
+                            Ok((chan, event)) => {
+                                if chan != channel {
+                                    continue;
+                                }
+                                let name = event_name(event.event());
+                                if (!requested.is_empty() && !requested.contains(&name))
+                                    || filter.as_ref().is_some_and(|filter| {
+                                        !event.json().is_some_and(|value| filter.matches(value))
+                                    })
+                                {
+                                    continue;
+                                }
+                                let size = event.payload().len();
+                                let msg = proto::Event {
+                                    id: event.id().into(),
+                                    channel: channel.to_string(),
+                                    event: name,
+                                    payload: event.payload().into(),
+                                    timestamp: event.timestamp(),
+                                    session_pid: event.session_pid(),
+                                };
+                                if tx.send(Ok(msg)).await.is_err() {
+                                    break;
+                                }
+                                bytes_sent.inc_by(size as u64);
+                            }
+                            Err(broadcast::error::RecvError::Lagged(n)) => {
+                                log::warn!("GRPC DROP({channel}): {n} events");
+                                dropped_events.inc_by(n);
+                            }
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        },
+                        _ = check.tick() => {
+                            if !registry.contains(&channel) {
+                                let _ = tx.send(Err(Status::not_found("Channel removed"))).await;
+                                break;
+                            }
+                        }
+                        _ = tx.closed() => break,
+                    }
+                }
+                subscribers.dec();
+                log::info!("GRPC UNSUBSCRIBE({channel})");
+            });
+
+            Ok(Response::new(ReceiverStream::new(rx)))
+        }
+    }
+
+    /// Start the gRPC server
+    ///
+    /// Return the sink feeding the subscription streams.
+    pub fn start(
+        conf: &GrpcConfig,
+        registry: ChannelRegistry,
+        metrics: Metrics,
+        events_buffer_size: usize,
+        buffer_size: usize,
+    ) -> Result<Box<dyn Sink>> {
+        let addr = conf
+            .listen
+            .parse()
+            .map_err(|err| Error::Config(format!("Invalid grpc listen address: {err}")))?;
+        let (events, _) = broadcast::channel(events_buffer_size.max(1));
+        let service = EventsService {
+            registry,
+            metrics,
+            events: events.clone(),
+            buffer_size: buffer_size.max(1),
+        };
+        eprintln!("Starting gRPC service on: {addr}");
+        tokio::spawn(async move {
+            if let Err(err) = tonic::transport::Server::builder()
+                .add_service(EventsServer::new(service))
+                .serve(addr)
+                .await
+            {
+                log::error!("gRPC server error: {err}");
+            }
+        });
+        Ok(Box::new(GrpcSink(events)))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tokio_stream::StreamExt;
+        use tonic::Code;
+
+        #[actix_web::test]
+        async fn grpc_subscribe() {
+            let registry = ChannelRegistry::default();
+            registry.insert(
+                toml::from_str(
+                    r#"id = "foo"
+api_keys = ["secret"]"#,
+                )
+                .unwrap(),
+            );
+
+            let (events, _) = broadcast::channel(4);
+            let service = EventsService {
+                registry,
+                metrics: Metrics::new(),
+                events: events.clone(),
+                buffer_size: 4,
+            };
+
+            let request = |channel: &str, key: Option<&str>| {
+                let mut request = Request::new(proto::SubscribeRequest {
+                    channel: channel.into(),
+                    ..Default::default()
+                });
+                if let Some(key) = key {
+                    request
+                        .metadata_mut()
+                        .insert("authorization", format!("Key {key}").parse().unwrap());
+                }
+                request
+            };
+
+            let code = |rv: Result<Response<_>, Status>| rv.err().map(|status| status.code());
+            assert_eq!(
+                code(service.subscribe(request("bar", None)).await),
+                Some(Code::NotFound)
+            );
+            assert_eq!(
+                code(service.subscribe(request("foo", None)).await),
+                Some(Code::Unauthenticated)
+            );
+            assert_eq!(
+                code(service.subscribe(request("foo", Some("wrong"))).await),
+                Some(Code::PermissionDenied)
+            );
+
+            let mut stream = service
+                .subscribe(request("foo", Some("secret")))
+                .await
+                .unwrap()
+                .into_inner();
+
+            let sink = GrpcSink(events);
+            sink.send("foo", &Event::default());
+
+            let event = stream.next().await.unwrap().unwrap();
+            assert_eq!(event.channel, "foo");
+        }
+    }
+}
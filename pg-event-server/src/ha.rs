@@ -0,0 +1,162 @@
+//!
+//! Active/standby high availability
+//!
+//! Instances sharing the same configuration compete for a
+//! postgres advisory lock: only the instance holding the lock
+//! connects the channels and serves events. Standby instances
+//! retry taking the lock and start when the active instance
+//! stops or loses its database session.
+//!
+//! The active instance checks its lock session periodically
+//! and stops if the session is lost, since the lock may then be
+//! taken by a standby instance.
+//!
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_postgres::Client;
+
+use crate::postgres::tls::PgTlsConnect;
+use crate::{Error, Result};
+
+const fn default_retry_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+///
+/// High availability configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct HaConfig {
+    /// Connection string of the database holding the lock
+    pub connection_string: Option<String>,
+    /// Key of the advisory lock
+    pub lock_key: i64,
+    /// Delay between attempts to take the lock and
+    /// between checks of the lock session
+    #[serde(default = "default_retry_interval", with = "humantime_serde")]
+    pub retry_interval: Duration,
+}
+
+impl HaConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.retry_interval.is_zero() {
+            return Err(Error::Config("HA retry_interval must be positive".into()));
+        }
+        Ok(())
+    }
+}
+
+/// Advisory lock held by the active instance
+pub struct Lock {
+    lost: watch::Receiver<bool>,
+}
+
+impl Lock {
+    /// Hold the lock taken on the session of `client`
+    fn hold(client: Client, interval: Duration) -> Self {
+        let (tx, lost) = watch::channel(false);
+        actix_web::rt::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                match tokio::time::timeout(interval, client.simple_query("SELECT 1")).await {
+                    Ok(Ok(_)) => (),
+                    Ok(Err(err)) => {
+                        log::error!("HA: lock session error: {err}");
+                        break;
+                    }
+                    Err(_) => {
+                        log::error!("HA: lock session is not responding");
+                        break;
+                    }
+                }
+            }
+            let _ = tx.send(true);
+        });
+        Self { lost }
+    }
+
+    /// Return true if the lock session is lost
+    pub fn is_lost(&self) -> bool {
+        *self.lost.borrow()
+    }
+
+    /// Stop the server when the lock session is lost
+    pub fn stop_on_loss(&self, handle: actix_web::dev::ServerHandle) {
+        let mut lost = self.lost.clone();
+        actix_web::rt::spawn(async move {
+            let _ = lost.wait_for(|lost| *lost).await;
+            log::error!("HA: advisory lock lost, stopping");
+            handle.stop(false).await;
+        });
+    }
+}
+
+/// Wait until the advisory lock is taken
+pub async fn acquire(conf: &HaConfig, tls: &PgTlsConnect) -> Result<Lock> {
+    let pgconfig = pg_client_config::load_config(conf.connection_string.as_deref())?;
+    let mut client: Option<Client> = None;
+    let mut standby = false;
+    loop {
+        if client.as_ref().is_none_or(Client::is_closed) {
+            client = match pgconfig.connect(tls.clone()).await {
+                Ok((client, connection)) => {
+                    actix_web::rt::spawn(async move {
+                        if let Err(err) = connection.await {
+                            log::error!("HA: lock connection closed: {err}");
+                        }
+                    });
+                    Some(client)
+                }
+                Err(err) => {
+                    log::error!("HA: failed to connect to the lock database: {err}");
+                    None
+                }
+            };
+        }
+        if let Some(session) = client.take() {
+            match session
+                .query_one("SELECT pg_try_advisory_lock($1)", &[&conf.lock_key])
+                .await
+            {
+                Ok(row) if row.get::<_, bool>(0) => {
+                    log::info!(
+                        "HA: advisory lock {} taken, instance is active",
+                        conf.lock_key
+                    );
+                    return Ok(Lock::hold(session, conf.retry_interval));
+                }
+                Ok(_) if !standby => {
+                    log::info!(
+                        "HA: advisory lock {} is held by another instance, standing by",
+                        conf.lock_key
+                    );
+                    #[cfg(unix)]
+                    crate::server::systemd::status("Standby");
+                    standby = true;
+                }
+                Ok(_) => (),
+                Err(err) => log::error!("HA: failed to take the advisory lock: {err}"),
+            }
+            client = Some(session);
+        }
+        tokio::time::sleep(conf.retry_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ha_config() {
+        let conf: HaConfig = toml::from_str("lock_key = 4242").unwrap();
+        assert_eq!(conf.lock_key, 4242);
+        assert_eq!(conf.retry_interval, Duration::from_secs(5));
+        assert!(conf.check().is_ok());
+
+        let conf: HaConfig = toml::from_str("lock_key = 1\nretry_interval = \"0s\"").unwrap();
+        assert!(conf.check().is_err());
+    }
+}
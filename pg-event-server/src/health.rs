@@ -0,0 +1,51 @@
+//!
+//! Health check endpoints
+//!
+use actix_web::{web, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::pool::{BackendStatus, PoolStatus};
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    backends: Vec<BackendStatus>,
+}
+
+/// Liveness probe
+///
+/// Always return 200 as long as the http server is running,
+/// independently of the state of the postgres backends.
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().content_type("text/plain").body("OK")
+}
+
+/// Readiness probe
+///
+/// Return the status of the postgres connections, with
+/// a 503 status if any required backend is down.
+pub async fn ready(status: web::Data<PoolStatus>) -> impl Responder {
+    let readiness = Readiness {
+        ready: status.is_ready(),
+        backends: status.backends(),
+    };
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App};
+
+    #[actix_web::test]
+    async fn healthz_ok() {
+        let app = test::init_service(App::new().route("/healthz", web::get().to(healthz))).await;
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,417 @@
+//!
+//! Event history
+//!
+//! Channels with a `history` section retain their last events so that
+//! subscribers may replay the events following a cursor, passed with the
+//! `since` query parameter or the `Last-Event-ID` header.
+//!
+//! The history is shared across all workers. When `history_dir` is
+//! configured, retained events are also appended to one file per channel
+//! and reloaded on startup. With a history store, the events are also
+//! shared with the other instances (see [`crate::historystore`]).
+//!
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::config::HistoryConfig;
+use crate::events::{ChanId, Event};
+use crate::historystore::{HistoryStore, HistoryStoreConfig};
+use crate::postgres::tls::PgTlsConnect;
+use crate::utils::timestamp_millis;
+use crate::Result;
+
+/// Persisted event
+#[derive(Serialize, Deserialize)]
+struct StoredEvent<'a> {
+    id: &'a str,
+    event: &'a str,
+    session: i32,
+    payload: &'a str,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    binary: bool,
+    ts: u64,
+}
+
+impl<'a> From<&'a Event> for StoredEvent<'a> {
+    fn from(event: &'a Event) -> Self {
+        Self {
+            id: event.id(),
+            event: event.event(),
+            session: event.session_pid(),
+            payload: event.payload(),
+            binary: event.is_binary(),
+            ts: event.timestamp(),
+        }
+    }
+}
+
+/// Append-only file of the events of a channel
+///
+/// One event is written per line as json. The file
+/// is rewritten with the retained events when it
+/// holds twice the maximum number of events.
+struct Segment {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Number of events in the file
+    len: usize,
+}
+
+impl Segment {
+    /// Open the segment at `path` and return
+    /// the events it holds for channel `id`
+    fn open(path: PathBuf, id: ChanId) -> io::Result<(Self, Vec<Event>)> {
+        let events: Vec<_> = match File::open(&path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .map_while(|line| line.ok())
+                .filter_map(|line| {
+                    // Ignore partially written lines
+                    serde_json::from_str::<StoredEvent>(&line)
+                        .map(|e| {
+                            Event::from_parts(
+                                id, e.id, e.event, e.session, e.payload, e.binary, e.ts,
+                            )
+                        })
+                        .ok()
+                })
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((
+            Self {
+                path,
+                file: BufWriter::new(file),
+                len: events.len(),
+            },
+            events,
+        ))
+    }
+
+    fn write(file: &mut BufWriter<File>, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut *file, &StoredEvent::from(event))?;
+        file.write_all(b"\n")
+    }
+
+    fn append(&mut self, event: &Event) -> io::Result<()> {
+        Self::write(&mut self.file, event)?;
+        self.file.flush()?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Rewrite the file with `events`
+    fn compact(&mut self, events: &VecDeque<Event>) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        events
+            .iter()
+            .try_for_each(|event| Self::write(&mut file, event))?;
+        file.flush()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        self.len = events.len();
+        Ok(())
+    }
+}
+
+/// Retained events of a channel
+struct Retained {
+    /// Channel name, empty if the channel
+    /// is not synchronized
+    name: Arc<str>,
+    conf: HistoryConfig,
+    events: VecDeque<Event>,
+    segment: Option<Segment>,
+}
+
+impl Retained {
+    /// Return the minimum timestamp of retained events
+    fn min_timestamp(&self, now: u64) -> u64 {
+        self.conf
+            .max_age
+            .map_or(0, |age| now.saturating_sub(age.as_millis() as u64))
+    }
+
+    /// Iterate over the events within the retention age
+    fn events(&self, now: u64) -> impl Iterator<Item = &Event> {
+        let min_ts = self.min_timestamp(now);
+        self.events
+            .iter()
+            .skip_while(move |e| e.timestamp() < min_ts)
+    }
+
+    fn expire(&mut self, now: u64) {
+        let min_ts = self.min_timestamp(now);
+        while self.events.len() > self.conf.max_events
+            || self.events.front().is_some_and(|e| e.timestamp() < min_ts)
+        {
+            self.events.pop_front();
+        }
+    }
+
+    /// Write `event` to the segment
+    ///
+    /// Persistence is disabled for the channel on error.
+    fn persist(&mut self, event: &Event) {
+        let Some(segment) = &mut self.segment else {
+            return;
+        };
+        let result = if segment.len >= 2 * self.conf.max_events.max(1) {
+            segment.compact(&self.events)
+        } else {
+            segment.append(event)
+        };
+        if let Err(err) = result {
+            log::error!(
+                "Failed to write history file {}: {err}, history will not be persisted",
+                segment.path.display(),
+            );
+            self.segment = None;
+        }
+    }
+}
+
+/// Range of the events available for replay
+#[derive(Debug, Default, Serialize)]
+pub struct HistoryRange {
+    pub count: usize,
+    pub oldest_id: Option<String>,
+    pub newest_id: Option<String>,
+    /// Reception time of the oldest event
+    /// in milliseconds since epoch
+    pub oldest_ts: Option<u64>,
+    /// Reception time of the newest event
+    /// in milliseconds since epoch
+    pub newest_ts: Option<u64>,
+}
+
+/// Shared history of events
+#[derive(Default, Clone)]
+pub struct History {
+    channels: Arc<RwLock<HashMap<ChanId, Retained>>>,
+    /// Directory of the history files
+    dir: Option<Arc<Path>>,
+    /// History shared with other instances
+    store: Option<HistoryStore>,
+}
+
+impl History {
+    /// Create a new history
+    ///
+    /// Events are persisted in `dir` if set.
+    pub fn new(dir: Option<&Path>) -> io::Result<Self> {
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Self {
+            dir: dir.map(Arc::from),
+            ..Self::default()
+        })
+    }
+
+    /// Share the history in the store `conf`
+    pub fn with_store(
+        mut self,
+        conf: &HistoryStoreConfig,
+        instance: String,
+        tls: PgTlsConnect,
+    ) -> Result<Self> {
+        let channels = self.channels.clone();
+        self.store = Some(HistoryStore::start(conf, instance, tls, move || {
+            channels
+                .read()
+                .unwrap()
+                .values()
+                .filter(|r| !r.name.is_empty())
+                .map(|r| (r.name.clone(), r.conf))
+                .collect()
+        })?);
+        Ok(self)
+    }
+
+    /// Return the history shared with other instances
+    pub fn store(&self) -> Option<HistoryStore> {
+        self.store.clone()
+    }
+
+    /// Synchronize the history with the channels
+    /// `(id, name, conf)` retaining events
+    ///
+    /// The history of other channels is dropped and
+    /// the persisted events of new channels are loaded.
+    pub fn sync<'a>(&self, channels: impl Iterator<Item = (ChanId, &'a str, &'a HistoryConfig)>) {
+        let channels = channels.collect::<Vec<_>>();
+        let mut retained = self.channels.write().unwrap();
+        retained.retain(|id, _| channels.iter().any(|(chan_id, ..)| chan_id == id));
+        for (id, name, conf) in channels {
+            if let Some(channel) = retained.get_mut(&id) {
+                channel.conf = *conf;
+                channel.name = name.into();
+                continue;
+            }
+            let (segment, events) = match self.open(id, name) {
+                Some(Ok((segment, events))) => (Some(segment), events),
+                Some(Err(err)) => {
+                    log::error!("Failed to open history of channel '{name}': {err}");
+                    (None, vec![])
+                }
+                None => (None, vec![]),
+            };
+            let mut channel = Retained {
+                name: name.into(),
+                conf: *conf,
+                events: events.into(),
+                segment,
+            };
+            channel.expire(timestamp_millis());
+            if !channel.events.is_empty() {
+                log::info!(
+                    "Restored {} event(s) in the history of channel '{name}'",
+                    channel.events.len()
+                );
+            }
+            retained.insert(id, channel);
+        }
+    }
+
+    /// Open the history file of channel `name`
+    fn open(&self, id: ChanId, name: &str) -> Option<io::Result<(Segment, Vec<Event>)>> {
+        let dir = self.dir.as_ref()?;
+        // Channel names may contain path separators
+        let file_name: String = form_urlencoded::byte_serialize(name.as_bytes()).collect();
+        Some(Segment::open(dir.join(format!("{file_name}.jsonl")), id))
+    }
+
+    /// Record `event` in the history of channel `id`
+    pub fn record(&self, id: ChanId, event: &Event, conf: &HistoryConfig) {
+        let mut channels = self.channels.write().unwrap();
+        let retained = channels.entry(id).or_insert_with(|| Retained {
+            name: "".into(),
+            conf: *conf,
+            events: VecDeque::new(),
+            segment: None,
+        });
+        retained.conf = *conf;
+        retained.events.push_back(event.clone());
+        retained.expire(timestamp_millis());
+        retained.persist(event);
+        if let Some(store) = self.store.as_ref().filter(|_| !retained.name.is_empty()) {
+            store.record(&retained.name, event);
+        }
+    }
+
+    /// Return the events of channel `id` following the event `since`
+    ///
+    /// Return `None` if `since` is no longer in the history.
+    pub fn since(&self, id: ChanId, since: &str) -> Option<Vec<Event>> {
+        let channels = self.channels.read().unwrap();
+        let mut events = channels.get(&id)?.events(timestamp_millis());
+        events.find(|e| e.id() == since)?;
+        Some(events.cloned().collect())
+    }
+
+    /// Return the events of channel `id` following the event `since`,
+    /// looking up the shared history if `since` is not in the
+    /// local history
+    pub async fn replay(&self, id: ChanId, since: &str) -> Option<Vec<Event>> {
+        if let Some(events) = self.since(id, since) {
+            return Some(events);
+        }
+        let store = self.store.as_ref()?;
+        let (name, conf) = {
+            let channels = self.channels.read().unwrap();
+            let retained = channels.get(&id).filter(|r| !r.name.is_empty())?;
+            (retained.name.clone(), retained.conf)
+        };
+        store.since(id, &name, since, &conf).await
+    }
+
+    /// Return the range of the events available
+    /// for replay on channel `id`
+    pub fn range(&self, id: ChanId) -> HistoryRange {
+        let channels = self.channels.read().unwrap();
+        let Some(retained) = channels.get(&id) else {
+            return HistoryRange::default();
+        };
+        let events = retained.events(timestamp_millis()).collect::<Vec<_>>();
+        let (oldest, newest) = (events.first(), events.last());
+        HistoryRange {
+            count: events.len(),
+            oldest_id: oldest.map(|e| e.id().into()),
+            newest_id: newest.map(|e| e.id().into()),
+            oldest_ts: oldest.map(|e| e.timestamp()),
+            newest_ts: newest.map(|e| e.timestamp()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention() {
+        let conf = HistoryConfig {
+            max_events: 2,
+            max_age: None,
+        };
+        let history = History::default();
+        for id in ["1", "2", "3"] {
+            history.record(1, &Event::with_id(id), &conf);
+        }
+
+        let range = history.range(1);
+        assert_eq!(range.count, 2);
+        assert_eq!(range.oldest_id.as_deref(), Some("2"));
+        assert_eq!(range.newest_id.as_deref(), Some("3"));
+
+        let ids = |events: Vec<Event>| {
+            events
+                .iter()
+                .map(|e| e.id().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(history.since(1, "2").map(ids), Some(vec!["3".into()]));
+        assert_eq!(history.since(1, "3").map(ids), Some(vec![]));
+        // Expired cursor
+        assert!(history.since(1, "1").is_none());
+        assert!(history.since(2, "2").is_none());
+        assert_eq!(history.range(2).count, 0);
+
+        // Removed channel
+        history.sync(std::iter::empty());
+        assert_eq!(history.range(1).count, 0);
+    }
+
+    #[test]
+    fn persistence() {
+        let dir = std::env::temp_dir().join(format!("pg-event-history-{}", std::process::id()));
+        let conf = HistoryConfig {
+            max_events: 2,
+            max_age: None,
+        };
+
+        let history = History::new(Some(&dir)).unwrap();
+        history.sync([(1, "foo/bar", &conf)].into_iter());
+        for id in ["1", "2", "3", "4", "5"] {
+            history.record(1, &Event::with_id(id), &conf);
+        }
+
+        // Restore in a new history
+        let history = History::new(Some(&dir)).unwrap();
+        history.sync([(3, "foo/bar", &conf)].into_iter());
+        let range = history.range(3);
+        assert_eq!(range.count, 2);
+        assert_eq!(range.oldest_id.as_deref(), Some("4"));
+        assert_eq!(range.newest_id.as_deref(), Some("5"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
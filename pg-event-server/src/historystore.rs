@@ -0,0 +1,624 @@
+//!
+//! Shared event history
+//!
+//! Instances serving the same channels behind a load balancer may
+//! share their history in a Postgres table, so that a subscriber
+//! reconnecting to another instance with a `Last-Event-ID` cursor
+//! gets the events following its cursor.
+//!
+//! Event ids are generated by each instance: the events are stored
+//! with the name of the instance and a cursor unknown to the local
+//! history is replayed from the events stored by the instance that
+//! issued it.
+//!
+//! Events are written by a background task, the table is pruned
+//! periodically according to the history settings of the channels.
+//!
+//! The events waiting for the acknowledgement of a consumer are
+//! stored in a second table, so that they are redelivered after a
+//! restart or by another instance. They are removed with the events
+//! out of the history.
+//!
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, MappedMutexGuard, Mutex, MutexGuard};
+use tokio_postgres::Client;
+
+use crate::config::HistoryConfig;
+use crate::events::{ChanId, Event};
+use crate::postgres::tls::PgTlsConnect;
+use crate::utils::{is_table_name, timestamp_millis};
+use crate::{Error, Result};
+
+fn default_table() -> String {
+    "pg_event_history".into()
+}
+
+fn default_ack_table() -> String {
+    "pg_event_acks".into()
+}
+
+const fn default_create_table() -> bool {
+    true
+}
+
+const fn default_prune_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Number of events waiting to be written
+const WRITE_QUEUE_SIZE: usize = 1024;
+
+///
+/// Shared history configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoryStoreConfig {
+    /// Connection string of the database
+    /// holding the history table
+    pub connection_string: Option<String>,
+    /// History table, optionally schema qualified
+    #[serde(default = "default_table")]
+    pub table: String,
+    /// Table of the unacknowledged events,
+    /// optionally schema qualified
+    #[serde(default = "default_ack_table")]
+    pub ack_table: String,
+    /// Create the tables if they do not exist
+    #[serde(default = "default_create_table")]
+    pub create_table: bool,
+    /// Delay between removals of the
+    /// events out of the history
+    #[serde(default = "default_prune_interval", with = "humantime_serde")]
+    pub prune_interval: Duration,
+}
+
+impl HistoryStoreConfig {
+    pub fn check(&self) -> Result<()> {
+        if let Some(table) = [&self.table, &self.ack_table]
+            .into_iter()
+            .find(|table| !is_table_name(table))
+        {
+            return Err(Error::Config(format!(
+                "History store: invalid table name '{table}'"
+            )));
+        }
+        if self.prune_interval.is_zero() {
+            return Err(Error::Config(
+                "History store: prune_interval must be positive".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Event written to the history table
+struct Row {
+    channel: Arc<str>,
+    id: String,
+    event: String,
+    session: i32,
+    payload: String,
+    binary: bool,
+    ts: i64,
+}
+
+/// Write to the tables
+enum Write {
+    Event(Row),
+    /// Event delivered to a consumer
+    Delivered {
+        channel: Arc<str>,
+        consumer: String,
+        id: String,
+    },
+    /// Events acknowledged by a consumer, or
+    /// no longer redelivered
+    Acked {
+        channel: Arc<str>,
+        consumer: String,
+        ids: Vec<String>,
+    },
+    /// Events redelivered to a consumer
+    Redelivered {
+        channel: Arc<str>,
+        consumer: String,
+        ids: Vec<String>,
+    },
+}
+
+/// Return the minimum timestamp of retained events
+fn min_timestamp(conf: &HistoryConfig) -> i64 {
+    conf.max_age.map_or(0, |age| {
+        timestamp_millis().saturating_sub(age.as_millis() as u64) as i64
+    })
+}
+
+/// Connection to the history table
+struct Store {
+    conf: HistoryStoreConfig,
+    pgconfig: pg_event_listener::Config,
+    tls: PgTlsConnect,
+    instance: String,
+    client: Mutex<Option<Client>>,
+}
+
+impl Store {
+    /// Return the client, reconnecting if
+    /// the connection is closed
+    async fn client(&self) -> Result<MappedMutexGuard<'_, Client>> {
+        let mut client = self.client.lock().await;
+        if client.as_ref().is_none_or(Client::is_closed) {
+            *client = Some(self.connect().await?);
+        }
+        Ok(MutexGuard::map(client, |c| c.as_mut().unwrap()))
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let (client, connection) = self.pgconfig.connect(self.tls.clone()).await?;
+        actix_web::rt::spawn(async move {
+            if let Err(err) = connection.await {
+                log::error!("History store: connection closed: {err}");
+            }
+        });
+        if self.conf.create_table {
+            let (table, ack_table) = (&self.conf.table, &self.conf.ack_table);
+            let index = table.rsplit('.').next().unwrap_or(table);
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE IF NOT EXISTS {table} (\
+                        seq bigserial PRIMARY KEY, \
+                        instance text NOT NULL, \
+                        channel text NOT NULL, \
+                        id text NOT NULL, \
+                        event text NOT NULL, \
+                        session integer NOT NULL, \
+                        payload text NOT NULL, \
+                        is_binary boolean NOT NULL, \
+                        ts bigint NOT NULL);\
+                    CREATE INDEX IF NOT EXISTS {index}_channel_id_idx ON {table} (channel, id);\
+                    CREATE TABLE IF NOT EXISTS {ack_table} (\
+                        channel text NOT NULL, \
+                        consumer text NOT NULL, \
+                        id text NOT NULL, \
+                        retries integer NOT NULL DEFAULT 0, \
+                        PRIMARY KEY (channel, consumer, id));"
+                ))
+                .await?;
+        }
+        log::info!("History store: connected, instance '{}'", self.instance);
+        Ok(client)
+    }
+
+    async fn write(&self, writes: &[Write]) -> Result<()> {
+        let client = self.client().await?;
+        let insert = client
+            .prepare(&format!(
+                "INSERT INTO {} (instance, channel, id, event, session, payload, is_binary, ts) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                self.conf.table
+            ))
+            .await?;
+        let ack_table = &self.conf.ack_table;
+        for write in writes {
+            match write {
+                Write::Event(row) => {
+                    client
+                        .execute(
+                            &insert,
+                            &[
+                                &self.instance,
+                                &&*row.channel,
+                                &row.id,
+                                &row.event,
+                                &row.session,
+                                &row.payload,
+                                &row.binary,
+                                &row.ts,
+                            ],
+                        )
+                        .await?
+                }
+                Write::Delivered {
+                    channel,
+                    consumer,
+                    id,
+                } => {
+                    client
+                        .execute(
+                            &format!(
+                                "INSERT INTO {ack_table} (channel, consumer, id) \
+                                VALUES ($1, $2, $3) ON CONFLICT DO NOTHING"
+                            ),
+                            &[&&**channel, consumer, id],
+                        )
+                        .await?
+                }
+                Write::Acked {
+                    channel,
+                    consumer,
+                    ids,
+                } => {
+                    client
+                        .execute(
+                            &format!(
+                                "DELETE FROM {ack_table} \
+                                WHERE channel = $1 AND consumer = $2 AND id = ANY($3)"
+                            ),
+                            &[&&**channel, consumer, ids],
+                        )
+                        .await?
+                }
+                Write::Redelivered {
+                    channel,
+                    consumer,
+                    ids,
+                } => {
+                    client
+                        .execute(
+                            &format!(
+                                "UPDATE {ack_table} SET retries = retries + 1 \
+                                WHERE channel = $1 AND consumer = $2 AND id = ANY($3)"
+                            ),
+                            &[&&**channel, consumer, ids],
+                        )
+                        .await?
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Remove the events of `channel` out of the history
+    ///
+    /// Unacknowledged events no longer in
+    /// the history are removed.
+    async fn prune(&self, channel: &str, conf: &HistoryConfig) -> Result<u64> {
+        let min_ts = min_timestamp(conf);
+        let max_events = conf.max_events as i64;
+        let (table, ack_table) = (&self.conf.table, &self.conf.ack_table);
+        let client = self.client().await?;
+        let removed = client
+            .execute(
+                &format!(
+                    "DELETE FROM {table} WHERE seq IN (\
+                        SELECT seq FROM (SELECT seq, ts, row_number() OVER (\
+                            PARTITION BY instance ORDER BY seq DESC) AS n \
+                            FROM {table} WHERE channel = $1) s \
+                        WHERE n > $2 OR ts < $3)"
+                ),
+                &[&channel, &max_events, &min_ts],
+            )
+            .await?;
+        client
+            .execute(
+                &format!(
+                    "DELETE FROM {ack_table} a WHERE a.channel = $1 AND NOT EXISTS (\
+                        SELECT 1 FROM {table} h WHERE h.channel = a.channel AND h.id = a.id)"
+                ),
+                &[&channel],
+            )
+            .await?;
+        Ok(removed)
+    }
+
+    /// Return the unacknowledged events of `consumer` on `channel`
+    /// with their number of redeliveries, counting this one
+    ///
+    /// Events already redelivered `max_retries` times are removed.
+    async fn pending(
+        &self,
+        chan_id: ChanId,
+        channel: &str,
+        consumer: &str,
+        max_retries: u32,
+    ) -> Result<Vec<(Event, u32)>> {
+        let (table, ack_table) = (&self.conf.table, &self.conf.ack_table);
+        let client = self.client().await?;
+        let exhausted = client
+            .execute(
+                &format!(
+                    "DELETE FROM {ack_table} \
+                    WHERE channel = $1 AND consumer = $2 AND retries >= $3"
+                ),
+                &[&channel, &consumer, &(max_retries as i32)],
+            )
+            .await?;
+        if exhausted > 0 {
+            log::warn!(
+                "History store: giving up redelivery of {exhausted} event(s) \
+                to consumer '{consumer}' of '{channel}'"
+            );
+        }
+        client
+            .execute(
+                &format!(
+                    "UPDATE {ack_table} SET retries = retries + 1 \
+                    WHERE channel = $1 AND consumer = $2"
+                ),
+                &[&channel, &consumer],
+            )
+            .await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT h.id, h.event, h.session, h.payload, h.is_binary, h.ts, a.retries \
+                    FROM {ack_table} a JOIN {table} h ON h.channel = a.channel AND h.id = a.id \
+                    WHERE a.channel = $1 AND a.consumer = $2 ORDER BY h.seq"
+                ),
+                &[&channel, &consumer],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let event = Event::from_parts(
+                    chan_id,
+                    row.get(0),
+                    row.get(1),
+                    row.get(2),
+                    row.get(3),
+                    row.get(4),
+                    row.get::<_, i64>(5) as u64,
+                );
+                (event, row.get::<_, i32>(6) as u32)
+            })
+            .collect())
+    }
+
+    /// Return the events of `channel` following the event `since`,
+    /// stored by the instance that issued `since`
+    async fn since(
+        &self,
+        chan_id: ChanId,
+        channel: &str,
+        since: &str,
+        conf: &HistoryConfig,
+    ) -> Result<Option<Vec<Event>>> {
+        let min_ts = min_timestamp(conf);
+        let table = &self.conf.table;
+        let client = self.client().await?;
+        let Some(cursor) = client
+            .query_opt(
+                &format!(
+                    "SELECT seq, instance FROM {table} WHERE channel = $1 AND id = $2 \
+                    ORDER BY seq DESC LIMIT 1"
+                ),
+                &[&channel, &since],
+            )
+            .await?
+        else {
+            return Ok(None);
+        };
+        let (seq, instance): (i64, String) = (cursor.get(0), cursor.get(1));
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT id, event, session, payload, is_binary, ts FROM {table} \
+                    WHERE channel = $1 AND instance = $2 AND seq > $3 AND ts >= $4 \
+                    ORDER BY seq LIMIT $5"
+                ),
+                &[
+                    &channel,
+                    &instance,
+                    &seq,
+                    &min_ts,
+                    &(conf.max_events as i64),
+                ],
+            )
+            .await?;
+        log::debug!(
+            "History store: {} event(s) of '{channel}' following {since} from '{instance}'",
+            rows.len()
+        );
+        Ok(Some(
+            rows.iter()
+                .map(|row| {
+                    Event::from_parts(
+                        chan_id,
+                        row.get(0),
+                        row.get(1),
+                        row.get(2),
+                        row.get(3),
+                        row.get(4),
+                        row.get::<_, i64>(5) as u64,
+                    )
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// Shared history of the instances
+#[derive(Clone)]
+pub struct HistoryStore {
+    store: Arc<Store>,
+    tx: mpsc::Sender<Write>,
+}
+
+impl HistoryStore {
+    /// Start the writer of the history table
+    ///
+    /// The channels retaining events are returned by `channels`
+    /// when the table is pruned.
+    pub fn start<F>(
+        conf: &HistoryStoreConfig,
+        instance: String,
+        tls: PgTlsConnect,
+        channels: F,
+    ) -> Result<Self>
+    where
+        F: Fn() -> Vec<(Arc<str>, HistoryConfig)> + 'static,
+    {
+        let pgconfig = pg_client_config::load_config(conf.connection_string.as_deref())?;
+        let store = Arc::new(Store {
+            conf: conf.clone(),
+            pgconfig,
+            tls,
+            instance,
+            client: Mutex::new(None),
+        });
+        let (tx, mut rx) = mpsc::channel::<Write>(WRITE_QUEUE_SIZE);
+        let writer = store.clone();
+        actix_web::rt::spawn(async move {
+            let mut prune = tokio::time::interval(writer.conf.prune_interval);
+            let mut rows = Vec::new();
+            loop {
+                tokio::select! {
+                    n = rx.recv_many(&mut rows, WRITE_QUEUE_SIZE) => {
+                        if n == 0 {
+                            break;
+                        }
+                        if let Err(err) = writer.write(&rows).await {
+                            log::error!(
+                                "History store: failed to write {} record(s): {err:?}",
+                                rows.len()
+                            );
+                        }
+                        rows.clear();
+                    }
+                    _ = prune.tick() => {
+                        for (channel, conf) in channels() {
+                            match writer.prune(&channel, &conf).await {
+                                Ok(0) => (),
+                                Ok(n) => log::debug!(
+                                    "History store: removed {n} event(s) of '{channel}'"
+                                ),
+                                Err(err) => log::error!(
+                                    "History store: failed to prune '{channel}': {err:?}"
+                                ),
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Self { store, tx })
+    }
+
+    /// Queue `event` of `channel` for writing
+    pub fn record(&self, channel: &Arc<str>, event: &Event) {
+        let row = Row {
+            channel: channel.clone(),
+            id: event.id().into(),
+            event: event.event().into(),
+            session: event.session_pid(),
+            payload: event.payload().into(),
+            binary: event.is_binary(),
+            ts: event.timestamp() as i64,
+        };
+        if self.tx.try_send(Write::Event(row)).is_err() {
+            log::warn!(
+                "History store: write queue is full, event {} is not shared",
+                event.id()
+            );
+        }
+    }
+
+    /// Queue the delivery of event `id` of `channel` to `consumer`
+    pub fn delivered(&self, channel: &Arc<str>, consumer: &str, id: &str) {
+        let write = Write::Delivered {
+            channel: channel.clone(),
+            consumer: consumer.into(),
+            id: id.into(),
+        };
+        if self.tx.try_send(write).is_err() {
+            log::warn!(
+                "History store: write queue is full, delivery of {id} to '{consumer}' is not stored"
+            );
+        }
+    }
+
+    /// Queue the removal of the events `ids` of `channel`
+    /// waiting for the acknowledgement of `consumer`
+    pub fn acked(&self, channel: &Arc<str>, consumer: &str, ids: Vec<String>) {
+        let write = Write::Acked {
+            channel: channel.clone(),
+            consumer: consumer.into(),
+            ids,
+        };
+        if self.tx.try_send(write).is_err() {
+            log::warn!("History store: write queue is full, acks of '{consumer}' are not stored");
+        }
+    }
+
+    /// Queue the redelivery of the events `ids` of `channel` to `consumer`
+    pub fn redelivered(&self, channel: &Arc<str>, consumer: &str, ids: Vec<String>) {
+        let write = Write::Redelivered {
+            channel: channel.clone(),
+            consumer: consumer.into(),
+            ids,
+        };
+        if self.tx.try_send(write).is_err() {
+            log::warn!(
+                "History store: write queue is full, redeliveries to '{consumer}' are not stored"
+            );
+        }
+    }
+
+    /// Return the stored unacknowledged events of `consumer`
+    /// on `channel`, with their number of redeliveries
+    pub async fn pending(
+        &self,
+        chan_id: ChanId,
+        channel: &str,
+        consumer: &str,
+        max_retries: u32,
+    ) -> Vec<(Event, u32)> {
+        match self
+            .store
+            .pending(chan_id, channel, consumer, max_retries)
+            .await
+        {
+            Ok(events) => events,
+            Err(err) => {
+                log::error!(
+                    "History store: failed to read the events of '{channel}' \
+                    pending for '{consumer}': {err:?}"
+                );
+                vec![]
+            }
+        }
+    }
+
+    /// Return the events of `channel` following the event `since`
+    ///
+    /// Return `None` if `since` is not in the shared history.
+    pub async fn since(
+        &self,
+        chan_id: ChanId,
+        channel: &str,
+        since: &str,
+        conf: &HistoryConfig,
+    ) -> Option<Vec<Event>> {
+        match self.store.since(chan_id, channel, since, conf).await {
+            Ok(events) => events,
+            Err(err) => {
+                log::error!("History store: failed to read the history of '{channel}': {err:?}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_store_config() {
+        let conf: HistoryStoreConfig = toml::from_str("").unwrap();
+        assert_eq!(conf.table, "pg_event_history");
+        assert_eq!(conf.ack_table, "pg_event_acks");
+        assert!(conf.create_table);
+        assert!(conf.check().is_ok());
+
+        let conf: HistoryStoreConfig = toml::from_str("table = \"events.history\"").unwrap();
+        assert!(conf.check().is_ok());
+        let conf: HistoryStoreConfig = toml::from_str("table = \"history; DROP\"").unwrap();
+        assert!(conf.check().is_err());
+        let conf: HistoryStoreConfig = toml::from_str("ack_table = \"acks; DROP\"").unwrap();
+        assert!(conf.check().is_err());
+    }
+}
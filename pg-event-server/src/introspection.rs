@@ -0,0 +1,177 @@
+//!
+//! OAuth2 token introspection
+//!
+//! Bearer tokens are validated against an RFC 7662 introspection
+//! endpoint. The scopes of active tokens are matched against the
+//! `allowed_scopes` of the channels.
+//!
+//! Introspection results are cached for `cache_ttl` or until
+//! the token expires.
+//!
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{utils::timestamp_millis, Error, Result};
+
+const fn default_cache_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+///
+/// Token introspection configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct IntrospectionConfig {
+    /// Introspection endpoint url
+    pub endpoint: String,
+    /// Client credentials used for authenticating
+    /// to the introspection endpoint
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    /// Duration of cached introspection results
+    #[serde(default = "default_cache_ttl", with = "humantime_serde")]
+    pub cache_ttl: Duration,
+    /// Request timeout
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+/// Scopes granted to an active token
+pub type Scopes = Arc<HashSet<String>>;
+
+/// Introspection response
+#[derive(Deserialize)]
+struct TokenInfo {
+    active: bool,
+    scope: Option<String>,
+    /// Expiration time in seconds since epoch
+    exp: Option<u64>,
+}
+
+impl TokenInfo {
+    /// Return the scopes if the token is active
+    fn scopes(&self) -> Option<Scopes> {
+        self.active.then(|| {
+            Arc::new(
+                self.scope
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+            )
+        })
+    }
+
+    /// Return the duration for caching this result
+    fn ttl(&self, max: Duration) -> Duration {
+        match self.exp {
+            Some(exp) => max.min(Duration::from_millis(
+                exp.saturating_mul(1000).saturating_sub(timestamp_millis()),
+            )),
+            None => max,
+        }
+    }
+}
+
+struct CachedToken {
+    scopes: Option<Scopes>,
+    expires: Instant,
+}
+
+/// Token introspection client
+pub struct Introspector {
+    conf: IntrospectionConfig,
+    /// Results indexed by the token digest
+    cache: Mutex<HashMap<Vec<u8>, CachedToken>>,
+}
+
+impl Introspector {
+    pub fn new(conf: IntrospectionConfig) -> Self {
+        Self {
+            conf,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the scopes granted to `token` or `None`
+    /// if the token is not active
+    pub async fn introspect(&self, token: &str) -> Result<Option<Scopes>> {
+        let key = Sha256::digest(token.as_bytes()).to_vec();
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            if cached.expires > Instant::now() {
+                return Ok(cached.scopes.clone());
+            }
+        }
+
+        let info = self.request(token).await?;
+        let scopes = info.scopes();
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, cached| cached.expires > now);
+        cache.insert(
+            key,
+            CachedToken {
+                scopes: scopes.clone(),
+                expires: now + info.ttl(self.conf.cache_ttl),
+            },
+        );
+        Ok(scopes)
+    }
+
+    async fn request(&self, token: &str) -> Result<TokenInfo> {
+        let client = awc::Client::builder().timeout(self.conf.timeout).finish();
+        let mut request = client.post(&self.conf.endpoint);
+        if let Some(client_id) = &self.conf.client_id {
+            request = request.basic_auth(
+                client_id,
+                self.conf.client_secret.as_deref().unwrap_or_default(),
+            );
+        }
+        let mut response = request
+            .send_form(&[("token", token), ("token_type_hint", "access_token")])
+            .await
+            .map_err(|err| Error::AuthUnavailable(err.to_string()))?;
+        if !response.status().is_success() {
+            return Err(Error::AuthUnavailable(format!(
+                "introspection endpoint returned {}",
+                response.status()
+            )));
+        }
+        response
+            .json::<TokenInfo>()
+            .await
+            .map_err(|err| Error::AuthUnavailable(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_info() {
+        let info: TokenInfo = serde_json::from_str(
+            r#"{ "active": true, "scope": "events:foo  events:bar", "client_id": "app" }"#,
+        )
+        .unwrap();
+        let scopes = info.scopes().unwrap();
+        assert!(scopes.contains("events:foo") && scopes.contains("events:bar"));
+        assert_eq!(scopes.len(), 2);
+        assert_eq!(info.ttl(Duration::from_secs(60)), Duration::from_secs(60));
+
+        let info: TokenInfo = serde_json::from_str(r#"{ "active": false }"#).unwrap();
+        assert!(info.scopes().is_none());
+
+        // Expired token
+        let info: TokenInfo = serde_json::from_str(r#"{ "active": true, "exp": 1000 }"#).unwrap();
+        assert_eq!(info.ttl(Duration::from_secs(60)), Duration::ZERO);
+    }
+}
@@ -1,9 +1,183 @@
 //
 // Landing page at root
 //
-use actix_web::{HttpRequest, HttpResponse, Result};
+// Return the channel catalogue as JSON or
+// as HTML depending on the `Accept` header.
+//
+use actix_web::{
+    http::header::{self, Header},
+    web, HttpRequest, HttpResponse, Result,
+};
+use serde::Serialize;
+
+use crate::config::Visibility;
+use crate::registry::{ChannelRegistry, Entry};
+use crate::stats::Stats;
+
+#[derive(Serialize)]
+struct ChannelInfo {
+    id: String,
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct Catalogue<'a> {
+    title: &'a str,
+    version: &'a str,
+    /// Uptime in seconds
+    uptime: u64,
+    url: String,
+    channels: Vec<ChannelInfo>,
+}
+
+/// Title of the server
+pub struct Title(pub String);
+
+pub async fn handler(
+    req: HttpRequest,
+    title: web::Data<Title>,
+    stats: web::Data<Stats>,
+    registry: web::Data<ChannelRegistry>,
+) -> Result<HttpResponse> {
+    let catalogue = Catalogue {
+        title: &title.0,
+        version: env!("CARGO_PKG_VERSION"),
+        uptime: stats.uptime(),
+        url: req.url_for_static("landing_page")?.to_string(),
+        channels: registry
+            .channels()
+            .into_iter()
+            .filter(|entry| entry.conf.visibility == Visibility::Public)
+            .map(|Entry { conf, .. }| {
+                Ok(ChannelInfo {
+                    url: req.url_for("subscribe", [&conf.id])?.to_string(),
+                    id: conf.id,
+                    description: conf.description,
+                    tags: conf.tags,
+                })
+            })
+            .collect::<Result<_>>()?,
+    };
+
+    if prefers_html(&req) {
+        Ok(HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(to_html(&catalogue)))
+    } else {
+        Ok(HttpResponse::Ok().json(catalogue))
+    }
+}
+
+/// Return true if html is preferred over json
+fn prefers_html(req: &HttpRequest) -> bool {
+    header::Accept::parse(req)
+        .ok()
+        .and_then(|accept| {
+            accept.ranked().into_iter().find_map(|mime| {
+                match (mime.type_().as_str(), mime.subtype().as_str()) {
+                    ("text", "html") => Some(true),
+                    ("application", "json") => Some(false),
+                    _ => None,
+                }
+            })
+        })
+        .unwrap_or(false)
+}
+
+fn to_html(catalogue: &Catalogue) -> String {
+    let channels = catalogue
+        .channels
+        .iter()
+        .map(|chan| {
+            format!(
+                "<li><a href=\"{}\">{}</a>{}{}</li>\n",
+                escape(&chan.url),
+                escape(&chan.id),
+                chan.description
+                    .as_deref()
+                    .map(|d| format!(" - {}", escape(d)))
+                    .unwrap_or_default(),
+                if chan.tags.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", escape(&chan.tags.join(", ")))
+                },
+            )
+        })
+        .collect::<String>();
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{title}</title></head>\n<body>\n\
+        <h1>{title}</h1>\n<p>Version {version}, up for {uptime}s</p>\n\
+        <h2>Channels</h2>\n<ul>\n{channels}</ul>\n</body>\n</html>\n",
+        title = escape(catalogue.title),
+        version = catalogue.version,
+        uptime = catalogue.uptime,
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.chars()
+        .fold(String::with_capacity(s.len()), |mut out, c| {
+            match c {
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '&' => out.push_str("&amp;"),
+                '"' => out.push_str("&quot;"),
+                '\'' => out.push_str("&#39;"),
+                _ => out.push(c),
+            }
+            out
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn landing_page_negotiation() {
+        let registry = ChannelRegistry::default();
+        registry.insert(toml::from_str(r#"id = "foo/<bar>""#).unwrap());
+        registry.insert(toml::from_str("id = 'baz'\ntags = ['qgis']").unwrap());
+        registry.insert(toml::from_str("id = 'internal'\nvisibility = 'hidden'").unwrap());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Title("Test".into())))
+                .app_data(web::Data::new(Stats::new()))
+                .app_data(web::Data::new(registry))
+                .service(
+                    web::resource("/")
+                        .name("landing_page")
+                        .route(web::get().to(handler)),
+                )
+                .service(web::resource("/events/subscribe/{id:.*}").name("subscribe")),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let value: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(value["channels"][0]["id"], "foo/<bar>");
+        assert!(value["channels"][0]["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/events/subscribe/foo/%3Cbar%3E"));
+        assert_eq!(value["channels"][1]["tags"], serde_json::json!(["qgis"]));
+        // Hidden channels are not listed
+        assert_eq!(value["channels"].as_array().unwrap().len(), 2);
 
-pub async fn handler(req: HttpRequest) -> Result<HttpResponse> {
-    let url = req.url_for_static("landing_page")?;
-    Ok(HttpResponse::Ok().body(url.to_string()))
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("Accept", "text/html,application/json;q=0.9"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.starts_with("<!DOCTYPE html>"));
+        assert!(body.contains("foo/&lt;bar&gt;"));
+        assert!(body.contains("baz</a> [qgis]"));
+    }
 }
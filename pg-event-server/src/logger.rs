@@ -0,0 +1,153 @@
+//!
+//! Log output backends
+//!
+//! The logger is installed before the configuration is read
+//! and logs to stderr until the configured output is set.
+//!
+use crate::accesslog;
+use crate::config::LogOutput;
+use crate::errors::{Error, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+struct Logger {
+    // Filtering and stderr output
+    stderr: env_logger::Logger,
+    // Configured output backend
+    backend: OnceLock<Box<dyn Log>>,
+    // Access log file
+    access: OnceLock<Mutex<File>>,
+}
+
+impl Logger {
+    fn access_log(&self, metadata: &Metadata) -> Option<&Mutex<File>> {
+        self.access
+            .get()
+            .filter(|_| metadata.target() == accesslog::TARGET)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.access_log(metadata).is_some() || self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(file) = self.access_log(record.metadata()) {
+            let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+            if let Err(err) = writeln!(file, "{}", record.args()) {
+                eprintln!("Failed to write access log: {err}");
+            }
+            return;
+        }
+        if !self.stderr.matches(record) {
+            return;
+        }
+        match self.backend.get() {
+            Some(backend) => backend.log(record),
+            None => self.stderr.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        match self.backend.get() {
+            Some(backend) => backend.flush(),
+            None => self.stderr.flush(),
+        }
+    }
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Install the global logger
+pub fn install(stderr: env_logger::Logger) {
+    let max_level = stderr.filter();
+    let logger = LOGGER.get_or_init(|| Logger {
+        stderr,
+        backend: OnceLock::new(),
+        access: OnceLock::new(),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Redirect logs to the configured output
+pub fn set_output(output: LogOutput) -> Result<()> {
+    match output {
+        LogOutput::Stderr => Ok(()),
+        #[cfg(feature = "syslog")]
+        LogOutput::Syslog => syslog_backend().map(set_backend),
+        #[cfg(feature = "journald")]
+        LogOutput::Journald => journald_backend().map(set_backend),
+        #[cfg(not(feature = "syslog"))]
+        LogOutput::Syslog => Err(Error::Config(
+            "Syslog output requires the 'syslog' feature".into(),
+        )),
+        #[cfg(not(feature = "journald"))]
+        LogOutput::Journald => Err(Error::Config(
+            "Journald output requires the 'journald' feature".into(),
+        )),
+    }
+}
+
+/// Write the access records to `path`
+pub fn set_access_log(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| Error::Config(format!("Cannot open access log {path:?}: {err}")))?;
+    if let Some(logger) = LOGGER.get() {
+        let _ = logger.access.set(Mutex::new(file));
+        // Access records are logged at the info level
+        log::set_max_level(log::max_level().max(LevelFilter::Info));
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "syslog", feature = "journald"))]
+fn set_backend<L: Log + 'static>(backend: L) {
+    if let Some(logger) = LOGGER.get() {
+        let _ = logger.backend.set(Box::new(backend));
+    }
+}
+
+//
+// Syslog backend
+//
+// Log levels are mapped to syslog severities:
+// error => LOG_ERR, warn => LOG_WARNING, info => LOG_INFO,
+// debug and trace => LOG_DEBUG
+//
+#[cfg(feature = "syslog")]
+fn syslog_backend() -> Result<syslog::BasicLogger> {
+    use syslog::{Facility, Formatter3164};
+
+    let formatter = Formatter3164 {
+        facility: Facility::LOG_DAEMON,
+        hostname: None,
+        process: env!("CARGO_PKG_NAME").into(),
+        pid: std::process::id(),
+    };
+    syslog::unix(formatter)
+        .map(syslog::BasicLogger::new)
+        .map_err(|err| Error::Config(format!("Cannot connect to syslog: {err}")))
+}
+
+//
+// Journald backend
+//
+// Log levels are mapped to journal priorities, the
+// module path and source location are sent as
+// structured fields.
+//
+#[cfg(feature = "journald")]
+fn journald_backend() -> Result<systemd_journal_logger::JournalLog> {
+    systemd_journal_logger::JournalLog::new()
+        .map(|journal| journal.with_syslog_identifier(env!("CARGO_PKG_NAME").into()))
+        .map_err(|err| Error::Config(format!("Cannot connect to journald: {err}")))
+}
@@ -12,65 +12,193 @@
 //!
 use log::LevelFilter;
 
+mod accesslog;
+mod acks;
+mod admin;
+mod alert;
+mod cluster;
 mod config;
+mod connectcheck;
+mod connections;
+mod debezium;
+mod debugclient;
+mod dedup;
 mod errors;
+mod eventpattern;
 mod events;
+mod filter;
+mod forwardauth;
+mod generate;
+mod gentrigger;
+mod groups;
+mod grpc;
+mod ha;
+mod health;
+mod history;
+mod historystore;
+mod introspection;
 mod landingpage;
+mod logger;
+mod metrics;
+mod notify;
+mod openapi;
+mod payloadtable;
+mod peer;
 mod pool;
 mod postgres;
+mod publish;
+mod ratelimit;
+mod registry;
+mod reload;
+mod replication;
+mod requestid;
+mod routes;
 mod server;
+mod signedurl;
+mod sinks;
+mod stats;
+mod statsd;
 mod subscribe;
+mod tail;
+mod telemetry;
+mod transform;
 mod utils;
+mod validation;
 
+use server::tls::ServerCerts;
 use subscribe::Broadcaster;
 
 use errors::{Error, Result};
-use std::path::Path;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Arc;
 
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
+#[command(subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Path to configuration file
-    #[arg(long)]
-    conf: String,
+    #[arg(long, required = true)]
+    conf: Option<String>,
     /// Increase verbosity
     #[arg(short, long, action = ArgAction::Count)]
     verbose: u8,
     /// Check configuration only
     #[arg(long)]
     check: bool,
+    /// Check configuration and the connection of each channel
+    #[arg(long)]
+    check_connect: bool,
+    /// Print the effective configuration with secrets redacted
+    #[arg(long)]
+    dump_config: bool,
+    /// Print the commented default configuration
+    #[arg(long, exclusive = true)]
+    print_default_config: bool,
+    /// Write the process id to file
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+    /// File mode creation mask (octal)
+    #[arg(long, value_parser = server::daemon::parse_umask)]
+    umask: Option<u32>,
+    /// Change to directory before starting
+    #[arg(long)]
+    workdir: Option<PathBuf>,
+    /// Override the listen address
+    #[arg(long)]
+    listen: Option<String>,
+    /// Override the number of workers
+    #[arg(long)]
+    num_workers: Option<usize>,
+    /// Set the log level (overrides verbosity and environment)
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+    /// Disable TLS on the listening socket
+    #[arg(long)]
+    no_tls: bool,
+    /// Add a channel (i.e `id=foo,events=bar,conn=service=pg`)
+    #[arg(long = "channel", value_parser = config::parse_channel)]
+    channels: Vec<toml::Table>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the SQL of a notification trigger for a table
+    GenTrigger(gentrigger::GenTrigger),
+    /// Print the events of a channel as they arrive
+    Tail(tail::Tail),
+    /// Send a notification on the database of a channel
+    Publish(notify::Publish),
+    /// Generate shell completions and man pages
+    Generate(generate::Generate),
 }
 
 //
-// Define M to N communication channel with 
-// tokio::async::watch
+// Define M to N communication channel with
+// tokio::sync::broadcast
 //
 // The dispatcher will run in the main thread.
 // Each worker will run a listener that will
 // send the event on each SSE subsriber channel.
 //
-use events::{Event, EventDispatch};
-use tokio::sync::watch::{self, Receiver, Sender};
+// Workers that lag behind the dispatcher lose the
+// oldest events, losses are logged and counted.
+//
+// Events are shared between workers with `Arc`.
+//
+use events::{DispatchHandle, Event, EventDispatch};
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
 //
 // Event dispatcher
 //
-async fn start_event_dispatcher(tx: Sender<Event>, conf: config::Config) -> Result<()> {
-    let dispatcher = EventDispatch::connect(&conf.settings).await?;
+async fn start_event_dispatcher(
+    tx: Sender<Arc<Event>>,
+    conf: config::Config,
+    status: pool::PoolStatus,
+    metrics: metrics::Metrics,
+    stats: stats::Stats,
+    history: history::History,
+) -> Result<(registry::ChannelRegistry, DispatchHandle)> {
+    let dispatcher =
+        EventDispatch::connect(&conf.settings, status, metrics.clone(), stats, history).await?;
+    let registry = dispatcher.registry();
+    let handle = dispatcher.handle();
+    #[cfg_attr(not(feature = "grpc"), allow(unused_mut))]
+    let mut sinks = sinks::Sinks::new(&conf.settings.sink, registry.clone(), &metrics)?;
+    if let Some(grpc) = &conf.settings.server.grpc {
+        #[cfg(feature = "grpc")]
+        sinks.add(grpc::start(
+            grpc,
+            registry.clone(),
+            metrics.clone(),
+            conf.settings.events_buffer_size,
+            conf.settings.worker_buffer_size,
+        )?);
+        #[cfg(not(feature = "grpc"))]
+        return Err(Error::Config(format!(
+            "gRPC service on {} requires the 'grpc' feature",
+            grpc.listen
+        )));
+    }
     // Start dispatching
     actix_web::rt::spawn(async move {
         dispatcher
             .dispatch(|event| {
-                if let Err(err) = tx.send(event) {
-                    log::error!("Dispatch error: {err:?}");
+                let event = Arc::new(event);
+                sinks.dispatch(&event);
+                // Fails only if no worker is started yet
+                if tx.send(event).is_err() {
+                    log::debug!("No worker listening for events");
                 }
             })
             .await;
     });
-    Ok(())
+    Ok((registry, handle))
 }
 //
 // Worker event listener
@@ -78,11 +206,21 @@ async fn start_event_dispatcher(tx: Sender<Event>, conf: config::Config) -> Resu
 // Each worker will listen to the incoming events
 // and will publish it to subscription channels.
 //
-fn start_event_listener(bc: Rc<Broadcaster>, mut rx: Receiver<Event>) {
+fn start_event_listener(
+    bc: Rc<Broadcaster>,
+    mut rx: Receiver<Arc<Event>>,
+    metrics: metrics::Metrics,
+) {
     actix_web::rt::spawn(async move {
-        while rx.changed().await.is_ok() {
-            let ev = rx.borrow();
-            bc.broadcast(&ev).await;
+        loop {
+            match rx.recv().await {
+                Ok(ev) => bc.broadcast(&ev).await,
+                Err(RecvError::Lagged(count)) => {
+                    log::error!("Worker lagging behind the dispatcher: {count} event(s) lost");
+                    metrics.lagged_events(count);
+                }
+                Err(RecvError::Closed) => break,
+            }
         }
     });
 }
@@ -93,32 +231,148 @@ fn start_event_listener(bc: Rc<Broadcaster>, mut rx: Receiver<Event>) {
 #[actix_web::main]
 async fn main() -> Result<()> {
     use actix_web::{
-        middleware::{DefaultHeaders, Logger},
+        middleware::{Compress, Condition, DefaultHeaders, Logger},
         web, App, HttpServer,
     };
 
     let args = Cli::parse();
 
-    init_logger(args.verbose);
+    match &args.command {
+        Some(Command::GenTrigger(gen)) => {
+            print!("{}", gen.sql());
+            return Ok(());
+        }
+        Some(Command::Tail(tail)) => {
+            init_logger(args.verbose, args.log_level);
+            return tail.run().await;
+        }
+        Some(Command::Publish(publish)) => {
+            init_logger(args.verbose, args.log_level);
+            return publish.run().await;
+        }
+        Some(Command::Generate(generate)) => {
+            return generate.run(Cli::command());
+        }
+        None => (),
+    }
 
-    let conf = config::read_config(Path::new(&args.conf))?;
+    if args.print_default_config {
+        print!("{}", config::DEFAULT_CONFIG);
+        return Ok(());
+    }
 
-    if args.check {
-        return conf.check().map(|_| {
-            println!("Configuration looks ok.");
-        });
+    init_logger(args.verbose, args.log_level);
+
+    // Required when no command is given
+    let conf = args.conf.as_deref().unwrap_or_default();
+
+    // Configuration is reloaded from the working directory
+    let conf_path = if args.workdir.is_some() {
+        std::fs::canonicalize(conf)?
+    } else {
+        PathBuf::from(conf)
+    };
+
+    let overrides = config::Overrides {
+        listen: args.listen,
+        num_workers: args.num_workers,
+        no_tls: args.no_tls,
+        channels: args.channels,
+    };
+
+    if args.dump_config {
+        let mut value = config::Config::read_value(&conf_path, &overrides)?;
+        config::redact(&mut value);
+        print!(
+            "{}",
+            toml::to_string(&value).map_err(|err| Error::Config(err.to_string()))?
+        );
+        return Ok(());
+    }
+
+    let conf = config::read_config(&conf_path, &overrides)?;
+
+    if args.check || args.check_connect {
+        conf.check()?;
+        if args.check_connect {
+            connectcheck::run(&conf.settings).await?;
+        }
+        println!("Configuration looks ok.");
+        return Ok(());
     }
 
+    #[cfg(unix)]
+    if let Some(mask) = args.umask {
+        server::daemon::set_umask(mask);
+    }
+    if let Some(workdir) = &args.workdir {
+        server::daemon::set_workdir(workdir)?;
+    }
+    let _pidfile = args
+        .pidfile
+        .as_deref()
+        .map(server::daemon::PidFile::create)
+        .transpose()?;
+
+    logger::set_output(conf.settings.log_output)?;
+    if let Some(path) = &conf.settings.server.access_log {
+        logger::set_access_log(path)?;
+    }
+
+    let _telemetry = telemetry::init(conf.settings.tracing.as_ref())?;
+
     let settings = &conf.settings;
 
     let title = settings.server.title.clone();
     let bind_address = settings.server.listen.clone();
     let worker_buffer_size = settings.worker_buffer_size;
-    let channels = settings
-        .channels
-        .iter()
-        .map(|c| c.id.clone())
-        .collect::<Vec<_>>();
+    let broadcast_concurrency = settings.broadcast_concurrency;
+    let cors = settings.server.cors.clone();
+    let admin = settings
+        .server
+        .admin
+        .as_ref()
+        .map(|admin| web::Data::new(admin::Admin::new(admin, conf.root(), &settings.defaults)));
+    let compression = settings.server.compression;
+    let base_path = settings.server.base_path.clone();
+    let debug_client = settings.server.debug_client;
+    let landing_title = web::Data::new(landingpage::Title(title.clone()));
+    let api_settings = web::Data::new(openapi::ApiSettings {
+        title: title.clone(),
+        base_path: base_path.clone(),
+        admin: admin.is_some(),
+    });
+    let proxies = server::proxy::TrustedProxies::new(&settings.server.trusted_proxies);
+    let url_signer = settings
+        .server
+        .url_signing_secret
+        .clone()
+        .map(|secret| web::Data::new(signedurl::UrlSigner::new(secret)));
+    let rate_limits = web::Data::new(ratelimit::RateLimits::new(&settings.server.rate_limit));
+    let forward_auth = settings
+        .server
+        .forward_auth
+        .clone()
+        .map(|conf| web::Data::new(forwardauth::ForwardAuth::new(conf)));
+    let introspector = settings
+        .server
+        .introspection
+        .clone()
+        .map(|conf| web::Data::new(introspection::Introspector::new(conf)));
+    let consumer_groups = groups::ConsumerGroups::default();
+    let mut history = history::History::new(settings.history_dir.as_deref())?;
+    if let Some(store) = &settings.history_store {
+        history = history.with_store(
+            store,
+            settings.server.instance_name(),
+            settings.postgres_tls.make_tls_connect()?,
+        )?;
+    }
+    let acks = acks::Acks::new(history.store());
+    let connections = connections::Connections::new(
+        settings.server.max_connections,
+        settings.server.max_connections_per_ip,
+    );
     let num_workers = settings
         .server
         .num_workers
@@ -126,50 +380,322 @@ async fn main() -> Result<()> {
 
     eprintln!("Starting pg event server on: {}", bind_address);
 
-    let tls_config = settings.server.make_tls_config()?;
+    let certs = settings.server.make_server_certs()?;
+    let tls_settings = settings.server.clone();
+    #[cfg(unix)]
+    let socket_permissions = server::unix::SocketPermissions::new(&settings.server)?;
+    #[cfg(unix)]
+    let credentials = server::unix::Credentials::new(&settings.server)?;
+    let conf_path = conf.path.clone();
+    let statsd = settings.metrics.statsd.clone();
+
+    let (tx, _) = broadcast::channel(settings.events_buffer_size.max(1));
+    let events_tx = tx.clone();
+
+    let pool_status = pool::PoolStatus::default();
+    let metrics = metrics::Metrics::new();
+    let stats = stats::Stats::new();
+
+    if let Some(statsd) = &statsd {
+        statsd::start(statsd, metrics.clone()).await?;
+    }
+
+    let cluster = match &conf.settings.cluster {
+        Some(cluster) => Some(cluster::Cluster::new(
+            cluster,
+            conf.settings.server.instance_name(),
+            conf.settings.postgres_tls.make_tls_connect()?,
+        )?),
+        None => None,
+    };
+
+    // Standby instances wait here until they
+    // take the advisory lock
+    let ha_lock = match &conf.settings.ha {
+        Some(ha) => {
+            let tls = conf.settings.postgres_tls.make_tls_connect()?;
+            Some(ha::acquire(ha, &tls).await?)
+        }
+        None => None,
+    };
+
+    let (registry, dispatch) = start_event_dispatcher(
+        tx,
+        conf,
+        pool_status.clone(),
+        metrics.clone(),
+        stats.clone(),
+        history.clone(),
+    )
+    .await?;
+
+    if let Some(cluster) = &cluster {
+        cluster.start(
+            registry.clone(),
+            metrics.clone(),
+            pool_status.clone(),
+            stats.clone(),
+        );
+    }
+
+    let reloader = web::Data::new(reload::Reloader::new(
+        conf_path,
+        overrides,
+        dispatch.clone(),
+        certs.clone(),
+    ));
+    #[cfg(unix)]
+    reload::reload_on_sighup(reloader.clone())?;
 
-    let (tx, rx) = watch::channel(Event::default());
+    let cluster_data = cluster.clone().map(web::Data::new);
 
-    start_event_dispatcher(tx, conf).await?;
+    use actix_web_lab::middleware::from_fn;
 
     let server = HttpServer::new(move || {
-        let broadcaster = Rc::new(Broadcaster::new(worker_buffer_size, channels.clone()));
+        let broadcaster = Rc::new(Broadcaster::new(
+            worker_buffer_size,
+            broadcast_concurrency,
+            registry.clone(),
+            connections.clone(),
+            metrics.clone(),
+            pool_status.clone(),
+            consumer_groups.clone(),
+        ));
 
-        start_event_listener(broadcaster.clone(), rx.clone());
+        start_event_listener(
+            broadcaster.clone(),
+            events_tx.subscribe(),
+            metrics.clone(),
+        );
 
         App::new()
-            .wrap(Logger::default())
-            .wrap(DefaultHeaders::new().add(("Server", title.as_str())))
-            .service(
-                web::resource("/")
-                    .name("landing_page")
-                    .route(web::get().to(landingpage::handler)),
+            .wrap(Condition::new(compression, Compress::default()))
+            .wrap(from_fn(requestid::middleware))
+            .wrap(
+                Logger::new(
+                    r#"%{client_ip}xi "%{request}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#,
+                )
+                    .custom_request_replace("request", accesslog::request_line)
+                    .custom_request_replace("client_ip", {
+                        let proxies = proxies.clone();
+                        move |req| {
+                            proxies
+                                .client_ip(req.request())
+                                .map(|ip| ip.to_string())
+                                .unwrap_or_else(|| "-".into())
+                        }
+                    }),
             )
+            .wrap(DefaultHeaders::new().add(("Server", title.as_str())))
+            .app_data(web::Data::new(proxies.clone()))
+            .app_data(web::Data::new(pool_status.clone()))
+            .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::Data::new(stats.clone()))
+            .app_data(web::Data::new(registry.clone()))
+            .app_data(web::Data::new(connections.clone()))
+            .app_data(landing_title.clone())
+            .app_data(api_settings.clone())
             .service(
-                web::scope("/events")
-                    .app_data(web::Data::new(broadcaster))
-                    .route(
-                        "/subscribe/{id:.*}",
-                        web::get().to(Broadcaster::do_subscribe),
+                web::scope(&base_path)
+                    .service(
+                        web::resource(["/", ""])
+                            .name("landing_page")
+                            .route(web::get().to(landingpage::handler)),
+                    )
+                    .route("/healthz", web::get().to(health::healthz))
+                    .route("/ready", web::get().to(health::ready))
+                    .route("/metrics", web::get().to(metrics::handler))
+                    .route("/stats", web::get().to(stats::handler))
+                    .route("/api.json", web::get().to(openapi::handler))
+                    .configure(|cfg| {
+                        if let Some(cluster) = cluster_data.as_ref() {
+                            cfg.service(
+                                web::scope("/cluster")
+                                    .app_data(cluster.clone())
+                                    .route("/members", web::get().to(cluster::members))
+                                    .route("/channels", web::get().to(cluster::channels)),
+                            );
+                        }
+                    })
+                    .configure(|cfg| {
+                        if debug_client {
+                            cfg.route("/debug/client", web::get().to(debugclient::handler));
+                        }
+                    })
+                    .configure(|cfg| {
+                        if let Some(admin) = admin.as_ref() {
+                            cfg.service(
+                                web::scope("/admin")
+                                    .app_data(admin.clone())
+                                    .app_data(web::Data::new(dispatch.clone()))
+                                    .app_data(rate_limits.clone())
+                                    .app_data(web::Data::new(connections.clone()))
+                                    .app_data(web::Data::new(acks.clone()))
+                                    .app_data(reloader.clone())
+                                    .wrap(from_fn(admin::authenticate))
+                                    .route("/channels", web::get().to(admin::list_channels))
+                                    .route("/pool", web::get().to(admin::pool_stats))
+                                    .route("/channels", web::post().to(admin::add_channel))
+                                    .route(
+                                        "/channels/{id:.*}",
+                                        web::delete().to(admin::remove_channel),
+                                    )
+                                    .route("/reload", web::post().to(reload::handler)),
+                            );
+                        }
+                    })
+                    .service(
+                        web::scope("/events")
+                            .app_data(web::Data::new(broadcaster))
+                            .configure(|cfg| {
+                                if let Some(signer) = url_signer.as_ref() {
+                                    cfg.app_data(signer.clone());
+                                }
+                                if let Some(introspector) = introspector.as_ref() {
+                                    cfg.app_data(introspector.clone());
+                                }
+                                if let Some(forward_auth) = forward_auth.as_ref() {
+                                    cfg.app_data(forward_auth.clone());
+                                }
+                            })
+                            .app_data(web::Data::new(dispatch.clone()))
+                            .app_data(web::Data::new(acks.clone()))
+                            .app_data(web::Data::new(history.clone()))
+                            .app_data(rate_limits.clone())
+                            .service(
+                                web::resource("/ack")
+                                    .wrap(Condition::new(
+                                        cors.is_some(),
+                                        server::cors::make_post_cors(cors.as_ref()),
+                                    ))
+                                    .route(web::post().to(Broadcaster::do_ack)),
+                            )
+                            .service(
+                                web::resource("/history/{id:.*}")
+                                    .wrap(Condition::new(
+                                        cors.is_some(),
+                                        server::cors::make_cors(cors.as_ref()),
+                                    ))
+                                    .route(web::get().to(Broadcaster::do_history)),
+                            )
+                            .service(
+                                web::resource("/subscribe/{id:.*}")
+                                    .name("subscribe")
+                                    .wrap(Condition::new(
+                                        cors.is_some(),
+                                        server::cors::make_cors(cors.as_ref()),
+                                    ))
+                                    .route(web::get().to(Broadcaster::do_subscribe)),
+                            )
+                            .service(
+                                web::resource("/publish/{id:.*}")
+                                    .wrap(Condition::new(
+                                        cors.is_some(),
+                                        server::cors::make_post_cors(cors.as_ref()),
+                                    ))
+                                    .route(web::post().to(publish::handler)),
+                            ),
                     ),
             )
-    });
+    })
+    .on_connect(server::tls::on_connect);
 
-    if let Some(tls_config) = tls_config {
-        server.bind_rustls(&bind_address, tls_config)?
-    } else {
-        server.bind(&bind_address)?
+    // Start before dropping privileges: the http-01
+    // challenges may be served on a privileged port
+    if let (Some(ServerCerts::Rustls(resolver)), Some(acme)) = (&certs, &tls_settings.acme) {
+        server::acme::start(acme, &tls_settings, resolver.clone())?;
     }
-    .workers(num_workers)
-    .run()
-    .await
-    .map_err(Error::from)
+
+    let server = server
+        .client_request_timeout(tls_settings.client_request_timeout)
+        .keep_alive(tls_settings.keep_alive)
+        .max_connection_rate(tls_settings.max_connection_rate)
+        .shutdown_timeout(tls_settings.shutdown_timeout.as_secs());
+
+    let server = 'bind: {
+        #[cfg(unix)]
+        {
+            use server::systemd::{listen_fds, Listener};
+            let listeners = listen_fds()?;
+            if !listeners.is_empty() {
+                log::info!("Using {} socket(s) passed by systemd", listeners.len());
+                let mut server = server;
+                for listener in listeners {
+                    server = match (listener, &certs) {
+                        (Listener::Tcp(lst), Some(ServerCerts::Rustls(resolver))) => server
+                            .listen_rustls(
+                                lst,
+                                server::tls::make_tls_config(&tls_settings, resolver.clone())?,
+                            )?,
+                        #[cfg(feature = "server-openssl")]
+                        (Listener::Tcp(lst), Some(ServerCerts::Openssl(contexts))) => server
+                            .listen_openssl(
+                                lst,
+                                server::openssl::make_acceptor(&tls_settings, contexts.clone())?,
+                            )?,
+                        (Listener::Tcp(lst), None) => server.listen(lst)?,
+                        (Listener::Unix(lst), _) => server.listen_uds(lst)?,
+                    };
+                }
+                break 'bind server;
+            }
+        }
+        #[cfg(unix)]
+        if let Some(path) = server::unix::socket_path(&bind_address) {
+            let server = server.bind_uds(path)?;
+            socket_permissions.apply(path)?;
+            break 'bind server;
+        }
+        match &certs {
+            Some(ServerCerts::Rustls(resolver)) => server.bind_rustls(
+                &bind_address,
+                server::tls::make_tls_config(&tls_settings, resolver.clone())?,
+            )?,
+            #[cfg(feature = "server-openssl")]
+            Some(ServerCerts::Openssl(contexts)) => server.bind_openssl(
+                &bind_address,
+                server::openssl::make_acceptor(&tls_settings, contexts.clone())?,
+            )?,
+            None => server.bind(&bind_address)?,
+        }
+    };
+
+    // Privileges are dropped before starting the workers
+    #[cfg(unix)]
+    if let Some(credentials) = &credentials {
+        credentials.apply()?;
+    }
+
+    let server = server.workers(num_workers).run();
+
+    if let Some(lock) = &ha_lock {
+        lock.stop_on_loss(server.handle());
+    }
+
+    // Postgres connections are established and
+    // the listening socket is bound
+    #[cfg(unix)]
+    server::systemd::ready();
+
+    let rv = server.await.map_err(Error::from);
+
+    #[cfg(unix)]
+    server::systemd::stopping();
+
+    if let Some(cluster) = &cluster {
+        cluster.leave().await;
+    }
+
+    if ha_lock.as_ref().is_some_and(ha::Lock::is_lost) {
+        return Err(Error::LockLost);
+    }
+    rv
 }
 
 //
 // Logger
 //
-fn init_logger(verbose: u8) {
+fn init_logger(verbose: u8, log_level: Option<LevelFilter>) {
     use env_logger::Env;
     use std::io::Write;
 
@@ -202,12 +728,14 @@ fn init_logger(verbose: u8) {
         });
     }
 
-    match verbose {
-        1 => builder.filter_level(LevelFilter::Debug),
-        _ if verbose > 1 => builder.filter_level(LevelFilter::Trace),
+    match (log_level, verbose) {
+        (Some(level), _) => builder.filter_level(level),
+        (None, 1) => builder.filter_level(LevelFilter::Debug),
+        (None, verbose) if verbose > 1 => builder.filter_level(LevelFilter::Trace),
         _ => &mut builder,
-    }
-    .init();
+    };
+
+    logger::install(builder.build());
 }
 
 #[cfg(test)]
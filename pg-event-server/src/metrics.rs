@@ -0,0 +1,446 @@
+//!
+//! Prometheus metrics
+//!
+//! Metrics are shared between the event dispatcher
+//! and the workers broadcasters.
+//!
+use actix_web::{web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::statsd::StatsdConfig;
+
+///
+/// Metrics configuration
+///
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct MetricsConfig {
+    /// Push metrics to a StatsD server
+    pub statsd: Option<StatsdConfig>,
+    /// Monitoring of the postgres notification queue
+    #[serde(default)]
+    pub queue_usage: QueueUsageConfig,
+}
+
+const fn default_queue_usage_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_queue_usage_threshold() -> f64 {
+    0.5
+}
+
+///
+/// Notification queue monitoring configuration
+///
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct QueueUsageConfig {
+    /// Interval between queries of the queue usage,
+    /// monitoring is disabled if zero
+    #[serde(default = "default_queue_usage_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    /// Usage above which a warning is logged
+    #[serde(default = "default_queue_usage_threshold")]
+    pub warn_threshold: f64,
+}
+
+impl Default for QueueUsageConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_queue_usage_interval(),
+            warn_threshold: default_queue_usage_threshold(),
+        }
+    }
+}
+use prometheus::{
+    proto::MetricFamily, Encoder, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Shared metrics registry
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    events_received: IntCounterVec,
+    events_broadcast: IntCounterVec,
+    subscribers: IntGaugeVec,
+    dropped_events: IntCounterVec,
+    deduplicated_events: IntCounterVec,
+    oversized_events: IntCounterVec,
+    invalid_events: IntCounterVec,
+    reconnections: IntCounterVec,
+    channel_reconnections: IntCounterVec,
+    reconnections_exhausted: IntCounterVec,
+    bytes_sent: IntCounterVec,
+    queue_usage: GaugeVec,
+    pg_connections: IntGauge,
+    lagged_events: IntCounter,
+    dispatch_latency: Histogram,
+    sink_deliveries: IntCounterVec,
+}
+
+/// Per channel totals
+#[derive(Debug, Serialize)]
+pub struct ChannelTotals {
+    pub events_broadcast: u64,
+    pub bytes_sent: u64,
+    pub dropped_events: u64,
+    pub reconnections: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    /// Create and register metrics
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("pg_event_server".into()), None).unwrap();
+
+        let events_received = IntCounterVec::new(
+            Opts::new(
+                "events_received_total",
+                "Number of events received per postgres connection",
+            ),
+            &["session"],
+        )
+        .unwrap();
+        let events_broadcast = IntCounterVec::new(
+            Opts::new(
+                "events_broadcast_total",
+                "Number of events broadcast per channel",
+            ),
+            &["channel"],
+        )
+        .unwrap();
+        let subscribers = IntGaugeVec::new(
+            Opts::new("subscribers", "Number of active subscribers per channel"),
+            &["channel"],
+        )
+        .unwrap();
+        let dropped_events = IntCounterVec::new(
+            Opts::new(
+                "dropped_events_total",
+                "Number of events dropped for slow consumers per channel",
+            ),
+            &["channel"],
+        )
+        .unwrap();
+        let deduplicated_events = IntCounterVec::new(
+            Opts::new(
+                "deduplicated_events_total",
+                "Number of duplicate events dropped per channel",
+            ),
+            &["channel"],
+        )
+        .unwrap();
+        let oversized_events = IntCounterVec::new(
+            Opts::new(
+                "oversized_events_total",
+                "Number of events exceeding the maximum payload size per channel",
+            ),
+            &["channel"],
+        )
+        .unwrap();
+        let invalid_events = IntCounterVec::new(
+            Opts::new(
+                "invalid_events_total",
+                "Number of events failing the schema validation per channel",
+            ),
+            &["channel"],
+        )
+        .unwrap();
+        let reconnections = IntCounterVec::new(
+            Opts::new(
+                "reconnections_total",
+                "Number of reconnection attempts to postgres",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let channel_reconnections = IntCounterVec::new(
+            Opts::new(
+                "channel_reconnections_total",
+                "Number of reconnection attempts to postgres per channel",
+            ),
+            &["channel", "result"],
+        )
+        .unwrap();
+        let reconnections_exhausted = IntCounterVec::new(
+            Opts::new(
+                "reconnections_exhausted_total",
+                "Number of times the maximum reconnection attempts was reached per channel",
+            ),
+            &["channel"],
+        )
+        .unwrap();
+        let queue_usage = GaugeVec::new(
+            Opts::new(
+                "notification_queue_usage",
+                "Fraction of the postgres notification queue in use per Postgres connection",
+            ),
+            &["session"],
+        )
+        .unwrap();
+        let pg_connections =
+            IntGauge::new("pg_connections", "Number of pooled postgres connections").unwrap();
+        let lagged_events = IntCounter::new(
+            "lagged_events_total",
+            "Number of events lost by workers lagging behind the dispatcher",
+        )
+        .unwrap();
+        let bytes_sent = IntCounterVec::new(
+            Opts::new(
+                "bytes_sent_total",
+                "Number of event data bytes sent to subscribers per channel",
+            ),
+            &["channel"],
+        )
+        .unwrap();
+        let dispatch_latency = Histogram::with_opts(HistogramOpts::new(
+            "dispatch_latency_seconds",
+            "Time between the reception of an event and its broadcast by a worker",
+        ))
+        .unwrap();
+        let sink_deliveries = IntCounterVec::new(
+            Opts::new(
+                "sink_deliveries_total",
+                "Number of events delivered to sinks",
+            ),
+            &["sink", "result"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(events_received.clone()))
+            .and_then(|_| registry.register(Box::new(events_broadcast.clone())))
+            .and_then(|_| registry.register(Box::new(subscribers.clone())))
+            .and_then(|_| registry.register(Box::new(dropped_events.clone())))
+            .and_then(|_| registry.register(Box::new(deduplicated_events.clone())))
+            .and_then(|_| registry.register(Box::new(oversized_events.clone())))
+            .and_then(|_| registry.register(Box::new(invalid_events.clone())))
+            .and_then(|_| registry.register(Box::new(reconnections.clone())))
+            .and_then(|_| registry.register(Box::new(channel_reconnections.clone())))
+            .and_then(|_| registry.register(Box::new(reconnections_exhausted.clone())))
+            .and_then(|_| registry.register(Box::new(bytes_sent.clone())))
+            .and_then(|_| registry.register(Box::new(queue_usage.clone())))
+            .and_then(|_| registry.register(Box::new(pg_connections.clone())))
+            .and_then(|_| registry.register(Box::new(lagged_events.clone())))
+            .and_then(|_| registry.register(Box::new(dispatch_latency.clone())))
+            .and_then(|_| registry.register(Box::new(sink_deliveries.clone())))
+            .unwrap();
+
+        Self {
+            registry,
+            events_received,
+            events_broadcast,
+            subscribers,
+            dropped_events,
+            deduplicated_events,
+            oversized_events,
+            invalid_events,
+            reconnections,
+            channel_reconnections,
+            reconnections_exhausted,
+            bytes_sent,
+            queue_usage,
+            pg_connections,
+            lagged_events,
+            dispatch_latency,
+            sink_deliveries,
+        }
+    }
+
+    /// Count an event received from the postgres session `session`
+    pub fn event_received(&self, session: i32) {
+        self.events_received
+            .with_label_values(&[&session.to_string()])
+            .inc()
+    }
+
+    /// Return the number of events received from
+    /// the postgres session `session`
+    pub fn events_received(&self, session: i32) -> u64 {
+        self.events_received
+            .with_label_values(&[&session.to_string()])
+            .get()
+    }
+
+    /// Count an event broadcast to `channel`
+    pub fn event_broadcast(&self, channel: &str) {
+        self.events_broadcast.with_label_values(&[channel]).inc()
+    }
+
+    /// Count a duplicate event dropped for `channel`
+    pub fn event_deduplicated(&self, channel: &str) {
+        self.deduplicated_events.with_label_values(&[channel]).inc()
+    }
+
+    /// Count an event exceeding the maximum
+    /// payload size of `channel`
+    pub fn event_oversized(&self, channel: &str) {
+        self.oversized_events.with_label_values(&[channel]).inc()
+    }
+
+    /// Count an event failing the schema
+    /// validation of `channel`
+    pub fn event_invalid(&self, channel: &str) {
+        self.invalid_events.with_label_values(&[channel]).inc()
+    }
+
+    /// Count a reconnection attempt of the connection
+    /// serving `channels`
+    pub fn reconnection(&self, channels: &[String], success: bool) {
+        let result = if success { "ok" } else { "error" };
+        self.reconnections.with_label_values(&[result]).inc();
+        channels.iter().for_each(|channel| {
+            self.channel_reconnections
+                .with_label_values(&[channel, result])
+                .inc()
+        });
+    }
+
+    /// Count the connection serving `channels` reaching
+    /// its maximum reconnection attempts
+    pub fn reconnections_exhausted(&self, channels: &[String]) {
+        channels.iter().for_each(|channel| {
+            self.reconnections_exhausted
+                .with_label_values(&[channel])
+                .inc()
+        });
+    }
+
+    /// Set the notification queue usage seen
+    /// from the postgres session `session`
+    pub fn set_queue_usage(&self, session: i32, usage: f64) {
+        self.queue_usage
+            .with_label_values(&[&session.to_string()])
+            .set(usage)
+    }
+
+    /// Remove the notification queue usage of
+    /// the closed postgres session `session`
+    pub fn remove_queue_usage(&self, session: i32) {
+        let _ = self
+            .queue_usage
+            .remove_label_values(&[&session.to_string()]);
+    }
+
+    /// Set the number of pooled postgres connections
+    pub fn set_pg_connections(&self, count: usize) {
+        self.pg_connections.set(count as i64)
+    }
+
+    /// Count events lost by a worker
+    pub fn lagged_events(&self, count: u64) {
+        self.lagged_events.inc_by(count)
+    }
+
+    /// Count a delivery to `sink` with `result`
+    pub fn sink_delivery(&self, sink: &str, result: &str) {
+        self.sink_deliveries
+            .with_label_values(&[sink, result])
+            .inc()
+    }
+
+    /// Record the dispatch latency in milliseconds
+    pub fn observe_latency(&self, millis: u64) {
+        self.dispatch_latency.observe(millis as f64 / 1000.)
+    }
+
+    /// Gauge of active subscribers for `channel`
+    pub fn subscribers(&self, channel: &str) -> IntGauge {
+        self.subscribers.with_label_values(&[channel])
+    }
+
+    /// Counter of dropped events for `channel`
+    pub fn dropped_events(&self, channel: &str) -> IntCounter {
+        self.dropped_events.with_label_values(&[channel])
+    }
+
+    /// Counter of bytes sent for `channel`
+    pub fn bytes_sent(&self, channel: &str) -> IntCounter {
+        self.bytes_sent.with_label_values(&[channel])
+    }
+
+    /// Return the totals for `channel`
+    pub fn channel_totals(&self, channel: &str) -> ChannelTotals {
+        ChannelTotals {
+            events_broadcast: self.events_broadcast.with_label_values(&[channel]).get(),
+            bytes_sent: self.bytes_sent(channel).get(),
+            dropped_events: self.dropped_events(channel).get(),
+            reconnections: ["ok", "error"]
+                .iter()
+                .map(|result| {
+                    self.channel_reconnections
+                        .with_label_values(&[channel, result])
+                        .get()
+                })
+                .sum(),
+        }
+    }
+
+    /// Collect all metrics
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        self.registry.gather()
+    }
+
+    /// Encode metrics in Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.gather(), &mut buf) {
+            log::error!("Failed to encode metrics: {err:?}");
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+/// Metrics handler
+pub async fn handler(metrics: web::Data<Metrics>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_encode() {
+        let metrics = Metrics::new();
+        metrics.event_received(1234);
+        metrics.event_broadcast("test");
+        metrics.subscribers("test").inc();
+
+        let text = metrics.encode();
+        assert!(text.contains(r#"pg_event_server_events_received_total{session="1234"} 1"#));
+        assert!(text.contains(r#"pg_event_server_events_broadcast_total{channel="test"} 1"#));
+        assert!(text.contains(r#"pg_event_server_subscribers{channel="test"} 1"#));
+
+        metrics.reconnection(&["test".into(), "other".into()], false);
+        metrics.reconnections_exhausted(&["test".into()]);
+        metrics.bytes_sent("test").inc_by(42);
+        metrics.set_queue_usage(1234, 0.25);
+        metrics.set_pg_connections(2);
+        metrics.lagged_events(3);
+        let text = metrics.encode();
+        assert!(text.contains(r#"pg_event_server_reconnections_exhausted_total{channel="test"} 1"#));
+        assert!(text.contains(r#"pg_event_server_reconnections_total{result="error"} 1"#));
+        assert!(text.contains(
+            r#"pg_event_server_channel_reconnections_total{channel="other",result="error"} 1"#
+        ));
+        assert!(text.contains(r#"pg_event_server_bytes_sent_total{channel="test"} 42"#));
+        assert!(text.contains(r#"pg_event_server_notification_queue_usage{session="1234"} 0.25"#));
+        assert!(text.contains("pg_event_server_pg_connections 2"));
+        assert!(text.contains("pg_event_server_lagged_events_total 3"));
+        assert_eq!(metrics.events_received(1234), 1);
+
+        let totals = metrics.channel_totals("test");
+        assert_eq!(totals.events_broadcast, 1);
+        assert_eq!(totals.bytes_sent, 42);
+        assert_eq!(totals.reconnections, 1);
+    }
+}
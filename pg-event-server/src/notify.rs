@@ -0,0 +1,141 @@
+//!
+//! Send a notification from the command line
+//!
+//! The notification is sent with `pg_notify` on the database
+//! of a channel: either the channel given with `--channel` or
+//! the first channel allowing the event.
+//!
+use clap::Args;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+
+use crate::config::{ChannelConfig, Config};
+use crate::eventpattern;
+use crate::{Error, Result};
+
+#[derive(Debug, Args)]
+pub struct Publish {
+    /// Postgres notification channel (event name)
+    event: String,
+    /// Payload of the notification, read from
+    /// stdin if not set or `-`
+    payload: Option<String>,
+    /// Path to the configuration file
+    #[arg(long)]
+    conf: PathBuf,
+    /// Id of the channel whose connection is used
+    #[arg(long)]
+    channel: Option<String>,
+}
+
+impl Publish {
+    pub async fn run(&self) -> Result<()> {
+        let conf = Config::read(&self.conf, &Default::default())?;
+        let channel = self.select_channel(&conf.settings.channels)?;
+        if channel.routed_only || channel.replication.is_some() || channel.peer.is_some() {
+            return Err(Error::Config(format!(
+                "Channel '{}' has no notification connection",
+                channel.id
+            )));
+        }
+        let payload = match self.payload.as_deref() {
+            Some("-") | None => {
+                let mut payload = String::new();
+                std::io::stdin().read_to_string(&mut payload)?;
+                // Strip the final newline of piped input
+                payload.truncate(payload.trim_end_matches(['\n', '\r']).len());
+                payload
+            }
+            Some(payload) => payload.into(),
+        };
+
+        let pgconfig = pg_client_config::load_config(channel.connection_string.as_deref())?;
+        let tls = channel
+            .postgres_tls
+            .as_ref()
+            .unwrap_or(&conf.settings.postgres_tls)
+            .make_tls_connect()?;
+        let (client, connection) = pgconfig.connect(tls).await?;
+        actix_web::rt::spawn(async move {
+            if let Err(err) = connection.await {
+                log::error!("Connection error: {err}");
+            }
+        });
+        client
+            .execute("SELECT pg_notify($1, $2)", &[&self.event, &payload])
+            .await?;
+        log::info!(
+            "Notified '{}' on the connection of channel '{}' ({} bytes)",
+            self.event,
+            channel.id,
+            payload.len()
+        );
+        Ok(())
+    }
+
+    /// Return the channel given with `--channel` or
+    /// the first channel allowing the event
+    fn select_channel<'a>(&self, channels: &'a [ChannelConfig]) -> Result<&'a ChannelConfig> {
+        match &self.channel {
+            Some(id) => channels
+                .iter()
+                .find(|c| c.id == *id || c.aliases.contains(id))
+                .ok_or_else(|| Error::Config(format!("Channel '{id}' not found"))),
+            None => channels
+                .iter()
+                .filter(|c| !c.routed_only && c.replication.is_none() && c.peer.is_none())
+                .find(|c| {
+                    eventpattern::is_allowed(
+                        &c.allowed_events,
+                        &c.deny_events,
+                        &HashMap::new(),
+                        &self.event,
+                    )
+                })
+                .ok_or_else(|| {
+                    Error::Config(format!(
+                        "No channel allows the event '{}', use '--channel'",
+                        self.event
+                    ))
+                }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{confdir, setup};
+    use std::env;
+    use std::path::Path;
+
+    fn publish(event: &str, channel: Option<&str>) -> Publish {
+        Publish {
+            event: event.into(),
+            payload: None,
+            conf: confdir!("config.toml").into(),
+            channel: channel.map(String::from),
+        }
+    }
+
+    #[test]
+    fn publish_channel() {
+        setup();
+        let conf = Config::read(confdir!("config.toml"), &Default::default()).unwrap();
+        let channels = &conf.settings.channels;
+        let select = |event, channel| {
+            publish(event, channel)
+                .select_channel(channels)
+                .map(|c| c.id.clone())
+        };
+        assert_eq!(select("foo", None).unwrap(), "test");
+        assert_eq!(select("hello", None).unwrap(), "other/channel");
+        assert_eq!(
+            select("foo", Some("other/channel")).unwrap(),
+            "other/channel"
+        );
+        assert!(select("nope", None).is_err());
+        assert!(select("foo", Some("nope")).is_err());
+    }
+}
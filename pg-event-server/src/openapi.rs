@@ -0,0 +1,392 @@
+//!
+//! OpenAPI description
+//!
+//! The document is generated on each request
+//! from the configured channels.
+//!
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde_json::{json, Map, Value};
+
+use crate::config::ChannelConfig;
+use crate::eventpattern::EventPattern;
+use crate::registry::{ChannelRegistry, Entry};
+
+/// Settings used for generating the document
+pub struct ApiSettings {
+    pub title: String,
+    pub base_path: String,
+    pub admin: bool,
+}
+
+fn subscribe_operation(conf: &ChannelConfig) -> Value {
+    // Patterns cannot be enumerated
+    let events = conf
+        .allowed_events
+        .iter()
+        .map(|e| {
+            e.name()
+                .map(|e| conf.event_map.get(e).map_or(e, String::as_str))
+        })
+        .collect::<Option<Vec<_>>>();
+    let mut event_schema = json!({ "type": "string" });
+    if let Some(events) = events.filter(|events| !events.is_empty()) {
+        event_schema["enum"] = json!(events);
+    }
+    let mut operation = json!({
+        "summary": conf.description.as_deref().unwrap_or(&conf.id),
+        "operationId": format!("subscribe:{}", conf.id),
+        "tags": ["subscribe"],
+        "parameters": [
+            {
+                "name": "event",
+                "in": "query",
+                "description": "Restrict the events sent",
+                "schema": { "type": "array", "items": event_schema },
+                "style": "form",
+                "explode": true,
+            },
+            {
+                "name": "channel",
+                "in": "query",
+                "description": "Additional channels",
+                "schema": { "type": "array", "items": { "type": "string" } },
+                "style": "form",
+                "explode": true,
+            },
+            {
+                "name": "filter",
+                "in": "query",
+                "description": "Filter on json payloads",
+                "schema": { "type": "string" },
+            },
+            {
+                "name": "format",
+                "in": "query",
+                "schema": { "type": "string", "enum": ["raw", "envelope", "debezium"] },
+            },
+            {
+                "name": "group",
+                "in": "query",
+                "description": "Consumer group sharing the events",
+                "schema": { "type": "string" },
+            },
+            {
+                "name": "group_key",
+                "in": "query",
+                "description": "Payload field distributing the events in the consumer group",
+                "schema": { "type": "string" },
+            },
+            {
+                "name": "since",
+                "in": "query",
+                "description": "Replay the retained events following this event id",
+                "schema": { "type": "string" },
+            },
+            {
+                "name": "consumer",
+                "in": "query",
+                "description": "Consumer id for acknowledged delivery",
+                "required": conf.ack.is_some(),
+                "schema": { "type": "string" },
+            },
+        ],
+        "responses": {
+            "200": {
+                "description": "Event stream",
+                "content": { "text/event-stream": { "schema": { "type": "string" } } },
+            },
+            "400": { "description": "Invalid request" },
+            "404": { "description": "Channel not found" },
+        },
+    });
+    let mut security = vec![];
+    if !conf.api_keys.is_empty() {
+        security.extend([json!({ "apiKey": [] }), json!({ "apiKeyQuery": [] })]);
+    }
+    if !conf.allowed_scopes.is_empty() {
+        security.push(json!({ "bearer": conf.allowed_scopes }));
+    }
+    if !security.is_empty() {
+        operation["security"] = json!(security);
+        operation["responses"]["401"] = json!({ "description": "Missing api key" });
+        operation["responses"]["403"] = json!({ "description": "Invalid api key" });
+    }
+    if conf.reject_when_down {
+        operation["responses"]["503"] = json!({ "description": "Channel backend is down" });
+    }
+    operation
+}
+
+fn publish_operation(conf: &ChannelConfig) -> Value {
+    let mut event_schema = json!({ "type": "string" });
+    if let Some(events) = conf
+        .allowed_events
+        .iter()
+        .map(EventPattern::name)
+        .collect::<Option<Vec<_>>>()
+    {
+        event_schema["enum"] = json!(events);
+    }
+    json!({
+        "summary": format!("Publish to {}", conf.id),
+        "operationId": format!("publish:{}", conf.id),
+        "tags": ["publish"],
+        "security": [{ "apiKey": [] }],
+        "parameters": [{
+            "name": "event",
+            "in": "query",
+            "required": true,
+            "schema": event_schema,
+        }],
+        "requestBody": {
+            "required": true,
+            "content": {
+                "text/plain": {
+                    "schema": { "type": "string", "maxLength": conf.publish.max_payload_size },
+                },
+            },
+        },
+        "responses": {
+            "204": { "description": "Event published" },
+            "400": { "description": "Invalid request" },
+            "401": { "description": "Missing api key" },
+            "403": { "description": "Invalid api key" },
+            "413": { "description": "Payload too large" },
+        },
+    })
+}
+
+fn admin_paths(paths: &mut Map<String, Value>) {
+    let security = json!([{ "apiKey": [] }]);
+    paths.insert(
+        "/admin/channels".into(),
+        json!({
+            "get": {
+                "summary": "List channels and subscribers",
+                "tags": ["admin"],
+                "security": security,
+                "responses": { "200": { "description": "Channel list" } },
+            },
+            "post": {
+                "summary": "Add a channel",
+                "tags": ["admin"],
+                "security": security,
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": { "type": "object" } } },
+                },
+                "responses": {
+                    "201": { "description": "Channel added" },
+                    "409": { "description": "Channel already exists" },
+                    "503": { "description": "Maximum number of postgres connections reached" },
+                },
+            },
+        }),
+    );
+    paths.insert(
+        "/admin/channels/{id}".into(),
+        json!({
+            "delete": {
+                "summary": "Remove a channel",
+                "tags": ["admin"],
+                "security": security,
+                "parameters": [{
+                    "name": "id",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "204": { "description": "Channel removed" },
+                    "404": { "description": "Channel not found" },
+                },
+            },
+        }),
+    );
+    paths.insert(
+        "/admin/pool".into(),
+        json!({
+            "get": {
+                "summary": "List postgres connections",
+                "tags": ["admin"],
+                "security": security,
+                "responses": { "200": { "description": "Connection pool stats" } },
+            },
+        }),
+    );
+    paths.insert(
+        "/admin/reload".into(),
+        json!({
+            "post": {
+                "summary": "Reload configuration",
+                "tags": ["admin"],
+                "security": security,
+                "responses": { "204": { "description": "Configuration reloaded" } },
+            },
+        }),
+    );
+}
+
+fn document(settings: &ApiSettings, server_url: &str, channels: &[Entry]) -> Value {
+    let mut paths = Map::new();
+    for (path, summary) in [
+        ("/", "Channel catalogue"),
+        ("/healthz", "Liveness probe"),
+        ("/ready", "Readiness probe"),
+        ("/stats", "Server stats"),
+        ("/metrics", "Prometheus metrics"),
+    ] {
+        paths.insert(
+            path.into(),
+            json!({
+                "get": {
+                    "summary": summary,
+                    "tags": ["server"],
+                    "responses": { "200": { "description": "OK" } },
+                },
+            }),
+        );
+    }
+    for Entry { conf, .. } in channels {
+        paths.insert(
+            format!("/events/subscribe/{}", conf.id),
+            json!({ "get": subscribe_operation(conf) }),
+        );
+        if conf.history.is_some() {
+            paths.insert(
+                format!("/events/history/{}", conf.id),
+                json!({
+                    "get": {
+                        "summary": "Range of the events available for replay",
+                        "tags": ["subscribe"],
+                        "responses": {
+                            "200": { "description": "History range" },
+                            "404": { "description": "Channel not found" },
+                        },
+                    },
+                }),
+            );
+        }
+        if conf.publish.enabled {
+            paths.insert(
+                format!("/events/publish/{}", conf.id),
+                json!({ "post": publish_operation(conf) }),
+            );
+        }
+    }
+    if channels.iter().any(|entry| entry.conf.ack.is_some()) {
+        paths.insert(
+            "/events/ack".into(),
+            json!({
+                "post": {
+                    "summary": "Acknowledge events",
+                    "tags": ["subscribe"],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "object",
+                                    "required": ["channel", "consumer", "ids"],
+                                    "properties": {
+                                        "channel": { "type": "string" },
+                                        "consumer": { "type": "string" },
+                                        "ids": { "type": "array", "items": { "type": "string" } },
+                                    },
+                                },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "204": { "description": "Events acknowledged" },
+                        "400": { "description": "Invalid request" },
+                        "404": { "description": "Channel not found" },
+                    },
+                },
+            }),
+        );
+    }
+    if settings.admin {
+        admin_paths(&mut paths);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": settings.title,
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": server_url }],
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "apiKey": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "Authorization",
+                    "description": "Key passed as `Key <key>`",
+                },
+                "apiKeyQuery": {
+                    "type": "apiKey",
+                    "in": "query",
+                    "name": "api_key",
+                },
+                "bearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                },
+            },
+        },
+    })
+}
+
+/// OpenAPI handler
+pub async fn handler(
+    req: HttpRequest,
+    settings: web::Data<ApiSettings>,
+    registry: web::Data<ChannelRegistry>,
+) -> impl Responder {
+    let info = req.connection_info();
+    let server_url = format!("{}://{}{}", info.scheme(), info.host(), settings.base_path);
+    HttpResponse::Ok().json(document(&settings, &server_url, &registry.channels()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openapi_document() {
+        let registry = ChannelRegistry::default();
+        registry.insert(
+            toml::from_str(
+                r#"
+                id = "foo"
+                allowed_events = ["a", "b"]
+                event_map = { "a" = "alpha" }
+                api_keys = ["secret"]
+                publish = { enabled = true, api_keys = ["writer"] }
+                "#,
+            )
+            .unwrap(),
+        );
+        let settings = ApiSettings {
+            title: "Test".into(),
+            base_path: String::new(),
+            admin: false,
+        };
+        let doc = document(&settings, "http://localhost", &registry.channels());
+
+        let op = &doc["paths"]["/events/subscribe/foo"]["get"];
+        assert_eq!(
+            op["parameters"][0]["schema"]["items"]["enum"],
+            json!(["alpha", "b"])
+        );
+        assert!(op["security"].is_array());
+        assert_eq!(
+            doc["paths"]["/events/publish/foo"]["post"]["parameters"][0]["schema"]["enum"],
+            json!(["a", "b"])
+        );
+        assert!(doc["paths"]["/admin/reload"].is_null());
+    }
+}
@@ -0,0 +1,177 @@
+//!
+//! Payload tables
+//!
+//! Postgres notifications are limited to 8000 bytes: triggers may
+//! store large payloads in a table and notify only the key of the
+//! row. The payload is fetched on the channel connection before the
+//! event is broadcast.
+//!
+//! Fetched payloads are cached by key, keys should not be reused
+//! for different payloads.
+//!
+use bytestring::ByteString;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::Mutex;
+
+use crate::pool::Pool;
+use crate::{Error, Result};
+
+fn default_query() -> String {
+    "SELECT payload FROM events WHERE id = $1".into()
+}
+
+const fn default_cache_size() -> usize {
+    256
+}
+
+const fn default_max_size() -> usize {
+    1024 * 1024
+}
+
+///
+/// Payload table configuration
+///
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PayloadTableConfig {
+    /// Query returning the payload as text,
+    /// `$1` is bound to the notified key
+    #[serde(default = "default_query")]
+    pub query: String,
+    /// Number of cached payloads
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
+    /// Maximum size in bytes of fetched payloads
+    #[serde(default = "default_max_size")]
+    pub max_size: usize,
+}
+
+impl PayloadTableConfig {
+    /// Check the configuration of the channel `id`
+    pub fn check(&self, id: &str) -> Result<()> {
+        if !self.query.contains("$1") {
+            return Err(Error::Config(format!(
+                "Channel '{id}': the payload query must use the key parameter '$1'"
+            )));
+        }
+        if self.max_size == 0 {
+            return Err(Error::Config(format!(
+                "Channel '{id}': the payload max_size must be positive"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Payload table of a channel
+pub struct PayloadTable {
+    conf: PayloadTableConfig,
+    cache: HashMap<String, ByteString>,
+    /// Cached keys in insertion order
+    keys: VecDeque<String>,
+}
+
+impl PayloadTable {
+    pub fn new(conf: PayloadTableConfig) -> Self {
+        Self {
+            conf,
+            cache: HashMap::new(),
+            keys: VecDeque::new(),
+        }
+    }
+
+    pub fn query(&self) -> &str {
+        &self.conf.query
+    }
+
+    /// Return the cached payload for `key`
+    pub fn cached(&self, key: &str) -> Option<ByteString> {
+        self.cache.get(key).cloned()
+    }
+
+    /// Cache the payload of `key`, the oldest
+    /// entries are evicted when the cache is full
+    pub fn insert(&mut self, key: &str, payload: ByteString) {
+        if self.conf.cache_size == 0 || self.cache.contains_key(key) {
+            return;
+        }
+        while self.keys.len() >= self.conf.cache_size {
+            if let Some(oldest) = self.keys.pop_front() {
+                self.cache.remove(&oldest);
+            }
+        }
+        self.keys.push_back(key.into());
+        self.cache.insert(key.into(), payload);
+    }
+
+    /// Fetch the payload of `key` on the connection `dispatch_id`
+    ///
+    /// Return `None` if the payload is not found, exceeds the
+    /// maximum size or cannot be fetched.
+    pub async fn fetch(
+        &mut self,
+        pool: &Mutex<Pool>,
+        dispatch_id: i32,
+        key: &str,
+        chan: &str,
+    ) -> Option<ByteString> {
+        if let Some(payload) = self.cached(key) {
+            return Some(payload);
+        }
+        let result = pool
+            .lock()
+            .await
+            .fetch_payload(dispatch_id, &self.conf.query, key)
+            .await;
+        match result {
+            Ok(Some(payload)) if payload.len() > self.conf.max_size => {
+                log::warn!(
+                    "Channel '{chan}': payload of key '{key}' exceeds {} bytes ({} bytes)",
+                    self.conf.max_size,
+                    payload.len()
+                );
+                None
+            }
+            Ok(Some(payload)) => {
+                let payload = ByteString::from(payload);
+                self.insert(key, payload.clone());
+                Some(payload)
+            }
+            Ok(None) => {
+                log::warn!("Channel '{chan}': no payload found for key '{key}'");
+                None
+            }
+            Err(err) => {
+                log::error!("Channel '{chan}': failed to fetch payload of key '{key}': {err:?}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_cache() {
+        let mut table = PayloadTable::new(PayloadTableConfig {
+            query: default_query(),
+            cache_size: 2,
+            max_size: default_max_size(),
+        });
+        table.insert("1", "one".into());
+        table.insert("2", "two".into());
+        assert_eq!(table.cached("1").as_deref(), Some("one"));
+        table.insert("3", "three".into());
+        assert!(table.cached("1").is_none());
+        assert_eq!(table.cached("2").as_deref(), Some("two"));
+        assert_eq!(table.cached("3").as_deref(), Some("three"));
+
+        let conf: PayloadTableConfig = toml::from_str("cache_size = 10").unwrap();
+        assert_eq!(conf.query, "SELECT payload FROM events WHERE id = $1");
+        assert!(conf.check("test").is_ok());
+        let conf: PayloadTableConfig = toml::from_str(r#"query = "SELECT 1""#).unwrap();
+        assert!(conf.check("test").is_err());
+    }
+}
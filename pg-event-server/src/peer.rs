@@ -0,0 +1,255 @@
+//!
+//! Hub federation
+//!
+//! Channels fed by a peer subscribe to the channel of an upstream
+//! instance and re-broadcast its events locally, so that regional
+//! instances can serve their subscribers without database connections.
+//!
+//! The subscription is retried after `retry_interval` when the stream
+//! is closed, with the id of the last received event in the
+//! `Last-Event-ID` header: events retained in the upstream history
+//! are replayed.
+//!
+use futures::StreamExt;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::pool::{PgNotification, PgNotificationDispatch};
+use crate::{Error, Result};
+
+const fn default_retry_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+///
+/// Upstream peer configuration
+///
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PeerConfig {
+    /// Subscription url of the upstream channel,
+    /// i.e `https://hub/events/subscribe/foo`
+    pub url: String,
+    /// Id of the local channel, default to the
+    /// channel id of the subscription url
+    pub channel: Option<String>,
+    /// Api key of the subscription
+    pub api_key: Option<String>,
+    /// Delay before subscribing again when the
+    /// stream is closed
+    #[serde(default = "default_retry_interval", with = "humantime_serde")]
+    pub retry_interval: Duration,
+}
+
+impl PeerConfig {
+    /// Return the id of the local channel
+    pub fn channel_id(&self) -> Result<&str> {
+        self.channel
+            .as_deref()
+            .or_else(|| {
+                self.url
+                    .split_once("/events/subscribe/")
+                    .map(|(_, id)| id.split(['?', '#']).next().unwrap_or(id))
+            })
+            .map(|id| id.trim_start_matches('/'))
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| {
+                Error::Config(format!(
+                    "Peer {}: cannot infer the channel id, set 'channel'",
+                    self.url
+                ))
+            })
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(Error::Config(format!(
+                "Peer {}: url must be an http(s) url",
+                self.url
+            )));
+        }
+        if self.retry_interval.is_zero() {
+            return Err(Error::Config(format!(
+                "Peer {}: retry_interval must be positive",
+                self.url
+            )));
+        }
+        self.channel_id().map(|_| ())
+    }
+}
+
+/// Subscription to a peer, stopped when dropped
+pub struct PeerSource(tokio::task::JoinHandle<()>);
+
+impl Drop for PeerSource {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Start forwarding the events of the peer to the channel `id`
+///
+/// Events are sent to `tx` with `dispatch_id`.
+pub fn start(
+    id: String,
+    conf: PeerConfig,
+    dispatch_id: i32,
+    tx: mpsc::Sender<PgNotificationDispatch>,
+) -> PeerSource {
+    PeerSource(actix_web::rt::spawn(async move {
+        let client = awc::Client::builder().disable_timeout().finish();
+        let mut last_id = None;
+        loop {
+            match forward(&client, &conf, dispatch_id, &tx, &mut last_id).await {
+                Ok(()) => break,
+                Err(err) => log::error!("Channel '{id}': {err}"),
+            }
+            actix_web::rt::time::sleep(conf.retry_interval).await;
+        }
+        log::debug!("Channel '{id}': peer source stopped");
+    }))
+}
+
+/// Subscribe to the peer and forward its events
+///
+/// Return when the dispatcher is gone.
+async fn forward(
+    client: &awc::Client,
+    conf: &PeerConfig,
+    dispatch_id: i32,
+    tx: &mpsc::Sender<PgNotificationDispatch>,
+    last_id: &mut Option<String>,
+) -> Result<()> {
+    let mut request = client.get(&conf.url);
+    if let Some(key) = &conf.api_key {
+        request = request.insert_header(("Authorization", format!("Key {key}")));
+    }
+    if let Some(id) = last_id.as_deref() {
+        request = request.insert_header(("Last-Event-ID", id));
+    }
+    let mut response = request
+        .send()
+        .await
+        .map_err(|err| Error::Peer(format!("failed to connect to {}: {err}", conf.url)))?;
+    if !response.status().is_success() {
+        return Err(Error::Peer(format!(
+            "subscription to {} failed with status {}",
+            conf.url,
+            response.status()
+        )));
+    }
+    log::info!("Subscribed to peer {}", conf.url);
+    let mut parser = SseParser::default();
+    while let Some(chunk) = response.next().await {
+        let chunk = chunk.map_err(|err| Error::Peer(format!("{}: {err}", conf.url)))?;
+        for event in parser.feed(&chunk) {
+            if event.id.is_some() {
+                last_id.clone_from(&event.id);
+            }
+            let notification = PgNotification::new(0, event.event, event.data);
+            if tx
+                .send(PgNotificationDispatch::new(notification, dispatch_id))
+                .await
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+    Err(Error::Peer(format!("{}: stream closed", conf.url)))
+}
+
+/// Event received from a SSE stream
+#[derive(Debug, Default, PartialEq)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    pub event: String,
+    pub data: String,
+}
+
+/// Parser of SSE streams
+#[derive(Default)]
+pub struct SseParser {
+    buf: Vec<u8>,
+    id: Option<String>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    /// Feed the parser with `chunk`, return the
+    /// completed events
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buf.extend_from_slice(chunk);
+        let mut events = vec![];
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(SseEvent {
+                        id: self.id.take(),
+                        event: self.event.take().unwrap_or_else(|| "message".into()),
+                        data: self.data.join("\n"),
+                    });
+                    self.data.clear();
+                }
+                continue;
+            }
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "id" => self.id = Some(value.into()),
+                "event" => self.event = Some(value.into()),
+                "data" => self.data.push(value.into()),
+                // Comments and other fields
+                _ => (),
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_parser() {
+        let mut parser = SseParser::default();
+        assert!(parser.feed(b": keep-alive\n\nid: 1\nevent: up").is_empty());
+        let events = parser.feed(b"date\r\ndata: {\"a\":1}\n\ndata: x\ndata: y\n\n");
+        assert_eq!(
+            events,
+            [
+                SseEvent {
+                    id: Some("1".into()),
+                    event: "update".into(),
+                    data: r#"{"a":1}"#.into(),
+                },
+                SseEvent {
+                    id: None,
+                    event: "message".into(),
+                    data: "x\ny".into(),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn peer_channel_id() {
+        let peer = |s: &str| toml::from_str::<PeerConfig>(s).unwrap();
+
+        let conf = peer(r#"url = "https://hub/events/subscribe/foo/bar?event=a""#);
+        assert_eq!(conf.channel_id().unwrap(), "foo/bar");
+        assert_eq!(conf.retry_interval, Duration::from_secs(5));
+        assert!(conf.check().is_ok());
+
+        let conf = peer("url = \"https://hub/events/subscribe/foo\"\nchannel = \"/local\"");
+        assert_eq!(conf.channel_id().unwrap(), "local");
+
+        assert!(peer(r#"url = "https://hub/stream""#).check().is_err());
+        assert!(peer(r#"url = "hub/events/subscribe/foo""#).check().is_err());
+    }
+}
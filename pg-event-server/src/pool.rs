@@ -1,7 +1,7 @@
 //!
 //! Handle Postgres connection pool
 //!
-//! Maintains a pool of event dispatchers for each distinct database connection 
+//! Maintains a pool of event dispatchers for each distinct database connection
 //! configuration.
 //!
 //! This allows us to use the same number of connections independently
@@ -9,90 +9,654 @@
 //!
 use futures::future;
 use pg_event_listener::{Config, Notification, PgEventDispatcher};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
-use crate::postgres::tls::PgTlsConnect;
-use crate::{config::ChannelConfig, Result};
+use crate::alert::{Alert, Alerts};
+use crate::peer::PeerSource;
+use crate::postgres::tls::{PgTlsConfig, PgTlsConnect};
+use crate::replication::ReplicationSource;
+use crate::utils::timestamp_millis;
+use crate::{
+    config::{ChannelConfig, PoolingMode},
+    metrics::Metrics,
+    Error, Result,
+};
+
+/// Dispatch id of the channels without database connection
+pub const NO_DISPATCH_ID: i32 = i32::MIN;
+
+/// Notification received from a connection
+/// or from a replication source
+#[derive(Debug, Clone)]
+pub struct PgNotification {
+    process_id: i32,
+    channel: String,
+    payload: String,
+}
+
+impl PgNotification {
+    pub fn new(process_id: i32, channel: String, payload: String) -> Self {
+        Self {
+            process_id,
+            channel,
+            payload,
+        }
+    }
+    pub fn process_id(&self) -> i32 {
+        self.process_id
+    }
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+    pub fn payload(&self) -> &str {
+        &self.payload
+    }
+    pub fn into_payload(self) -> String {
+        self.payload
+    }
+}
+
+impl From<Notification> for PgNotification {
+    fn from(notification: Notification) -> Self {
+        Self::new(
+            notification.process_id(),
+            notification.channel().into(),
+            notification.payload().into(),
+        )
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PgNotificationDispatch {
-    notification: Notification,
+    notification: PgNotification,
     dispatch_id: i32,
 }
 
 impl PgNotificationDispatch {
-    pub fn notification(&self) -> &Notification {
+    pub fn new(notification: PgNotification, dispatch_id: i32) -> Self {
+        Self {
+            notification,
+            dispatch_id,
+        }
+    }
+    pub fn notification(&self) -> &PgNotification {
         &self.notification
     }
     pub fn dispatch_id(&self) -> i32 {
         self.dispatch_id
     }
-    pub fn take_notification(self) -> Notification {
+    pub fn take_notification(self) -> PgNotification {
         self.notification
     }
 }
 
+/// Change of the state of a pool connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateChange {
+    pub dispatch_id: i32,
+    pub connected: bool,
+}
+
+/// Status of a pool connection
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendStatus {
+    /// Dispatch id of the connection
+    #[serde(skip)]
+    pub dispatch_id: i32,
+    /// True if the connection is up
+    pub connected: bool,
+    /// Postgres backend session
+    pub session_pid: i32,
+    /// Time of the last reconnection attempt
+    /// in milliseconds since epoch
+    pub last_reconnect_attempt: Option<u64>,
+    /// Number of successive failed
+    /// reconnection attempts
+    pub failed_attempts: u32,
+    /// Channels served by this connection
+    pub channels: Vec<String>,
+    /// True if any of the served channels is required
+    pub required: bool,
+}
+
+/// Shared status of the pool connections
+///
+/// Connections are listed in the order of the pool.
+#[derive(Debug, Default, Clone)]
+pub struct PoolStatus(Arc<RwLock<Vec<BackendStatus>>>);
+
+impl PoolStatus {
+    /// Return a snapshot of the connections status
+    pub fn backends(&self) -> Vec<BackendStatus> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Return true if all required backends are connected
+    pub fn is_ready(&self) -> bool {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .all(|b| b.connected || !b.required)
+    }
+
+    /// Return true if the connections serving
+    /// the channel `id` are up
+    pub fn is_channel_connected(&self, id: &str) -> bool {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|b| b.channels.iter().any(|c| c == id))
+            .all(|b| b.connected)
+    }
+
+    /// Return a snapshot of the pool connections
+    /// with the number of events forwarded by each
+    pub fn stats(&self, metrics: &Metrics) -> PoolStats {
+        let backends = self.0.read().unwrap();
+        PoolStats {
+            connections: backends.len(),
+            backends: backends
+                .iter()
+                .map(|b| ConnectionStats {
+                    session_pid: b.session_pid,
+                    connected: b.connected,
+                    channels: b.channels.clone(),
+                    events_forwarded: metrics.events_received(b.dispatch_id),
+                })
+                .collect(),
+        }
+    }
+
+    fn push(&self, status: BackendStatus) {
+        self.0.write().unwrap().push(status)
+    }
+
+    fn remove(&self, index: usize) {
+        self.0.write().unwrap().remove(index);
+    }
+
+    fn update<F: FnOnce(&mut BackendStatus)>(&self, index: usize, f: F) {
+        if let Some(status) = self.0.write().unwrap().get_mut(index) {
+            f(status)
+        }
+    }
+}
+
+/// Stats of a pool connection
+#[derive(Debug, Serialize)]
+pub struct ConnectionStats {
+    pub session_pid: i32,
+    pub connected: bool,
+    pub channels: Vec<String>,
+    pub events_forwarded: u64,
+}
+
+/// Snapshot of the pool connections
+#[derive(Debug, Serialize)]
+pub struct PoolStats {
+    /// Number of connections
+    pub connections: usize,
+    pub backends: Vec<ConnectionStats>,
+}
+
+/// Connection of the pool
+enum Backend {
+    Connected(PgEventDispatcher),
+    /// The database was unreachable when the connection
+    /// was added, events are listened once connected
+    Pending {
+        config: Config,
+        events: HashSet<String>,
+    },
+}
+
+impl Backend {
+    fn config(&self) -> &Config {
+        match self {
+            Self::Connected(dispatcher) => dispatcher.config(),
+            Self::Pending { config, .. } => config,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            Self::Connected(dispatcher) => dispatcher.is_closed(),
+            Self::Pending { .. } => true,
+        }
+    }
+
+    /// Listen `events` and unlisten the events
+    /// that are not in `events`
+    async fn sync_events(&mut self, events: HashSet<String>) -> Result<()> {
+        match self {
+            Self::Connected(dispatcher) => {
+                let current = dispatcher.events();
+                let listen = events.difference(current).cloned().collect::<Vec<_>>();
+                let unlisten = current.difference(&events).cloned().collect::<Vec<_>>();
+                if !listen.is_empty() || !unlisten.is_empty() {
+                    log::debug!(
+                        "Session {}: listen {:?}, unlisten {:?}",
+                        dispatcher.session_pid(),
+                        listen,
+                        unlisten
+                    );
+                }
+                dispatcher.batch_update(listen, unlisten).await?;
+            }
+            Self::Pending {
+                events: pending, ..
+            } => *pending = events,
+        }
+        Ok(())
+    }
+}
+
+/// Reconnection settings of a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ReconnectPolicy {
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+    backoff_factor: f64,
+}
+
+impl ReconnectPolicy {
+    fn new(conf: &ChannelConfig, reconnect_delay: Duration) -> Self {
+        Self {
+            max_delay: conf
+                .reconnect_delay
+                .map(|secs| Duration::from_secs(secs.max(1).into()))
+                .unwrap_or(reconnect_delay),
+            max_attempts: conf.max_attempts,
+            backoff_factor: conf.backoff_factor,
+        }
+    }
+
+    /// Merge the settings of another channel
+    /// sharing the connection
+    ///
+    /// The most tolerant settings are retained.
+    fn merge(&mut self, other: &Self) {
+        self.max_delay = self.max_delay.min(other.max_delay);
+        self.max_attempts = self
+            .max_attempts
+            .zip(other.max_attempts)
+            .map(|(a, b)| a.max(b));
+        self.backoff_factor = self.backoff_factor.min(other.backoff_factor);
+    }
+
+    fn initial_delay(&self) -> Duration {
+        Duration::from_secs(1).min(self.max_delay)
+    }
+}
+
+/// Reconnection state of a connection
+struct Reconnect {
+    policy: ReconnectPolicy,
+    attempts: u32,
+    delay: Duration,
+    next_attempt: Option<Instant>,
+    exhausted: bool,
+}
+
+impl Reconnect {
+    fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            attempts: 0,
+            delay: policy.initial_delay(),
+            next_attempt: None,
+            exhausted: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.policy);
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        !self.exhausted && self.next_attempt.is_none_or(|next| next <= now)
+    }
+
+    /// Schedule the next attempt after a failure
+    ///
+    /// Return true if the maximum number of
+    /// attempts is reached.
+    fn failed(&mut self, now: Instant) -> bool {
+        self.attempts += 1;
+        if self
+            .policy
+            .max_attempts
+            .is_some_and(|max| self.attempts >= max)
+        {
+            self.exhausted = true;
+            self.next_attempt = None;
+            return true;
+        }
+        self.next_attempt = Some(now + self.delay);
+        self.delay = self
+            .delay
+            .mul_f64(self.policy.backoff_factor)
+            .min(self.policy.max_delay);
+        false
+    }
+}
+
+/// Pool options
+#[derive(Debug, Clone, Copy)]
+pub struct PoolOptions {
+    /// Default maximum delay between failed
+    /// reconnection attempts
+    pub reconnect_delay: Duration,
+    /// Sharing of connections between channels
+    pub pooling: PoolingMode,
+    /// Maximum number of connections
+    pub max_connections: Option<usize>,
+}
+
 pub struct Pool {
-    pool: Vec<PgEventDispatcher>,
+    pool: Vec<Backend>,
+    reconnect: Vec<Reconnect>,
+    options: PoolOptions,
+    /// Dispatch ids of the connections, the dispatch id
+    /// is the session pid at the creation of the connection,
+    /// or a negative id for pending connections
+    dispatch_ids: Vec<i32>,
+    next_pending_id: i32,
+    /// Replication sources with their dispatch id
+    sources: Vec<(i32, ReplicationSource)>,
+    /// Peer sources with their dispatch id
+    peers: Vec<(i32, PeerSource)>,
     tx: mpsc::Sender<PgNotificationDispatch>,
+    closed_tx: mpsc::UnboundedSender<i32>,
+    state_tx: mpsc::UnboundedSender<StateChange>,
     tls: PgTlsConnect,
+    /// Tls connectors of the connections with
+    /// their own tls configuration
+    channel_tls: Vec<Option<ChannelTls>>,
+    status: PoolStatus,
+    metrics: Metrics,
+    alerts: Alerts,
+}
+
+/// Tls connector of a channel tls configuration
+struct ChannelTls {
+    conf: PgTlsConfig,
+    connect: PgTlsConnect,
 }
 
 impl Pool {
     /// Create a new Pool that will forward notification to `tx`
-    pub fn new(tx: mpsc::Sender<PgNotificationDispatch>, tls: PgTlsConnect) -> Self {
+    ///
+    /// The dispatch id of a connection is sent to `closed_tx`
+    /// when the connection is closed. Connections going down
+    /// or up are reported to `state_tx`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        tx: mpsc::Sender<PgNotificationDispatch>,
+        closed_tx: mpsc::UnboundedSender<i32>,
+        state_tx: mpsc::UnboundedSender<StateChange>,
+        tls: PgTlsConnect,
+        options: PoolOptions,
+        status: PoolStatus,
+        metrics: Metrics,
+        alerts: Alerts,
+    ) -> Self {
         Self {
             pool: vec![],
+            reconnect: vec![],
+            options,
+            dispatch_ids: vec![],
+            next_pending_id: -1,
+            sources: vec![],
+            peers: vec![],
             tx,
+            closed_tx,
+            state_tx,
             tls,
+            channel_tls: vec![],
+            status,
+            metrics,
+            alerts,
         }
     }
 
+    /// Return true if all connections are up
+    pub fn is_connected(&self) -> bool {
+        !self.pool.iter().any(Backend::is_closed)
+    }
+
+    /// Signal the closure of the current connection
+    /// of `dispatcher`
+    fn watch_closed(
+        dispatcher: &PgEventDispatcher,
+        dispatch_id: i32,
+        closed_tx: &mpsc::UnboundedSender<i32>,
+    ) {
+        let closed = dispatcher.closed();
+        let closed_tx = closed_tx.clone();
+        actix_web::rt::spawn(async move {
+            closed.await;
+            let _ = closed_tx.send(dispatch_id);
+        });
+    }
+
     /// Handle reconnection
-    pub async fn reconnect(&mut self) {
-        if !self.pool.iter().any(|d| d.is_closed()) {
-            return;
+    ///
+    /// Closed connections due for a reconnection attempt
+    /// are reconnected. Pending connections are connected
+    /// and their events are listened.
+    ///
+    /// Return the time of the next attempt, `None` if there
+    /// is no connection left to reconnect.
+    pub async fn reconnect(&mut self) -> Option<Instant> {
+        if self.is_connected() {
+            return None;
         }
 
-        let _ = future::join_all(self.pool.iter_mut().map(|dispatcher| async {
-            if dispatcher.is_closed() {
-                if let Err(err) = dispatcher.respawn(self.tls.clone()).await {
-                    let conf = dispatcher.config();
-                    log::error!(
-                        "Failed to reconnect to database {} on {:?}: {:?}",
-                        conf.get_dbname().unwrap_or("<unknown>"),
-                        conf.get_hosts(),
-                        err
-                    );
-                } else {
-                    let conf = dispatcher.config();
-                    log::info!(
-                        "Succeded to reconnect to database {} on {:?} (backend session: {})",
-                        conf.get_dbname().unwrap_or("<unknown>"),
-                        conf.get_hosts(),
-                        dispatcher.session_pid(),
-                    );
-                }
+        let now = Instant::now();
+        let Self {
+            pool,
+            reconnect,
+            dispatch_ids,
+            tx,
+            closed_tx,
+            state_tx,
+            tls,
+            channel_tls,
+            status,
+            metrics,
+            alerts,
+            ..
+        } = self;
+        let (tx, closed_tx, state_tx, tls, status, metrics, alerts) = (
+            &*tx,
+            &*closed_tx,
+            &*state_tx,
+            &*tls,
+            &*status,
+            &*metrics,
+            &*alerts,
+        );
+        let _ = future::join_all(
+            pool.iter_mut()
+                .zip(reconnect.iter_mut())
+                .zip(dispatch_ids.iter())
+                .zip(channel_tls.iter())
+                .enumerate()
+                .map(
+                    |(i, (((backend, state), dispatch_id), channel_tls))| async move {
+                        if !backend.is_closed() || !state.is_due(now) {
+                            return;
+                        }
+                        let tls = channel_tls.as_ref().map_or(tls, |t| &t.connect);
+                        let mut channels = vec![];
+                        let mut was_connected = false;
+                        status.update(i, |s| {
+                            was_connected = s.connected;
+                            s.connected = false;
+                            s.last_reconnect_attempt = Some(timestamp_millis());
+                            channels.clone_from(&s.channels);
+                        });
+                        if was_connected {
+                            let _ = state_tx.send(StateChange {
+                                dispatch_id: *dispatch_id,
+                                connected: false,
+                            });
+                        }
+                        let result = match backend {
+                            Backend::Connected(dispatcher) => dispatcher
+                                .respawn(tls.clone())
+                                .await
+                                .map(|_| Self::watch_closed(dispatcher, *dispatch_id, closed_tx))
+                                .map_err(Error::from),
+                            Backend::Pending { config, events } => {
+                                let result = async {
+                                    let mut dispatcher = Self::start_dispatcher(
+                                        config.clone(),
+                                        Some(*dispatch_id),
+                                        tls,
+                                        tx,
+                                        closed_tx,
+                                    )
+                                    .await?;
+                                    dispatcher.batch_listen(events.drain()).await?;
+                                    Ok(dispatcher)
+                                }
+                                .await;
+                                result.map(|dispatcher| *backend = Backend::Connected(dispatcher))
+                            }
+                        };
+                        metrics.reconnection(&channels, result.is_ok());
+                        let conf = backend.config();
+                        match (result, &*backend) {
+                            (Err(err), _) => {
+                                log::error!(
+                                    "Failed to reconnect to database {} on {:?}: {:?}",
+                                    conf.get_dbname().unwrap_or("<unknown>"),
+                                    conf.get_hosts(),
+                                    err
+                                );
+                                let exhausted = state.failed(now);
+                                status.update(i, |s| s.failed_attempts = state.attempts);
+                                if exhausted {
+                                    metrics.reconnections_exhausted(&channels);
+                                    alerts.fire(&Alert::reconnect_failed(
+                                        &channels,
+                                        conf,
+                                        state.attempts,
+                                    ));
+                                }
+                            }
+                            (Ok(_), Backend::Connected(dispatcher)) => {
+                                state.reset();
+                                status.update(i, |s| {
+                                    s.connected = true;
+                                    s.session_pid = dispatcher.session_pid();
+                                    s.failed_attempts = 0;
+                                });
+                                let _ = state_tx.send(StateChange {
+                                    dispatch_id: *dispatch_id,
+                                    connected: true,
+                                });
+                                log::info!(
+                            "Succeded to reconnect to database {} on {:?} (backend session: {})",
+                            conf.get_dbname().unwrap_or("<unknown>"),
+                            conf.get_hosts(),
+                            dispatcher.session_pid(),
+                        );
+                            }
+                            (Ok(_), Backend::Pending { .. }) => (),
+                        }
+                    },
+                ),
+        )
+        .await;
+
+        self.pool
+            .iter()
+            .zip(self.reconnect.iter())
+            .filter(|(backend, state)| backend.is_closed() && !state.exhausted)
+            .map(|(_, state)| state.next_attempt.unwrap_or(now))
+            .min()
+    }
+
+    /// Retry the connections that reached
+    /// their maximum reconnection attempts
+    pub fn retry_exhausted(&mut self) {
+        for (state, dispatch_id) in self.reconnect.iter_mut().zip(self.dispatch_ids.iter()) {
+            if state.exhausted {
+                state.reset();
+                let _ = self.closed_tx.send(*dispatch_id);
             }
-        }))
+        }
+    }
+
+    /// Query the notification queue usage on
+    /// each connection
+    ///
+    /// A warning is logged if the usage is above `threshold`.
+    pub async fn check_queue_usage(&self, threshold: f64) {
+        let _ = future::join_all(self.pool.iter().zip(self.dispatch_ids.iter()).map(
+            |(backend, dispatch_id)| async move {
+                let Backend::Connected(dispatcher) = backend else {
+                    return;
+                };
+                if dispatcher.is_closed() {
+                    return;
+                }
+                match dispatcher.notification_queue_usage().await {
+                    Ok(usage) => {
+                        self.metrics.set_queue_usage(*dispatch_id, usage);
+                        if usage > threshold {
+                            let conf = dispatcher.config();
+                            log::warn!(
+                                "Notification queue of database {} on {:?} is {:.1}% full",
+                                conf.get_dbname().unwrap_or("<unknown>"),
+                                conf.get_hosts(),
+                                usage * 100.,
+                            );
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Failed to query notification queue usage: {err:?}")
+                    }
+                }
+            },
+        ))
         .await;
     }
 
     /// Spaw a new dispatcher task
-    async fn start_dispatcher(&self, config: Config) -> Result<PgEventDispatcher> {
-        let (tx, mut rx) = mpsc::channel(1);
-        let dispatcher = PgEventDispatcher::connect(config, tx, self.tls.clone()).await?;
+    ///
+    /// The dispatch id defaults to the session pid
+    /// of the connection.
+    async fn start_dispatcher(
+        config: Config,
+        dispatch_id: Option<i32>,
+        tls: &PgTlsConnect,
+        tx: &mpsc::Sender<PgNotificationDispatch>,
+        closed_tx: &mpsc::UnboundedSender<i32>,
+    ) -> Result<PgEventDispatcher> {
+        let (tx_dispatch, mut rx) = mpsc::channel(1);
+        let dispatcher = PgEventDispatcher::connect(config, tx_dispatch, tls.clone()).await?;
 
-        let dispatch_id = dispatcher.session_pid();
-        let tx_fwd = self.tx.clone();
+        let dispatch_id = dispatch_id.unwrap_or_else(|| dispatcher.session_pid());
+        Self::watch_closed(&dispatcher, dispatch_id, closed_tx);
+        let tx_fwd = tx.clone();
         // Wrap the event and forward it
         actix_web::rt::spawn(async move {
             while let Some(notification) = rx.recv().await {
                 if let Err(error) = tx_fwd
-                    .send(PgNotificationDispatch {
-                        notification,
+                    .send(PgNotificationDispatch::new(
+                        notification.into(),
                         dispatch_id,
-                    })
+                    ))
                     .await
                 {
                     log::error!("{:?}", error);
@@ -112,15 +676,21 @@ impl Pool {
     /// Addd a new connection to the connection pool
     ///
     /// No new connection is created if a connection already exists which
-    /// target the same host, user and database.
+    /// is shared according to the pooling mode.
+    ///
+    /// If the database is unreachable and the channel is not required,
+    /// the connection is added as pending and connected by the
+    /// reconnection handler.
     pub async fn add_connection(&mut self, conf: &ChannelConfig) -> Result<i32> {
-        async fn listen(dispatcher: &mut PgEventDispatcher, events: &[String]) -> Result<()> {
-            for event in events.iter() {
-                dispatcher.listen(event).await?;
-            }
-            Ok(())
+        if conf.peer.is_some() {
+            return self.add_peer_source(conf);
+        }
+        if conf.routed_only {
+            return Ok(NO_DISPATCH_ID);
+        }
+        if conf.replication.is_some() {
+            return self.add_replication_source(conf);
         }
-
         let connection_string = conf.connection_string.as_deref();
 
         // Created postgres configuration
@@ -131,32 +701,443 @@ impl Pool {
         );
 
         let pgconfig = pg_client_config::load_config(connection_string)?;
+        // Connections are shared only with the same tls configuration
         match self
             .pool
-            .iter_mut()
-            .find(|d| Self::use_same_connection(d, &pgconfig))
-        {
-            Some(dispatcher) => {
-                listen(dispatcher, &conf.allowed_events).await?;
-                Ok(dispatcher.session_pid())
+            .iter()
+            .zip(self.channel_tls.iter())
+            .position(|(b, tls)| {
+                Self::use_same_connection(self.options.pooling, b.config(), &pgconfig)
+                    && tls.as_ref().map(|t| &t.conf) == conf.postgres_tls.as_ref()
+            }) {
+            Some(index) => {
+                self.status.update(index, |s| {
+                    if !s.channels.contains(&conf.id) {
+                        s.channels.push(conf.id.clone());
+                    }
+                    s.required |= conf.required;
+                });
+                self.reconnect[index]
+                    .policy
+                    .merge(&ReconnectPolicy::new(conf, self.options.reconnect_delay));
+                Ok(self.dispatch_ids[index])
             }
             None => {
-                let mut dispatcher = self.start_dispatcher(pgconfig).await?;
-                listen(&mut dispatcher, &conf.allowed_events).await?;
-                let session_pid = dispatcher.session_pid();
-                self.pool.push(dispatcher);
-                log::info!("Pool: Added pg_event dispatcher for session: {session_pid}");
-                Ok(session_pid)
+                if let Some(max) = self
+                    .options
+                    .max_connections
+                    .filter(|max| self.pool.len() >= *max)
+                {
+                    return Err(Error::TooManyPgConnections(max));
+                }
+                let channel_tls = Self::channel_tls(conf)?;
+                let (backend, dispatch_id) = match Self::start_dispatcher(
+                    pgconfig.clone(),
+                    None,
+                    channel_tls.as_ref().map_or(&self.tls, |t| &t.connect),
+                    &self.tx,
+                    &self.closed_tx,
+                )
+                .await
+                {
+                    Ok(dispatcher) => {
+                        let session_pid = dispatcher.session_pid();
+                        (Backend::Connected(dispatcher), session_pid)
+                    }
+                    Err(err) if !conf.required => {
+                        log::warn!(
+                            "Channel '{}': database {} on {:?} is unreachable, connection is pending: {err:?}",
+                            conf.id,
+                            pgconfig.get_dbname().unwrap_or("<unknown>"),
+                            pgconfig.get_hosts(),
+                        );
+                        let dispatch_id = self.next_pending_id;
+                        self.next_pending_id -= 1;
+                        let backend = Backend::Pending {
+                            config: pgconfig,
+                            events: HashSet::new(),
+                        };
+                        (backend, dispatch_id)
+                    }
+                    Err(err) => return Err(err),
+                };
+                let connected = !backend.is_closed();
+                self.pool.push(backend);
+                self.reconnect.push(Reconnect::new(ReconnectPolicy::new(
+                    conf,
+                    self.options.reconnect_delay,
+                )));
+                self.dispatch_ids.push(dispatch_id);
+                self.channel_tls.push(channel_tls);
+                self.status.push(BackendStatus {
+                    dispatch_id,
+                    connected,
+                    session_pid: if connected { dispatch_id } else { 0 },
+                    last_reconnect_attempt: None,
+                    failed_attempts: 0,
+                    channels: vec![conf.id.clone()],
+                    required: conf.required,
+                });
+                self.metrics.set_pg_connections(self.pool.len());
+                if connected {
+                    log::info!("Pool: Added pg_event dispatcher for session: {dispatch_id}");
+                } else {
+                    // Start the reconnection handler
+                    let _ = self.closed_tx.send(dispatch_id);
+                }
+                Ok(dispatch_id)
+            }
+        }
+    }
+
+    /// Start a replication source for the channel
+    ///
+    /// Replication sources are never shared, each source
+    /// has its own connection and a negative dispatch id.
+    fn add_replication_source(&mut self, conf: &ChannelConfig) -> Result<i32> {
+        let Some(replication) = &conf.replication else {
+            return Err(Error::Config(format!(
+                "Channel '{}' has no replication source",
+                conf.id
+            )));
+        };
+        #[cfg(feature = "replication")]
+        {
+            let pgconfig = pg_client_config::load_config(conf.connection_string.as_deref())?;
+            let dispatch_id = self.next_pending_id;
+            self.next_pending_id -= 1;
+            let source = crate::replication::start(
+                conf.id.clone(),
+                replication.clone(),
+                pgconfig,
+                Self::channel_tls(conf)?.map_or_else(|| self.tls.clone(), |t| t.connect),
+                dispatch_id,
+                self.tx.clone(),
+                ReconnectPolicy::new(conf, self.options.reconnect_delay).max_delay,
+            );
+            self.sources.push((dispatch_id, source));
+            log::info!(
+                "Pool: Added replication source for channel '{}' from slot '{}'",
+                conf.id,
+                replication.slot
+            );
+            Ok(dispatch_id)
+        }
+        #[cfg(not(feature = "replication"))]
+        Err(Error::Config(format!(
+            "Channel '{}': replication from slot '{}' requires the 'replication' feature",
+            conf.id, replication.slot
+        )))
+    }
+
+    /// Start forwarding the events of the channel peer
+    ///
+    /// Like replication sources, peer sources are never shared.
+    fn add_peer_source(&mut self, conf: &ChannelConfig) -> Result<i32> {
+        let Some(peer) = &conf.peer else {
+            return Err(Error::Config(format!("Channel '{}' has no peer", conf.id)));
+        };
+        let dispatch_id = self.next_pending_id;
+        self.next_pending_id -= 1;
+        let source =
+            crate::peer::start(conf.id.clone(), peer.clone(), dispatch_id, self.tx.clone());
+        self.peers.push((dispatch_id, source));
+        log::info!(
+            "Pool: Added peer source for channel '{}' from {}",
+            conf.id,
+            peer.url
+        );
+        Ok(dispatch_id)
+    }
+
+    /// Set the events listened on the connection `dispatch_id`
+    ///
+    /// Only the difference with the currently listened events
+    /// is sent to the server, as a single batch of `LISTEN`
+    /// and `UNLISTEN` commands.
+    pub async fn sync_events(&mut self, dispatch_id: i32, events: HashSet<String>) -> Result<()> {
+        match self.dispatch_ids.iter().position(|d| *d == dispatch_id) {
+            Some(index) => self.pool[index].sync_events(events).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Remove the channel `id` from the connection `dispatch_id`
+    ///
+    /// The connection is closed if it serves no other channel.
+    pub fn remove_channel(&mut self, dispatch_id: i32, id: &str) {
+        if let Some(index) = self.sources.iter().position(|(d, _)| *d == dispatch_id) {
+            self.sources.remove(index);
+            log::info!("Pool: Stopped replication source of channel '{id}'");
+            return;
+        }
+        if let Some(index) = self.peers.iter().position(|(d, _)| *d == dispatch_id) {
+            self.peers.remove(index);
+            log::info!("Pool: Stopped peer source of channel '{id}'");
+            return;
+        }
+        let Some(index) = self.dispatch_ids.iter().position(|d| *d == dispatch_id) else {
+            return;
+        };
+        let mut idle = false;
+        self.status.update(index, |s| {
+            s.channels.retain(|c| c != id);
+            idle = s.channels.is_empty();
+        });
+        if idle {
+            self.close(index);
+        }
+    }
+
+    /// Close the connection at `index`
+    fn close(&mut self, index: usize) {
+        let dispatch_id = self.dispatch_ids.remove(index);
+        self.pool.remove(index);
+        self.reconnect.remove(index);
+        self.channel_tls.remove(index);
+        self.status.remove(index);
+        self.metrics.remove_queue_usage(dispatch_id);
+        self.metrics.set_pg_connections(self.pool.len());
+        log::info!("Pool: Closed idle connection {dispatch_id}");
+    }
+
+    /// Send a notification on the connection `dispatch_id`
+    pub async fn notify(&self, dispatch_id: i32, event: &str, payload: &str) -> Result<()> {
+        let index = self
+            .dispatch_ids
+            .iter()
+            .position(|d| *d == dispatch_id)
+            .ok_or(Error::DispatcherUnavailable)?;
+        match &self.pool[index] {
+            Backend::Connected(dispatcher) if !dispatcher.is_closed() => {
+                dispatcher.notify(event, payload).await.map_err(Error::from)
             }
+            _ => Err(Error::DispatcherUnavailable),
+        }
+    }
+
+    /// Fetch the payload stored with `key` on the
+    /// connection `dispatch_id`
+    pub async fn fetch_payload(
+        &self,
+        dispatch_id: i32,
+        query: &str,
+        key: &str,
+    ) -> Result<Option<String>> {
+        let index = self
+            .dispatch_ids
+            .iter()
+            .position(|d| *d == dispatch_id)
+            .ok_or(Error::DispatcherUnavailable)?;
+        match &self.pool[index] {
+            Backend::Connected(dispatcher) if !dispatcher.is_closed() => dispatcher
+                .fetch_payload(query, key)
+                .await
+                .map_err(Error::from),
+            _ => Err(Error::DispatcherUnavailable),
         }
     }
 
+    /// Replace the tls connector used for new connections
+    pub fn set_tls(&mut self, tls: PgTlsConnect) {
+        self.tls = tls;
+    }
+
+    /// Return the tls connector of the channel
+    /// tls configuration if any
+    fn channel_tls(conf: &ChannelConfig) -> Result<Option<ChannelTls>> {
+        conf.postgres_tls
+            .as_ref()
+            .map(|tls| {
+                Ok(ChannelTls {
+                    conf: tls.clone(),
+                    connect: tls.make_tls_connect()?,
+                })
+            })
+            .transpose()
+    }
+
     /// Compare the configurations
-    /// Return true if the host, user and database are the same
-    fn use_same_connection(dispatcher: &PgEventDispatcher, config: &Config) -> bool {
-        let this = dispatcher.config();
-        this.get_hosts() == config.get_hosts()
-            && this.get_dbname() == config.get_dbname()
-            && this.get_user() == config.get_user()
+    ///
+    /// In `host-db-user` mode, return true if the host, user and database
+    /// are the same. In `strict` mode, return true if all the
+    /// connection parameters are the same.
+    fn use_same_connection(pooling: PoolingMode, this: &Config, config: &Config) -> bool {
+        match pooling {
+            PoolingMode::Strict => Self::normalized(this) == Self::normalized(config),
+            PoolingMode::HostDbUser => {
+                this.get_hosts() == config.get_hosts()
+                    && this.get_dbname() == config.get_dbname()
+                    && this.get_user() == config.get_user()
+            }
+            PoolingMode::None => false,
+        }
+    }
+
+    /// Return the configuration with the default port set
+    fn normalized(config: &Config) -> Config {
+        let mut config = config.clone();
+        if config.get_ports().is_empty() {
+            config.port(5432);
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(connected: bool, required: bool) -> BackendStatus {
+        BackendStatus {
+            dispatch_id: 0,
+            connected,
+            session_pid: 0,
+            last_reconnect_attempt: None,
+            failed_attempts: 0,
+            channels: vec![],
+            required,
+        }
+    }
+
+    #[test]
+    fn pool_status_ready() {
+        let status = PoolStatus::default();
+        assert!(status.is_ready());
+
+        status.push(backend(true, true));
+        status.push(backend(false, false));
+        assert!(status.is_ready());
+
+        status.update(0, |s| s.connected = false);
+        assert!(!status.is_ready());
+    }
+
+    #[test]
+    fn pool_status_channel() {
+        let status = PoolStatus::default();
+        status.push(BackendStatus {
+            channels: vec!["foo".into()],
+            ..backend(true, true)
+        });
+        status.push(BackendStatus {
+            channels: vec!["bar".into()],
+            ..backend(false, false)
+        });
+        assert!(status.is_channel_connected("foo"));
+        assert!(!status.is_channel_connected("bar"));
+        // Unknown channels are not reported as down
+        assert!(status.is_channel_connected("baz"));
+    }
+
+    #[actix_web::test]
+    async fn pool_pending_connection() {
+        let (tx, _rx) = mpsc::channel(1);
+        let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+        let (state_tx, mut state_rx) = mpsc::unbounded_channel();
+        let status = PoolStatus::default();
+        let mut pool = Pool::new(
+            tx,
+            closed_tx,
+            state_tx,
+            PgTlsConfig::default().make_tls_connect().unwrap(),
+            PoolOptions {
+                reconnect_delay: Duration::from_secs(60),
+                pooling: PoolingMode::default(),
+                max_connections: Some(1),
+            },
+            status.clone(),
+            Metrics::new(),
+            Alerts::default(),
+        );
+
+        // Nothing listens on port 1
+        let conf = |required| -> ChannelConfig {
+            toml::from_str(&format!(
+                r#"
+                id = "test"
+                allowed_events = ["foo"]
+                connection_string = "host=127.0.0.1 port=1 user=postgres connect_timeout=1"
+                required = {required}
+                "#
+            ))
+            .unwrap()
+        };
+        assert!(pool.add_connection(&conf(true)).await.is_err());
+
+        let dispatch_id = pool.add_connection(&conf(false)).await.unwrap();
+        assert_eq!(dispatch_id, -1);
+        assert_eq!(closed_rx.recv().await, Some(dispatch_id));
+        assert!(!pool.is_connected());
+        assert!(!status.backends()[0].connected);
+        assert!(status.is_ready());
+        assert!(matches!(
+            pool.notify(dispatch_id, "foo", "").await,
+            Err(Error::DispatcherUnavailable)
+        ));
+
+        // Same connection
+        assert_eq!(pool.add_connection(&conf(false)).await.unwrap(), -1);
+        pool.reconnect().await;
+        assert!(status.backends()[0].last_reconnect_attempt.is_some());
+        // Pending connections were never up
+        assert!(state_rx.try_recv().is_err());
+
+        // Maximum number of connections reached
+        let mut other = conf(false);
+        other.connection_string = Some("host=127.0.0.1 port=1 user=other".into());
+        assert!(matches!(
+            pool.add_connection(&other).await,
+            Err(Error::TooManyPgConnections(1))
+        ));
+
+        let stats = status.stats(&Metrics::new());
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.backends[0].channels, ["test"]);
+
+        // Pending connections record the events
+        let events = HashSet::from(["foo".to_string(), "bar".to_string()]);
+        pool.sync_events(dispatch_id, events.clone()).await.unwrap();
+        assert!(matches!(&pool.pool[0], Backend::Pending { events: e, .. } if *e == events));
+
+        // Idle connections are closed
+        pool.remove_channel(dispatch_id, "test");
+        assert!(status.backends().is_empty());
+        assert!(pool.is_connected());
+    }
+
+    #[test]
+    fn pooling_modes() {
+        let config = |s: &str| -> Config { s.parse().unwrap() };
+        let this = config("host=db user=foo dbname=test");
+        let same = |pooling, other: &str| Pool::use_same_connection(pooling, &this, &config(other));
+
+        assert!(same(
+            PoolingMode::HostDbUser,
+            "host=db user=foo dbname=test port=5433"
+        ));
+        assert!(!same(
+            PoolingMode::HostDbUser,
+            "host=db user=bar dbname=test"
+        ));
+
+        assert!(same(
+            PoolingMode::Strict,
+            "host=db user=foo dbname=test port=5432"
+        ));
+        assert!(!same(
+            PoolingMode::Strict,
+            "host=db user=foo dbname=test port=5433"
+        ));
+        assert!(!same(
+            PoolingMode::Strict,
+            "host=db user=foo dbname=test sslmode=disable"
+        ));
+        assert!(!same(
+            PoolingMode::Strict,
+            "host=db user=foo dbname=test options=-cfoo=bar"
+        ));
+
+        assert!(!same(PoolingMode::None, "host=db user=foo dbname=test"));
     }
 }
@@ -8,7 +8,7 @@ use std::{fs, io};
 
 use tokio_postgres_rustls::MakeRustlsConnect;
 
-#[derive(Default, Debug, Clone, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Deserialize)]
 pub struct PgTlsConfig {
     /// Server ca file
     /// The file should contain a sequence of PEM-formatted CA certificates.
@@ -109,6 +109,19 @@ impl PgTlsConfig {
         Ok(MakeRustlsConnect::new(builder))
     }
 
+    /// Resolve relative paths from `root`
+    pub fn sanitize(&mut self, root: &Path) {
+        [
+            &mut self.tls_ca_file,
+            &mut self.tls_client_auth_key,
+            &mut self.tls_client_auth_cert,
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|path| !path.has_root())
+        .for_each(|path| *path = root.join(&*path));
+    }
+
     pub fn check(&self) -> Result<()> {
         if let Some(cafile) = &self.tls_ca_file {
             if !cafile.as_path().is_file() {
@@ -0,0 +1,149 @@
+//!
+//! Publish endpoint
+//!
+//! Publish events to a channel with `pg_notify` on the
+//! channel's database connection.
+//!
+//! Publishing must be enabled in the channel `[publish]`
+//! section and requires one of the publish api keys.
+//!
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::Deserialize;
+
+use crate::{
+    accesslog,
+    config::{PayloadSizePolicy, PublishConfig},
+    events::DispatchHandle,
+    ratelimit::RateLimits,
+    registry::ChannelRegistry,
+    requestid::request_id,
+    server::proxy::TrustedProxies,
+    utils::authorization_key,
+    Error, Result,
+};
+
+#[derive(Deserialize)]
+pub struct PublishParams {
+    /// The postgres event name
+    event: String,
+}
+
+/// Check that the request present a valid publish key
+fn check_api_key(conf: &PublishConfig, req: &HttpRequest) -> Result<()> {
+    match authorization_key(req) {
+        Some(key) if conf.api_keys.iter().any(|k| k == key) => Ok(()),
+        Some(_) => Err(Error::Forbidden),
+        None => Err(Error::Unauthorized),
+    }
+}
+
+/// Publish the request body as payload of
+/// the `event` notification
+pub async fn handler(
+    req: HttpRequest,
+    registry: web::Data<ChannelRegistry>,
+    dispatch: web::Data<DispatchHandle>,
+    params: web::Query<PublishParams>,
+    limits: web::Data<RateLimits>,
+    body: web::Bytes,
+) -> Result<impl Responder> {
+    let PublishParams { event } = params.into_inner();
+    let size = body.len();
+    let result = publish(&req, &registry, &dispatch, &limits, &event, body).await;
+
+    let client_ip = req
+        .app_data::<web::Data<TrustedProxies>>()
+        .and_then(|proxies| proxies.client_ip(&req))
+        .map(|ip| ip.to_string());
+    let status = match &result {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(err) => err.status_code(),
+    };
+    accesslog::publish(
+        &request_id(&req),
+        req.match_info().query("id"),
+        &event,
+        size,
+        client_ip.as_deref(),
+        status.as_u16(),
+    );
+
+    result.map(|_| HttpResponse::NoContent().finish())
+}
+
+async fn publish(
+    req: &HttpRequest,
+    registry: &ChannelRegistry,
+    dispatch: &DispatchHandle,
+    limits: &RateLimits,
+    event: &str,
+    body: web::Bytes,
+) -> Result<()> {
+    if let Some(limiter) = &limits.publish {
+        limiter.check(req)?;
+    }
+    let conf = registry
+        .get(req.match_info().query("id"))
+        .filter(|entry| entry.conf.publish.enabled)
+        .ok_or(Error::SubscriptionNotFound)?
+        .conf;
+
+    check_api_key(&conf.publish, req)?;
+
+    if !conf.allowed_events.iter().any(|e| e.matches(event))
+        || conf.deny_events.iter().any(|e| e.matches(event))
+    {
+        return Err(Error::InvalidRequest(format!(
+            "Event '{event}' is not allowed"
+        )));
+    }
+    if body.len() > conf.publish.max_payload_size
+        || (conf.payload_size_policy == PayloadSizePolicy::RejectAtPublish
+            && conf.max_payload_size.is_some_and(|size| body.len() > size))
+    {
+        return Err(Error::PayloadTooLarge);
+    }
+    let payload = String::from_utf8(body.to_vec())
+        .map_err(|_| Error::InvalidRequest("Payload is not valid UTF-8".into()))?;
+
+    log::debug!(
+        "PUBLISH({}) {event} (request: '{}')",
+        conf.id,
+        request_id(req)
+    );
+    dispatch.publish(conf.id, event.into(), payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header, test::TestRequest};
+
+    #[test]
+    fn publish_api_key() {
+        let conf: PublishConfig = toml::from_str(
+            r#"
+            enabled = true
+            api_keys = ["secret"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.max_payload_size, 7999);
+
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(
+            check_api_key(&conf, &req),
+            Err(Error::Unauthorized)
+        ));
+
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Key other"))
+            .to_http_request();
+        assert!(matches!(check_api_key(&conf, &req), Err(Error::Forbidden)));
+
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Key secret"))
+            .to_http_request();
+        assert!(check_api_key(&conf, &req).is_ok());
+    }
+}
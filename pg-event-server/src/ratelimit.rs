@@ -0,0 +1,209 @@
+//!
+//! Rate limiting
+//!
+//! Requests are limited with token buckets per client
+//! ip and per client id. Buckets are shared across all workers.
+//!
+use actix_web::{web, HttpRequest};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{server::proxy::TrustedProxies, server::tls::PeerIdentity, Error, Result};
+
+/// Number of buckets above which full buckets
+/// are evicted
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+///
+/// Rate limit configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    /// Number of requests allowed per second
+    pub rate: f64,
+    /// Maximum number of requests allowed in a burst
+    pub burst: u32,
+}
+
+impl RateLimitConfig {
+    pub fn check(&self) -> Result<()> {
+        if !self.rate.is_finite() || self.rate <= 0. {
+            return Err(Error::Config(format!(
+                "Rate limit: rate must be a positive number, found {}",
+                self.rate
+            )));
+        }
+        Ok(())
+    }
+}
+
+///
+/// Rate limits of the endpoints
+///
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateLimitsConfig {
+    /// Limits of subscription requests
+    pub subscribe: Option<RateLimitConfig>,
+    /// Limits of publish requests
+    pub publish: Option<RateLimitConfig>,
+}
+
+impl RateLimitsConfig {
+    pub fn check(&self) -> Result<()> {
+        [&self.subscribe, &self.publish]
+            .into_iter()
+            .flatten()
+            .try_for_each(RateLimitConfig::check)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter
+#[derive(Clone)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(conf: &RateLimitConfig) -> Self {
+        Self {
+            rate: conf.rate,
+            burst: conf.burst.max(1).into(),
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Take a token from the bucket of each key
+    ///
+    /// Return the delay before a token is available if
+    /// any of the buckets is empty.
+    fn acquire(&self, keys: &[String], now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let mut wait = Duration::ZERO;
+        for key in keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: self.burst,
+                updated: now,
+            });
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+            bucket.updated = now;
+            if bucket.tokens < 1.0 {
+                let missing = (1.0 - bucket.tokens) / self.rate;
+                wait = wait.max(Duration::try_from_secs_f64(missing).unwrap_or(Duration::MAX));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for key in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the rate limit of the request
+    ///
+    /// Requests are limited by client ip and by client id,
+    /// the client id is taken from the client certificate or
+    /// from the `X-Identity` header.
+    pub fn check(&self, req: &HttpRequest) -> Result<()> {
+        let mut keys = Vec::with_capacity(2);
+        if let Some(ip) = req
+            .app_data::<web::Data<TrustedProxies>>()
+            .and_then(|proxies| proxies.client_ip(req))
+        {
+            keys.push(format!("ip:{ip}"));
+        }
+        if let Some(id) = req
+            .conn_data::<PeerIdentity>()
+            .map(|PeerIdentity(id)| id.as_str())
+            .or_else(|| {
+                req.headers()
+                    .get("X-Identity")
+                    .and_then(|v| v.to_str().ok())
+            })
+        {
+            keys.push(format!("id:{id}"));
+        }
+        self.acquire(&keys, Instant::now()).map_err(|wait| {
+            log::warn!("Rate limit exceeded for {}", keys.join(", "));
+            Error::RateLimited(wait.as_secs_f64().ceil() as u64)
+        })
+    }
+}
+
+/// Rate limiters of the endpoints
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    pub subscribe: Option<RateLimiter>,
+    pub publish: Option<RateLimiter>,
+}
+
+impl RateLimits {
+    pub fn new(conf: &RateLimitsConfig) -> Self {
+        Self {
+            subscribe: conf.subscribe.as_ref().map(RateLimiter::new),
+            publish: conf.publish.as_ref().map(RateLimiter::new),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            rate: 2.0,
+            burst: 3,
+        });
+        let keys = ["ip:127.0.0.1".to_string(), "id:foo".to_string()];
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.acquire(&keys, now).is_ok());
+        }
+        assert_eq!(limiter.acquire(&keys, now), Err(Duration::from_millis(500)));
+        // Other clients are not limited
+        assert!(limiter.acquire(&["ip:::1".to_string()], now).is_ok());
+        // Refilled
+        assert!(limiter
+            .acquire(&keys, now + Duration::from_millis(500))
+            .is_ok());
+        assert!(limiter
+            .acquire(&keys, now + Duration::from_millis(500))
+            .is_err());
+    }
+
+    #[test]
+    fn rate_limit_config() {
+        let conf: RateLimitsConfig =
+            toml::from_str("subscribe = { rate = 0.5, burst = 2 }").unwrap();
+        assert!(conf.check().is_ok());
+
+        for rate in ["0", "-1.0", "inf", "nan"] {
+            let conf: RateLimitsConfig =
+                toml::from_str(&format!("publish = {{ rate = {rate}, burst = 2 }}")).unwrap();
+            assert!(conf.check().is_err(), "rate = {rate}");
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//!
+//! Shared registry of configured channels
+//!
+//! The registry is updated by the event dispatcher when
+//! channels are added or removed at runtime. Workers compare
+//! the registry generation with their own to know when
+//! their subscriptions must be updated.
+//!
+use std::sync::{Arc, RwLock};
+
+use crate::config::ChannelConfig;
+use crate::events::ChanId;
+
+/// Registry entry
+#[derive(Clone)]
+pub struct Entry {
+    pub chan_id: ChanId,
+    /// Generation of the last change
+    /// of the configuration
+    pub version: u64,
+    pub conf: ChannelConfig,
+}
+
+impl Entry {
+    /// Return true if `id` is the channel
+    /// id or one of its aliases
+    pub fn is(&self, id: &str) -> bool {
+        self.conf.id == id || self.conf.aliases.iter().any(|alias| alias == id)
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    generation: u64,
+    next_id: ChanId,
+    channels: Vec<Entry>,
+}
+
+/// Shared channel registry
+#[derive(Default, Clone)]
+pub struct ChannelRegistry(Arc<RwLock<Inner>>);
+
+impl ChannelRegistry {
+    /// Return the current generation of the registry
+    ///
+    /// The generation is incremented on each change.
+    pub fn generation(&self) -> u64 {
+        self.0.read().unwrap().generation
+    }
+
+    /// Return a snapshot of the channels
+    pub fn channels(&self) -> Vec<Entry> {
+        self.0.read().unwrap().channels.clone()
+    }
+
+    /// Return a snapshot of the channels along
+    /// with the registry generation
+    pub fn snapshot(&self) -> (u64, Vec<Entry>) {
+        let inner = self.0.read().unwrap();
+        (inner.generation, inner.channels.clone())
+    }
+
+    /// Return the channel with `id` or
+    /// with the alias `id`
+    pub fn get(&self, id: &str) -> Option<Entry> {
+        self.0
+            .read()
+            .unwrap()
+            .channels
+            .iter()
+            .find(|e| e.is(id))
+            .cloned()
+    }
+
+    /// Return the id of the channel `chan_id`
+    pub fn id_of(&self, chan_id: ChanId) -> Option<String> {
+        self.0
+            .read()
+            .unwrap()
+            .channels
+            .iter()
+            .find(|e| e.chan_id == chan_id)
+            .map(|e| e.conf.id.clone())
+    }
+
+    /// Return true if a channel with `id`
+    /// or with the alias `id` exists
+    pub fn contains(&self, id: &str) -> bool {
+        self.0.read().unwrap().channels.iter().any(|e| e.is(id))
+    }
+
+    /// Add channel and return its internal id
+    pub fn insert(&self, conf: ChannelConfig) -> ChanId {
+        let mut inner = self.0.write().unwrap();
+        let chan_id = inner.next_id;
+        inner.next_id += 1;
+        inner.generation += 1;
+        let version = inner.generation;
+        inner.channels.push(Entry {
+            chan_id,
+            version,
+            conf,
+        });
+        chan_id
+    }
+
+    /// Update the configuration of an existing channel
+    ///
+    /// The channel keeps its internal id.
+    pub fn update(&self, conf: ChannelConfig) -> Option<ChanId> {
+        let mut inner = self.0.write().unwrap();
+        let version = inner.generation + 1;
+        let entry = inner.channels.iter_mut().find(|e| e.conf.id == conf.id)?;
+        entry.version = version;
+        entry.conf = conf;
+        let chan_id = entry.chan_id;
+        inner.generation = version;
+        Some(chan_id)
+    }
+
+    /// Remove the channel with `id`
+    pub fn remove(&self, id: &str) -> Option<Entry> {
+        let mut inner = self.0.write().unwrap();
+        let index = inner.channels.iter().position(|e| e.conf.id == id)?;
+        inner.generation += 1;
+        Some(inner.channels.remove(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_changes() {
+        let registry = ChannelRegistry::default();
+
+        let foo = registry.insert(toml::from_str(r#"id = "foo""#).unwrap());
+        let bar = registry.insert(toml::from_str(r#"id = "bar""#).unwrap());
+        assert_ne!(foo, bar);
+        assert!(registry.contains("foo"));
+
+        let generation = registry.generation();
+        assert_eq!(
+            registry.update(toml::from_str(r#"id = "bar""#).unwrap()),
+            Some(bar)
+        );
+        assert_eq!(registry.get("bar").unwrap().version, registry.generation());
+        assert!(registry
+            .update(toml::from_str(r#"id = "baz""#).unwrap())
+            .is_none());
+
+        assert_eq!(registry.remove("foo").map(|e| e.chan_id), Some(foo));
+        assert!(registry.remove("foo").is_none());
+        assert!(registry.generation() > generation);
+
+        // Ids are not reused
+        let baz = registry.insert(toml::from_str(r#"id = "baz""#).unwrap());
+        assert!(baz != foo && baz != bar);
+        assert_eq!(registry.channels().len(), 2);
+
+        let conf = toml::from_str("id = 'bar'\naliases = ['old-bar']").unwrap();
+        registry.update(conf);
+        assert_eq!(registry.get("old-bar").map(|e| e.chan_id), Some(bar));
+        assert!(registry.contains("old-bar"));
+    }
+}
@@ -0,0 +1,76 @@
+//!
+//! Configuration reload
+//!
+//! The configuration is reloaded on `SIGHUP` or with
+//! the `/admin/reload` endpoint. Only channels and TLS
+//! material are reloaded, other settings require a restart.
+//!
+use actix_web::{web, HttpResponse, Responder};
+use std::path::PathBuf;
+
+use crate::{
+    config::{self, Overrides},
+    events::DispatchHandle,
+    server::tls::ServerCerts,
+    Result,
+};
+
+/// Reload the configuration from file
+pub struct Reloader {
+    path: PathBuf,
+    overrides: Overrides,
+    dispatch: DispatchHandle,
+    certs: Option<ServerCerts>,
+}
+
+impl Reloader {
+    pub fn new(
+        path: PathBuf,
+        overrides: Overrides,
+        dispatch: DispatchHandle,
+        certs: Option<ServerCerts>,
+    ) -> Self {
+        Self {
+            path,
+            overrides,
+            dispatch,
+            certs,
+        }
+    }
+
+    /// Re-read the configuration and apply changes
+    ///
+    /// Subscribers of channels that still exist
+    /// are not disconnected.
+    pub async fn reload(&self) -> Result<()> {
+        log::info!("Reloading configuration from {}", self.path.display());
+        let conf = config::read_config(&self.path, &self.overrides)?;
+        if let Some(certs) = &self.certs {
+            certs.reload(&conf.settings.server)?;
+        }
+        let tls = conf.settings.postgres_tls.make_tls_connect()?;
+        self.dispatch.reload(conf.settings.channels, tls).await
+    }
+}
+
+/// Reload the configuration on `SIGHUP`
+#[cfg(unix)]
+pub fn reload_on_sighup(reloader: web::Data<Reloader>) -> Result<()> {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    actix_web::rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if let Err(err) = reloader.reload().await {
+                log::error!("Failed to reload configuration: {err}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reload handler
+pub async fn handler(reloader: web::Data<Reloader>) -> Result<impl Responder> {
+    reloader.reload().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
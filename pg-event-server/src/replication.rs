@@ -0,0 +1,744 @@
+//!
+//! Logical replication source
+//!
+//! Channels with a `replication` section receive the row changes
+//! decoded from a logical replication slot instead of notifications.
+//!
+//! The slot is polled with the SQL interface of logical decoding
+//! (`pg_logical_slot_peek_changes`) and advanced once the changes
+//! are forwarded to the dispatcher: changes are not lost while
+//! the server is down or disconnected, and changes forwarded just
+//! before a failure may be delivered twice.
+//!
+//! Changes are sent as json events:
+//!
+//! ```json
+//! {"table": "public.mytable", "db": "mydb", "op": "UPDATE", "data": {...}, "old": {...}, "lsn": "0/16B3748"}
+//! ```
+//!
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+use crate::{Error, Result};
+
+/// Logical decoding output plugin
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputPlugin {
+    /// Builtin plugin, requires a publication
+    #[default]
+    Pgoutput,
+    /// The wal2json extension
+    Wal2json,
+}
+
+impl OutputPlugin {
+    #[cfg_attr(not(feature = "replication"), allow(dead_code))]
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pgoutput => "pgoutput",
+            Self::Wal2json => "wal2json",
+        }
+    }
+}
+
+/// Row change operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+    Truncate,
+}
+
+impl Operation {
+    #[cfg_attr(not(feature = "replication"), allow(dead_code))]
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Insert => "INSERT",
+            Self::Update => "UPDATE",
+            Self::Delete => "DELETE",
+            Self::Truncate => "TRUNCATE",
+        }
+    }
+}
+
+/// Routing of the changes of a table
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Route {
+    /// Table, optionally schema qualified
+    pub table: String,
+    /// Routed operations, all operations if empty
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+    /// Event name, default to the qualified table name
+    pub event: Option<String>,
+}
+
+impl Route {
+    /// Return true if the route matches
+    /// the operation `op` on `schema.table`
+    #[cfg_attr(not(feature = "replication"), allow(dead_code))]
+    fn matches(&self, schema: &str, table: &str, op: Operation) -> bool {
+        let name_matches = match self.table.split_once('.') {
+            Some((s, t)) => s == schema && t == table,
+            None => self.table == table,
+        };
+        name_matches && (self.operations.is_empty() || self.operations.contains(&op))
+    }
+}
+
+const fn default_poll_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+const fn default_batch_size() -> i32 {
+    1000
+}
+
+///
+/// Replication source configuration
+///
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ReplicationConfig {
+    /// Name of the logical replication slot
+    pub slot: String,
+    /// Output plugin of the slot
+    #[serde(default)]
+    pub plugin: OutputPlugin,
+    /// Publications decoded by `pgoutput`
+    #[serde(default)]
+    pub publications: Vec<String>,
+    /// Create the slot if it does not exist
+    #[serde(default)]
+    pub create_slot: bool,
+    /// Delay between polls of an idle slot
+    #[serde(default = "default_poll_interval", with = "humantime_serde")]
+    pub poll_interval: Duration,
+    /// Maximum number of changes fetched per poll
+    #[serde(default = "default_batch_size")]
+    pub batch_size: i32,
+    /// Routing of the changes, all changes are
+    /// forwarded if empty
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+impl ReplicationConfig {
+    /// Check the configuration of the channel `id`
+    pub fn check(&self, id: &str) -> Result<()> {
+        if !cfg!(feature = "replication") {
+            return Err(Error::Config(format!(
+                "Channel '{id}': logical replication requires the 'replication' feature"
+            )));
+        }
+        if self.plugin == OutputPlugin::Pgoutput && self.publications.is_empty() {
+            return Err(Error::Config(format!(
+                "Channel '{id}': the pgoutput plugin requires publications"
+            )));
+        }
+        if self.batch_size <= 0 {
+            return Err(Error::Config(format!(
+                "Channel '{id}': batch_size must be positive"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Return the event name of the operation `op`
+    /// on `schema.table`
+    ///
+    /// Return `None` if the change is not routed.
+    #[cfg_attr(not(feature = "replication"), allow(dead_code))]
+    fn route(&self, schema: &str, table: &str, op: Operation) -> Option<String> {
+        if self.routes.is_empty() {
+            return Some(format!("{schema}.{table}"));
+        }
+        self.routes
+            .iter()
+            .find(|route| route.matches(schema, table, op))
+            .map(|route| {
+                route
+                    .event
+                    .clone()
+                    .unwrap_or_else(|| format!("{schema}.{table}"))
+            })
+    }
+}
+
+/// Row change decoded from the slot
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "replication"), allow(dead_code))]
+struct Change {
+    schema: String,
+    table: String,
+    op: Operation,
+    /// New row values
+    new: Option<Map<String, Value>>,
+    /// Old row values or replica identity
+    old: Option<Map<String, Value>>,
+}
+
+impl Change {
+    /// Return the json payload of the change
+    #[cfg_attr(not(feature = "replication"), allow(dead_code))]
+    fn payload(self, lsn: &str, db: &str) -> String {
+        let mut payload = Map::new();
+        payload.insert(
+            "table".into(),
+            format!("{}.{}", self.schema, self.table).into(),
+        );
+        payload.insert("db".into(), db.into());
+        payload.insert("op".into(), self.op.as_str().into());
+        // Deleted rows are sent as data
+        let (data, old) = match self.op {
+            Operation::Delete => (self.old, None),
+            _ => (self.new, self.old),
+        };
+        payload.insert("data".into(), data.map_or(Value::Null, Value::Object));
+        if let Some(old) = old {
+            payload.insert("old".into(), Value::Object(old));
+        }
+        payload.insert("lsn".into(), lsn.into());
+        Value::Object(payload).to_string()
+    }
+}
+
+/// Running replication source
+///
+/// The source is stopped when dropped.
+pub struct ReplicationSource(tokio::task::JoinHandle<()>);
+
+impl Drop for ReplicationSource {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(feature = "replication")]
+pub use source::start;
+
+#[cfg(feature = "replication")]
+mod source {
+    use pg_event_listener::Config;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio_postgres::Client;
+
+    use super::{Change, OutputPlugin, ReplicationConfig, ReplicationSource};
+    use crate::{
+        pool::{PgNotification, PgNotificationDispatch},
+        postgres::tls::PgTlsConnect,
+        Error, Result,
+    };
+
+    /// Start polling the slot of the channel `id`
+    ///
+    /// Decoded changes are sent to `tx` with `dispatch_id`. The
+    /// connection is retried after `retry_delay` on failure.
+    pub fn start(
+        id: String,
+        conf: ReplicationConfig,
+        pgconfig: Config,
+        tls: PgTlsConnect,
+        dispatch_id: i32,
+        tx: mpsc::Sender<PgNotificationDispatch>,
+        retry_delay: Duration,
+    ) -> ReplicationSource {
+        ReplicationSource(actix_web::rt::spawn(async move {
+            let mut relations = decode::Relations::default();
+            loop {
+                match poll(&conf, &pgconfig, &tls, dispatch_id, &tx, &mut relations).await {
+                    Ok(()) => break,
+                    Err(err) => {
+                        log::error!(
+                            "Channel '{id}': replication from slot '{}' failed: {err:?}",
+                            conf.slot
+                        );
+                        actix_web::rt::time::sleep(retry_delay).await;
+                    }
+                }
+            }
+            log::debug!("Channel '{id}': replication source stopped");
+        }))
+    }
+
+    /// Connect and poll the slot
+    ///
+    /// Return when the dispatcher is gone.
+    async fn poll(
+        conf: &ReplicationConfig,
+        pgconfig: &Config,
+        tls: &PgTlsConnect,
+        dispatch_id: i32,
+        tx: &mpsc::Sender<PgNotificationDispatch>,
+        relations: &mut decode::Relations,
+    ) -> Result<()> {
+        let (client, connection) = pgconfig.connect(tls.clone()).await?;
+        actix_web::rt::spawn(async move {
+            if let Err(err) = connection.await {
+                log::error!("Replication connection closed: {err}");
+            }
+        });
+        let row = client
+            .query_one("SELECT pg_backend_pid(), current_database()", &[])
+            .await?;
+        let (session_pid, db): (i32, String) = (row.get(0), row.get(1));
+        if conf.create_slot {
+            create_slot(&client, conf).await?;
+        }
+        log::info!(
+            "Polling replication slot '{}' (backend session: {session_pid})",
+            conf.slot
+        );
+
+        let publications = conf.publications.join(",");
+        let peek = client
+            .prepare(match conf.plugin {
+                OutputPlugin::Pgoutput => {
+                    "SELECT lsn::text, data FROM pg_logical_slot_peek_binary_changes(\
+                        $1, NULL, $2, 'proto_version', '1', 'publication_names', $3)"
+                }
+                OutputPlugin::Wal2json => {
+                    "SELECT lsn::text, convert_to(data, 'UTF8') FROM pg_logical_slot_peek_changes(\
+                        $1, NULL, $2, 'format-version', '2')"
+                }
+            })
+            .await?;
+        let advance = client
+            .prepare("SELECT pg_replication_slot_advance($1, $2::text::pg_lsn)")
+            .await?;
+        loop {
+            let rows = match conf.plugin {
+                OutputPlugin::Pgoutput => {
+                    client
+                        .query(&peek, &[&conf.slot, &conf.batch_size, &publications])
+                        .await?
+                }
+                OutputPlugin::Wal2json => {
+                    client.query(&peek, &[&conf.slot, &conf.batch_size]).await?
+                }
+            };
+            let Some(last) = rows.last() else {
+                actix_web::rt::time::sleep(conf.poll_interval).await;
+                continue;
+            };
+            let last_lsn: String = last.get(0);
+            for row in &rows {
+                let lsn: &str = row.get(0);
+                let data: &[u8] = row.get(1);
+                let changes = match conf.plugin {
+                    OutputPlugin::Pgoutput => relations.decode(data),
+                    OutputPlugin::Wal2json => decode::wal2json(data),
+                }
+                .map_err(|err| Error::Replication(format!("{lsn}: {err}")))?;
+                for notification in changes
+                    .into_iter()
+                    .filter_map(|change| notify(conf, change, lsn, &db, session_pid))
+                {
+                    if tx
+                        .send(PgNotificationDispatch::new(notification, dispatch_id))
+                        .await
+                        .is_err()
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+            // Batches end on transaction boundaries
+            client.execute(&advance, &[&conf.slot, &last_lsn]).await?;
+        }
+    }
+
+    /// Create the slot if it does not exist
+    async fn create_slot(client: &Client, conf: &ReplicationConfig) -> Result<()> {
+        let exists = client
+            .query_opt(
+                "SELECT 1 FROM pg_replication_slots WHERE slot_name = $1",
+                &[&conf.slot],
+            )
+            .await?
+            .is_some();
+        if !exists {
+            client
+                .execute(
+                    "SELECT pg_create_logical_replication_slot($1, $2)",
+                    &[&conf.slot, &conf.plugin.as_str()],
+                )
+                .await?;
+            log::info!("Created replication slot '{}'", conf.slot);
+        }
+        Ok(())
+    }
+
+    /// Return the notification of a routed change
+    fn notify(
+        conf: &ReplicationConfig,
+        change: Change,
+        lsn: &str,
+        db: &str,
+        session_pid: i32,
+    ) -> Option<PgNotification> {
+        match conf.route(&change.schema, &change.table, change.op) {
+            Some(event) => Some(PgNotification::new(
+                session_pid,
+                event,
+                change.payload(lsn, db),
+            )),
+            None => {
+                log::trace!(
+                    "Ignoring {} on {}.{}",
+                    change.op.as_str(),
+                    change.schema,
+                    change.table
+                );
+                None
+            }
+        }
+    }
+
+    /// Output plugins decoding
+    pub(super) mod decode {
+        use serde::Deserialize;
+        use serde_json::{Map, Value};
+
+        use super::{Change, HashMap};
+        use crate::replication::Operation;
+
+        // Type oids with a json representation
+        const BOOL: u32 = 16;
+        const INT8: u32 = 20;
+        const INT2: u32 = 21;
+        const INT4: u32 = 23;
+        const OID: u32 = 26;
+        const JSON: u32 = 114;
+        const FLOAT4: u32 = 700;
+        const FLOAT8: u32 = 701;
+        const NUMERIC: u32 = 1700;
+        const JSONB: u32 = 3802;
+
+        type DecodeResult<T> = std::result::Result<T, String>;
+
+        struct Relation {
+            schema: String,
+            name: String,
+            /// Column names and type oids
+            columns: Vec<(String, u32)>,
+        }
+
+        impl Relation {
+            fn change(&self, op: Operation) -> Change {
+                Change {
+                    schema: self.schema.clone(),
+                    table: self.name.clone(),
+                    op,
+                    new: None,
+                    old: None,
+                }
+            }
+        }
+
+        /// Relations announced by `pgoutput`
+        #[derive(Default)]
+        pub struct Relations(HashMap<u32, Relation>);
+
+        /// Reader of a `pgoutput` message
+        struct Reader<'a>(&'a [u8]);
+
+        impl<'a> Reader<'a> {
+            fn bytes(&mut self, n: usize) -> DecodeResult<&'a [u8]> {
+                if self.0.len() < n {
+                    return Err("Unexpected end of message".into());
+                }
+                let (bytes, rest) = self.0.split_at(n);
+                self.0 = rest;
+                Ok(bytes)
+            }
+            fn u8(&mut self) -> DecodeResult<u8> {
+                Ok(self.bytes(1)?[0])
+            }
+            fn i16(&mut self) -> DecodeResult<i16> {
+                Ok(i16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+            }
+            fn u32(&mut self) -> DecodeResult<u32> {
+                Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+            }
+            fn string(&mut self) -> DecodeResult<String> {
+                let len = self
+                    .0
+                    .iter()
+                    .position(|b| *b == 0)
+                    .ok_or("Unterminated string")?;
+                let s = String::from_utf8_lossy(self.bytes(len)?).into_owned();
+                self.bytes(1)?;
+                Ok(s)
+            }
+        }
+
+        /// Convert the text representation of a value
+        fn value(type_oid: u32, text: &str) -> Value {
+            match type_oid {
+                BOOL => Value::Bool(text == "t"),
+                INT2 | INT4 | INT8 | OID | FLOAT4 | FLOAT8 | NUMERIC | JSON | JSONB => {
+                    // NaN and infinite values are kept as strings
+                    serde_json::from_str(text).unwrap_or_else(|_| text.into())
+                }
+                _ => text.into(),
+            }
+        }
+
+        impl Relations {
+            /// Decode a `pgoutput` message
+            ///
+            /// Messages other than row changes are
+            /// decoded as no change.
+            pub fn decode(&mut self, data: &[u8]) -> DecodeResult<Vec<Change>> {
+                let mut msg = Reader(data);
+                let op = match msg.u8()? {
+                    b'R' => {
+                        let oid = msg.u32()?;
+                        let schema = msg.string()?;
+                        let name = msg.string()?;
+                        let _replica_identity = msg.u8()?;
+                        let columns = (0..msg.i16()?)
+                            .map(|_| {
+                                let _flags = msg.u8()?;
+                                let name = msg.string()?;
+                                let type_oid = msg.u32()?;
+                                let _type_modifier = msg.u32()?;
+                                Ok((name, type_oid))
+                            })
+                            .collect::<DecodeResult<_>>()?;
+                        self.0.insert(
+                            oid,
+                            Relation {
+                                schema,
+                                name,
+                                columns,
+                            },
+                        );
+                        return Ok(vec![]);
+                    }
+                    b'I' => Operation::Insert,
+                    b'U' => Operation::Update,
+                    b'D' => Operation::Delete,
+                    b'T' => {
+                        let count = msg.u32()?;
+                        let _options = msg.u8()?;
+                        return (0..count)
+                            .map(|_| Ok(self.relation(msg.u32()?)?.change(Operation::Truncate)))
+                            .collect();
+                    }
+                    _ => return Ok(vec![]),
+                };
+                let relation = self.relation(msg.u32()?)?;
+                let mut change = relation.change(op);
+                while !msg.0.is_empty() {
+                    let tuple = match msg.u8()? {
+                        b'N' => &mut change.new,
+                        b'K' | b'O' => &mut change.old,
+                        kind => return Err(format!("Unexpected tuple type {kind}")),
+                    };
+                    *tuple = Some(Self::tuple(&mut msg, relation)?);
+                }
+                Ok(vec![change])
+            }
+
+            fn relation(&self, oid: u32) -> DecodeResult<&Relation> {
+                self.0
+                    .get(&oid)
+                    .ok_or_else(|| format!("Unknown relation {oid}"))
+            }
+
+            fn tuple(msg: &mut Reader, relation: &Relation) -> DecodeResult<Map<String, Value>> {
+                let count = msg.i16()? as usize;
+                let mut values = Map::new();
+                for (name, type_oid) in relation.columns.iter().take(count) {
+                    match msg.u8()? {
+                        b'n' => {
+                            values.insert(name.clone(), Value::Null);
+                        }
+                        // Unchanged toasted value
+                        b'u' => (),
+                        b't' => {
+                            let len = msg.u32()? as usize;
+                            let text = String::from_utf8_lossy(msg.bytes(len)?);
+                            values.insert(name.clone(), value(*type_oid, &text));
+                        }
+                        kind => return Err(format!("Unexpected column kind {kind}")),
+                    }
+                }
+                Ok(values)
+            }
+        }
+
+        /// `wal2json` column
+        #[derive(Deserialize)]
+        struct Column {
+            name: String,
+            value: Value,
+        }
+
+        /// `wal2json` change, format version 2
+        #[derive(Deserialize)]
+        struct Wal2jsonChange {
+            action: String,
+            #[serde(default)]
+            schema: String,
+            #[serde(default)]
+            table: String,
+            columns: Option<Vec<Column>>,
+            identity: Option<Vec<Column>>,
+        }
+
+        fn columns(columns: Option<Vec<Column>>) -> Option<Map<String, Value>> {
+            columns.map(|columns| columns.into_iter().map(|c| (c.name, c.value)).collect())
+        }
+
+        /// Decode a `wal2json` change
+        ///
+        /// Messages other than row changes are
+        /// decoded as no change.
+        pub fn wal2json(data: &[u8]) -> DecodeResult<Vec<Change>> {
+            let change: Wal2jsonChange =
+                serde_json::from_slice(data).map_err(|err| err.to_string())?;
+            let op = match change.action.as_str() {
+                "I" => Operation::Insert,
+                "U" => Operation::Update,
+                "D" => Operation::Delete,
+                "T" => Operation::Truncate,
+                _ => return Ok(vec![]),
+            };
+            Ok(vec![Change {
+                schema: change.schema,
+                table: change.table,
+                op,
+                new: columns(change.columns),
+                old: columns(change.identity),
+            }])
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn relation() -> Vec<u8> {
+                let mut msg = b"R".to_vec();
+                msg.extend(16384u32.to_be_bytes());
+                msg.extend(b"public\0items\0d");
+                msg.extend(2i16.to_be_bytes());
+                for (name, oid) in [("id", INT4), ("name", 25)] {
+                    msg.push(1);
+                    msg.extend(name.as_bytes());
+                    msg.push(0);
+                    msg.extend(oid.to_be_bytes());
+                    msg.extend((-1i32).to_be_bytes());
+                }
+                msg
+            }
+
+            #[test]
+            fn pgoutput_changes() {
+                let mut relations = Relations::default();
+                assert_eq!(relations.decode(&relation()), Ok(vec![]));
+                assert_eq!(relations.decode(b"B"), Ok(vec![]));
+
+                let mut msg = b"U".to_vec();
+                msg.extend(16384u32.to_be_bytes());
+                msg.push(b'N');
+                msg.extend(2i16.to_be_bytes());
+                msg.push(b't');
+                msg.extend(2u32.to_be_bytes());
+                msg.extend(b"42");
+                msg.push(b'n');
+
+                let change = relations.decode(&msg).unwrap().remove(0);
+                assert_eq!(change.op, Operation::Update);
+                assert_eq!(change.table, "items");
+                let new = Value::Object(change.new.unwrap());
+                assert_eq!(new, serde_json::json!({"id": 42, "name": null}));
+                assert!(change.old.is_none());
+
+                // Truncated message
+                assert!(relations.decode(&msg[..msg.len() - 2]).is_err());
+                // Unknown relation
+                assert!(relations.decode(b"D\0\0\0\x01").is_err());
+
+                let mut msg = b"T".to_vec();
+                msg.extend(1u32.to_be_bytes());
+                msg.push(0);
+                msg.extend(16384u32.to_be_bytes());
+                let changes = relations.decode(&msg).unwrap();
+                assert_eq!(changes.len(), 1);
+                assert_eq!(changes[0].op, Operation::Truncate);
+            }
+
+            #[test]
+            fn wal2json_changes() {
+                let change = wal2json(
+                    br#"{"action":"D","schema":"public","table":"items",
+                        "identity":[{"name":"id","type":"integer","value":1}]}"#,
+                )
+                .unwrap()
+                .remove(0);
+                assert_eq!(change.op, Operation::Delete);
+                let payload: Value = serde_json::from_str(&change.payload("0/1", "test")).unwrap();
+                assert_eq!(
+                    payload,
+                    serde_json::json!({
+                        "table": "public.items",
+                        "db": "test",
+                        "op": "DELETE",
+                        "data": {"id": 1},
+                        "lsn": "0/1",
+                    })
+                );
+                assert_eq!(wal2json(br#"{"action":"B"}"#), Ok(vec![]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replication_routes() {
+        let conf: ReplicationConfig = toml::from_str(
+            r#"
+            slot = "test"
+            [[routes]]
+            table = "public.items"
+            operations = ["insert"]
+            event = "new_items"
+            [[routes]]
+            table = "orders"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.plugin, OutputPlugin::Pgoutput);
+        assert_eq!(
+            conf.route("public", "items", Operation::Insert).as_deref(),
+            Some("new_items")
+        );
+        assert_eq!(conf.route("public", "items", Operation::Delete), None);
+        assert_eq!(
+            conf.route("sales", "orders", Operation::Delete).as_deref(),
+            Some("sales.orders")
+        );
+        assert_eq!(conf.route("public", "other", Operation::Insert), None);
+
+        let conf = ReplicationConfig {
+            routes: vec![],
+            ..conf
+        };
+        assert_eq!(
+            conf.route("public", "other", Operation::Insert).as_deref(),
+            Some("public.other")
+        );
+    }
+}
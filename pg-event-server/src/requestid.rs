@@ -0,0 +1,109 @@
+//!
+//! Request id
+//!
+//! Each request is assigned an id taken from the `X-Request-ID`
+//! header, or from the trace id of the `traceparent` header,
+//! or generated if absent.
+//!
+//! The id is returned in the `X-Request-ID` response header
+//! and is included in the log records of the request.
+//!
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    HttpMessage, HttpRequest,
+};
+use actix_web_lab::middleware::Next;
+use uuid::Uuid;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const TRACEPARENT: &str = "traceparent";
+
+// Maximum length of client provided ids
+const MAX_LENGTH: usize = 128;
+
+/// The request id stored in the request extensions
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Return the request id of `req`
+pub fn request_id(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<RequestId>()
+        .map(|RequestId(id)| id.clone())
+        .unwrap_or_default()
+}
+
+// Return the id provided by the client
+fn from_headers(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| {
+            !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map(String::from)
+        .or_else(|| {
+            // version-traceid-parentid-flags
+            headers
+                .get(TRACEPARENT)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split('-').nth(1))
+                .filter(|id| {
+                    id.len() == 32
+                        && id.bytes().all(|b| b.is_ascii_hexdigit())
+                        && id.bytes().any(|b| b != b'0')
+                })
+                .map(str::to_ascii_lowercase)
+        })
+}
+
+/// Middleware assigning the request id
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = from_headers(req.headers()).unwrap_or_else(|| Uuid::new_v4().to_string());
+    let value = HeaderValue::from_str(&id);
+    req.extensions_mut().insert(RequestId(id));
+    let mut res = next.call(req).await?;
+    if let Ok(value) = value {
+        res.headers_mut().insert(X_REQUEST_ID, value);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn request_id_headers() {
+        let req = TestRequest::default()
+            .insert_header((X_REQUEST_ID, "abc-123"))
+            .to_http_request();
+        assert_eq!(from_headers(req.headers()).as_deref(), Some("abc-123"));
+
+        let req = TestRequest::default()
+            .insert_header((X_REQUEST_ID, "not valid"))
+            .insert_header((
+                TRACEPARENT,
+                "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            ))
+            .to_http_request();
+        assert_eq!(
+            from_headers(req.headers()).as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+
+        let req = TestRequest::default()
+            .insert_header((
+                TRACEPARENT,
+                "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            ))
+            .to_http_request();
+        assert_eq!(from_headers(req.headers()), None);
+    }
+}
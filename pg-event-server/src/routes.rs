@@ -0,0 +1,174 @@
+//!
+//! Routing rules
+//!
+//! Routes forward the events of a channel to other channels
+//! selected from the payload content, i.e. for fanning out the
+//! events of a single notification channel to tenant channels:
+//!
+//! ```toml
+//! [[channel.routes]]
+//! event = "tasks"
+//! when = '$.kind == "task"'
+//! to = "tenant/{$.tenant_id}"
+//! ```
+//!
+//! The target is a template where `{<path>}` is replaced by the
+//! selected payload value and `{event}` by the postgres event name.
+//! No route is taken if a value is missing, is not a scalar or
+//! contains a `/`.
+//!
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::filter::{Filter, Path};
+use crate::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Event,
+    Path(Path),
+}
+
+#[derive(Deserialize)]
+struct RouteDef {
+    event: Option<String>,
+    when: Option<String>,
+    to: String,
+}
+
+/// Compiled routing rule
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "RouteDef")]
+pub struct Route {
+    /// Routed event, all events if not set
+    event: Option<String>,
+    /// Condition on the payload
+    when: Option<Filter>,
+    /// Target channel template
+    to: Vec<Part>,
+}
+
+impl TryFrom<RouteDef> for Route {
+    type Error = Error;
+
+    fn try_from(def: RouteDef) -> Result<Self, Error> {
+        let when = def
+            .when
+            .as_deref()
+            .map(Filter::parse)
+            .transpose()
+            .map_err(|err| Error::Config(format!("Invalid route condition: {err}")))?;
+        let mut to = vec![];
+        let mut rest = def.to.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or_else(|| {
+                Error::Config(format!("Unclosed '{{' in route target '{}'", def.to))
+            })? + start;
+            if start > 0 {
+                to.push(Part::Text(rest[..start].into()));
+            }
+            to.push(match rest[start + 1..end].trim() {
+                "event" => Part::Event,
+                path => Part::Path(Path::parse(path).map_err(|err| {
+                    Error::Config(format!("Invalid path in route target '{}': {err}", def.to))
+                })?),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            to.push(Part::Text(rest.into()));
+        }
+        Ok(Self {
+            event: def.event,
+            when,
+            to,
+        })
+    }
+}
+
+impl Route {
+    /// Return the target channel of `event`
+    ///
+    /// `json` is the payload parsed as json if valid.
+    pub fn target(&self, event: &str, json: Option<&Value>) -> Option<String> {
+        if self.event.as_deref().is_some_and(|e| e != event) {
+            return None;
+        }
+        if let Some(when) = &self.when {
+            if !json.is_some_and(|json| when.matches(json)) {
+                return None;
+            }
+        }
+        let mut target = String::new();
+        for part in &self.to {
+            match part {
+                Part::Text(text) => target.push_str(text),
+                Part::Event => target.push_str(event),
+                Part::Path(path) => {
+                    let value = match path.select(json?)? {
+                        Value::String(s) => s.clone(),
+                        value @ (Value::Number(_) | Value::Bool(_)) => value.to_string(),
+                        _ => return None,
+                    };
+                    if value.is_empty() || value.contains('/') {
+                        return None;
+                    }
+                    target.push_str(&value);
+                }
+            }
+        }
+        Some(target.trim_start_matches('/').into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn route(s: &str) -> Result<Route, toml::de::Error> {
+        toml::from_str(s)
+    }
+
+    #[test]
+    fn route_targets() {
+        let route = route(
+            r#"
+            event = "tasks"
+            when = '$.kind == "task"'
+            to = "tenant/{$.tenant_id}/{event}"
+            "#,
+        )
+        .unwrap();
+        let payload = json!({"kind": "task", "tenant_id": 42});
+        assert_eq!(
+            route.target("tasks", Some(&payload)).as_deref(),
+            Some("tenant/42/tasks")
+        );
+        assert!(route.target("jobs", Some(&payload)).is_none());
+        assert!(route.target("tasks", None).is_none());
+        assert!(route
+            .target("tasks", Some(&json!({"kind": "job", "tenant_id": 42})))
+            .is_none());
+        assert!(route
+            .target("tasks", Some(&json!({"kind": "task"})))
+            .is_none());
+        assert!(route
+            .target("tasks", Some(&json!({"kind": "task", "tenant_id": "a/b"})))
+            .is_none());
+    }
+
+    #[test]
+    fn route_errors() {
+        assert!(route(r#"to = "tenant/{$.id""#).is_err());
+        assert!(route(r#"to = "tenant/{id}""#).is_err());
+        assert!(route(
+            r#"
+            when = "$.kind =="
+            to = "tenant"
+            "#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,517 @@
+//!
+//! ACME certificate management
+//!
+//! The server certificate is obtained and renewed from an ACME
+//! provider (i.e Let's Encrypt) using the `tls-alpn-01` or the
+//! `http-01` challenge.
+//!
+//! The account credentials, the certificate and its key are stored
+//! in the state directory. A temporary self-signed certificate is used
+//! until the first certificate is issued.
+//!
+use rustls::sign::CertifiedKey;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::config::Server;
+use crate::errors::{Error, Result};
+use crate::server::tls::CertResolver;
+
+/// ALPN protocol of the `tls-alpn-01` challenge
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+fn default_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".into()
+}
+
+fn default_state_dir() -> PathBuf {
+    "acme".into()
+}
+
+fn default_http_listen() -> String {
+    "0.0.0.0:80".into()
+}
+
+const fn default_renew_before() -> Duration {
+    Duration::from_secs(30 * 24 * 3600)
+}
+
+/// ACME challenge type
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ChallengeType {
+    /// Challenge served on the TLS listening socket
+    #[default]
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+    /// Challenge served on plain http
+    #[serde(rename = "http-01")]
+    Http01,
+}
+
+///
+/// ACME configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+pub struct AcmeConfig {
+    /// Domain names of the certificate
+    pub domains: Vec<String>,
+    /// Contact emails of the account
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Directory url of the ACME provider
+    #[serde(default = "default_directory")]
+    pub directory: String,
+    /// Storage of the account credentials
+    /// and certificate
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+    /// Challenge type
+    #[serde(default)]
+    pub challenge: ChallengeType,
+    /// Address serving the `http-01` challenges
+    #[serde(default = "default_http_listen")]
+    pub http_listen: String,
+    /// Renew the certificate this long before expiration
+    #[serde(default = "default_renew_before", with = "humantime_serde")]
+    pub renew_before: Duration,
+}
+
+impl AcmeConfig {
+    /// Set the certificate files of the server
+    pub fn sanitize(&mut self, root: &Path, server: &mut Server) -> Result<()> {
+        if self.domains.is_empty() {
+            return Err(Error::Config("ACME requires at least one domain".into()));
+        }
+        if !server.ssl_enabled {
+            return Err(Error::Config("ACME requires 'ssl_enabled'".into()));
+        }
+        if server.ssl_cert_file.is_some() || server.ssl_key_file.is_some() {
+            return Err(Error::Config(
+                "'ssl_cert_file' and 'ssl_key_file' cannot be used with ACME".into(),
+            ));
+        }
+        if !self.state_dir.has_root() {
+            self.state_dir = root.join(&self.state_dir);
+        }
+        server.ssl_cert_file = Some(self.cert_file());
+        server.ssl_key_file = Some(self.key_file());
+        Ok(())
+    }
+
+    fn cert_file(&self) -> PathBuf {
+        self.state_dir.join("cert.pem")
+    }
+
+    fn key_file(&self) -> PathBuf {
+        self.state_dir.join("key.pem")
+    }
+}
+
+///
+/// Pending challenges
+///
+#[derive(Default)]
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+pub struct Challenges {
+    // Key authorizations by token
+    http: RwLock<HashMap<String, String>>,
+    // Challenge certificates by domain
+    alpn: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl Challenges {
+    /// Return the `tls-alpn-01` certificate for `domain`
+    pub fn alpn_cert(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        self.alpn.read().unwrap().get(domain).cloned()
+    }
+}
+
+#[cfg(feature = "acme")]
+pub use client::{initial_key, start};
+
+#[cfg(not(feature = "acme"))]
+pub fn initial_key(_config: &Server, _acme: &AcmeConfig) -> Result<CertifiedKey> {
+    Err(Error::Config(
+        "ACME certificates require the 'acme' feature".into(),
+    ))
+}
+
+#[cfg(not(feature = "acme"))]
+pub fn start(_acme: &AcmeConfig, _config: &Server, _resolver: Arc<CertResolver>) -> Result<()> {
+    Err(Error::Config(
+        "ACME certificates require the 'acme' feature".into(),
+    ))
+}
+
+#[cfg(feature = "acme")]
+mod client {
+    use super::*;
+    use crate::server::tls::load_certified_key;
+    use actix_web::{rt::time::sleep, web, App, HttpResponse, HttpServer};
+    use instant_acme::{
+        Account, AccountCredentials, AuthorizationStatus, Identifier, KeyAuthorization, NewAccount,
+        NewOrder, Order, OrderStatus,
+    };
+    use rcgen::{CertificateParams, CustomExtension, DistinguishedName, KeyPair};
+    use rustls::{sign, Certificate, PrivateKey};
+    use std::fs;
+    use std::io::Write;
+    use std::time::SystemTime;
+    use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+    // Path of the `http-01` challenges
+    const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/{token}";
+
+    // Delay between certificate checks
+    const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+    // Delay before retrying a failed order
+    const RETRY_DELAY: Duration = Duration::from_secs(3600);
+    // Maximum number of polls of an order
+    const MAX_POLLS: usize = 10;
+
+    fn acme_error(err: impl std::fmt::Display) -> Error {
+        Error::Acme(err.to_string())
+    }
+
+    // Serve `http-01` challenges
+    async fn http_challenge(
+        challenges: web::Data<Challenges>,
+        token: web::Path<String>,
+    ) -> HttpResponse {
+        match challenges.http.read().unwrap().get(token.as_str()) {
+            Some(key_authorization) => HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .body(key_authorization.clone()),
+            None => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    fn certified_key(cert: &[u8], key: Vec<u8>) -> Result<CertifiedKey> {
+        sign::any_supported_type(&PrivateKey(key))
+            .map(|key| CertifiedKey::new(vec![Certificate(cert.into())], key))
+            .map_err(acme_error)
+    }
+
+    /// Return the server certificate
+    ///
+    /// Use a temporary self-signed certificate if no
+    /// certificate has been issued yet.
+    pub fn initial_key(config: &Server, acme: &AcmeConfig) -> Result<CertifiedKey> {
+        if acme.cert_file().exists() {
+            load_certified_key(config)
+        } else {
+            log::info!(
+                "No ACME certificate found in {}, using a temporary self-signed certificate",
+                acme.state_dir.display()
+            );
+            let rcgen::CertifiedKey { cert, key_pair } =
+                rcgen::generate_simple_self_signed(acme.domains.clone()).map_err(acme_error)?;
+            certified_key(cert.der(), key_pair.serialize_der())
+        }
+    }
+
+    /// Start the certificate renewal
+    ///
+    /// Start the `http-01` challenge server if required.
+    pub fn start(acme: &AcmeConfig, config: &Server, resolver: Arc<CertResolver>) -> Result<()> {
+        let challenges = resolver
+            .challenges()
+            .ok_or_else(|| Error::Config("Missing ACME challenges".into()))?;
+
+        if acme.challenge == ChallengeType::Http01 {
+            let data = web::Data::from(challenges.clone());
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(data.clone())
+                    .route(HTTP_CHALLENGE_PATH, web::get().to(http_challenge))
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(&acme.http_listen)?
+            .run();
+            log::info!("Serving ACME challenges on {}", acme.http_listen);
+            actix_web::rt::spawn(server);
+        }
+
+        fs::create_dir_all(&acme.state_dir)?;
+
+        let acme = acme.clone();
+        let config = config.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                let delay = renew_in(&acme);
+                if !delay.is_zero() {
+                    sleep(delay.min(CHECK_INTERVAL)).await;
+                    continue;
+                }
+                log::info!("Requesting certificate for {}", acme.domains.join(", "));
+                let delay = match issue(&acme, &challenges).await {
+                    Ok(()) => {
+                        match resolver.reload(&config) {
+                            Ok(()) => log::info!("ACME certificate installed"),
+                            Err(err) => log::error!("Failed to load ACME certificate: {err}"),
+                        }
+                        CHECK_INTERVAL
+                    }
+                    Err(err) => {
+                        log::error!("Failed to obtain ACME certificate: {err}");
+                        RETRY_DELAY
+                    }
+                };
+                sleep(delay).await;
+            }
+        });
+        Ok(())
+    }
+
+    // Return the delay before renewing the certificate
+    //
+    // Renew immediately if the certificate is missing
+    // or if the domains have changed.
+    fn renew_in(acme: &AcmeConfig) -> Duration {
+        let Ok(pem) = fs::read(acme.cert_file()) else {
+            return Duration::ZERO;
+        };
+        let Some(der) = rustls_pemfile::certs(&mut pem.as_slice())
+            .ok()
+            .and_then(|certs| certs.into_iter().next())
+        else {
+            return Duration::ZERO;
+        };
+        let Ok((_, cert)) = X509Certificate::from_der(&der) else {
+            return Duration::ZERO;
+        };
+        let names: Vec<&str> = cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|san| {
+                san.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name) => Some(*name),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        if !acme.domains.iter().all(|d| names.contains(&d.as_str())) {
+            log::info!("ACME domains have changed");
+            return Duration::ZERO;
+        }
+        let not_after = cert.validity().not_after.timestamp();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        let renew_at = not_after - acme.renew_before.as_secs() as i64;
+        Duration::from_secs(renew_at.saturating_sub(now).max(0) as u64)
+    }
+
+    // Write `contents` to `path` readable only by the owner
+    fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&tmp)?.write_all(contents)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    // Load or create the account
+    async fn account(acme: &AcmeConfig) -> Result<Account> {
+        let path = acme.state_dir.join("account.json");
+        if let Ok(credentials) = fs::read_to_string(&path) {
+            let credentials: AccountCredentials =
+                serde_json::from_str(&credentials).map_err(acme_error)?;
+            return Account::from_credentials(credentials)
+                .await
+                .map_err(acme_error);
+        }
+        let contact: Vec<String> = acme
+            .contact
+            .iter()
+            .map(|email| {
+                if email.starts_with("mailto:") {
+                    email.clone()
+                } else {
+                    format!("mailto:{email}")
+                }
+            })
+            .collect();
+        let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+        let (account, credentials) = Account::create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            &acme.directory,
+            None,
+        )
+        .await
+        .map_err(acme_error)?;
+        write_private(
+            &path,
+            serde_json::to_string(&credentials)
+                .map_err(acme_error)?
+                .as_bytes(),
+        )?;
+        log::info!("Created ACME account {}", account.id());
+        Ok(account)
+    }
+
+    // Create the `tls-alpn-01` challenge certificate
+    fn alpn_cert(domain: &str, key_authorization: &KeyAuthorization) -> Result<CertifiedKey> {
+        let mut params = CertificateParams::new(vec![domain.into()]).map_err(acme_error)?;
+        params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+            key_authorization.digest().as_ref(),
+        )];
+        let key = KeyPair::generate().map_err(acme_error)?;
+        let cert = params.self_signed(&key).map_err(acme_error)?;
+        certified_key(cert.der(), key.serialize_der())
+    }
+
+    // Order a new certificate
+    async fn issue(acme: &AcmeConfig, challenges: &Challenges) -> Result<()> {
+        let account = account(acme).await?;
+        let identifiers: Vec<_> = acme
+            .domains
+            .iter()
+            .map(|domain| Identifier::Dns(domain.clone()))
+            .collect();
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &identifiers,
+            })
+            .await
+            .map_err(acme_error)?;
+
+        let rv = validate(acme, challenges, &mut order).await;
+        challenges.http.write().unwrap().clear();
+        challenges.alpn.write().unwrap().clear();
+        rv?;
+
+        let mut params = CertificateParams::new(acme.domains.clone()).map_err(acme_error)?;
+        params.distinguished_name = DistinguishedName::new();
+        let key = KeyPair::generate().map_err(acme_error)?;
+        let csr = params.serialize_request(&key).map_err(acme_error)?;
+        order.finalize(csr.der()).await.map_err(acme_error)?;
+
+        let mut delay = Duration::from_secs(1);
+        for _ in 0..MAX_POLLS {
+            if let Some(cert) = order.certificate().await.map_err(acme_error)? {
+                write_private(&acme.key_file(), key.serialize_pem().as_bytes())?;
+                write_private(&acme.cert_file(), cert.as_bytes())?;
+                return Ok(());
+            }
+            sleep(delay).await;
+            delay = (delay * 2).min(Duration::from_secs(30));
+        }
+        Err(Error::Acme("Certificate was not issued in time".into()))
+    }
+
+    // Complete the authorizations of the order
+    async fn validate(acme: &AcmeConfig, challenges: &Challenges, order: &mut Order) -> Result<()> {
+        let kind = match acme.challenge {
+            ChallengeType::TlsAlpn01 => instant_acme::ChallengeType::TlsAlpn01,
+            ChallengeType::Http01 => instant_acme::ChallengeType::Http01,
+        };
+        let mut ready = Vec::new();
+        for authz in order.authorizations().await.map_err(acme_error)? {
+            match authz.status {
+                AuthorizationStatus::Pending => (),
+                AuthorizationStatus::Valid => continue,
+                status => {
+                    return Err(Error::Acme(format!("Authorization is {status:?}")));
+                }
+            }
+            let Identifier::Dns(domain) = &authz.identifier;
+            let challenge = authz
+                .challenges
+                .iter()
+                .find(|challenge| challenge.r#type == kind)
+                .ok_or_else(|| Error::Acme(format!("No {kind:?} challenge for {domain}")))?;
+            let key_authorization = order.key_authorization(challenge);
+            match acme.challenge {
+                ChallengeType::TlsAlpn01 => {
+                    challenges.alpn.write().unwrap().insert(
+                        domain.clone(),
+                        Arc::new(alpn_cert(domain, &key_authorization)?),
+                    );
+                }
+                ChallengeType::Http01 => {
+                    challenges
+                        .http
+                        .write()
+                        .unwrap()
+                        .insert(challenge.token.clone(), key_authorization.as_str().into());
+                }
+            }
+            ready.push(challenge.url.clone());
+        }
+        for url in &ready {
+            order.set_challenge_ready(url).await.map_err(acme_error)?;
+        }
+
+        let mut delay = Duration::from_secs(1);
+        for _ in 0..MAX_POLLS {
+            sleep(delay).await;
+            let state = order.refresh().await.map_err(acme_error)?;
+            match state.status {
+                OrderStatus::Ready => return Ok(()),
+                OrderStatus::Invalid => {
+                    return Err(Error::Acme(format!(
+                        "Order is invalid: {:?}",
+                        state.error.as_ref().and_then(|err| err.detail.as_deref())
+                    )))
+                }
+                _ => delay = (delay * 2).min(Duration::from_secs(30)),
+            }
+        }
+        Err(Error::Acme("Order was not ready in time".into()))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn acme_renewal() {
+            let dir =
+                std::env::temp_dir().join(format!("pg-event-server-acme-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let mut acme = AcmeConfig {
+                domains: vec!["localhost".into()],
+                contact: vec![],
+                directory: default_directory(),
+                state_dir: dir.clone(),
+                challenge: ChallengeType::TlsAlpn01,
+                http_listen: default_http_listen(),
+                renew_before: default_renew_before(),
+            };
+            // Missing certificate
+            assert!(renew_in(&acme).is_zero());
+
+            let rcgen::CertifiedKey { cert, .. } =
+                rcgen::generate_simple_self_signed(acme.domains.clone()).unwrap();
+            fs::write(acme.cert_file(), cert.pem()).unwrap();
+
+            // rcgen certificates are valid until 4096
+            assert!(renew_in(&acme) > Duration::from_secs(3600 * 24 * 365));
+
+            // Domains changed
+            acme.domains.push("example.com".into());
+            assert!(renew_in(&acme).is_zero());
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//!
+//! CORS configuration
+//!
+use crate::config::CorsConfig;
+use actix_cors::Cors;
+use actix_web::http::{header, Method};
+
+/// Build the CORS middleware for the subscription endpoints
+///
+/// Return a default (i.e same origin only) middleware
+/// if there is no configuration.
+pub fn make_cors(config: Option<&CorsConfig>) -> Cors {
+    match config {
+        Some(config) => with_origins(
+            Cors::default()
+                .allowed_methods([Method::GET])
+                .allowed_headers([
+                    header::AUTHORIZATION,
+                    header::CACHE_CONTROL,
+                    header::HeaderName::from_static("last-event-id"),
+                    header::HeaderName::from_static("x-identity"),
+                ]),
+            config,
+        ),
+        None => Cors::default(),
+    }
+}
+
+/// Build the CORS middleware for the publish
+/// and acknowledgement endpoints
+///
+/// Return a default (i.e same origin only) middleware
+/// if there is no configuration.
+pub fn make_post_cors(config: Option<&CorsConfig>) -> Cors {
+    match config {
+        Some(config) => with_origins(
+            Cors::default()
+                .allowed_methods([Method::POST])
+                .allowed_headers([header::AUTHORIZATION, header::CONTENT_TYPE]),
+            config,
+        ),
+        None => Cors::default(),
+    }
+}
+
+/// Apply the origins and credentials settings
+fn with_origins(cors: Cors, config: &CorsConfig) -> Cors {
+    let mut cors = cors.max_age(config.max_age);
+
+    if config.allowed_origins.iter().any(|o| o == "*") {
+        cors = cors.allow_any_origin();
+    } else {
+        cors = config
+            .allowed_origins
+            .iter()
+            .fold(cors, |cors, origin| cors.allowed_origin(origin));
+    }
+
+    if config.allow_credentials {
+        cors = cors.supports_credentials();
+    }
+
+    cors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn cors_preflight() {
+        let config: CorsConfig =
+            toml::from_str(r#"allowed_origins = ["https://app.example.com"]"#).unwrap();
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::resource("/subscribe")
+                        .wrap(make_cors(Some(&config)))
+                        .route(web::get().to(HttpResponse::Ok)),
+                )
+                .service(
+                    web::resource("/publish")
+                        .wrap(make_post_cors(Some(&config)))
+                        .route(web::post().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+
+        let preflight = |uri: &str, method: &str| {
+            test::TestRequest::default()
+                .method(Method::OPTIONS)
+                .uri(uri)
+                .insert_header((header::ORIGIN, "https://app.example.com"))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+                .insert_header((
+                    header::ACCESS_CONTROL_REQUEST_HEADERS,
+                    "content-type, authorization",
+                ))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, preflight("/publish", "POST")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let allowed = resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_HEADERS)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        assert!(allowed.contains("content-type") && allowed.contains("authorization"));
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://app.example.com"
+        );
+
+        // The subscription endpoint does not accept POST
+        let resp = test::call_service(&app, preflight("/subscribe", "POST")).await;
+        assert!(resp.status().is_client_error());
+    }
+}
@@ -0,0 +1,108 @@
+//!
+//! Process options for init systems
+//! supervising the server with a pid file
+//!
+use crate::errors::{Error, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Parse an octal file mode creation mask
+pub fn parse_umask(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mask| *mask <= 0o777)
+        .ok_or_else(|| format!("invalid umask '{s}'"))
+}
+
+/// Set the file mode creation mask
+#[cfg(unix)]
+pub fn set_umask(mask: u32) {
+    // SAFETY: umask cannot fail
+    unsafe {
+        libc::umask(mask as libc::mode_t);
+    }
+}
+
+/// Change the working directory
+pub fn set_workdir(path: &Path) -> Result<()> {
+    std::env::set_current_dir(path).map_err(|err| {
+        Error::Config(format!(
+            "Cannot change working directory to {}: {err}",
+            path.display()
+        ))
+    })
+}
+
+/// Pid file removed when dropped
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Write the process id to `path`
+    ///
+    /// Fails if the file holds the pid of a running process.
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(pid) = fs::read_to_string(path)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok())
+        {
+            if pid != std::process::id() && is_running(pid) {
+                return Err(Error::Config(format!(
+                    "Server already running with pid {pid} ({})",
+                    path.display()
+                )));
+            }
+        }
+        fs::write(path, format!("{}\n", std::process::id())).map_err(|err| {
+            Error::Config(format!("Cannot write pid file {}: {err}", path.display()))
+        })?;
+        Ok(Self(path.into()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.0) {
+            log::error!("Cannot remove pid file {}: {err}", self.0.display());
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks for the existence of the process
+    let rv = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    rv == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daemon_pidfile() {
+        assert_eq!(parse_umask("027"), Ok(0o027));
+        assert!(parse_umask("8").is_err());
+        assert!(parse_umask("1777").is_err());
+
+        let path = std::env::temp_dir().join(format!("pg-event-server-{}.pid", std::process::id()));
+
+        // Running process
+        fs::write(&path, "1\n").unwrap();
+        assert!(PidFile::create(&path).is_err());
+
+        // Stale pid file
+        fs::write(&path, format!("{}\n", u32::MAX >> 1)).unwrap();
+        let pidfile = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
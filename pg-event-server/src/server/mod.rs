@@ -1,4 +1,14 @@
 //!
-//! Server TLS configuration
+//! Server TLS, CORS, proxies, unix socket and process configuration
 //!
+pub mod acme;
+pub mod cors;
+pub mod daemon;
+#[cfg(feature = "server-openssl")]
+pub mod openssl;
+pub mod proxy;
+#[cfg(unix)]
+pub mod systemd;
 pub mod tls;
+#[cfg(unix)]
+pub mod unix;
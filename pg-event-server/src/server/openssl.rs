@@ -0,0 +1,214 @@
+//!
+//! OpenSSL server configuration
+//!
+//! SSL contexts are selected by server name on each
+//! handshake so that certificates can be reloaded
+//! without restarting the server.
+//!
+use crate::config::Server;
+use crate::errors::{Error, Result};
+use ::openssl::ssl::{
+    AlpnError, NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype,
+    SslMethod, SslVerifyMode,
+};
+use ::openssl::x509::{X509Name, X509};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+fn ssl_error(path: &Path, err: impl std::fmt::Display) -> Error {
+    Error::Config(format!("Failed to configure ssl with {path:?}: {err}"))
+}
+
+// Create an acceptor builder with the client
+// certificate verification of the server
+fn acceptor_builder(config: &Server) -> Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())
+        .map_err(|err| Error::Config(format!("Failed to configure ssl: {err}")))?;
+    if let Some(path) = &config.ssl_client_ca_file {
+        builder
+            .set_ca_file(path)
+            .map_err(|err| ssl_error(path, err))?;
+        builder.set_client_ca_list(
+            X509Name::load_client_ca_file(path).map_err(|err| ssl_error(path, err))?,
+        );
+        builder.set_verify(if config.ssl_client_auth_required {
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+        } else {
+            SslVerifyMode::PEER
+        });
+    }
+    // The ALPN callback of the selected context is used
+    let server_protocols: &'static [u8] = if config.http2 {
+        b"\x02h2\x08http/1.1"
+    } else {
+        b"\x08http/1.1"
+    };
+    builder.set_alpn_select_callback(move |_, protocols| {
+        ::openssl::ssl::select_next_proto(server_protocols, protocols).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder)
+}
+
+// Load a certificate and its key
+fn load_context(config: &Server, cert_path: &Path, key_path: &Path) -> Result<SslContext> {
+    log::debug!("Loading SSL cert file at {cert_path:?}");
+    let mut builder = acceptor_builder(config)?;
+    builder
+        .set_certificate_chain_file(cert_path)
+        .map_err(|err| ssl_error(cert_path, err))?;
+    builder
+        .set_private_key_file(key_path, SslFiletype::PEM)
+        .map_err(|err| ssl_error(key_path, err))?;
+    builder
+        .check_private_key()
+        .map_err(|err| ssl_error(key_path, err))?;
+    Ok(builder.build().into_context())
+}
+
+// Return the DNS names of the certificate
+fn dns_names(cert_path: &Path) -> Result<Vec<String>> {
+    let pem = std::fs::read(cert_path)?;
+    let cert = X509::from_pem(&pem).map_err(|err| ssl_error(cert_path, err))?;
+    Ok(cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+// Server contexts
+struct Contexts {
+    default: SslContext,
+    by_name: HashMap<String, SslContext>,
+}
+
+impl Contexts {
+    fn load(config: &Server) -> Result<Self> {
+        let mut by_name = HashMap::new();
+        let mut first = None;
+        for cert in &config.certificates {
+            let context = load_context(config, &cert.ssl_cert_file, &cert.ssl_key_file)?;
+            let names = if cert.server_names.is_empty() {
+                dns_names(&cert.ssl_cert_file)?
+            } else {
+                cert.server_names.clone()
+            };
+            if names.is_empty() {
+                return Err(Error::Config(format!(
+                    "No server names for certificate {:?}",
+                    cert.ssl_cert_file
+                )));
+            }
+            for name in names {
+                by_name.insert(name.to_ascii_lowercase(), context.clone());
+            }
+            first.get_or_insert(context);
+        }
+        let default = match (&config.ssl_cert_file, &config.ssl_key_file, first) {
+            (Some(cert_path), Some(key_path), _) => load_context(config, cert_path, key_path)?,
+            // Default to the first certificate
+            (None, _, Some(first)) => first,
+            (None, _, None) => return Err(Error::Config("Missing ssl cert file option".into())),
+            (_, None, _) => return Err(Error::Config("Missing ssl key file option".into())),
+        };
+        Ok(Self { default, by_name })
+    }
+
+    // Select the context for `server_name`
+    fn get(&self, server_name: Option<&str>) -> &SslContext {
+        server_name
+            .map(str::to_ascii_lowercase)
+            .and_then(|name| {
+                self.by_name.get(&name).or_else(|| {
+                    name.split_once('.')
+                        .and_then(|(_, domain)| self.by_name.get(&format!("*.{domain}")))
+                })
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Server SSL contexts that can be reloaded
+/// without restarting the server
+pub struct SslContexts(RwLock<Arc<Contexts>>);
+
+impl SslContexts {
+    pub fn new(config: &Server) -> Result<Self> {
+        Contexts::load(config).map(|contexts| Self(RwLock::new(Arc::new(contexts))))
+    }
+
+    /// Reload certificate and key files
+    ///
+    /// The previous certificates are kept on error.
+    pub fn reload(&self, config: &Server) -> Result<()> {
+        let contexts = Contexts::load(config)?;
+        *self.0.write().unwrap() = Arc::new(contexts);
+        Ok(())
+    }
+}
+
+/// Create the acceptor of the server
+pub fn make_acceptor(config: &Server, contexts: Arc<SslContexts>) -> Result<SslAcceptorBuilder> {
+    let mut builder = acceptor_builder(config)?;
+    // Called on every handshake, with or
+    // without server name indication
+    builder.set_servername_callback(move |ssl, _| {
+        let contexts = contexts.0.read().unwrap().clone();
+        ssl.set_ssl_context(contexts.get(ssl.servername(NameType::HOST_NAME)))
+            .map_err(|_| SniError::ALERT_FATAL)
+    });
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::server::tls::CertificateConfig;
+    use crate::tests::{confdir, setup};
+    use std::env;
+
+    #[test]
+    fn openssl_contexts() {
+        setup();
+        let mut conf = Config::read(confdir!("config.toml"), &Default::default())
+            .unwrap()
+            .settings
+            .server;
+        conf.certificates = vec![CertificateConfig {
+            server_names: vec![],
+            ssl_cert_file: confdir!("certs/other.pem").into(),
+            ssl_key_file: confdir!("certs/other.key").into(),
+        }];
+        assert_eq!(
+            dns_names(&conf.certificates[0].ssl_cert_file).unwrap(),
+            ["events.example.org", "*.other.org"]
+        );
+        let contexts = SslContexts::new(&conf).unwrap();
+        let contexts = contexts.0.read().unwrap().clone();
+        let subject = |name| {
+            contexts
+                .get(name)
+                .certificate()
+                .unwrap()
+                .subject_name()
+                .entries()
+                .last()
+                .unwrap()
+                .data()
+                .to_string()
+                .unwrap()
+        };
+        assert_eq!(subject(Some("notify.other.org")), "events.example.org");
+        assert_eq!(subject(Some("localhost")), "localhost");
+        assert_eq!(subject(None), "localhost");
+
+        conf.ssl_key_file = Some(confdir!("certs/other.key").into());
+        assert!(SslContexts::new(&conf).is_err());
+    }
+}
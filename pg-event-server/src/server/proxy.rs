@@ -0,0 +1,115 @@
+//!
+//! Trusted proxies
+//!
+//! Forwarding headers are only honored when the
+//! peer is a trusted proxy.
+//!
+use actix_web::{http::header, HttpRequest};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Resolve the client ip from trusted proxies
+#[derive(Default, Clone)]
+pub struct TrustedProxies(Arc<Vec<IpNet>>);
+
+impl TrustedProxies {
+    pub fn new(nets: &[IpNet]) -> Self {
+        Self(Arc::new(nets.to_vec()))
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    /// Return the client ip of the request
+    ///
+    /// Forwarded addresses are walked from the nearest
+    /// hop and the first untrusted address is returned.
+    /// Peers connected on a unix socket are trusted.
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        if peer.is_some_and(|ip| !self.is_trusted(&ip)) {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in forwarded_for(req).iter().rev() {
+            match hop.parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = Some(ip);
+                    if !self.is_trusted(&ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        client
+    }
+}
+
+/// Return the forwarded addresses from the `Forwarded`
+/// or `X-Forwarded-For` headers
+fn forwarded_for(req: &HttpRequest) -> Vec<String> {
+    let headers = req.headers();
+    let forwarded = headers
+        .get_all(header::FORWARDED)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|elem| {
+            elem.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for")
+                    .then(|| strip_forwarded_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect::<Vec<_>>();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all(header::X_FORWARDED_FOR)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .collect()
+}
+
+/// Remove port and brackets from a `Forwarded` node
+fn strip_forwarded_node(node: &str) -> String {
+    match node.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or_default().into(),
+        None => node.split(':').next().unwrap_or_default().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn trusted_proxies() {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".parse().unwrap()]);
+
+        // Untrusted peer
+        let req = TestRequest::default()
+            .peer_addr("192.168.1.1:1234".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "1.2.3.4"))
+            .to_http_request();
+        assert_eq!(proxies.client_ip(&req), "192.168.1.1".parse().ok());
+
+        // Spoofed entries are skipped
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "1.2.3.4, 5.6.7.8, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(proxies.client_ip(&req), "5.6.7.8".parse().ok());
+
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("Forwarded", r#"for="[2001:db8::1]:4711";proto=https"#))
+            .to_http_request();
+        assert_eq!(proxies.client_ip(&req), "2001:db8::1".parse().ok());
+    }
+}
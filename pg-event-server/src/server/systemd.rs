@@ -0,0 +1,240 @@
+//!
+//! Systemd service notifications
+//!
+//! Readiness and watchdog notifications are sent to the
+//! `NOTIFY_SOCKET` socket when running as a `Type=notify` unit.
+//! Nothing is sent when not started by systemd.
+//!
+//! Listening sockets may be passed by systemd with
+//! socket activation.
+//!
+use crate::errors::{Error, Result};
+use std::env;
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::time::Duration;
+
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID: &str = "WATCHDOG_PID";
+const LISTEN_FDS: &str = "LISTEN_FDS";
+const LISTEN_PID: &str = "LISTEN_PID";
+
+// First file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+// Send `state` to the socket at `path`
+//
+// Paths starting with '@' are in the abstract namespace.
+fn send(path: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+            socket.send_to_addr(
+                state.as_bytes(),
+                &SocketAddr::from_abstract_name(name.as_bytes())?,
+            )?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Abstract sockets are not supported",
+            ))
+        }
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+fn notify(state: &str) {
+    if let Some(path) = env::var_os(NOTIFY_SOCKET) {
+        if let Err(err) = send(&path.to_string_lossy(), state) {
+            log::error!("Failed to notify systemd: {err}");
+        }
+    }
+}
+
+// Return the watchdog timeout if the watchdog is
+// enabled for this process
+fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var(WATCHDOG_PID) {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    env::var(WATCHDOG_USEC)
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Notify that the service is ready
+///
+/// Start sending watchdog pings if the watchdog
+/// is enabled.
+pub fn ready() {
+    if env::var_os(NOTIFY_SOCKET).is_none() {
+        return;
+    }
+    notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+    if let Some(timeout) = watchdog_timeout() {
+        log::info!("Sending systemd watchdog pings every {:?}", timeout / 2);
+        actix_web::rt::spawn(async move {
+            // Pings are sent from the main thread, they
+            // stop if the dispatcher is stuck.
+            let mut interval = tokio::time::interval(timeout / 2);
+            loop {
+                interval.tick().await;
+                notify("WATCHDOG=1");
+            }
+        });
+    }
+}
+
+/// Notify the status of the service
+pub fn status(status: &str) {
+    notify(&format!("STATUS={status}"));
+}
+
+/// Notify that the service is stopping
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Listening socket passed by systemd
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Return the listening sockets passed by systemd
+///
+/// The list is empty if the process was not socket activated.
+pub fn listen_fds() -> Result<Vec<Listener>> {
+    if env::var(LISTEN_PID).ok().and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Ok(vec![]);
+    }
+    let count: RawFd = env::var(LISTEN_FDS)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            listener(fd).map_err(|err| {
+                Error::Config(format!("Invalid socket passed by systemd (fd {fd}): {err}"))
+            })
+        })
+        .collect()
+}
+
+// Take ownership of the socket `fd`
+fn listener(fd: RawFd) -> io::Result<Listener> {
+    // SAFETY: the descriptor is checked to be a socket
+    // and is owned by the returned listener
+    unsafe {
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut kind: libc::c_int = 0;
+        let mut len = mem::size_of_val(&kind) as libc::socklen_t;
+        if libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut _ as *mut libc::c_void,
+            &mut len,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if kind != libc::SOCK_STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a stream socket",
+            ));
+        }
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(TcpListener::from_raw_fd(fd))),
+            libc::AF_UNIX => Ok(Listener::Unix(UnixListener::from_raw_fd(fd))),
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported address family {family}"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn systemd_notify() {
+        let dir = env::temp_dir().join(format!("pg-event-server-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+            let name = format!("pg-event-server-notify-{}", std::process::id());
+            let receiver =
+                UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap())
+                    .unwrap();
+            send(&format!("@{name}"), "WATCHDOG=1").unwrap();
+            let n = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"WATCHDOG=1");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn systemd_listeners() {
+        use std::os::fd::IntoRawFd;
+
+        let fd = TcpListener::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        assert!(matches!(listener(fd), Ok(Listener::Tcp(_))));
+
+        let dir = env::temp_dir().join(format!("pg-event-server-listen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fd = UnixListener::bind(dir.join("listen.sock"))
+            .unwrap()
+            .into_raw_fd();
+        assert!(matches!(listener(fd), Ok(Listener::Unix(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Not owned on errors
+        for fd in [
+            UnixDatagram::unbound().unwrap().into_raw_fd(),
+            std::fs::File::open("/dev/null").unwrap().into_raw_fd(),
+        ] {
+            assert!(listener(fd).is_err());
+            // SAFETY: fd is not used afterwards
+            unsafe { libc::close(fd) };
+        }
+    }
+}
@@ -3,12 +3,302 @@
 //!
 use crate::config::Server;
 use crate::errors::{Error, Result};
-use rustls::{Certificate, PrivateKey, ServerConfig as RustlsServerConfig};
+use crate::server::acme::{self, Challenges};
+use actix_tls::accept::rustls_0_20::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use rustls::{
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+        ResolvesServerCert,
+    },
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, RootCertStore, ServerConfig as RustlsServerConfig,
+};
+use serde::Deserialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::{fs, io};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
 
 pub type TlsServerConfig = RustlsServerConfig;
 
-pub fn make_tls_config(config: &Server) -> Result<TlsServerConfig> {
+///
+/// Additional certificate selected by
+/// the server name requested by clients (SNI)
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct CertificateConfig {
+    /// Server names of the certificate, wildcards
+    /// are allowed as the first label.
+    /// Default to the DNS names of the certificate.
+    #[serde(default)]
+    pub server_names: Vec<String>,
+    /// Path to the certificate file
+    pub ssl_cert_file: PathBuf,
+    /// Path to the key file
+    pub ssl_key_file: PathBuf,
+}
+
+// Server certificates
+struct CertKeys {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertKeys {
+    // Load the certificates, use `default` as the default
+    // certificate instead of the server certificate files
+    fn load(config: &Server, default: Option<Arc<CertifiedKey>>) -> Result<Self> {
+        let mut by_name = HashMap::new();
+        let mut first = None;
+        for cert in &config.certificates {
+            let key = Arc::new(load_key_pair(&cert.ssl_cert_file, &cert.ssl_key_file)?);
+            let names = if cert.server_names.is_empty() {
+                dns_names(&key)
+            } else {
+                cert.server_names.clone()
+            };
+            if names.is_empty() {
+                return Err(Error::Config(format!(
+                    "No server names for certificate {:?}",
+                    cert.ssl_cert_file
+                )));
+            }
+            for name in names {
+                by_name.insert(name.to_ascii_lowercase(), key.clone());
+            }
+            first.get_or_insert(key);
+        }
+        let default = match (default, first) {
+            (Some(default), _) => default,
+            // Default to the first certificate
+            (None, Some(first)) if config.ssl_cert_file.is_none() => first,
+            _ => Arc::new(load_certified_key(config)?),
+        };
+        Ok(Self { default, by_name })
+    }
+
+    // Select the certificate for `server_name`
+    fn get(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        server_name
+            .map(str::to_ascii_lowercase)
+            .and_then(|name| {
+                self.by_name.get(&name).or_else(|| {
+                    name.split_once('.')
+                        .and_then(|(_, domain)| self.by_name.get(&format!("*.{domain}")))
+                })
+            })
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+// Return the DNS names of the certificate
+fn dns_names(key: &CertifiedKey) -> Vec<String> {
+    key.end_entity_cert()
+        .ok()
+        .and_then(|cert| X509Certificate::from_der(&cert.0).ok())
+        .and_then(|(_, cert)| {
+            cert.subject_alternative_name().ok().flatten().map(|san| {
+                san.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(name) => Some(name.to_string()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// TLS material of the server
+#[derive(Clone)]
+pub enum ServerCerts {
+    Rustls(Arc<CertResolver>),
+    #[cfg(feature = "server-openssl")]
+    Openssl(Arc<super::openssl::SslContexts>),
+}
+
+impl ServerCerts {
+    /// Reload certificate and key files
+    pub fn reload(&self, config: &Server) -> Result<()> {
+        match self {
+            Self::Rustls(resolver) => resolver.reload(config),
+            #[cfg(feature = "server-openssl")]
+            Self::Openssl(contexts) => contexts.reload(config),
+        }
+    }
+}
+
+/// Server certificates that can be reloaded
+/// without restarting the server
+pub struct CertResolver {
+    keys: RwLock<Arc<CertKeys>>,
+    challenges: Option<Arc<Challenges>>,
+}
+
+impl CertResolver {
+    pub fn new(config: &Server) -> Result<Self> {
+        let (default, challenges) = match &config.acme {
+            Some(acme) => (
+                Some(Arc::new(acme::initial_key(config, acme)?)),
+                Some(Arc::default()),
+            ),
+            None => (None, None),
+        };
+        CertKeys::load(config, default).map(|keys| Self {
+            keys: RwLock::new(Arc::new(keys)),
+            challenges,
+        })
+    }
+
+    /// Return the ACME challenges
+    #[cfg_attr(not(feature = "acme"), allow(dead_code))]
+    pub fn challenges(&self) -> Option<Arc<Challenges>> {
+        self.challenges.clone()
+    }
+
+    /// Reload certificate and key files
+    ///
+    /// The previous certificates are kept on error.
+    pub fn reload(&self, config: &Server) -> Result<()> {
+        // Keep the temporary certificate until
+        // the ACME certificate is issued
+        let default = if self.challenges.is_some()
+            && !config.ssl_cert_file.as_ref().is_some_and(|p| p.exists())
+        {
+            Some(self.keys.read().unwrap().default.clone())
+        } else {
+            None
+        };
+        let keys = CertKeys::load(config, default)?;
+        *self.keys.write().unwrap() = Arc::new(keys);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(challenges) = &self.challenges {
+            if hello
+                .alpn()
+                .is_some_and(|mut protocols| protocols.any(|p| p == acme::ACME_TLS_ALPN))
+            {
+                return hello
+                    .server_name()
+                    .and_then(|domain| challenges.alpn_cert(domain));
+            }
+        }
+        Some(self.keys.read().unwrap().get(hello.server_name()))
+    }
+}
+
+pub fn make_tls_config(config: &Server, resolver: Arc<CertResolver>) -> Result<TlsServerConfig> {
+    let builder = RustlsServerConfig::builder().with_safe_defaults();
+    let mut tls_config = match &config.ssl_client_ca_file {
+        Some(path) => {
+            let roots = load_client_ca_file(path)?;
+            if config.ssl_client_auth_required {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            } else {
+                builder
+                    .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+            }
+        }
+        None => builder.with_no_client_auth(),
+    }
+    .with_cert_resolver(resolver);
+    if config
+        .acme
+        .as_ref()
+        .is_some_and(|acme| acme.challenge == acme::ChallengeType::TlsAlpn01)
+    {
+        tls_config.alpn_protocols.push(acme::ACME_TLS_ALPN.into());
+    }
+    Ok(tls_config)
+}
+
+fn load_client_ca_file(path: &Path) -> Result<RootCertStore> {
+    log::debug!("Loading SSL client CA file at {path:?}");
+    let mut store = RootCertStore::empty();
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(fs::File::open(path)?))
+        .map_err(|err| Error::Config(format!("Failed to read client CA {path:?} : {err:?}")))?;
+    for cert in certs {
+        store
+            .add(&Certificate(cert))
+            .map_err(|err| Error::Config(format!("Invalid client CA {path:?} : {err:?}")))?;
+    }
+    Ok(store)
+}
+
+///
+/// Identity of the client taken from its certificate
+///
+#[derive(Debug, Clone)]
+pub struct PeerIdentity(pub String);
+
+impl PeerIdentity {
+    /// Extract the identity from a DER certificate
+    ///
+    /// Use the subject common name or the first
+    /// DNS or email subject alternative name.
+    fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .find_map(|cn| cn.as_str().ok())
+            .map(String::from);
+        common_name
+            .or_else(|| {
+                cert.subject_alternative_name()
+                    .ok()
+                    .flatten()
+                    .and_then(|san| {
+                        san.value.general_names.iter().find_map(|name| match name {
+                            GeneralName::DNSName(s) | GeneralName::RFC822Name(s) => {
+                                Some(s.to_string())
+                            }
+                            _ => None,
+                        })
+                    })
+            })
+            .map(Self)
+    }
+}
+
+/// Connection callback storing the identity
+/// of authenticated clients in the connection data
+pub fn on_connect(conn: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        if let Some(identity) = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| PeerIdentity::from_der(&cert.0))
+        {
+            data.insert(identity);
+        }
+    }
+    #[cfg(feature = "server-openssl")]
+    if let Some(stream) = conn.downcast_ref::<actix_tls::accept::openssl::TlsStream<TcpStream>>() {
+        if let Some(identity) = stream
+            .ssl()
+            .peer_certificate()
+            .and_then(|cert| cert.to_der().ok())
+            .and_then(|der| PeerIdentity::from_der(&der))
+        {
+            data.insert(identity);
+        }
+    }
+}
+
+pub fn load_certified_key(config: &Server) -> Result<CertifiedKey> {
     let cert_path = config
         .ssl_cert_file
         .as_ref()
@@ -19,7 +309,10 @@ pub fn make_tls_config(config: &Server) -> Result<TlsServerConfig> {
         .as_ref()
         .ok_or(Error::Config("Missing ssl key file option".into()))?
         .as_path();
+    load_key_pair(cert_path, key_path)
+}
 
+fn load_key_pair(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
     let cert_file = &mut io::BufReader::new(fs::File::open(cert_path)?);
     let key_file = &mut io::BufReader::new(fs::File::open(key_path)?);
 
@@ -42,13 +335,82 @@ pub fn make_tls_config(config: &Server) -> Result<TlsServerConfig> {
     }
     .map(PrivateKey);
 
-    if key.is_none() {
-        return Err(Error::Config(format!("No TLS key found for {key_path:?}")));
-    }
+    let key = key.ok_or_else(|| Error::Config(format!("No TLS key found for {key_path:?}")))?;
 
-    RustlsServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, key.unwrap())
+    sign::any_supported_type(&key)
+        .map(|key| CertifiedKey::new(cert_chain, key))
         .map_err(|err| Error::Config(format!("Failed to configure tls: {err:?}")))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::tests::{confdir, setup};
+    use std::env;
+    use std::path::Path;
+
+    #[test]
+    fn reload_certificate() {
+        setup();
+        let mut conf = Config::read(confdir!("config.toml"), &Default::default())
+            .unwrap()
+            .settings
+            .server;
+        let resolver = CertResolver::new(&conf).unwrap();
+        let key = resolver.keys.read().unwrap().default.clone();
+
+        resolver.reload(&conf).unwrap();
+        assert!(!Arc::ptr_eq(&key, &resolver.keys.read().unwrap().default));
+
+        // Keep the previous certificate on error
+        let key = resolver.keys.read().unwrap().default.clone();
+        conf.ssl_cert_file = Some("not/found.pem".into());
+        assert!(resolver.reload(&conf).is_err());
+        assert!(Arc::ptr_eq(&key, &resolver.keys.read().unwrap().default));
+    }
+
+    #[test]
+    fn sni_certificates() {
+        setup();
+        let mut conf = Config::read(confdir!("config.toml"), &Default::default())
+            .unwrap()
+            .settings
+            .server;
+        conf.certificates = vec![CertificateConfig {
+            server_names: vec![],
+            ssl_cert_file: confdir!("certs/other.pem").into(),
+            ssl_key_file: confdir!("certs/other.key").into(),
+        }];
+        let keys = CertKeys::load(&conf, None).unwrap();
+        assert_eq!(
+            dns_names(&keys.get(Some("events.example.org"))),
+            ["events.example.org", "*.other.org"]
+        );
+        assert!(Arc::ptr_eq(
+            &keys.get(Some("Notify.Other.org")),
+            &keys.get(Some("events.example.org"))
+        ));
+        assert!(Arc::ptr_eq(&keys.get(Some("localhost")), &keys.default));
+        assert!(Arc::ptr_eq(
+            &keys.get(Some("a.notify.other.org")),
+            &keys.default
+        ));
+        assert!(Arc::ptr_eq(&keys.get(None), &keys.default));
+        assert_eq!(dns_names(&keys.default), ["localhost"]);
+
+        // Default to the first certificate
+        conf.ssl_cert_file = None;
+        let keys = CertKeys::load(&conf, None).unwrap();
+        assert!(Arc::ptr_eq(&keys.get(Some("localhost")), &keys.default));
+        assert_eq!(dns_names(&keys.default)[0], "events.example.org");
+    }
+
+    #[test]
+    fn peer_identity() {
+        let file = fs::File::open(confdir!("certs/cert.pem")).unwrap();
+        let certs = rustls_pemfile::certs(&mut io::BufReader::new(file)).unwrap();
+        let identity = PeerIdentity::from_der(&certs[0]).unwrap();
+        assert_eq!(identity.0, "localhost");
+    }
+}
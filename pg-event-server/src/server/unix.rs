@@ -0,0 +1,210 @@
+//!
+//! Unix domain socket support
+//!
+use crate::config::Server;
+use crate::errors::{Error, Result};
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Return the socket path if the listen
+/// address is a unix socket address
+pub fn socket_path(listen: &str) -> Option<&Path> {
+    listen.strip_prefix("unix:").map(Path::new)
+}
+
+/// Permissions applied to the unix socket
+pub struct SocketPermissions {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl SocketPermissions {
+    pub fn new(config: &Server) -> Result<Self> {
+        let (uid, gid) = match config.socket_owner.as_deref() {
+            Some(owner) => {
+                let (user, group) = match owner.split_once(':') {
+                    Some((user, group)) => (user, Some(group)),
+                    None => (owner, None),
+                };
+                (
+                    Some(user)
+                        .filter(|s| !s.is_empty())
+                        .map(lookup_user)
+                        .transpose()?,
+                    group.map(lookup_group).transpose()?,
+                )
+            }
+            None => (None, None),
+        };
+        Ok(Self {
+            mode: config.socket_mode,
+            uid,
+            gid,
+        })
+    }
+
+    /// Apply permissions to the socket file
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            std::os::unix::fs::chown(path, self.uid, self.gid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Process credentials set after binding
+/// the listening sockets
+pub struct Credentials {
+    user: Option<CString>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl Credentials {
+    pub fn new(config: &Server) -> Result<Option<Self>> {
+        if config.user.is_none() && config.group.is_none() {
+            return Ok(None);
+        }
+        let (user, uid, user_gid) = match config.user.as_deref() {
+            Some(name) => {
+                let (uid, gid) = lookup_passwd(name)?;
+                (
+                    CString::new(name)
+                        .ok()
+                        .filter(|_| name.parse::<u32>().is_err()),
+                    Some(uid),
+                    gid,
+                )
+            }
+            None => (None, None, None),
+        };
+        Ok(Some(Self {
+            user,
+            uid,
+            // Default to the primary group of the user
+            gid: config
+                .group
+                .as_deref()
+                .map(lookup_group)
+                .transpose()?
+                .or(user_gid),
+        }))
+    }
+
+    /// Switch to the user and group
+    pub fn apply(&self) -> Result<()> {
+        let error = |what: &str| {
+            Error::Config(format!(
+                "Cannot change {what}: {}",
+                std::io::Error::last_os_error()
+            ))
+        };
+        // SAFETY: plain system calls, `user` is a valid C string
+        unsafe {
+            if let Some(gid) = self.gid {
+                if libc::geteuid() == 0 {
+                    let rv = match &self.user {
+                        Some(user) => libc::initgroups(user.as_ptr(), gid as _),
+                        None => libc::setgroups(1, &(gid as libc::gid_t)),
+                    };
+                    if rv != 0 {
+                        return Err(error("supplementary groups"));
+                    }
+                }
+                if libc::setgid(gid) != 0 {
+                    return Err(error("group"));
+                }
+            }
+            if let Some(uid) = self.uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(error("user"));
+                }
+            }
+        }
+        log::info!(
+            "Running as uid {}, gid {}",
+            // SAFETY: getuid and getgid cannot fail
+            unsafe { libc::getuid() },
+            unsafe { libc::getgid() }
+        );
+        Ok(())
+    }
+}
+
+// Resolve user name or numeric uid with
+// its primary group if known
+fn lookup_passwd(name: &str) -> Result<(u32, Option<u32>)> {
+    // SAFETY: the returned pointer is checked and only
+    // read before any other call to getpwnam/getpwuid
+    unsafe {
+        let pw = match name.parse() {
+            Ok(uid) => {
+                let pw = libc::getpwuid(uid);
+                if pw.is_null() {
+                    return Ok((uid, None));
+                }
+                pw
+            }
+            Err(_) => {
+                let cname = CString::new(name)
+                    .map_err(|_| Error::Config(format!("Invalid user {name}")))?;
+                libc::getpwnam(cname.as_ptr())
+            }
+        };
+        if pw.is_null() {
+            Err(Error::Config(format!("Unknown user {name}")))
+        } else {
+            Ok(((*pw).pw_uid, Some((*pw).pw_gid)))
+        }
+    }
+}
+
+/// Resolve user name or numeric uid
+pub fn lookup_user(name: &str) -> Result<u32> {
+    lookup_passwd(name).map(|(uid, _)| uid)
+}
+
+/// Resolve group name or numeric gid
+pub fn lookup_group(name: &str) -> Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let cname = CString::new(name).map_err(|_| Error::Config(format!("Invalid group {name}")))?;
+    // SAFETY: the returned pointer is checked and only
+    // read before any other call to getgrnam
+    unsafe {
+        let gr = libc::getgrnam(cname.as_ptr());
+        if gr.is_null() {
+            Err(Error::Config(format!("Unknown group {name}")))
+        } else {
+            Ok((*gr).gr_gid)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_socket_config() {
+        assert_eq!(
+            socket_path("unix:/run/pg-event-server.sock"),
+            Some(Path::new("/run/pg-event-server.sock"))
+        );
+        assert!(socket_path("127.0.0.1:4001").is_none());
+
+        assert_eq!(lookup_user("root").unwrap(), 0);
+        assert_eq!(lookup_user("1000").unwrap(), 1000);
+        assert!(lookup_group("no-such-group-for-tests").is_err());
+
+        assert_eq!(lookup_passwd("root").unwrap(), (0, Some(0)));
+        assert!(lookup_passwd("no-such-user-for-tests").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+//!
+//! Event sinks
+//!
+//! Sinks forward events of selected channels
+//! to external systems.
+//!
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::{events::Event, metrics::Metrics, registry::ChannelRegistry, Result};
+
+pub mod mqtt;
+pub mod nats;
+pub mod webhook;
+
+///
+/// Sinks configuration
+///
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct SinkConfig {
+    #[serde(default)]
+    pub webhook: Vec<webhook::WebhookConfig>,
+    #[serde(default)]
+    pub mqtt: Vec<mqtt::MqttConfig>,
+    #[serde(default)]
+    pub nats: Vec<nats::NatsConfig>,
+}
+
+impl SinkConfig {
+    /// Resolve relative paths from `root`
+    pub fn sanitize(&mut self, root: &Path) {
+        let resolve = |path: &mut Option<PathBuf>| {
+            if let Some(p) = path.as_ref().filter(|p| !p.has_root()) {
+                *path = Some(root.join(p));
+            }
+        };
+        for conf in &mut self.nats {
+            resolve(&mut conf.credentials_file);
+            resolve(&mut conf.tls_ca_file);
+            resolve(&mut conf.tls_client_cert);
+            resolve(&mut conf.tls_client_key);
+        }
+    }
+}
+
+/// Expand `{channel}`, `{event}` and `{id}` in `template`
+///
+/// `reserved` characters in the values are replaced by `_`.
+#[cfg_attr(not(any(feature = "mqtt", feature = "nats")), allow(dead_code))]
+fn expand_template(
+    template: &str,
+    channel: &str,
+    event: &str,
+    id: &str,
+    reserved: &[char],
+) -> String {
+    template
+        .replace("{channel}", &channel.replace(reserved, "_"))
+        .replace("{event}", &event.replace(reserved, "_"))
+        .replace("{id}", id)
+}
+
+/// Event sink
+pub trait Sink {
+    /// Forward the event received for `channel`
+    fn send(&self, channel: &str, event: &Event);
+}
+
+/// Channels forwarded to a sink
+struct Target {
+    /// Channel ids, all channels if empty
+    channels: HashSet<String>,
+    sink: Box<dyn Sink>,
+}
+
+/// Dispatch events to sinks
+pub struct Sinks {
+    registry: ChannelRegistry,
+    targets: Vec<Target>,
+}
+
+impl Sinks {
+    /// Create sinks from configuration
+    ///
+    /// Must be called from within the actix runtime.
+    pub fn new(conf: &SinkConfig, registry: ChannelRegistry, metrics: &Metrics) -> Result<Self> {
+        #[cfg_attr(not(any(feature = "mqtt", feature = "nats")), allow(unused_mut))]
+        let mut targets = conf
+            .webhook
+            .iter()
+            .map(|conf| Target {
+                channels: conf.channels.iter().cloned().collect(),
+                sink: Box::new(webhook::WebhookSink::new(conf.clone(), metrics.clone())),
+            })
+            .collect::<Vec<_>>();
+
+        #[cfg(feature = "mqtt")]
+        for conf in &conf.mqtt {
+            targets.push(Target {
+                channels: conf.channels.iter().cloned().collect(),
+                sink: Box::new(mqtt::MqttSink::new(conf.clone(), metrics.clone())?),
+            });
+        }
+        #[cfg(not(feature = "mqtt"))]
+        if !conf.mqtt.is_empty() {
+            return Err(crate::Error::Config(
+                "MQTT sinks require the 'mqtt' feature".into(),
+            ));
+        }
+
+        #[cfg(feature = "nats")]
+        for conf in &conf.nats {
+            targets.push(Target {
+                channels: conf.channels.iter().cloned().collect(),
+                sink: Box::new(nats::NatsSink::new(conf.clone(), metrics.clone())),
+            });
+        }
+        #[cfg(not(feature = "nats"))]
+        if !conf.nats.is_empty() {
+            return Err(crate::Error::Config(
+                "NATS sinks require the 'nats' feature".into(),
+            ));
+        }
+
+        Ok(Self { registry, targets })
+    }
+
+    /// Add a sink receiving events of all channels
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    pub fn add(&mut self, sink: Box<dyn Sink>) {
+        self.targets.push(Target {
+            channels: HashSet::new(),
+            sink,
+        });
+    }
+
+    /// Forward the event to the sinks
+    /// subscribed to its channels
+    ///
+    /// Status events are not forwarded.
+    pub fn dispatch(&self, event: &Event) {
+        if self.targets.is_empty() || event.is_status() {
+            return;
+        }
+        for chan_id in event.channels() {
+            if let Some(channel) = self.registry.id_of(*chan_id) {
+                self.targets
+                    .iter()
+                    .filter(|t| t.channels.is_empty() || t.channels.contains(&channel))
+                    .for_each(|t| t.sink.send(&channel, event));
+            }
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//!
+//! MQTT sink
+//!
+//! Events are published to an MQTT broker on
+//! topics built from a template.
+//!
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::config::OutputFormat;
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "pg-event-server".into()
+}
+
+fn default_topic() -> String {
+    "pg/{channel}/{event}".into()
+}
+
+const fn default_keep_alive() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_reconnect_delay() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn default_queue_size() -> usize {
+    1024
+}
+
+///
+/// MQTT configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub struct MqttConfig {
+    /// Broker host
+    pub host: String,
+    /// Broker port
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Channels forwarded to the broker,
+    /// all channels if empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Topic template, `{channel}`, `{event}` and `{id}`
+    /// are replaced by the event values
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    /// Quality of service (0, 1 or 2)
+    #[serde(default)]
+    pub qos: u8,
+    /// Publish retained messages
+    #[serde(default)]
+    pub retain: bool,
+    /// Format of the published message
+    #[serde(default)]
+    pub format: OutputFormat,
+    #[serde(default = "default_keep_alive", with = "humantime_serde")]
+    pub keep_alive: Duration,
+    /// Delay before reconnecting to the broker
+    #[serde(default = "default_reconnect_delay", with = "humantime_serde")]
+    pub reconnect_delay: Duration,
+    /// Maximum number of pending messages,
+    /// events are dropped when the queue is full
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+/// Build the topic for the event
+///
+/// Wildcard characters are not allowed in
+/// published topics and are replaced by `_`.
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub fn topic(template: &str, channel: &str, event: &str, id: &str) -> String {
+    super::expand_template(template, channel, event, id, &['+', '#'])
+}
+
+#[cfg(feature = "mqtt")]
+pub use sink::MqttSink;
+
+#[cfg(feature = "mqtt")]
+mod sink {
+    use rumqttc::{mqttbytes, AsyncClient, MqttOptions};
+
+    use super::{topic, MqttConfig};
+    use crate::{
+        config::OutputFormat, debezium::ChangeEvent, errors::Error, events::Event,
+        metrics::Metrics, sinks::Sink, subscribe::Envelope, Result,
+    };
+
+    /// MQTT sink
+    pub struct MqttSink {
+        conf: MqttConfig,
+        name: String,
+        qos: mqttbytes::QoS,
+        client: AsyncClient,
+        metrics: Metrics,
+    }
+
+    impl MqttSink {
+        pub fn new(conf: MqttConfig, metrics: Metrics) -> Result<Self> {
+            let qos = mqttbytes::qos(conf.qos)
+                .map_err(|_| Error::Config(format!("Invalid MQTT qos: {}", conf.qos)))?;
+
+            let mut options = MqttOptions::new(&conf.client_id, &conf.host, conf.port);
+            options.set_keep_alive(conf.keep_alive);
+            if let Some(username) = &conf.username {
+                options.set_credentials(username, conf.password.as_deref().unwrap_or_default());
+            }
+
+            let name = format!("mqtt://{}:{}", conf.host, conf.port);
+            let (client, mut eventloop) = AsyncClient::new(options, conf.queue_size.max(1));
+
+            let (broker, delay) = (name.clone(), conf.reconnect_delay);
+            actix_web::rt::spawn(async move {
+                let mut connected = false;
+                loop {
+                    match eventloop.poll().await {
+                        Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                            log::info!("MQTT: connected to {broker}");
+                            connected = true;
+                        }
+                        Ok(_) => (),
+                        Err(err) => {
+                            if connected {
+                                log::error!("MQTT: connection to {broker} lost: {err}");
+                                connected = false;
+                            } else {
+                                log::debug!("MQTT: failed to connect to {broker}: {err}");
+                            }
+                            actix_web::rt::time::sleep(delay).await;
+                        }
+                    }
+                }
+            });
+
+            Ok(Self {
+                conf,
+                name,
+                qos,
+                client,
+                metrics,
+            })
+        }
+    }
+
+    impl Sink for MqttSink {
+        fn send(&self, channel: &str, event: &Event) {
+            let payload = match self.conf.format {
+                OutputFormat::Raw => event.payload_bytes().into_owned(),
+                OutputFormat::Envelope => {
+                    match serde_json::to_vec(&Envelope::new(channel, event, None)) {
+                        Ok(payload) => payload,
+                        Err(err) => {
+                            log::error!("MQTT: failed to serialize event {}: {err}", event.id());
+                            return;
+                        }
+                    }
+                }
+                OutputFormat::Debezium => ChangeEvent::new(channel, event)
+                    .and_then(|change| serde_json::to_vec(&change).ok())
+                    .unwrap_or_else(|| event.payload_bytes().into_owned()),
+            };
+            let topic = topic(&self.conf.topic, channel, event.event(), event.id());
+            let result = match self
+                .client
+                .try_publish(topic, self.qos, self.conf.retain, payload)
+            {
+                Ok(_) => "ok",
+                Err(err) => {
+                    log::warn!("MQTT {}: dropping event {}: {err}", self.name, event.id());
+                    "dropped"
+                }
+            };
+            self.metrics.sink_delivery(&self.name, result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mqtt_topic() {
+        assert_eq!(
+            topic("pg/{channel}/{event}", "foo/bar", "up+date", "1"),
+            "pg/foo/bar/up_date"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//!
+//! NATS sink
+//!
+//! Events are published on NATS subjects
+//! built from a template.
+//!
+use serde::Deserialize;
+use std::path::PathBuf;
+
+use crate::config::OutputFormat;
+
+fn default_url() -> String {
+    "nats://localhost:4222".into()
+}
+
+fn default_subject() -> String {
+    "events.{channel}.{event}".into()
+}
+
+const fn default_queue_size() -> usize {
+    1024
+}
+
+///
+/// NATS configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "nats"), allow(dead_code))]
+pub struct NatsConfig {
+    /// Server urls, as a comma separated list
+    #[serde(default = "default_url")]
+    pub url: String,
+    /// Connection name
+    pub name: Option<String>,
+    /// Channels forwarded to NATS,
+    /// all channels if empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Subject template, `{channel}`, `{event}` and `{id}`
+    /// are replaced by the event values
+    #[serde(default = "default_subject")]
+    pub subject: String,
+    /// Format of the published message
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Path to a credentials (`.creds`) file
+    pub credentials_file: Option<PathBuf>,
+    pub token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Require TLS connection
+    #[serde(default)]
+    pub tls_required: bool,
+    /// CA file used for verifying the server certificate
+    pub tls_ca_file: Option<PathBuf>,
+    /// Client certificate and key
+    pub tls_client_cert: Option<PathBuf>,
+    pub tls_client_key: Option<PathBuf>,
+    /// Maximum number of pending messages,
+    /// events are dropped when the queue is full
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+/// Build the subject for the event
+///
+/// Token separators, wildcards and whitespaces are
+/// not allowed in tokens and are replaced by `_`.
+#[cfg_attr(not(feature = "nats"), allow(dead_code))]
+pub fn subject(template: &str, channel: &str, event: &str, id: &str) -> String {
+    super::expand_template(template, channel, event, id, &['.', '*', '>', ' ', '\t'])
+}
+
+#[cfg(feature = "nats")]
+pub use sink::NatsSink;
+
+#[cfg(feature = "nats")]
+mod sink {
+    use async_nats::ConnectOptions;
+    use tokio::sync::mpsc;
+
+    use super::{subject, NatsConfig};
+    use crate::{
+        config::OutputFormat, debezium::ChangeEvent, events::Event, metrics::Metrics, sinks::Sink,
+        subscribe::Envelope,
+    };
+
+    /// Pending message
+    struct Message {
+        subject: String,
+        payload: Vec<u8>,
+    }
+
+    /// NATS sink
+    pub struct NatsSink {
+        conf: NatsConfig,
+        tx: mpsc::Sender<Message>,
+        metrics: Metrics,
+    }
+
+    impl NatsSink {
+        pub fn new(conf: NatsConfig, metrics: Metrics) -> Self {
+            let (tx, rx) = mpsc::channel(conf.queue_size.max(1));
+            actix_web::rt::spawn(run(conf.clone(), rx, metrics.clone()));
+            Self { conf, tx, metrics }
+        }
+    }
+
+    async fn connect(conf: &NatsConfig) -> Result<async_nats::Client, String> {
+        let mut options = match &conf.credentials_file {
+            Some(path) => ConnectOptions::with_credentials_file(path)
+                .await
+                .map_err(|err| format!("Failed to read credentials {path:?}: {err}"))?,
+            None => ConnectOptions::new(),
+        };
+        if let Some(token) = &conf.token {
+            options = options.token(token.clone());
+        }
+        if let Some(username) = &conf.username {
+            options = options
+                .user_and_password(username.clone(), conf.password.clone().unwrap_or_default());
+        }
+        if let Some(name) = &conf.name {
+            options = options.name(name);
+        }
+        if let Some(path) = &conf.tls_ca_file {
+            options = options.add_root_certificates(path.clone());
+        }
+        if let (Some(cert), Some(key)) = (&conf.tls_client_cert, &conf.tls_client_key) {
+            options = options.add_client_certificate(cert.clone(), key.clone());
+        }
+        options
+            .require_tls(conf.tls_required)
+            .retry_on_initial_connect()
+            .connect(conf.url.as_str())
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    async fn run(conf: NatsConfig, mut rx: mpsc::Receiver<Message>, metrics: Metrics) {
+        let client = match connect(&conf).await {
+            Ok(client) => client,
+            Err(err) => {
+                log::error!("NATS {}: {err}", conf.url);
+                return;
+            }
+        };
+        while let Some(msg) = rx.recv().await {
+            let result = match client.publish(msg.subject, msg.payload.into()).await {
+                Ok(_) => "ok",
+                Err(err) => {
+                    log::error!("NATS {}: failed to publish: {err}", conf.url);
+                    "error"
+                }
+            };
+            metrics.sink_delivery(&conf.url, result);
+        }
+    }
+
+    impl Sink for NatsSink {
+        fn send(&self, channel: &str, event: &Event) {
+            let payload = match self.conf.format {
+                OutputFormat::Raw => event.payload_bytes().into_owned(),
+                OutputFormat::Envelope => {
+                    match serde_json::to_vec(&Envelope::new(channel, event, None)) {
+                        Ok(payload) => payload,
+                        Err(err) => {
+                            log::error!("NATS: failed to serialize event {}: {err}", event.id());
+                            return;
+                        }
+                    }
+                }
+                OutputFormat::Debezium => ChangeEvent::new(channel, event)
+                    .and_then(|change| serde_json::to_vec(&change).ok())
+                    .unwrap_or_else(|| event.payload_bytes().into_owned()),
+            };
+            let msg = Message {
+                subject: subject(&self.conf.subject, channel, event.event(), event.id()),
+                payload,
+            };
+            if self.tx.try_send(msg).is_err() {
+                log::warn!(
+                    "NATS {}: queue full, dropping event {}",
+                    self.conf.url,
+                    event.id()
+                );
+                self.metrics.sink_delivery(&self.conf.url, "dropped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nats_subject() {
+        assert_eq!(
+            subject("events.{channel}.{event}", "foo.bar", "up date", "1"),
+            "events.foo_bar.up_date"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+//!
+//! Webhook sink
+//!
+//! Events are posted as JSON envelopes to an
+//! HTTP endpoint. Failed deliveries are retried
+//! with an exponential backoff.
+//!
+use actix_web::http::StatusCode;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+
+use super::Sink;
+use crate::{events::Event, metrics::Metrics, subscribe::Envelope};
+
+const fn default_concurrency() -> usize {
+    4
+}
+
+const fn default_max_retries() -> u32 {
+    5
+}
+
+const fn default_retry_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+const fn default_queue_size() -> usize {
+    1024
+}
+
+///
+/// Webhook configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    /// Endpoint url
+    pub url: String,
+    /// Channels forwarded to the endpoint,
+    /// all channels if empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Secret used for signing the request body
+    pub secret: Option<String>,
+    /// Maximum number of concurrent requests
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Maximum number of retries for a failed delivery
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Initial delay between retries, doubled on each retry
+    #[serde(default = "default_retry_delay", with = "humantime_serde")]
+    pub retry_delay: Duration,
+    /// Request timeout
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Maximum number of pending events,
+    /// events are dropped when the queue is full
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+/// Pending delivery
+struct Delivery {
+    id: String,
+    body: Vec<u8>,
+}
+
+/// Webhook sink
+pub struct WebhookSink {
+    url: String,
+    tx: mpsc::Sender<Delivery>,
+    metrics: Metrics,
+}
+
+impl WebhookSink {
+    pub fn new(conf: WebhookConfig, metrics: Metrics) -> Self {
+        let (tx, rx) = mpsc::channel(conf.queue_size.max(1));
+        let url = conf.url.clone();
+        actix_web::rt::spawn(run(Rc::new(conf), rx, metrics.clone()));
+        Self { url, tx, metrics }
+    }
+}
+
+impl Sink for WebhookSink {
+    fn send(&self, channel: &str, event: &Event) {
+        let body = match serde_json::to_vec(&Envelope::new(channel, event, None)) {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("Webhook: failed to serialize event {}: {err}", event.id());
+                return;
+            }
+        };
+        let delivery = Delivery {
+            id: event.id().into(),
+            body,
+        };
+        if self.tx.try_send(delivery).is_err() {
+            log::warn!(
+                "Webhook {}: queue full, dropping event {}",
+                self.url,
+                event.id()
+            );
+            self.metrics.sink_delivery(&self.url, "dropped");
+        }
+    }
+}
+
+async fn run(conf: Rc<WebhookConfig>, mut rx: mpsc::Receiver<Delivery>, metrics: Metrics) {
+    let client = Rc::new(awc::Client::builder().timeout(conf.timeout).finish());
+    let semaphore = Arc::new(Semaphore::new(conf.concurrency.max(1)));
+    while let Some(delivery) = rx.recv().await {
+        // The semaphore is never closed
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let (client, conf, metrics) = (client.clone(), conf.clone(), metrics.clone());
+        actix_web::rt::spawn(async move {
+            let result = deliver(&client, &conf, &delivery).await;
+            metrics.sink_delivery(&conf.url, if result { "ok" } else { "error" });
+            drop(permit);
+        });
+    }
+}
+
+/// Deliver the event, retrying on failure
+///
+/// Return false if the event could not be delivered.
+async fn deliver(client: &awc::Client, conf: &WebhookConfig, delivery: &Delivery) -> bool {
+    let signature = conf
+        .secret
+        .as_deref()
+        .map(|secret| sign(secret, &delivery.body));
+    let mut delay = conf.retry_delay;
+    for attempt in 0..=conf.max_retries {
+        if attempt > 0 {
+            actix_web::rt::time::sleep(delay).await;
+            delay *= 2;
+        }
+        let mut req = client
+            .post(&conf.url)
+            .content_type("application/json")
+            .insert_header(("X-Event-Id", delivery.id.as_str()));
+        if let Some(signature) = &signature {
+            req = req.insert_header(("X-Signature-256", signature.as_str()));
+        }
+        match req.send_body(delivery.body.clone()).await {
+            Ok(resp) if resp.status().is_success() => return true,
+            Ok(resp) if !is_retryable(resp.status()) => {
+                log::error!(
+                    "Webhook {}: event {} rejected with status {}",
+                    conf.url,
+                    delivery.id,
+                    resp.status()
+                );
+                return false;
+            }
+            Ok(resp) => log::warn!(
+                "Webhook {}: delivery of event {} failed with status {} (attempt {})",
+                conf.url,
+                delivery.id,
+                resp.status(),
+                attempt + 1,
+            ),
+            Err(err) => log::warn!(
+                "Webhook {}: delivery of event {} failed: {err} (attempt {})",
+                conf.url,
+                delivery.id,
+                attempt + 1,
+            ),
+        }
+    }
+    log::error!(
+        "Webhook {}: giving up delivery of event {}",
+        conf.url,
+        delivery.id
+    );
+    false
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Return the `sha256=<hex>` HMAC signature of `body`
+fn sign(secret: &str, body: &[u8]) -> String {
+    // Hmac accepts keys of any size
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let hex = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("sha256={hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_signature() {
+        // Test vector from RFC 4231 (test case 2)
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert!(is_retryable(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_retryable(StatusCode::BAD_REQUEST));
+    }
+}
@@ -0,0 +1,198 @@
+//!
+//! JSON stats
+//!
+//! Provide a snapshot of the server state for
+//! monitoring scripts.
+//!
+use actix_web::{web, HttpResponse, Responder};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::connections::Connections;
+use crate::metrics::{ChannelTotals, Metrics};
+use crate::pool::{BackendStatus, PoolStatus};
+use crate::registry::{ChannelRegistry, Entry};
+use crate::utils::timestamp_millis;
+
+/// Last event received for a channel
+#[derive(Debug, Clone, Serialize)]
+pub struct LastEvent {
+    id: String,
+    /// Reception time in milliseconds since epoch
+    ts: u64,
+}
+
+/// Shared server stats
+#[derive(Clone)]
+pub struct Stats {
+    start: Instant,
+    started_at: u64,
+    last_events: Arc<RwLock<HashMap<String, LastEvent>>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+    /// Create new stats
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            started_at: timestamp_millis(),
+            last_events: Arc::default(),
+        }
+    }
+
+    /// Record the last event sent to `channel`
+    pub fn last_event(&self, channel: &str, id: &str, ts: u64) {
+        let mut last_events = self.last_events.write().unwrap();
+        // Do not allocate the channel name on each event
+        match last_events.get_mut(channel) {
+            Some(last) => {
+                last.id.replace_range(.., id);
+                last.ts = ts;
+            }
+            None => {
+                last_events.insert(channel.into(), LastEvent { id: id.into(), ts });
+            }
+        }
+    }
+
+    /// Start time in milliseconds since epoch
+    pub fn started_at(&self) -> u64 {
+        self.started_at
+    }
+
+    /// Uptime in seconds
+    pub fn uptime(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    fn snapshot(
+        &self,
+        metrics: &Metrics,
+        status: &PoolStatus,
+        registry: &ChannelRegistry,
+        connections: &Connections,
+    ) -> Snapshot {
+        let last_events = self.last_events.read().unwrap();
+        let subscribers = connections.subscribers();
+        Snapshot {
+            uptime: self.uptime(),
+            started_at: self.started_at,
+            channels: registry
+                .channels()
+                .into_iter()
+                .map(|Entry { conf, .. }| ChannelStats {
+                    subscribers: metrics.subscribers(&conf.id).get(),
+                    totals: metrics.channel_totals(&conf.id),
+                    last_event: last_events.get(&conf.id).cloned(),
+                    dropping_subscribers: subscribers
+                        .iter()
+                        .filter(|s| s.dropped > 0 && s.channels.contains(&conf.id))
+                        .map(|s| DroppingSubscriber {
+                            id: s.id,
+                            dropped: s.dropped,
+                        })
+                        .collect(),
+                    id: conf.id,
+                })
+                .collect(),
+            backends: status.backends(),
+        }
+    }
+}
+
+/// Subscriber for which events were dropped
+#[derive(Serialize)]
+struct DroppingSubscriber {
+    id: Uuid,
+    dropped: u64,
+}
+
+#[derive(Serialize)]
+struct ChannelStats {
+    id: String,
+    subscribers: i64,
+    #[serde(flatten)]
+    totals: ChannelTotals,
+    last_event: Option<LastEvent>,
+    dropping_subscribers: Vec<DroppingSubscriber>,
+}
+
+#[derive(Serialize)]
+struct Snapshot {
+    /// Uptime in seconds
+    uptime: u64,
+    /// Start time in milliseconds since epoch
+    started_at: u64,
+    channels: Vec<ChannelStats>,
+    backends: Vec<BackendStatus>,
+}
+
+/// Stats handler
+pub async fn handler(
+    stats: web::Data<Stats>,
+    metrics: web::Data<Metrics>,
+    status: web::Data<PoolStatus>,
+    registry: web::Data<ChannelRegistry>,
+    connections: web::Data<Connections>,
+) -> impl Responder {
+    HttpResponse::Ok().json(stats.snapshot(&metrics, &status, &registry, &connections))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::SubscriberInfo;
+
+    #[test]
+    fn stats_snapshot() {
+        let stats = Stats::new();
+        let metrics = Metrics::new();
+        let registry = ChannelRegistry::default();
+        registry.insert(toml::from_str(r#"id = "foo""#).unwrap());
+        registry.insert(toml::from_str(r#"id = "bar""#).unwrap());
+
+        metrics.subscribers("foo").inc();
+        stats.last_event("foo", "1", 1000);
+
+        let connections = Connections::default();
+        let mut guard = connections.acquire(None).unwrap();
+        let id = Uuid::new_v4();
+        guard.register(SubscriberInfo {
+            id,
+            channels: vec!["foo".into()],
+            client_id: None,
+            remote_ip: None,
+            connected_at: 0,
+            dropped: 0,
+        });
+        connections.drop_event(&id);
+
+        let value = serde_json::to_value(stats.snapshot(
+            &metrics,
+            &PoolStatus::default(),
+            &registry,
+            &connections,
+        ))
+        .unwrap();
+        assert_eq!(value["channels"][0]["subscribers"], 1);
+        assert_eq!(value["channels"][0]["last_event"]["id"], "1");
+        assert_eq!(
+            value["channels"][0]["dropping_subscribers"][0]["dropped"],
+            1
+        );
+        assert!(value["channels"][1]["last_event"].is_null());
+        assert_eq!(
+            value["channels"][1]["dropping_subscribers"],
+            serde_json::json!([])
+        );
+    }
+}
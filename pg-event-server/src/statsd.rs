@@ -0,0 +1,240 @@
+//!
+//! StatsD metrics exporter
+//!
+//! Metrics are periodically pushed to a StatsD server
+//! (i.e the Datadog agent) with Dogstatsd tags:
+//!
+//! * counters are sent as the increment since the last push,
+//! * gauges are sent as their current value,
+//! * histograms are sent as `<name>.count` and `<name>.sum` counters.
+//!
+//! Prometheus labels are sent as tags.
+//!
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use crate::{metrics::Metrics, Result};
+
+// Keep datagrams below the usual MTU
+const MAX_PACKET_SIZE: usize = 1432;
+
+// Prefix of the registry metric names
+const NAMESPACE: &str = "pg_event_server_";
+
+fn default_address() -> String {
+    "127.0.0.1:8125".into()
+}
+
+fn default_prefix() -> String {
+    "pg_event_server".into()
+}
+
+const fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+///
+/// StatsD exporter configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsdConfig {
+    /// Address of the StatsD server
+    #[serde(default = "default_address")]
+    pub address: String,
+    /// Prefix of the metric names
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Tags added to all metrics
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Push interval
+    #[serde(default = "default_interval", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+/// Convert metric families to statsd lines
+///
+/// `last` holds the counter values of the previous push.
+struct Collector {
+    prefix: String,
+    tags: Vec<String>,
+    last: HashMap<String, f64>,
+}
+
+impl Collector {
+    fn new(conf: &StatsdConfig) -> Self {
+        Self {
+            prefix: conf.prefix.clone(),
+            tags: conf.tags.clone(),
+            last: HashMap::new(),
+        }
+    }
+
+    // Return the increment of the counter `key`
+    fn delta(&mut self, key: String, value: f64) -> f64 {
+        let last = self.last.insert(key, value).unwrap_or(0.);
+        // Counter reset
+        if value < last {
+            value
+        } else {
+            value - last
+        }
+    }
+
+    fn collect(&mut self, families: &[MetricFamily]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for family in families {
+            let name = family.get_name();
+            let name = format!(
+                "{}.{}",
+                self.prefix,
+                name.strip_prefix(NAMESPACE).unwrap_or(name)
+            );
+            for metric in family.get_metric() {
+                let tags = self.format_tags(
+                    metric
+                        .get_label()
+                        .iter()
+                        .map(|label| (label.get_name(), label.get_value())),
+                );
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let delta =
+                            self.delta(format!("{name}{tags}"), metric.get_counter().get_value());
+                        if delta > 0. {
+                            lines.push(format!("{name}:{delta}|c{tags}"));
+                        }
+                    }
+                    MetricType::GAUGE => {
+                        let value = metric.get_gauge().get_value();
+                        lines.push(format!("{name}:{value}|g{tags}"));
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        let count = self.delta(
+                            format!("{name}.count{tags}"),
+                            histogram.get_sample_count() as f64,
+                        );
+                        if count > 0. {
+                            let sum =
+                                self.delta(format!("{name}.sum{tags}"), histogram.get_sample_sum());
+                            lines.push(format!("{name}.count:{count}|c{tags}"));
+                            lines.push(format!("{name}.sum:{sum}|c{tags}"));
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        lines
+    }
+
+    // Format labels and constant tags as Dogstatsd tags
+    fn format_tags<'a>(&self, labels: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+        let mut tags = String::new();
+        labels
+            .map(|(name, value)| format!("{name}:{}", value.replace([',', '|', '#'], "_")))
+            .chain(self.tags.iter().cloned())
+            .for_each(|tag| {
+                tags.push(if tags.is_empty() { '#' } else { ',' });
+                tags.push_str(&tag);
+            });
+        if !tags.is_empty() {
+            tags.insert(0, '|');
+        }
+        tags
+    }
+}
+
+// Pack lines into datagrams
+fn packets(lines: Vec<String>) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET_SIZE {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(&line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+/// Start pushing metrics to the StatsD server
+pub async fn start(conf: &StatsdConfig, metrics: Metrics) -> Result<()> {
+    let socket = UdpSocket::bind(if conf.address.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    })
+    .await?;
+    socket.connect(&conf.address).await?;
+
+    log::info!("Pushing metrics to statsd server {}", conf.address);
+
+    let mut collector = Collector::new(conf);
+    let mut interval = tokio::time::interval(conf.interval);
+    actix_web::rt::spawn(async move {
+        loop {
+            interval.tick().await;
+            for packet in packets(collector.collect(&metrics.gather())) {
+                if let Err(err) = socket.send(packet.as_bytes()).await {
+                    log::error!("Statsd error: {err}");
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statsd_lines() {
+        let metrics = Metrics::new();
+        let mut collector = Collector::new(&StatsdConfig {
+            address: default_address(),
+            prefix: default_prefix(),
+            tags: vec!["env:test".into()],
+            interval: default_interval(),
+        });
+
+        metrics.event_broadcast("test");
+        metrics.event_broadcast("test");
+        metrics.subscribers("test").inc();
+        metrics.observe_latency(500);
+
+        let lines = collector.collect(&metrics.gather());
+        assert!(lines
+            .contains(&"pg_event_server.events_broadcast_total:2|c|#channel:test,env:test".into()));
+        assert!(lines.contains(&"pg_event_server.subscribers:1|g|#channel:test,env:test".into()));
+        assert!(
+            lines.contains(&"pg_event_server.dispatch_latency_seconds.count:1|c|#env:test".into())
+        );
+        assert!(
+            lines.contains(&"pg_event_server.dispatch_latency_seconds.sum:0.5|c|#env:test".into())
+        );
+
+        // Only increments are sent
+        metrics.event_broadcast("test");
+        let lines = collector.collect(&metrics.gather());
+        assert!(lines
+            .contains(&"pg_event_server.events_broadcast_total:1|c|#channel:test,env:test".into()));
+        assert!(!lines.iter().any(|l| l.contains("dispatch_latency")));
+
+        let packets = packets(vec!["x".repeat(1000), "y".repeat(1000), "z".into()]);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1], format!("{}\nz", "y".repeat(1000)));
+    }
+}
@@ -4,33 +4,374 @@
 //! A channel may be open for any number of subscriptions.
 //! Each subscription should be given a unique id.
 //!
+//! A subscription may be attached to multiple channels, in this
+//! case the event name is prefixed with the id of the originating
+//! channel: `{channel_id}:{event}`.
 //!
-use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+//! Subscribers are notified of the state of the postgres
+//! connection of their channels with `_status` events.
+//!
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::future::Future;
+use std::iter;
+use std::net::IpAddr;
+use std::pin::Pin;
 use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 //use std::time::SystemTime;
 
-use actix_web::{web, HttpRequest, Responder};
-use actix_web_lab::sse;
-use futures::future;
+use actix_web::{
+    http::header,
+    web::{self, Bytes, BytesMut},
+    HttpRequest, HttpResponse, Responder,
+};
+use futures::{stream, Stream, StreamExt};
+use ipnet::IpNet;
+use prometheus::{IntCounter, IntGauge};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::Sleep;
+use tracing::Instrument;
 use uuid::Uuid;
 
 use crate::{
+    accesslog::{CloseReason, Session, SessionGuard},
+    acks::Acks,
+    config::{AckConfig, CoalesceConfig, OutputFormat, SlowConsumerPolicy},
+    connections::{ConnectionGuard, Connections, SubscriberInfo},
+    debezium::ChangeEvent,
+    eventpattern::{self, EventPattern},
     events::{ChanId, Event},
+    filter::Filter,
+    forwardauth::ForwardAuth,
+    groups::{ConsumerGroups, GroupGuard, GroupMember, GroupSettings},
+    history::History,
+    introspection::{Introspector, Scopes},
+    metrics::Metrics,
+    pool::PoolStatus,
+    ratelimit::RateLimits,
+    registry::{ChannelRegistry, Entry},
+    requestid::request_id,
+    server::{proxy::TrustedProxies, tls::PeerIdentity},
+    signedurl::UrlSigner,
+    utils::{authorization_key, bearer_token, is_ip_allowed, timestamp_millis},
     Error, Result,
 };
 
-type Subscriptions = RefCell<HashMap<ChanId, Vec<Channel>>>;
+/// Subscribers of each channel
+///
+/// Pools are copied on write if a broadcast holds
+/// a snapshot of them.
+type Subscriptions = RefCell<HashMap<ChanId, Rc<Vec<Rc<Channel>>>>>;
+
+/// JSON envelope for events
+#[derive(Serialize)]
+pub(crate) struct Envelope<'a> {
+    id: &'a str,
+    ts: u64,
+    channel: &'a str,
+    pg_channel: &'a str,
+    session_pid: i32,
+    payload: Payload<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Payload<'a> {
+    Json(&'a Value),
+    Text(&'a str),
+    Compressed(String),
+}
+
+impl<'a> Envelope<'a> {
+    pub(crate) fn new(channel: &'a str, event: &'a Event, compress_above: Option<usize>) -> Self {
+        let (payload, encoding) = match compress_above {
+            // Binary payloads are sent as base64
+            _ if event.is_binary() => (Payload::Text(event.payload()), Some("base64")),
+            Some(size) if event.payload().len() > size => (
+                Payload::Compressed(gzip_base64(event.payload())),
+                Some("gzip"),
+            ),
+            _ => (
+                event
+                    .json()
+                    .map_or(Payload::Text(event.payload()), Payload::Json),
+                None,
+            ),
+        };
+        Self {
+            id: event.id(),
+            ts: event.timestamp(),
+            channel,
+            pg_channel: event.event(),
+            session_pid: event.session_pid(),
+            payload,
+            encoding,
+        }
+    }
+}
+
+/// Compress data with gzip and encode the result as base64
+fn gzip_base64(data: &str) -> String {
+    use base64::Engine;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing to a Vec cannot fail
+    encoder.write_all(data.as_bytes()).unwrap();
+    base64::engine::general_purpose::STANDARD.encode(encoder.finish().unwrap())
+}
+
+/// Serialize an SSE event in event-stream format
+fn sse_frame(id: Option<&str>, name: &str, data: &str) -> Bytes {
+    let mut buf = BytesMut::with_capacity(data.len() + name.len() + id.map_or(0, str::len) + 32);
+    if let Some(id) = id {
+        buf.extend_from_slice(b"id: ");
+        buf.extend_from_slice(id.as_bytes());
+        buf.extend_from_slice(b"\n");
+    }
+    buf.extend_from_slice(b"event: ");
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(b"\n");
+    for line in data.split('\n') {
+        buf.extend_from_slice(b"data: ");
+        buf.extend_from_slice(line.as_bytes());
+        buf.extend_from_slice(b"\n");
+    }
+    buf.extend_from_slice(b"\n");
+    buf.freeze()
+}
+
+/// SSE event with the size of its data
+struct Frame {
+    chan_id: ChanId,
+    /// Number of events
+    count: usize,
+    size: usize,
+    event: Bytes,
+}
+
+/// Key of the rendering of an event for a subscription:
+/// the subscription id, whether the event name is prefixed
+/// by the subscription name, and the output format
+type FrameKey = (ChanId, bool, OutputFormat);
+
+/// SSE frames of an event
+///
+/// Frames are serialized once per rendering and shared
+/// between all the subscribers of the event.
+#[derive(Default)]
+struct Frames(RefCell<HashMap<FrameKey, (Bytes, usize)>>);
+
+impl Frames {
+    fn get_or_insert_with(
+        &self,
+        key: FrameKey,
+        f: impl FnOnce() -> (Bytes, usize),
+    ) -> (Bytes, usize) {
+        self.0.borrow_mut().entry(key).or_insert_with(f).clone()
+    }
+}
+
+/// Message sent to the event stream
+enum Message {
+    Event(Frame),
+    /// Event coalesced with the events of
+    /// the same channel
+    Batched {
+        chan_id: ChanId,
+        coalesce: CoalesceConfig,
+        name: String,
+        id: String,
+        item: Value,
+    },
+}
+
+/// Pending batch of events
+struct Batch {
+    chan_id: ChanId,
+    name: String,
+    /// Id of the last event
+    id: String,
+    items: Vec<Value>,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl Batch {
+    fn into_frame(self) -> Frame {
+        let count = self.items.len();
+        let data = Value::Array(self.items).to_string();
+        Frame {
+            chan_id: self.chan_id,
+            count,
+            size: data.len(),
+            event: sse_frame(Some(&self.id), &self.name, &data),
+        }
+    }
+}
+
+/// SSE event stream returned to the client
+///
+/// The connection guard is released when the
+/// response is dropped, i.e when the client disconnect.
+struct EventStream {
+    rx: mpsc::Receiver<Message>,
+    /// Frames sent before the received events
+    backlog: VecDeque<Frame>,
+    batches: Vec<Batch>,
+    _guard: ConnectionGuard,
+    _group: Option<GroupGuard>,
+    subscribers: SubscribersGuard,
+    session: SessionGuard,
+}
+
+impl EventStream {
+    /// Count the bytes sent for the frame channel
+    fn send(&self, frame: Frame) -> Poll<Option<Result<Bytes, Infallible>>> {
+        if let Some(sub) = self
+            .subscribers
+            .0
+            .iter()
+            .find(|sub| sub.id == frame.chan_id)
+        {
+            sub.bytes_sent.inc_by(frame.size as u64);
+        }
+        self.session.0.sent(frame.count, frame.size);
+        Poll::Ready(Some(Ok(frame.event)))
+    }
+
+    /// Add item to the batch of its channel
+    ///
+    /// Return the batch if it is full.
+    fn push(&mut self, msg: Message) -> Option<Frame> {
+        let Message::Batched {
+            chan_id,
+            coalesce,
+            name,
+            id,
+            item,
+        } = msg
+        else {
+            return None;
+        };
+        let index = match self.batches.iter().position(|b| b.chan_id == chan_id) {
+            Some(index) => index,
+            None => {
+                self.batches.push(Batch {
+                    chan_id,
+                    name,
+                    id: String::new(),
+                    items: Vec::with_capacity(coalesce.max),
+                    deadline: Box::pin(tokio::time::sleep(coalesce.window)),
+                });
+                self.batches.len() - 1
+            }
+        };
+        let batch = &mut self.batches[index];
+        batch.id = id;
+        batch.items.push(item);
+        (batch.items.len() >= coalesce.max).then(|| self.batches.remove(index).into_frame())
+    }
+}
+
+/// Track active subscribers of channels
+struct SubscribersGuard(Vec<Rc<Subscription>>);
+
+impl SubscribersGuard {
+    fn new(subs: Vec<Rc<Subscription>>) -> Self {
+        subs.iter().for_each(|sub| sub.subscribers.inc());
+        Self(subs)
+    }
+}
+
+impl Drop for SubscribersGuard {
+    fn drop(&mut self) {
+        self.0.iter().for_each(|sub| sub.subscribers.dec());
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Result<Bytes, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(frame) = self.backlog.pop_front() {
+            return self.send(frame);
+        }
+        loop {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(Message::Event(frame))) => return self.send(frame),
+                Poll::Ready(Some(msg)) => {
+                    if let Some(frame) = self.push(msg) {
+                        return self.send(frame);
+                    }
+                }
+                Poll::Ready(None) => {
+                    // Flush pending batches before closing
+                    if self.batches.is_empty() {
+                        self.session.0.close(CloseReason::Server);
+                        return Poll::Ready(None);
+                    }
+                    let frame = self.batches.remove(0).into_frame();
+                    return self.send(frame);
+                }
+                Poll::Pending => break,
+            }
+        }
+        // Flush expired batches
+        match self
+            .batches
+            .iter_mut()
+            .position(|batch| batch.deadline.as_mut().poll(cx).is_ready())
+        {
+            Some(index) => {
+                let frame = self.batches.remove(index).into_frame();
+                self.send(frame)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
 
 struct Channel {
-    id: ChanId,
+    /// Subscribed channels
+    subs: Vec<Rc<Subscription>>,
     path: String,
     ident: Uuid,
-    sender: sse::Sender,
+    sender: mpsc::Sender<Message>,
     //timestamp: u64,
     realip_remote_addr: Option<String>,
     peer_addr: Option<String>,
     client_id: Option<String>,
+    /// Events requested by the subscriber,
+    /// all events if empty
+    events: HashSet<String>,
+    /// Filter on json payloads
+    filter: Option<Filter>,
+    /// Requested output format, use the channel
+    /// format if not set
+    format: Option<OutputFormat>,
+    /// Consumer group
+    group: Option<GroupMember>,
+    /// Consumer id and unacknowledged events
+    /// for acknowledged delivery
+    consumer: Option<(String, Acks)>,
+    policy: SlowConsumerPolicy,
+    timeout: Duration,
+    /// Registry holding the number of events
+    /// dropped for this subscriber
+    connections: Connections,
+    /// Access log session
+    session: Rc<Session>,
+    /// Id of the subscription request
+    request_id: String,
 }
 
 impl Channel {
@@ -44,64 +385,833 @@ impl Channel {
     fn peer_addr(&self) -> Option<&str> {
         self.peer_addr.as_deref()
     }
+
+    /// Return the subscription for `id`
+    fn subscription(&self, id: ChanId) -> Option<&Subscription> {
+        self.subs
+            .iter()
+            .find(|sub| sub.id == id)
+            .map(|sub| sub.as_ref())
+    }
+
+    /// Return true if the subscriber requested that event
+    ///
+    /// Status events are always accepted.
+    ///
+    /// In a consumer group, only the selected member
+    /// accepts the event.
+    fn accept(&self, sub: &Subscription, event: &Event) -> bool {
+        event.is_status()
+            || (self.events.is_empty() || self.events.contains(sub.event_name(event.event())))
+                && self
+                    .filter
+                    .as_ref()
+                    .is_none_or(|filter| event.json().is_some_and(|value| filter.matches(value)))
+                && self
+                    .group
+                    .as_ref()
+                    .is_none_or(|group| group.is_selected(sub.id, event))
+    }
+
+    fn drop_event(&self, sub: &Subscription, event: &Event) {
+        sub.dropped_events.inc();
+        let dropped = self.connections.drop_event(&self.ident);
+        log::warn!(
+            "DROP({},{}) {}: {} (dropped: {}, request: '{}')",
+            self.path,
+            self.ident,
+            event.event(),
+            event.id(),
+            dropped,
+            self.request_id,
+        );
+    }
+
+    /// Send the event according to the slow consumer policy
+    ///
+    /// Return false if the connection must be closed.
+    ///
+    /// The SSE frame is taken from `frames` if already
+    /// serialized for another subscriber.
+    async fn send(&self, sub: &Subscription, event: &Event, frames: &Frames) -> bool {
+        if let (Some(coalesce), false) = (sub.coalesce, event.is_status()) {
+            return self
+                .send_message(sub, event, self.batched(sub, event, coalesce))
+                .await;
+        }
+        let multi = self.subs.len() > 1;
+        let format = self.format.unwrap_or(sub.format);
+        let (data, size) =
+            frames.get_or_insert_with((sub.id, multi, format), || self.render(sub, event, format));
+        let msg = Message::Event(Frame {
+            chan_id: sub.id,
+            count: 1,
+            size,
+            event: data,
+        });
+        self.send_message(sub, event, msg).await
+    }
+
+    /// Return the frames of the unacknowledged
+    /// events of the consumer
+    async fn redelivered(&self) -> VecDeque<Frame> {
+        let mut frames = VecDeque::new();
+        let Some((consumer, acks)) = &self.consumer else {
+            return frames;
+        };
+        for (sub, conf) in self
+            .subs
+            .iter()
+            .filter_map(|sub| sub.ack.as_ref().map(|conf| (sub, conf)))
+        {
+            let events = acks.redeliver(sub.id, &sub.name, consumer, conf).await;
+            frames.extend(events.iter().map(|event| self.frame(sub, event)));
+        }
+        frames
+    }
+
+    /// Return the frames of the retained events
+    /// following the event `since`
+    ///
+    /// Nothing is replayed if `since` is no longer
+    /// in the history.
+    async fn replayed(&self, history: &History, since: &str) -> VecDeque<Frame> {
+        let mut frames = VecDeque::new();
+        for sub in self.subs.iter().filter(|sub| sub.history) {
+            let events = history.replay(sub.id, since).await.unwrap_or_else(|| {
+                log::debug!("Event {since} not in the history of '{}'", sub.name);
+                vec![]
+            });
+            frames.extend(
+                events
+                    .into_iter()
+                    .filter(|event| self.accept(sub, event))
+                    .map(|event| self.frame(sub, &event)),
+            );
+        }
+        frames
+    }
+
+    /// Return the frame of the event
+    fn frame(&self, sub: &Subscription, event: &Event) -> Frame {
+        let (data, size) = self.render(sub, event, self.format.unwrap_or(sub.format));
+        Frame {
+            chan_id: sub.id,
+            count: 1,
+            size,
+            event: data,
+        }
+    }
+
+    /// Serialize the SSE frame of the event
+    ///
+    /// Return the frame and the size of its data.
+    fn render(&self, sub: &Subscription, event: &Event, format: OutputFormat) -> (Bytes, usize) {
+        let name = if self.subs.len() > 1 {
+            format!("{}:{}", sub.name, sub.event_name(event.event()))
+        } else {
+            sub.event_name(event.event()).into()
+        };
+        if event.is_status() {
+            // Status events have no id, so that they
+            // do not change the last event id of the client
+            let data = event.payload();
+            return (sse_frame(None, &name, data), data.len());
+        }
+        let data = match format {
+            OutputFormat::Raw => Cow::Borrowed(event.payload()),
+            OutputFormat::Envelope => Cow::Owned(
+                serde_json::to_string(&Envelope::new(&sub.name, event, sub.compress_above))
+                    .unwrap_or_else(|err| {
+                        log::error!("Failed to serialize envelope: {err:?}");
+                        event.payload().into()
+                    }),
+            ),
+            // Payloads other than row changes are sent raw
+            OutputFormat::Debezium => ChangeEvent::new(&sub.name, event)
+                .and_then(|change| serde_json::to_string(&change).ok())
+                .map_or(Cow::Borrowed(event.payload()), Cow::Owned),
+        };
+        (sse_frame(Some(event.id()), &name, &data), data.len())
+    }
+
+    /// Return the coalesced message for the event
+    ///
+    /// Batch items are the envelopes in `envelope` format,
+    /// the payloads otherwise.
+    fn batched(&self, sub: &Subscription, event: &Event, coalesce: CoalesceConfig) -> Message {
+        let name = if self.subs.len() > 1 {
+            format!("{}:batch", sub.name)
+        } else {
+            "batch".into()
+        };
+        let item = match self.format.unwrap_or(sub.format) {
+            OutputFormat::Raw => event
+                .json()
+                .cloned()
+                .unwrap_or_else(|| Value::String(event.payload().into())),
+            OutputFormat::Envelope => {
+                serde_json::to_value(Envelope::new(&sub.name, event, sub.compress_above))
+                    .unwrap_or_else(|err| {
+                        log::error!("Failed to serialize envelope: {err:?}");
+                        Value::String(event.payload().into())
+                    })
+            }
+            OutputFormat::Debezium => ChangeEvent::new(&sub.name, event)
+                .and_then(|change| serde_json::to_value(change).ok())
+                .or_else(|| event.json().cloned())
+                .unwrap_or_else(|| Value::String(event.payload().into())),
+        };
+        Message::Batched {
+            chan_id: sub.id,
+            coalesce,
+            name,
+            id: event.id().into(),
+            item,
+        }
+    }
+
+    /// Send the message according to the slow consumer policy
+    ///
+    /// Return false if the connection must be closed.
+    async fn send_message(&self, sub: &Subscription, event: &Event, msg: Message) -> bool {
+        match self.policy {
+            SlowConsumerPolicy::Drop => match self.sender.try_send(msg) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.drop_event(sub, event);
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            },
+            SlowConsumerPolicy::Disconnect => match self.sender.try_send(msg) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!(
+                        "Disconnecting slow consumer {} ({}, request: '{}')",
+                        self.ident,
+                        self.path,
+                        self.request_id
+                    );
+                    self.session.close(CloseReason::SlowConsumer);
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            },
+            SlowConsumerPolicy::BlockWithTimeout => {
+                match tokio::time::timeout(self.timeout, self.sender.send(msg)).await {
+                    Ok(result) => result.is_ok(),
+                    Err(_) => {
+                        self.drop_event(sub, event);
+                        true
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Subscription access rules
+struct Subscription {
+    id: ChanId,
+    /// Version of the channel configuration
+    version: u64,
+    /// The channel id used in subscription requests
+    name: String,
+    /// Allowed api keys, no restriction if empty
+    /// and no scopes are defined
+    api_keys: HashSet<String>,
+    /// Allowed OAuth2 scopes
+    scopes: HashSet<String>,
+    /// Allowed networks, no restriction if empty
+    allow_ips: Vec<IpNet>,
+    /// Denied networks
+    deny_ips: Vec<IpNet>,
+    /// Allowed client certificate identities,
+    /// no restriction if empty
+    identities: HashSet<String>,
+    /// Allowed events, all events allowed if empty
+    events: Vec<EventPattern>,
+    /// Denied events
+    denied: Vec<EventPattern>,
+    /// Mapping of postgres events to SSE event names
+    event_map: HashMap<String, String>,
+    format: OutputFormat,
+    compress_above: Option<usize>,
+    policy: SlowConsumerPolicy,
+    timeout: Duration,
+    coalesce: Option<CoalesceConfig>,
+    /// Acknowledged delivery
+    ack: Option<AckConfig>,
+    /// Events are retained for replay
+    history: bool,
+    /// Reject subscriptions while the
+    /// backend is down
+    reject_when_down: bool,
+    /// Active subscribers metric
+    subscribers: IntGauge,
+    /// Dropped events metric
+    dropped_events: IntCounter,
+    /// Bytes sent metric
+    bytes_sent: IntCounter,
+}
+
+impl Subscription {
+    fn new(entry: &Entry, metrics: &Metrics) -> Self {
+        let conf = &entry.conf;
+        Self {
+            id: entry.chan_id,
+            version: entry.version,
+            name: conf.id.clone(),
+            event_map: conf.event_map.clone(),
+            format: conf.format,
+            compress_above: conf.compress_payload_above,
+            api_keys: conf.api_keys.iter().cloned().collect(),
+            scopes: conf.allowed_scopes.iter().cloned().collect(),
+            allow_ips: conf.allow_ips.clone(),
+            deny_ips: conf.deny_ips.clone(),
+            identities: conf.allowed_identities.iter().cloned().collect(),
+            events: conf.allowed_events.clone(),
+            denied: conf.deny_events.clone(),
+            policy: conf.slow_consumer,
+            timeout: conf.slow_consumer_timeout,
+            coalesce: conf.coalesce,
+            ack: conf.ack,
+            history: conf.history.is_some(),
+            reject_when_down: conf.reject_when_down,
+            subscribers: metrics.subscribers(&conf.id),
+            dropped_events: metrics.dropped_events(&conf.id),
+            bytes_sent: metrics.bytes_sent(&conf.id),
+        }
+    }
+
+    /// Check that the request present valid credentials
+    ///
+    /// The api key may be passed either in the `Authorization`
+    /// header with the `Key` scheme or as the `api_key`
+    /// query parameter.
+    ///
+    /// `scopes` are the scopes granted to the bearer token
+    /// of the request, if any.
+    fn check_credentials(
+        &self,
+        req: &HttpRequest,
+        params: &SubscribeParams,
+        scopes: Option<&Scopes>,
+    ) -> Result<()> {
+        if self.api_keys.is_empty() && self.scopes.is_empty() {
+            return Ok(());
+        }
+        if let Some(scopes) = scopes {
+            return if self.scopes.is_disjoint(scopes) {
+                Err(Error::Forbidden)
+            } else {
+                Ok(())
+            };
+        }
+        let key = authorization_key(req).or(params.api_key.as_deref());
+        match key {
+            Some(key) if self.api_keys.contains(key) => Ok(()),
+            Some(_) => Err(Error::Forbidden),
+            None => Err(Error::Unauthorized),
+        }
+    }
+
+    /// Check that the channel backend is up
+    /// if subscriptions are rejected when down
+    fn check_backend(&self, status: &PoolStatus) -> Result<()> {
+        if self.reject_when_down && !status.is_channel_connected(&self.name) {
+            Err(Error::BackendUnavailable(self.name.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that the client ip is allowed
+    fn check_ip(&self, ip: Option<IpAddr>) -> Result<()> {
+        if is_ip_allowed(&self.allow_ips, &self.deny_ips, ip) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden)
+        }
+    }
+
+    /// Check that the client certificate identity
+    /// is allowed
+    fn check_identity(&self, identity: Option<&PeerIdentity>) -> Result<()> {
+        if self.identities.is_empty() {
+            return Ok(());
+        }
+        match identity {
+            Some(PeerIdentity(id)) if self.identities.contains(id) => Ok(()),
+            Some(_) => Err(Error::Forbidden),
+            None => Err(Error::Unauthorized),
+        }
+    }
+
+    /// Return the SSE event name for the postgres event
+    fn event_name<'a>(&'a self, event: &'a str) -> &'a str {
+        self.event_map.get(event).map_or(event, String::as_str)
+    }
+
+    /// Return true if the SSE event `name` is allowed for this subscription
+    fn is_allowed(&self, name: &str) -> bool {
+        eventpattern::is_allowed(&self.events, &self.denied, &self.event_map, name)
+    }
+}
+
+/// Subscription query parameters
+#[derive(Default)]
+struct SubscribeParams {
+    api_key: Option<String>,
+    /// Additional channels
+    channels: Vec<String>,
+    /// Restrict events sent to the subscriber
+    events: HashSet<String>,
+    /// Filter applied on json payloads
+    filter: Option<Filter>,
+    /// Output format
+    format: Option<OutputFormat>,
+    /// Expiration time of signed urls
+    exp: Option<u64>,
+    /// Signature of signed urls
+    sig: Option<String>,
+    /// Consumer group
+    group: Option<String>,
+    /// Payload field used for distributing
+    /// events in the consumer group
+    group_key: Option<String>,
+    /// Consumer id for acknowledged delivery
+    consumer: Option<String>,
+    /// Replay the events following this event id
+    since: Option<String>,
+}
+
+/// Acknowledgement request
+#[derive(Deserialize)]
+pub struct AckRequest {
+    channel: String,
+    consumer: String,
+    /// Acknowledged event ids
+    ids: Vec<String>,
+}
+
+impl SubscribeParams {
+    fn from_query(query: &str) -> Result<Self> {
+        let params = form_urlencoded::parse(query.as_bytes()).try_fold(
+            Self::default(),
+            |mut params, (k, v)| {
+                match k.as_ref() {
+                    "api_key" => params.api_key = Some(v.into_owned()),
+                    "channel" => params.channels.push(v.into_owned()),
+                    "event" => {
+                        params.events.insert(v.into_owned());
+                    }
+                    "filter" => params.filter = Some(Filter::parse(&v)?),
+                    "format" => params.format = Some(v.parse()?),
+                    "exp" => {
+                        params.exp = Some(v.parse().map_err(|_| {
+                            Error::InvalidRequest(format!("Invalid expiration time '{v}'"))
+                        })?)
+                    }
+                    "sig" => params.sig = Some(v.into_owned()),
+                    "group" if v.is_empty() => {
+                        return Err(Error::InvalidRequest("Empty consumer group".into()))
+                    }
+                    "group" => params.group = Some(v.into_owned()),
+                    "group_key" => params.group_key = Some(v.into_owned()),
+                    "consumer" => params.consumer = Some(v.into_owned()),
+                    "since" => params.since = Some(v.into_owned()),
+                    _ => (),
+                }
+                Ok(params)
+            },
+        )?;
+        if params.group_key.is_some() && params.group.is_none() {
+            return Err(Error::InvalidRequest(
+                "'group_key' requires a consumer group".into(),
+            ));
+        }
+        Ok(params)
+    }
 }
 
 #[derive(Default)]
 pub struct Broadcaster {
     buffer_size: usize,
+    /// Maximum number of concurrent sends
+    concurrency: usize,
+    connections: Connections,
+    groups: ConsumerGroups,
+    metrics: Metrics,
+    status: PoolStatus,
     subs: Subscriptions,
-    allowed_subscriptions: HashMap<String, ChanId>,
-    pending_subscriptions: RefCell<Vec<Channel>>,
+    registry: ChannelRegistry,
+    /// Generation of the registry used for
+    /// the allowed subscriptions
+    generation: Cell<u64>,
+    allowed_subscriptions: RefCell<HashMap<String, Rc<Subscription>>>,
 }
 
 // Handlers
 impl Broadcaster {
     /// Subscrible handler
+    ///
+    /// Multiple channels may be requested either as a comma separated
+    /// list of channels ids or with `channel` query parameters.
     pub async fn do_subscribe(req: HttpRequest, bc: web::Data<Rc<Self>>) -> Result<impl Responder> {
-        let channel = req.match_info().query("id");
+        if let Some(limiter) = req
+            .app_data::<web::Data<RateLimits>>()
+            .and_then(|limits| limits.subscribe.as_ref())
+        {
+            limiter.check(&req)?;
+        }
+        let mut params = SubscribeParams::from_query(req.query_string())?;
+        let channels = std::mem::take(&mut params.channels);
+
+        let mut ids = Vec::<&str>::new();
+        req.match_info()
+            .query("id")
+            .split(',')
+            .chain(channels.iter().map(String::as_str))
+            .filter(|id| !id.is_empty())
+            .for_each(|id| {
+                if !ids.contains(&id) {
+                    ids.push(id)
+                }
+            });
+
+        bc.sync_subscriptions();
+
+        let mut subs = Vec::<Rc<Subscription>>::new();
+        for id in &ids {
+            let sub = bc.subscription(id).ok_or(Error::SubscriptionNotFound)?;
+            // A channel may be requested by id and alias
+            if !subs.iter().any(|s| s.id == sub.id) {
+                subs.push(sub);
+            }
+        }
+
+        if subs.is_empty() {
+            return Err(Error::SubscriptionNotFound);
+        }
+
+        // Signed urls replace api keys
+        let signed = match (params.exp, params.sig.as_deref()) {
+            (None, None) => false,
+            (Some(exp), Some(sig)) => {
+                req.app_data::<web::Data<UrlSigner>>()
+                    .ok_or(Error::Forbidden)?
+                    .verify(&ids, exp, sig)?;
+                true
+            }
+            _ => {
+                return Err(Error::InvalidRequest(
+                    "Signed urls require both 'exp' and 'sig'".into(),
+                ))
+            }
+        };
+
+        let client_ip = req
+            .app_data::<web::Data<TrustedProxies>>()
+            .and_then(|proxies| proxies.client_ip(&req));
+        for sub in &subs {
+            sub.check_ip(client_ip)?;
+        }
+
+        // Signed urls are not forwarded to the auth service
+        let identity = match req.app_data::<web::Data<ForwardAuth>>() {
+            Some(forward_auth) if !signed => forward_auth.check(&req).await?,
+            _ => None,
+        };
+
+        let scopes = if signed {
+            None
+        } else {
+            Self::scopes(&req, &subs).await?
+        };
+
+        let peer_identity = req.conn_data::<PeerIdentity>();
+        for sub in &subs {
+            if !signed {
+                sub.check_credentials(&req, &params, scopes.as_ref())?;
+            }
+            sub.check_identity(peer_identity)?;
+        }
+
+        for sub in &subs {
+            sub.check_backend(&bc.status)?;
+        }
+
+        if let Some(sub) = subs
+            .iter()
+            .find(|sub| sub.ack.is_some() && params.consumer.is_none())
+        {
+            return Err(Error::InvalidRequest(format!(
+                "Channel '{}' requires a 'consumer' parameter",
+                sub.name
+            )));
+        }
+
+        // Requested events must be allowed by at least
+        // one of the channels
+        if let Some(event) = params
+            .events
+            .iter()
+            .find(|e| !subs.iter().any(|sub| sub.is_allowed(e)))
+        {
+            return Err(Error::InvalidRequest(format!(
+                "Event '{event}' is not allowed"
+            )));
+        }
+
+        bc.new_channel(&req, subs, params, identity).await
+    }
+
+    /// Acknowledgement handler
+    ///
+    /// Acknowledged events are not redelivered to the consumer.
+    pub async fn do_ack(
+        req: HttpRequest,
+        bc: web::Data<Rc<Self>>,
+        acks: web::Data<Acks>,
+        body: web::Json<AckRequest>,
+    ) -> Result<HttpResponse> {
+        let params = SubscribeParams::from_query(req.query_string())?;
+
+        bc.sync_subscriptions();
+        let sub = bc
+            .subscription(&body.channel)
+            .ok_or(Error::SubscriptionNotFound)?;
+        if sub.ack.is_none() {
+            return Err(Error::InvalidRequest(format!(
+                "Channel '{}' does not use acknowledgements",
+                sub.name
+            )));
+        }
 
-        match bc.allowed_subscriptions.get(channel) {
-            Some(id) => bc.new_channel(&req, channel, *id).await,
-            None => Err(Error::SubscriptionNotFound),
+        Self::authorize(&req, &sub, &params).await?;
+
+        let count = acks.ack(sub.id, &sub.name, &body.consumer, &body.ids);
+        log::debug!(
+            "ACK({}) {count} event(s) for consumer '{}' (request: '{}')",
+            sub.name,
+            body.consumer,
+            request_id(&req),
+        );
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// History handler
+    ///
+    /// Return the range of the events available for replay.
+    pub async fn do_history(
+        req: HttpRequest,
+        bc: web::Data<Rc<Self>>,
+        history: web::Data<History>,
+    ) -> Result<HttpResponse> {
+        let params = SubscribeParams::from_query(req.query_string())?;
+
+        bc.sync_subscriptions();
+        let sub = bc
+            .subscription(req.match_info().query("id"))
+            .filter(|sub| sub.history)
+            .ok_or(Error::SubscriptionNotFound)?;
+
+        Self::authorize(&req, &sub, &params).await?;
+
+        Ok(HttpResponse::Ok().json(history.range(sub.id)))
+    }
+
+    /// Check that the request is allowed to access
+    /// the channel `sub` outside of a subscription
+    async fn authorize(
+        req: &HttpRequest,
+        sub: &Rc<Subscription>,
+        params: &SubscribeParams,
+    ) -> Result<()> {
+        let client_ip = req
+            .app_data::<web::Data<TrustedProxies>>()
+            .and_then(|proxies| proxies.client_ip(req));
+        sub.check_ip(client_ip)?;
+        let scopes = Self::scopes(req, std::slice::from_ref(sub)).await?;
+        sub.check_credentials(req, params, scopes.as_ref())?;
+        sub.check_identity(req.conn_data::<PeerIdentity>())
+    }
+
+    /// Return the scopes of the bearer token of the request
+    ///
+    /// Bearer tokens are introspected only if required
+    /// by one of the channels
+    async fn scopes(req: &HttpRequest, subs: &[Rc<Subscription>]) -> Result<Option<Scopes>> {
+        match (bearer_token(req), req.app_data::<web::Data<Introspector>>()) {
+            (Some(token), Some(introspector)) if subs.iter().any(|sub| !sub.scopes.is_empty()) => {
+                Ok(Some(
+                    introspector
+                        .introspect(token)
+                        .await?
+                        .ok_or(Error::Unauthorized)?,
+                ))
+            }
+            _ => Ok(None),
         }
     }
 }
 
 impl Broadcaster {
     /// Crate new Broadcaster
-    pub fn new(buffer_size: usize, channels: Vec<String>) -> Self {
-        Self {
+    pub fn new(
+        buffer_size: usize,
+        concurrency: usize,
+        registry: ChannelRegistry,
+        connections: Connections,
+        metrics: Metrics,
+        status: PoolStatus,
+        groups: ConsumerGroups,
+    ) -> Self {
+        let this = Self {
             buffer_size,
-            allowed_subscriptions: channels
-                .into_iter()
-                .enumerate()
-                .map(|(i, s)| (s, i))
-                .collect(),
+            concurrency: concurrency.max(1),
+            connections,
+            groups,
+            metrics,
+            status,
+            registry,
             ..Self::default()
+        };
+        this.sync_subscriptions();
+        this
+    }
+
+    /// Return the allowed subscription for the channel `id`
+    fn subscription(&self, id: &str) -> Option<Rc<Subscription>> {
+        self.allowed_subscriptions.borrow().get(id).cloned()
+    }
+
+    /// Update allowed subscriptions from the channel registry
+    ///
+    /// Subscribers of removed channels are disconnected.
+    fn sync_subscriptions(&self) {
+        if self.generation.get() == self.registry.generation() {
+            return;
         }
+        let mut subs = self.subs.borrow_mut();
+        let (generation, channels) = self.registry.snapshot();
+        let mut allowed = self.allowed_subscriptions.borrow_mut();
+        let previous = std::mem::take(&mut *allowed);
+        *allowed = channels
+            .into_iter()
+            .flat_map(|entry| {
+                let sub = previous
+                    .get(&entry.conf.id)
+                    .filter(|sub| sub.id == entry.chan_id && sub.version == entry.version)
+                    .cloned()
+                    .unwrap_or_else(|| Rc::new(Subscription::new(&entry, &self.metrics)));
+                // Aliases resolve to the same subscription
+                iter::once(entry.conf.id)
+                    .chain(entry.conf.aliases)
+                    .map(move |id| (id, sub.clone()))
+            })
+            .collect();
+        subs.retain(|chan_id, pool| {
+            let keep = allowed.values().any(|sub| sub.id == *chan_id);
+            if !keep {
+                pool.iter()
+                    .for_each(|chan| chan.session.close(CloseReason::ChannelRemoved));
+            }
+            keep
+        });
+        self.generation.set(generation);
     }
 
     /// Create a new communication channel and register it
+    ///
+    /// The slow consumer policy is taken from the first
+    /// subscribed channel.
+    ///
+    /// `identity` is the subscriber identity returned
+    /// by the forward auth service.
     async fn new_channel(
         &self,
         req: &HttpRequest,
-        path: &str,
-        id: ChanId,
+        subs: Vec<Rc<Subscription>>,
+        params: SubscribeParams,
+        identity: Option<String>,
     ) -> Result<impl Responder> {
+        let path = subs
+            .iter()
+            .map(|sub| sub.name.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        let (policy, timeout) = (subs[0].policy, subs[0].timeout);
+
+        // The client certificate identity and the forward
+        // auth identity take precedence over the `X-Identity` header
         let client_id: Option<String> = req
-            .headers()
-            .get("X-Identity")
-            .map(|s| s.to_str().unwrap().into());
+            .conn_data::<PeerIdentity>()
+            .map(|PeerIdentity(id)| id.clone())
+            .or(identity)
+            .or_else(|| {
+                req.headers()
+                    .get("X-Identity")
+                    .map(|s| s.to_str().unwrap().into())
+            });
 
-        let connection_info = req.connection_info();
-        let realip_remote_addr = connection_info.realip_remote_addr().map(String::from);
-        let peer_addr = connection_info.peer_addr().map(String::from);
+        let realip_remote_addr = req
+            .app_data::<web::Data<TrustedProxies>>()
+            .and_then(|proxies| proxies.client_ip(req))
+            .map(|ip| ip.to_string());
+        let peer_addr = req.connection_info().peer_addr().map(String::from);
 
-        let (tx, rx) = sse::channel(self.buffer_size);
+        let remote_ip = realip_remote_addr.clone();
+        let mut guard = self.connections.acquire(remote_ip.as_deref())?;
+
+        let (tx, rx) = mpsc::channel(self.buffer_size);
+        let subscribers = SubscribersGuard::new(subs.clone());
+        let ident = Uuid::new_v4();
+        let request_id = request_id(req);
+        let session = Rc::new(Session::start(
+            ident,
+            request_id.clone(),
+            path.clone(),
+            client_id.as_deref(),
+            realip_remote_addr.as_deref(),
+            peer_addr.as_deref(),
+        ));
+        let (group_guard, group) = match params.group {
+            Some(name) => {
+                let settings = GroupSettings {
+                    key: params.group_key,
+                    events: params.events.clone(),
+                    filter: params.filter.clone(),
+                };
+                let guard = self.groups.join(
+                    subs.iter().map(|sub| sub.id).collect(),
+                    &name,
+                    &settings,
+                    ident,
+                )?;
+                (
+                    Some(guard),
+                    Some(GroupMember::new(
+                        self.groups.clone(),
+                        name,
+                        settings.key,
+                        ident,
+                    )),
+                )
+            }
+            None => (None, None),
+        };
+        let since = params.since;
+        let consumer = params
+            .consumer
+            .zip(req.app_data::<web::Data<Acks>>())
+            .map(|(consumer, acks)| (consumer, acks.get_ref().clone()));
         let chan = Channel {
-            id,
-            path: path.into(),
-            ident: Uuid::new_v4(),
+            subs,
+            path,
+            ident,
             sender: tx,
             //timestamp: SystemTime::now()
             //    .duration_since(SystemTime::UNIX_EPOCH)?
@@ -109,135 +1219,231 @@ impl Broadcaster {
             realip_remote_addr,
             peer_addr,
             client_id,
+            events: params.events,
+            filter: params.filter,
+            format: params.format,
+            group,
+            consumer,
+            policy,
+            timeout,
+            connections: self.connections.clone(),
+            session: session.clone(),
+            request_id,
         };
 
+        guard.register(SubscriberInfo {
+            id: chan.ident,
+            channels: chan.subs.iter().map(|sub| sub.name.clone()).collect(),
+            client_id: chan.client_id.clone(),
+            remote_ip,
+            connected_at: timestamp_millis(),
+            dropped: 0,
+        });
+
         log::info!(
-            "SUBSCRIBE({path},{}) <{}> (peer: '{}')",
+            "SUBSCRIBE({},{}) <{}> (peer: '{}', request: '{}')",
+            chan.path,
             chan.client_id_str(),
             chan.realip_remote_addr().unwrap_or(""),
             chan.peer_addr().unwrap_or(""),
+            chan.request_id,
         );
 
-        // Add channel to pool
-        // We cannot be sure that the
-        // the collection is not actually borrowed
-        // while broadcasting, prevent panicking.
-        match self.subs.try_borrow_mut() {
-            Ok(mut subs) => match subs.get_mut(&chan.id) {
-                Some(pool) => pool.push(chan),
-                None => {
-                    subs.insert(chan.id, vec![chan]);
-                }
-            },
-            Err(_) => {
-                // Add to pending suscriptions
-                self.pending_subscriptions.borrow_mut().push(chan)
-            }
+        // Unacknowledged and replayed events are
+        // sent before any new event
+        let mut backlog = chan.redelivered().await;
+        if !backlog.is_empty() {
+            log::info!(
+                "Redelivering {} event(s) to {} (request: '{}')",
+                backlog.len(),
+                chan.ident,
+                chan.request_id,
+            );
+        }
+        let since = since.or_else(|| {
+            req.headers()
+                .get("Last-Event-ID")
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        });
+        let replayed = match since.as_deref().zip(req.app_data::<web::Data<History>>()) {
+            Some((since, history)) => chan.replayed(history, since).await,
+            None => VecDeque::new(),
+        };
+        if !replayed.is_empty() {
+            log::info!(
+                "Replaying {} event(s) to {} (request: '{}')",
+                replayed.len(),
+                chan.ident,
+                chan.request_id,
+            );
+            backlog.extend(replayed);
         }
 
-        Ok(rx)
+        // Add channel to pool
+        Self::register(&mut self.subs.borrow_mut(), Rc::new(chan));
+
+        // Stream the serialized SSE frames, the response is
+        // not forced to identity encoding so that it may be
+        // compressed
+        Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+            .streaming(EventStream {
+                rx,
+                backlog,
+                batches: Vec::new(),
+                _guard: guard,
+                _group: group_guard,
+                subscribers,
+                session: SessionGuard(session),
+            }))
     }
 
-    /// Resolve pendings subscriptions that
-    /// occured when adding new subscriptions
-    fn resolve_pending_subscriptions(&self) {
-        let mut pendings = self.pending_subscriptions.replace(Vec::new());
-        if !pendings.is_empty() {
-            log::info!("Resolving {} pending subscriptions", pendings.len());
-            // Collect all pendings subcriptions
-            let mut subs = self.subs.borrow_mut();
-            pendings
-                .drain(..)
-                .for_each(|chan| match subs.get_mut(&chan.id) {
-                    Some(pool) => pool.push(chan),
-                    None => {
-                        subs.insert(chan.id, vec![chan]);
-                    }
-                });
-        }
+    /// Add channel to the pool of each
+    /// of its subscribed channels
+    fn register(subs: &mut HashMap<ChanId, Rc<Vec<Rc<Channel>>>>, chan: Rc<Channel>) {
+        chan.subs
+            .iter()
+            .for_each(|sub| Rc::make_mut(subs.entry(sub.id).or_default()).push(chan.clone()));
     }
 
     /// Send event to subscribers
-    async fn send_event(chan: &Channel, event: &Event) -> Option<Uuid> {
-        let result = chan
-            .sender
-            .send(
-                sse::Data::new(event.payload())
-                    .id(event.id())
-                    .event(event.event()),
-            )
-            .await;
-
-        let ok = result.is_ok();
+    async fn send_event(
+        chan: &Channel,
+        id: ChanId,
+        event: &Event,
+        frames: &Frames,
+    ) -> Option<Uuid> {
+        let sub = chan.subscription(id)?;
+        if !chan.accept(sub, event) {
+            return None;
+        }
+        if let (Some(conf), Some((consumer, acks)), false) =
+            (&sub.ack, &chan.consumer, event.is_status())
+        {
+            acks.delivered(id, &sub.name, consumer, event, conf);
+        }
+        let ok = chan.send(sub, event, frames).await;
         if !ok {
             let ident = chan.ident;
             log::info!(
-                "Connection closed for {ident} '{}' <{}> (peer: '{}')",
+                "Connection closed for {ident} '{}' <{}> (peer: '{}', dropped: {}, request: '{}')",
                 chan.client_id_str(),
                 chan.realip_remote_addr().unwrap_or(""),
                 chan.peer_addr().unwrap_or(""),
+                chan.connections.dropped(&ident),
+                chan.request_id,
             );
             Some(ident)
         } else {
             log::debug!(
-                "SEND({},{}) {}: {}",
+                "SEND({},{}) {}: {} (request: '{}')",
                 chan.path,
                 event.session_pid(),
                 event.event(),
-                event.id()
+                event.id(),
+                chan.request_id,
             );
             None
         }
     }
 
-    #[allow(clippy::await_holding_refcell_ref)]
+    /// Send the event to the subscribers of its channels
+    ///
+    /// The subscribers are taken from a snapshot of the channel
+    /// pools, so that no borrow is held across the sends and
+    /// new subscriptions are registered immediately.
     async fn broadcast_event(&self, event: &Event) {
-        // We hold the borrow accross the await call
-        // this may lead to potential problem because
-        // we can do a mutable borrow during the execution
-        // of the futures.
-        //
-        // This should be ok as long as in every other place where we
-        // perform a mutable borrow we use the `try_borrow_mut()`
-        // method to ensure availability.
-        let res = {
+        let pools = {
             let subs = self.subs.borrow();
-            future::join_all(
-                event
-                    .channels()
+            event
+                .channels()
+                .iter()
+                .filter_map(|channel| subs.get(channel).map(|pool| (*channel, pool.clone())))
+                .collect::<Vec<_>>()
+        };
+
+        // At most `concurrency` subscribers are sent the event
+        // concurrently, and the worker yields after each batch
+        // of sends so that a large channel does not starve
+        // the other tasks.
+        let frames = Frames::default();
+        let mut res = HashSet::new();
+        {
+            let mut sends = stream::iter(
+                pools
                     .iter()
-                    .filter_map(|channel| subs.get(channel))
-                    .flat_map(|pool| pool.iter())
-                    .map(|chan| Self::send_event(chan, event)),
+                    .flat_map(|(channel, pool)| pool.iter().map(move |chan| (*channel, chan))),
             )
-            .await
+            .map(|(channel, chan)| {
+                Self::send_event(chan, channel, event, &frames).instrument(tracing::info_span!(
+                    "sse_send",
+                    subscriber = %chan.ident,
+                    path = %chan.path,
+                ))
+            })
+            .buffer_unordered(self.concurrency);
+
+            let mut count = 0;
+            while let Some(closed) = sends.next().await {
+                res.extend(closed);
+                count += 1;
+                if count % self.concurrency == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
         }
-        .into_iter()
-        .flatten()
-        .collect::<HashSet<_>>();
+        // Release the snapshot before cleaning up
+        // so that pools are not copied on write
+        drop(pools);
 
         if !res.is_empty() {
             // Clean up dead connections
+            // Connections may be registered in other channels
+            // than the event's ones.
             let mut subs = self.subs.borrow_mut();
-            event.channels().iter().for_each(|channel| {
-                if let Some(pool) = subs.get_mut(channel) {
-                    pool.retain(|chan| {
+            subs.values_mut()
+                .filter(|pool| pool.iter().any(|chan| res.contains(&chan.ident)))
+                .for_each(|pool| {
+                    Rc::make_mut(pool).retain(|chan| {
                         let closed = res.contains(&chan.ident);
                         if closed {
                             log::debug!("Cleaning closed connection: {:?}", chan.ident);
                         }
                         !closed
                     })
-                }
-            })
+                })
         }
     }
 
     /// Broadcast event to all listener of the subscription `id`
     pub async fn broadcast(&self, event: &Event) {
-        self.broadcast_event(event).await;
+        if !event.channels().is_empty() {
+            let span = tracing::info_span!("broadcast");
+            event.trace_context().attach(&span);
+            self.broadcast_event(event).instrument(span).await;
+            self.metrics
+                .observe_latency(timestamp_millis().saturating_sub(event.timestamp()));
+        }
+
+        self.sync_subscriptions()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Resolve pendings subscriptions
-        self.resolve_pending_subscriptions()
+    #[test]
+    fn sse_frames() {
+        assert_eq!(
+            sse_frame(Some("1"), "foo", "bar"),
+            "id: 1\nevent: foo\ndata: bar\n\n"
+        );
+        assert_eq!(
+            sse_frame(None, "_status", "{\n}"),
+            "event: _status\ndata: {\ndata: }\n\n"
+        );
     }
 }
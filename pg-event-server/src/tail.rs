@@ -0,0 +1,187 @@
+//!
+//! Print the events of a channel
+//!
+//! Events are received either from the database of the channel,
+//! by listening its events like a `psql` `LISTEN` session, or from
+//! the subscription endpoint of a running server.
+//!
+//! Notifications received from the database are printed as is:
+//! the channel event mapping and payload formats are applied by
+//! the server only.
+//!
+use clap::Args;
+use futures::StreamExt;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::config::{ChannelConfig, Config};
+use crate::eventpattern::EventPattern;
+use crate::peer::SseParser;
+use crate::{Error, Result};
+
+#[derive(Debug, Args)]
+pub struct Tail {
+    /// Channel id
+    channel: String,
+    /// Path to the configuration file, for listening
+    /// to the channel database
+    #[arg(long, required_unless_present = "url")]
+    conf: Option<PathBuf>,
+    /// Url of a running server (i.e `http://localhost:4001`),
+    /// for subscribing to the channel
+    #[arg(long, conflicts_with = "conf")]
+    url: Option<String>,
+    /// Api key of the subscription
+    #[arg(long, requires = "url")]
+    api_key: Option<String>,
+    /// Event to print, may be repeated
+    #[arg(long = "event")]
+    events: Vec<String>,
+    /// Print events as json lines
+    #[arg(long)]
+    json: bool,
+}
+
+/// Printed event
+#[derive(Debug, Default, Serialize)]
+struct TailEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    event: String,
+    data: String,
+    /// Session of the notifying backend
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<i32>,
+}
+
+impl Tail {
+    pub async fn run(&self) -> Result<()> {
+        match (&self.url, &self.conf) {
+            (Some(url), _) => self.subscribe(url).await,
+            (None, Some(conf)) => self.listen(conf).await,
+            (None, None) => Err(Error::Config("Missing '--conf' or '--url'".into())),
+        }
+    }
+
+    fn print(&self, event: &TailEvent) {
+        if self.json {
+            match serde_json::to_string(event) {
+                Ok(line) => println!("{line}"),
+                Err(err) => log::error!("Failed to serialize event: {err}"),
+            }
+        } else {
+            println!("{}: {}", event.event, event.data);
+        }
+    }
+
+    /// Listen to the channel events on its database
+    async fn listen(&self, path: &Path) -> Result<()> {
+        let conf = Config::read(path, &Default::default())?;
+        let channel = conf
+            .settings
+            .channels
+            .iter()
+            .find(|c| c.id == self.channel || c.aliases.contains(&self.channel))
+            .ok_or_else(|| Error::Config(format!("Channel '{}' not found", self.channel)))?;
+        if channel.routed_only || channel.replication.is_some() || channel.peer.is_some() {
+            return Err(Error::Config(format!(
+                "Channel '{}' has no notification connection, use '--url'",
+                channel.id
+            )));
+        }
+        let events = self.listened_events(channel);
+        if events.is_empty() {
+            return Err(Error::Config(format!(
+                "Channel '{}' has no event name to listen, use '--event'",
+                channel.id
+            )));
+        }
+
+        let pgconfig = pg_client_config::load_config(channel.connection_string.as_deref())?;
+        let tls = channel
+            .postgres_tls
+            .as_ref()
+            .unwrap_or(&conf.settings.postgres_tls)
+            .make_tls_connect()?;
+        let mut listener = pg_event_listener::PgEventListener::connect(pgconfig, tls).await?;
+        for event in &events {
+            listener.listen(event).await?;
+        }
+        log::info!(
+            "Listening to {} (session {})",
+            events.join(", "),
+            listener.session_pid()
+        );
+        while let Some(notification) = listener.recv().await {
+            self.print(&TailEvent {
+                event: notification.channel().into(),
+                data: notification.payload().into(),
+                pid: Some(notification.process_id()),
+                ..Default::default()
+            });
+        }
+        log::warn!("Connection closed");
+        Ok(())
+    }
+
+    /// Return the listened events: the requested events or
+    /// the allowed event names of the channel
+    fn listened_events<'a>(&'a self, channel: &'a ChannelConfig) -> Vec<&'a str> {
+        if self.events.is_empty() {
+            channel
+                .allowed_events
+                .iter()
+                .filter_map(EventPattern::name)
+                .collect()
+        } else {
+            self.events.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// Subscribe to the channel on a running server
+    async fn subscribe(&self, url: &str) -> Result<()> {
+        let mut url = format!(
+            "{}/events/subscribe/{}",
+            url.trim_end_matches('/'),
+            self.channel.trim_start_matches('/')
+        );
+        if !self.events.is_empty() {
+            url.push('?');
+            url.push_str(
+                &form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(self.events.iter().map(|e| ("event", e)))
+                    .finish(),
+            );
+        }
+        let client = awc::Client::builder().disable_timeout().finish();
+        let mut request = client.get(&url);
+        if let Some(key) = &self.api_key {
+            request = request.insert_header(("Authorization", format!("Key {key}")));
+        }
+        let mut response = request
+            .send()
+            .await
+            .map_err(|err| Error::Config(format!("Failed to connect to {url}: {err}")))?;
+        if !response.status().is_success() {
+            return Err(Error::Config(format!(
+                "Subscription to {url} failed with status {}",
+                response.status()
+            )));
+        }
+        log::info!("Subscribed to {url}");
+        let mut parser = SseParser::default();
+        while let Some(chunk) = response.next().await {
+            let chunk = chunk.map_err(|err| Error::Config(format!("Stream error: {err}")))?;
+            for event in parser.feed(&chunk) {
+                self.print(&TailEvent {
+                    id: event.id,
+                    event: event.event,
+                    data: event.data,
+                    pid: None,
+                });
+            }
+        }
+        log::warn!("Stream closed by the server");
+        Ok(())
+    }
+}
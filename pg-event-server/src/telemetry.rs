@@ -0,0 +1,194 @@
+//!
+//! OpenTelemetry tracing
+//!
+//! The dispatch path is instrumented with spans:
+//!
+//! * `notification` - a notification received from postgres,
+//! * `channel_match` - selection of the channels for the notification,
+//! * `broadcast` - the event broadcast in a worker,
+//! * `sse_send` - the event sent to a subscriber.
+//!
+//! Spans are exported to an OTLP collector.
+//!
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::Span;
+
+use crate::{Error, Result};
+
+fn default_service_name() -> String {
+    env!("CARGO_PKG_NAME").into()
+}
+
+const fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+///
+/// Tracing configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+pub struct TracingConfig {
+    /// OTLP grpc endpoint of the collector
+    pub endpoint: String,
+    /// Service name reported to the collector
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Ratio of sampled notifications
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+    /// Export timeout
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl TracingConfig {
+    pub fn sanitize(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            return Err(Error::Config(format!(
+                "Tracing sample ratio must be between 0 and 1, found {}",
+                self.sample_ratio
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Trace context propagated with the events
+/// from the dispatcher to the workers
+#[derive(Debug, Default, Clone)]
+pub struct TraceContext {
+    #[cfg(feature = "otel")]
+    cx: opentelemetry::Context,
+}
+
+#[cfg(feature = "otel")]
+impl TraceContext {
+    /// Return the trace context of `span`
+    pub fn of(span: &Span) -> Self {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        Self { cx: span.context() }
+    }
+    /// Attach `span` to the trace context
+    pub fn attach(&self, span: &Span) {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        span.set_parent(self.cx.clone());
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+impl TraceContext {
+    pub fn of(_span: &Span) -> Self {
+        Self {}
+    }
+    pub fn attach(&self, _span: &Span) {}
+}
+
+/// Handle on the installed tracer
+///
+/// Pending spans are flushed when dropped.
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: opentelemetry_sdk::trace::TracerProvider,
+    // The exporter runs in its own runtime so that flushing
+    // does not depend on the main thread.
+    #[cfg(feature = "otel")]
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(err) = self.provider.shutdown() {
+            log::error!("Tracing shutdown error: {err}");
+        }
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Install the tracer
+///
+/// Spans are discarded if tracing is not configured,
+/// they would be forwarded to the log otherwise.
+pub fn init(conf: Option<&TracingConfig>) -> Result<Option<Telemetry>> {
+    match conf {
+        Some(conf) => start_exporter(conf).map(Some),
+        None => {
+            let _ = tracing::dispatcher::set_global_default(tracing::Dispatch::none());
+            Ok(None)
+        }
+    }
+}
+
+/// Install the OTLP exporter
+#[cfg(feature = "otel")]
+fn start_exporter(conf: &TracingConfig) -> Result<Telemetry> {
+    use opentelemetry::{trace::TracerProvider as _, KeyValue};
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{
+        runtime,
+        trace::{Sampler, TracerProvider},
+        Resource,
+    };
+    use tracing::Level;
+    use tracing_subscriber::{filter::Targets, layer::SubscriberExt, Layer};
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("telemetry")
+        .enable_all()
+        .build()?;
+
+    let provider = {
+        let _guard = rt.enter();
+        let exporter = SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(&conf.endpoint)
+            .with_timeout(conf.timeout)
+            .build()
+            .map_err(|err| Error::Config(format!("Invalid tracing configuration: {err}")))?;
+
+        TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                conf.sample_ratio,
+            ))))
+            .with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                conf.service_name.clone(),
+            )]))
+            .build()
+    };
+
+    // Only export our own spans: the exporter client is
+    // itself instrumented.
+    let subscriber = tracing_subscriber::registry().with(
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+            .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::TRACE)),
+    );
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|err| Error::Config(format!("Cannot install tracer: {err}")))?;
+
+    log::info!("Exporting traces to {}", conf.endpoint);
+    Ok(Telemetry {
+        provider,
+        runtime: Some(rt),
+    })
+}
+
+#[cfg(not(feature = "otel"))]
+fn start_exporter(conf: &TracingConfig) -> Result<Telemetry> {
+    Err(Error::Config(format!(
+        "Exporting traces to {} requires the 'otel' feature",
+        conf.endpoint
+    )))
+}
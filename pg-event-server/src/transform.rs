@@ -0,0 +1,107 @@
+//!
+//! Payload transformation templates
+//!
+//! The outgoing payload of a channel may be rendered from a
+//! [minijinja](https://docs.rs/minijinja) template, i.e. for
+//! removing internal columns or renaming fields:
+//!
+//! ```text
+//! {"id": {{ payload.id }}, "label": {{ payload.name|tojson }}}
+//! ```
+//!
+//! The template context holds the `payload` (the parsed json
+//! payload or the payload as a string), the postgres `event`
+//! name and the `channel` id.
+//!
+use minijinja::{context, Environment};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::Error;
+
+const TEMPLATE_NAME: &str = "payload";
+
+/// Compiled payload template
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    source: String,
+    env: Arc<Environment<'static>>,
+}
+
+impl TryFrom<String> for Template {
+    type Error = Error;
+
+    fn try_from(source: String) -> Result<Self, Error> {
+        let mut env = Environment::new();
+        env.add_template_owned(TEMPLATE_NAME, source.clone())
+            .map_err(|err| Error::Config(format!("Invalid transform template: {err}")))?;
+        Ok(Self {
+            source,
+            env: Arc::new(env),
+        })
+    }
+}
+
+impl PartialEq for Template {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Debug for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Template").field(&self.source).finish()
+    }
+}
+
+impl Template {
+    /// Render the payload of `event` for `channel`
+    ///
+    /// `json` is the payload parsed as json if valid.
+    pub fn render(
+        &self,
+        channel: &str,
+        event: &str,
+        payload: &str,
+        json: Option<&Value>,
+    ) -> Result<String, minijinja::Error> {
+        let payload = match json {
+            Some(json) => minijinja::Value::from_serialize(json),
+            None => minijinja::Value::from(payload),
+        };
+        self.env
+            .get_template(TEMPLATE_NAME)?
+            .render(context! { payload, event, channel })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_templates() {
+        let template = Template::try_from(
+            r#"{"id": {{ payload.id }}, "label": {{ payload.name|tojson }}, "on": "{{ channel }}/{{ event }}"}"#
+                .to_string(),
+        )
+        .unwrap();
+        let payload = r#"{"id": 1, "name": "foo \"bar\"", "internal": true}"#;
+        let json: Value = serde_json::from_str(payload).unwrap();
+        let rendered = template
+            .render("items", "update", payload, Some(&json))
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&rendered).unwrap(),
+            serde_json::json!({"id": 1, "label": "foo \"bar\"", "on": "items/update"})
+        );
+
+        let template = Template::try_from("<{{ payload }}>".to_string()).unwrap();
+        assert_eq!(template.render("c", "e", "text", None).unwrap(), "<text>");
+
+        assert!(Template::try_from("{{ payload".to_string()).is_err());
+    }
+}
@@ -1,5 +1,79 @@
 //! Utilities
+use actix_web::{http::header, HttpRequest};
+use ipnet::IpNet;
+use std::collections::HashSet;
 use std::iter;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Return the current time in milliseconds since epoch
+pub fn timestamp_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Return the host name of the machine
+pub fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: the buffer is valid for its length
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+            let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            return String::from_utf8_lossy(&buf[..len]).into_owned();
+        }
+    }
+    std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".into())
+}
+
+/// Return true if `name` is a plain sql identifier,
+/// optionally schema qualified
+pub fn is_table_name(name: &str) -> bool {
+    let mut parts = name.split('.');
+    parts.clone().count() <= 2
+        && parts.all(|part| {
+            part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+/// Return the key from the `Authorization` header
+/// using the `Key` scheme
+pub fn authorization_key(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Key "))
+        .map(str::trim)
+}
+
+/// Return the token from the `Authorization` header
+/// using the `Bearer` scheme
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Return true if `ip` is allowed by the `allow`
+/// and `deny` network lists
+///
+/// Denied networks take precedence, an unknown address
+/// is allowed only if `allow` is empty.
+pub fn is_ip_allowed(allow: &[IpNet], deny: &[IpNet], ip: Option<IpAddr>) -> bool {
+    match ip {
+        Some(ip) => {
+            !deny.iter().any(|net| net.contains(&ip))
+                && (allow.is_empty() || allow.iter().any(|net| net.contains(&ip)))
+        }
+        None => allow.is_empty(),
+    }
+}
 
 /// A simple readonly type for not allocating memory
 /// when we have only one element, which should be
@@ -72,3 +146,51 @@ impl<T> Values<T> {
     }
     */
 }
+
+/// Interning table for names
+///
+/// Each name is allocated once and then shared.
+#[derive(Default)]
+pub struct Interner(HashSet<Arc<str>>);
+
+impl Interner {
+    /// Return the shared instance of `name`
+    pub fn intern(&mut self, name: &str) -> Arc<str> {
+        match self.0.get(name) {
+            Some(name) => name.clone(),
+            None => {
+                let name: Arc<str> = name.into();
+                self.0.insert(name.clone());
+                name
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interned_names() {
+        let mut names = Interner::default();
+        let foo = names.intern("foo");
+        assert!(Arc::ptr_eq(&foo, &names.intern("foo")));
+        assert!(!Arc::ptr_eq(&foo, &names.intern("bar")));
+        assert_eq!(&*foo, "foo");
+    }
+
+    #[test]
+    fn ip_allow_deny() {
+        let allow: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let deny: Vec<IpNet> = vec!["10.1.0.0/16".parse().unwrap()];
+        let ip = |s: &str| s.parse::<IpAddr>().ok();
+
+        assert!(is_ip_allowed(&allow, &deny, ip("10.2.0.1")));
+        assert!(!is_ip_allowed(&allow, &deny, ip("10.1.0.1")));
+        assert!(!is_ip_allowed(&allow, &deny, ip("192.168.0.1")));
+        assert!(!is_ip_allowed(&allow, &deny, None));
+        assert!(is_ip_allowed(&[], &deny, ip("192.168.0.1")));
+        assert!(is_ip_allowed(&[], &deny, None));
+    }
+}
@@ -0,0 +1,117 @@
+//!
+//! JSON Schema validation of payloads
+//!
+//! Events of a channel with a `validation` section are validated
+//! against a JSON Schema before being broadcast: invalid events are
+//! dropped or sent to a dead-letter channel.
+//!
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::{Error, Result};
+
+///
+/// Validation configuration
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidationConfig {
+    /// Path of the JSON Schema
+    pub schema_file: PathBuf,
+    /// Channel receiving the invalid events
+    pub dead_letter: Option<String>,
+    /// Loaded schema
+    #[serde(skip)]
+    schema: Value,
+    /// Compiled schema
+    #[serde(skip)]
+    validator: Option<Arc<jsonschema::Validator>>,
+}
+
+impl PartialEq for ValidationConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.schema_file == other.schema_file
+            && self.dead_letter == other.dead_letter
+            && self.schema == other.schema
+    }
+}
+
+impl ValidationConfig {
+    /// Load the schema of the channel `id`
+    ///
+    /// Relative paths are resolved from `root`.
+    pub fn load(&mut self, root: &Path, id: &str) -> Result<()> {
+        if self.dead_letter.as_deref() == Some(id) {
+            return Err(Error::Config(format!(
+                "Channel '{id}': the dead-letter channel must be another channel"
+            )));
+        }
+        if !self.schema_file.has_root() {
+            self.schema_file = root.join(&self.schema_file);
+        }
+        let path = &self.schema_file;
+        log::debug!("Loading schema for channel {id} from {path:?}");
+        let schema: Value = fs::read_to_string(path)
+            .map_err(|err| Error::Config(format!("Failed to read schema file {path:?}: {err:?}")))
+            .and_then(|s| {
+                serde_json::from_str(&s).map_err(|err| {
+                    Error::Config(format!("Invalid json in schema file {path:?}: {err}"))
+                })
+            })?;
+        let validator = jsonschema::validator_for(&schema).map_err(|err| {
+            Error::Config(format!("Channel '{id}': invalid schema {path:?}: {err}"))
+        })?;
+        self.schema = schema;
+        self.validator = Some(Arc::new(validator));
+        Ok(())
+    }
+
+    /// Validate `payload`
+    ///
+    /// Return the validation error for invalid
+    /// or non json payloads.
+    pub fn validate(&self, payload: &str) -> std::result::Result<(), String> {
+        let validator = self.validator.as_ref().ok_or("schema not loaded")?;
+        let value: Value =
+            serde_json::from_str(payload).map_err(|err| format!("invalid json: {err}"))?;
+        validator
+            .validate(&value)
+            .map_err(|err| format!("{err} at '{}'", err.instance_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{confdir, setup};
+    use std::env;
+
+    #[test]
+    fn payload_validation() {
+        setup();
+        let mut conf: ValidationConfig = toml::from_str(
+            r#"
+            schema_file = "item.schema.json"
+            dead_letter = "invalid"
+            "#,
+        )
+        .unwrap();
+        assert!(conf.validate(r#"{"id": 1}"#).is_err());
+        conf.load(confdir!(""), "items").unwrap();
+
+        assert!(conf.validate(r#"{"id": 1, "name": "foo"}"#).is_ok());
+        assert!(conf.validate(r#"{"id": "1"}"#).is_err());
+        assert!(conf.validate("not json").is_err());
+
+        let mut conf: ValidationConfig = toml::from_str(
+            r#"
+            schema_file = "item.schema.json"
+            dead_letter = "items"
+            "#,
+        )
+        .unwrap();
+        assert!(conf.load(confdir!(""), "items").is_err());
+    }
+}